@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use surf::{StatusCode, Url};
+
+use crate::{
+    configuration::{EmailClientSettings, Secret},
+    email::{Email, EmailClient, SendError},
+    telemetry::pii,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Send emails through a Postmark compatible REST API.
+#[derive(Clone)]
+pub(crate) struct HttpEmailClient {
+    http: surf::Client,
+    base_url: Url,
+    sender: String,
+    authorization_token: Secret<String>,
+    timeout: Duration,
+}
+
+impl HttpEmailClient {
+    pub(crate) fn new(settings: &EmailClientSettings) -> Result<Self, surf::http::url::ParseError> {
+        Ok(Self {
+            http: surf::Client::new(),
+            base_url: Url::parse(&settings.base_url)?,
+            sender: settings.sender.clone(),
+            authorization_token: settings.authorization_token.clone(),
+            timeout: settings.timeout.unwrap_or(DEFAULT_TIMEOUT),
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+}
+
+#[async_trait::async_trait]
+impl EmailClient for HttpEmailClient {
+    #[tracing::instrument(
+        name = "Sending an email",
+        skip(self, email),
+        fields(
+            to = %pii::email(&email.to),
+        )
+    )]
+    async fn send(&self, email: &Email) -> Result<(), SendError> {
+        let url = self
+            .base_url
+            .join("email")
+            .map_err(|e| SendError::Permanent(e.to_string()))?;
+        let body = surf::Body::from_json(&SendEmailRequest {
+            from: email.from.as_deref().unwrap_or(&self.sender),
+            to: &email.to,
+            subject: &email.subject,
+            html_body: &email.html,
+            text_body: &email.text,
+        })
+        .map_err(|e| SendError::Permanent(e.to_string()))?;
+        let request = self
+            .http
+            .post(url.as_str())
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose().as_str(),
+            )
+            .body(body)
+            .send();
+        let response = async_std::future::timeout(self.timeout, request)
+            .await
+            .map_err(|_| SendError::Transient(format!("no response in {:?}", self.timeout)))?
+            .map_err(|e| SendError::Transient(e.to_string()))?;
+        check_status(response.status())
+    }
+}
+
+fn check_status(status: StatusCode) -> Result<(), SendError> {
+    if status.is_success() {
+        Ok(())
+    } else if status == StatusCode::TooManyRequests || status.is_server_error() {
+        Err(SendError::Transient(status.to_string()))
+    } else {
+        Err(SendError::Permanent(status.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest(status, transient,
+        case::rate_limited(StatusCode::TooManyRequests, true),
+        case::server_error(StatusCode::InternalServerError, true),
+        case::unavailable(StatusCode::ServiceUnavailable, true),
+        case::bad_request(StatusCode::BadRequest, false),
+        case::unauthorized(StatusCode::Unauthorized, false),
+        case::unprocessable(StatusCode::UnprocessableEntity, false),
+    )]
+    fn should_classify_failures(status: StatusCode, transient: bool) {
+        let error = check_status(status).unwrap_err();
+
+        assert_eq!(transient, matches!(error, SendError::Transient(_)));
+    }
+
+    #[test]
+    fn should_accept_success() {
+        assert_eq!(Ok(()), check_status(StatusCode::Ok));
+    }
+}
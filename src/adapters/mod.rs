@@ -1 +1,14 @@
+pub(crate) mod http_email_client;
+pub(crate) mod mongodb_admin_users;
+pub(crate) mod mongodb_analytics;
+pub(crate) mod mongodb_delivery_queue;
+pub(crate) mod mongodb_idempotency_store;
+pub(crate) mod mongodb_issues;
+pub(crate) mod mongodb_lists;
+pub(crate) mod mongodb_outbox;
 pub(crate) mod mongodb_repository;
+pub(crate) mod mongodb_schedules;
+pub(crate) mod mongodb_session_store;
+pub(crate) mod mongodb_tracking;
+pub(crate) mod mongodb_webhooks;
+pub(crate) mod smtp_email_client;
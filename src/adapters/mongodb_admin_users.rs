@@ -0,0 +1,53 @@
+use mongodb::{
+    bson::{doc, Document},
+    options::UpdateOptions,
+    Database,
+};
+
+use crate::{authentication::AdminUsersRepository, repository};
+
+const COLLECTION: &str = "admin_users";
+
+#[derive(Clone)]
+pub(crate) struct MongoAdminUsers {
+    db: Database,
+}
+
+impl MongoAdminUsers {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait::async_trait]
+impl AdminUsersRepository for MongoAdminUsers {
+    async fn password_hash(&self, username: &str) -> repository::Result<Option<String>> {
+        let found = self
+            .db
+            .collection::<Document>(COLLECTION)
+            .find_one(doc! { "_id": username }, None)
+            .await
+            .map_err(|e| repository::Error::QueryDb {
+                query_desc: format!("admin user '{}'", username),
+                source: Box::new(e),
+            })?;
+        Ok(found.and_then(|d| d.get_str("password_hash").ok().map(str::to_owned)))
+    }
+
+    #[tracing::instrument(name = "Saving an admin user", skip(self, password_hash))]
+    async fn save(&self, username: &str, password_hash: &str) -> repository::Result<()> {
+        self.db
+            .collection::<Document>(COLLECTION)
+            .update_one(
+                doc! { "_id": username },
+                doc! { "$set": { "password_hash": password_hash } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("admin user '{}'", username),
+                source: Box::new(e),
+            })?;
+        Ok(())
+    }
+}
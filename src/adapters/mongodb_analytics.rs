@@ -0,0 +1,387 @@
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::ReplaceOptions,
+    Collection, Database,
+};
+
+use super::{mongodb_delivery_queue as queue, mongodb_repository as users, mongodb_tracking};
+use crate::{
+    analytics::{self, Analytics, Hour, LinkClicks, Report, Totals},
+    email_events::{BounceType, EventKind},
+    repository,
+    tracking::Kind,
+};
+
+/// The rollups: `_id` is the id of the issue
+const COLLECTION: &str = "issue_analytics";
+
+/// What the aggregations look the events of an issue up by
+const INDEXES: &[(&str, &str, &str)] = &[
+    (queue::COLLECTION, "email.issue_id", "email.issue_id_1"),
+    (mongodb_tracking::COLLECTION, "issue_id", "issue_id_1"),
+    (users::EVENTS, "email", "email_1"),
+    (users::CHANGES, "email", "email_1"),
+];
+
+#[derive(Clone)]
+pub(crate) struct MongoAnalytics {
+    db: Database,
+}
+
+impl MongoAnalytics {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.db.collection::<Document>(COLLECTION)
+    }
+
+    pub(crate) async fn ensure_indexes(&self) -> mongodb::error::Result<()> {
+        for &(collection, key, name) in INDEXES {
+            self.db
+                .run_command(
+                    doc! {
+                        "createIndexes": collection,
+                        "indexes": [{ "key": { (key): 1 }, "name": name }],
+                    },
+                    None,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The only document `pipeline` on `collection` results in: empty if none.
+    async fn aggregate_one(
+        &self,
+        collection: &str,
+        pipeline: Vec<Document>,
+    ) -> repository::Result<Document> {
+        let query_err = |e: mongodb::error::Error| repository::Error::QueryDb {
+            query_desc: format!("analytics on {}", collection),
+            source: Box::new(e),
+        };
+        let mut cursor = self
+            .db
+            .collection::<Document>(collection)
+            .aggregate(pipeline, None)
+            .await
+            .map_err(query_err)?;
+        Ok(cursor
+            .try_next()
+            .await
+            .map_err(query_err)?
+            .unwrap_or_default())
+    }
+}
+
+/// Count the delivery jobs of issue `id` of `list`, with the recipients who bounced
+/// or left `list` between `since` and `until`.
+fn deliveries(id: &str, list: &str, since: DateTime<Utc>, until: DateTime<Utc>) -> Vec<Document> {
+    let within = |field: &str| {
+        doc! { "$and": [
+            { "$gte": [field, since] },
+            { "$lt": [field, until] },
+        ] }
+    };
+    let bounces: Vec<_> = [BounceType::Hard, BounceType::Soft]
+        .iter()
+        .map(|&bounce_type| EventKind::Bounce { bounce_type }.code())
+        .collect();
+    let count = |condition: Document| doc! { "$sum": { "$cond": [condition, 1, 0] } };
+    vec![
+        doc! { "$match": { "email.issue_id": id } },
+        doc! { "$lookup": {
+            "from": users::EVENTS,
+            "let": { "email": "$email.to" },
+            "pipeline": [
+                { "$match": { "$expr": { "$and": [
+                    { "$eq": ["$email", "$$email"] },
+                    { "$in": ["$type", bounces] },
+                    within("$occurred_at"),
+                ] } } },
+                { "$limit": 1 },
+            ],
+            "as": "bounces",
+        } },
+        doc! { "$lookup": {
+            "from": users::CHANGES,
+            "let": { "email": "$email.to" },
+            "pipeline": [
+                { "$match": { "$expr": { "$and": [
+                    { "$eq": ["$email", "$$email"] },
+                    { "$eq": ["$field", "lists"] },
+                    within("$at"),
+                    { "$in": [list, { "$split": ["$from", ","] }] },
+                    { "$not": [{ "$in": [list, { "$split": ["$to", ","] }] }] },
+                ] } } },
+                { "$limit": 1 },
+            ],
+            "as": "unsubscribes",
+        } },
+        doc! { "$group": {
+            "_id": Bson::Null,
+            "recipients": { "$sum": 1 },
+            "delivered": count(doc! { "$eq": ["$status", queue::DONE] }),
+            "failed": count(doc! { "$eq": ["$status", queue::DEAD] }),
+            "bounced": count(doc! { "$gt": [{ "$size": "$bounces" }, 0] }),
+            "unsubscribes": count(doc! { "$gt": [{ "$size": "$unsubscribes" }, 0] }),
+        } },
+    ]
+}
+
+/// Count the opens and clicks of issue `id` that don't come from bots: in total,
+/// by link and by hour.
+fn engagement(id: &str) -> Vec<Document> {
+    let (open, click) = (Kind::Open.code(), Kind::Click.code());
+    let unique = |kind: &str| {
+        vec![
+            doc! { "$match": { "kind": kind } },
+            doc! { "$group": { "_id": "$email" } },
+            doc! { "$count": "count" },
+        ]
+    };
+    let count = |kind: &str| doc! { "$sum": { "$cond": [{ "$eq": ["$kind", kind] }, 1, 0] } };
+    vec![
+        doc! { "$match": { "issue_id": id, "bot": false } },
+        doc! { "$facet": {
+            "opens": unique(open),
+            "clicks": unique(click),
+            "links": [
+                { "$match": { "kind": click } },
+                { "$group": {
+                    "_id": "$url",
+                    "clicks": { "$sum": 1 },
+                    "emails": { "$addToSet": "$email" },
+                } },
+                { "$project": { "clicks": 1, "unique_clicks": { "$size": "$emails" } } },
+                { "$sort": { "clicks": -1, "_id": 1 } },
+                { "$limit": analytics::TOP_LINKS },
+            ],
+            "hours": [
+                { "$group": {
+                    "_id": { "$dateFromParts": {
+                        "year": { "$year": "$at" },
+                        "month": { "$month": "$at" },
+                        "day": { "$dayOfMonth": "$at" },
+                        "hour": { "$hour": "$at" },
+                    } },
+                    "opens": count(open),
+                    "clicks": count(click),
+                } },
+                { "$sort": { "_id": 1 } },
+            ],
+        } },
+    ]
+}
+
+/// `$sum` makes an `Int32` or an `Int64` depending on the total.
+fn number(doc: &Document, key: &str) -> u64 {
+    match doc.get(key) {
+        Some(Bson::Int32(n)) => *n as u64,
+        Some(Bson::Int64(n)) => *n as u64,
+        _ => 0,
+    }
+}
+
+fn documents<'a>(doc: &'a Document, key: &str) -> impl Iterator<Item = &'a Document> {
+    doc.get_array(key)
+        .into_iter()
+        .flatten()
+        .filter_map(Bson::as_document)
+}
+
+/// The distinct count of a `$count` facet.
+fn distinct(facets: &Document, key: &str) -> u64 {
+    documents(facets, key)
+        .next()
+        .map_or(0, |counted| number(counted, "count"))
+}
+
+fn rollup_doc(report: &Report) -> Document {
+    let totals = &report.totals;
+    let links: Vec<_> = report
+        .top_links
+        .iter()
+        .map(|link| {
+            doc! {
+                "url": &link.url,
+                "clicks": link.clicks as i64,
+                "unique_clicks": link.unique_clicks as i64,
+            }
+        })
+        .collect();
+    let hours: Vec<_> = report
+        .hours
+        .iter()
+        .map(|hour| {
+            doc! {
+                "hour": hour.hour,
+                "opens": hour.opens as i64,
+                "clicks": hour.clicks as i64,
+            }
+        })
+        .collect();
+    doc! {
+        "_id": &report.issue_id,
+        "list": &report.list,
+        "recipients": totals.recipients as i64,
+        "delivered": totals.delivered as i64,
+        "failed": totals.failed as i64,
+        "bounced": totals.bounced as i64,
+        "unique_opens": totals.unique_opens as i64,
+        "unique_clicks": totals.unique_clicks as i64,
+        "unsubscribes": totals.unsubscribes as i64,
+        "top_links": links,
+        "hours": hours,
+        "computed_at": report.computed_at,
+    }
+}
+
+fn saved_rollup(doc: &Document) -> Option<Report> {
+    Some(Report {
+        issue_id: doc.get_str("_id").ok()?.to_owned(),
+        list: doc.get_str("list").ok()?.to_owned(),
+        totals: Totals {
+            recipients: number(doc, "recipients"),
+            delivered: number(doc, "delivered"),
+            failed: number(doc, "failed"),
+            bounced: number(doc, "bounced"),
+            unique_opens: number(doc, "unique_opens"),
+            unique_clicks: number(doc, "unique_clicks"),
+            unsubscribes: number(doc, "unsubscribes"),
+        },
+        top_links: documents(doc, "top_links")
+            .filter_map(|link| {
+                Some(LinkClicks {
+                    url: link.get_str("url").ok()?.to_owned(),
+                    clicks: number(link, "clicks"),
+                    unique_clicks: number(link, "unique_clicks"),
+                })
+            })
+            .collect(),
+        hours: documents(doc, "hours")
+            .filter_map(|hour| {
+                Some(Hour {
+                    hour: hour.get_datetime("hour").ok()?.to_chrono(),
+                    opens: number(hour, "opens"),
+                    clicks: number(hour, "clicks"),
+                })
+            })
+            .collect(),
+        computed_at: doc.get_datetime("computed_at").ok()?.to_chrono(),
+    })
+}
+
+#[async_trait::async_trait]
+impl Analytics for MongoAnalytics {
+    async fn rollup(&self, id: &str) -> repository::Result<Option<Report>> {
+        let found = self
+            .collection()
+            .find_one(doc! { "_id": id }, None)
+            .await
+            .map_err(|e| repository::Error::QueryDb {
+                query_desc: format!("rollup of issue {}", id),
+                source: Box::new(e),
+            })?;
+        Ok(found.as_ref().and_then(saved_rollup))
+    }
+
+    #[tracing::instrument(name = "Aggregating the analytics of an issue", skip(self))]
+    async fn aggregate(
+        &self,
+        id: &str,
+        list: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> repository::Result<Report> {
+        let deliveries = self
+            .aggregate_one(queue::COLLECTION, deliveries(id, list, since, until))
+            .await?;
+        let engagement = self
+            .aggregate_one(mongodb_tracking::COLLECTION, engagement(id))
+            .await?;
+        let report = Report {
+            issue_id: id.to_owned(),
+            list: list.to_owned(),
+            totals: Totals {
+                recipients: number(&deliveries, "recipients"),
+                delivered: number(&deliveries, "delivered"),
+                failed: number(&deliveries, "failed"),
+                bounced: number(&deliveries, "bounced"),
+                unique_opens: distinct(&engagement, "opens"),
+                unique_clicks: distinct(&engagement, "clicks"),
+                unsubscribes: number(&deliveries, "unsubscribes"),
+            },
+            top_links: documents(&engagement, "links")
+                .filter_map(|link| {
+                    Some(LinkClicks {
+                        url: link.get_str("_id").ok()?.to_owned(),
+                        clicks: number(link, "clicks"),
+                        unique_clicks: number(link, "unique_clicks"),
+                    })
+                })
+                .collect(),
+            hours: documents(&engagement, "hours")
+                .filter_map(|hour| {
+                    Some(Hour {
+                        hour: hour.get_datetime("_id").ok()?.to_chrono(),
+                        opens: number(hour, "opens"),
+                        clicks: number(hour, "clicks"),
+                    })
+                })
+                .collect(),
+            computed_at: now,
+        };
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.collection()
+            .replace_one(doc! { "_id": id }, rollup_doc(&report), options)
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("rollup of issue {}", id),
+                source: Box::new(e),
+            })?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn rollup_should_survive_the_round_trip() {
+        let report = Report {
+            issue_id: "5fd0".to_owned(),
+            list: "rust".to_owned(),
+            totals: Totals {
+                recipients: 3,
+                delivered: 2,
+                failed: 1,
+                bounced: 1,
+                unique_opens: 2,
+                unique_clicks: 1,
+                unsubscribes: 1,
+            },
+            top_links: vec![LinkClicks {
+                url: "https://example.com".to_owned(),
+                clicks: 2,
+                unique_clicks: 1,
+            }],
+            hours: vec![Hour {
+                hour: Utc.ymd(2020, 12, 9).and_hms(10, 0, 0),
+                opens: 3,
+                clicks: 2,
+            }],
+            computed_at: Utc.ymd(2020, 12, 9).and_hms(12, 0, 0),
+        };
+
+        assert_eq!(Some(report.clone()), saved_rollup(&rollup_doc(&report)));
+    }
+}
@@ -0,0 +1,270 @@
+use std::{collections::HashSet, time::Duration};
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Document},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    delivery::{self, DeadLetter, Job},
+    email::Email,
+    repository,
+};
+
+pub(super) const COLLECTION: &str = "issue_delivery_queue";
+
+const PENDING: &str = "pending";
+const IN_PROGRESS: &str = "in_progress";
+pub(super) const DONE: &str = "done";
+pub(super) const DEAD: &str = "dead";
+
+#[derive(Clone)]
+pub(crate) struct MongoDeliveryQueue {
+    db: Database,
+}
+
+impl MongoDeliveryQueue {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.db.collection::<Document>(COLLECTION)
+    }
+
+    /// The jobs to claim and the dead letters are found without scanning the queue.
+    pub(crate) async fn ensure_indexes(&self) -> mongodb::error::Result<()> {
+        self.db
+            .run_command(
+                doc! {
+                    "createIndexes": COLLECTION,
+                    "indexes": [
+                        {
+                            "key": { "status": 1, "next_attempt_at": 1 },
+                            "name": "status_1_next_attempt_at_1",
+                        },
+                        {
+                            "key": { "status": 1, "lease_until": 1 },
+                            "name": "status_1_lease_until_1",
+                        },
+                        {
+                            "key": { "status": 1, "updated_at": -1 },
+                            "name": "status_1_updated_at_-1",
+                        },
+                    ],
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Close `job` if nobody else claimed it in the meantime.
+    async fn close(&self, job: &Job, update: Document) -> repository::Result<()> {
+        let update_err =
+            |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::UpdateDb {
+                entry_desc: format!("delivery job {}", job.id),
+                source: e,
+            };
+        let id = ObjectId::parse_str(&job.id).map_err(|e| update_err(Box::new(e)))?;
+        self.collection()
+            .update_one(
+                doc! { "_id": id, "status": IN_PROGRESS, "attempts": job.attempts as i32 },
+                update,
+                None,
+            )
+            .await
+            .map_err(|e| update_err(Box::new(e)))?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JobDocument {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    email: Email,
+    status: String,
+    attempts: i32,
+    next_attempt_at: bson::DateTime,
+    #[serde(default)]
+    lease_until: Option<bson::DateTime>,
+    #[serde(default)]
+    last_error: Option<String>,
+    updated_at: bson::DateTime,
+}
+
+impl JobDocument {
+    fn id(&self) -> String {
+        self.id.map(ObjectId::to_hex).unwrap_or_default()
+    }
+}
+
+impl From<JobDocument> for Job {
+    fn from(d: JobDocument) -> Self {
+        Self {
+            id: d.id(),
+            attempts: d.attempts as u32,
+            email: d.email,
+        }
+    }
+}
+
+impl From<JobDocument> for DeadLetter {
+    fn from(d: JobDocument) -> Self {
+        Self {
+            id: d.id(),
+            attempts: d.attempts as u32,
+            last_error: d.last_error.unwrap_or_default(),
+            failed_at: d.updated_at.to_chrono(),
+            to: d.email.to,
+            subject: d.email.subject,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl delivery::DeliveryQueue for MongoDeliveryQueue {
+    #[tracing::instrument(name = "Enqueuing an email", skip(self, email))]
+    async fn enqueue(&self, email: Email) -> repository::Result<()> {
+        let insert_err =
+            |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::InsertDb {
+                entry_desc: format!("{:?}", &email),
+                source: e,
+            };
+        let now = Utc::now();
+        let doc = bson::to_document(&JobDocument {
+            id: None,
+            email: email.clone(),
+            status: PENDING.to_owned(),
+            attempts: 0,
+            next_attempt_at: now.into(),
+            lease_until: None,
+            last_error: None,
+            updated_at: now.into(),
+        })
+        .map_err(|e| insert_err(Box::new(e)))?;
+        self.collection()
+            .insert_one(doc, None)
+            .await
+            .map_err(|e| insert_err(Box::new(e)))?;
+        Ok(())
+    }
+
+    async fn claim(&self, lease: Duration) -> repository::Result<Option<Job>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: "claim a delivery job".to_owned(),
+            source: e,
+        };
+        let now = Utc::now();
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! { "next_attempt_at": 1 })
+            .return_document(ReturnDocument::After)
+            .build();
+        let claimed = self
+            .collection()
+            .find_one_and_update(
+                doc! { "$or": [
+                    { "status": PENDING, "next_attempt_at": { "$lte": now } },
+                    { "status": IN_PROGRESS, "lease_until": { "$lte": now } },
+                ] },
+                doc! {
+                    "$set": {
+                        "status": IN_PROGRESS,
+                        "lease_until": delivery::after(now, lease),
+                        "updated_at": now,
+                    },
+                    "$inc": { "attempts": 1 },
+                },
+                options,
+            )
+            .await
+            .map_err(|e| query_err(Box::new(e)))?;
+        claimed
+            .map(|d| {
+                bson::from_document::<JobDocument>(d)
+                    .map(Job::from)
+                    .map_err(|e| query_err(Box::new(e)))
+            })
+            .transpose()
+    }
+
+    async fn complete(&self, job: &Job) -> repository::Result<()> {
+        self.close(
+            job,
+            doc! { "$set": { "status": DONE, "updated_at": Utc::now() } },
+        )
+        .await
+    }
+
+    async fn retry(&self, job: &Job, at: DateTime<Utc>, error: &str) -> repository::Result<()> {
+        self.close(
+            job,
+            doc! { "$set": {
+                "status": PENDING,
+                "next_attempt_at": at,
+                "last_error": error,
+                "updated_at": Utc::now(),
+            } },
+        )
+        .await
+    }
+
+    async fn dead_letter(&self, job: &Job, error: &str) -> repository::Result<()> {
+        self.close(
+            job,
+            doc! { "$set": {
+                "status": DEAD,
+                "last_error": error,
+                "updated_at": Utc::now(),
+            } },
+        )
+        .await
+    }
+
+    async fn dead_letters(&self, skip: u64, limit: u64) -> repository::Result<Vec<DeadLetter>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: "dead delivery jobs".to_owned(),
+            source: e,
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "updated_at": -1 })
+            .skip(skip)
+            .limit(limit as i64)
+            .build();
+        let docs: Vec<_> = self
+            .collection()
+            .find(doc! { "status": DEAD }, options)
+            .await
+            .map_err(|e| query_err(Box::new(e)))?
+            .try_collect()
+            .await
+            .map_err(|e| query_err(Box::new(e)))?;
+        docs.into_iter()
+            .map(|d| {
+                bson::from_document::<JobDocument>(d)
+                    .map(DeadLetter::from)
+                    .map_err(|e| query_err(Box::new(e)))
+            })
+            .collect()
+    }
+
+    async fn recipients_of(&self, issue_id: &str) -> repository::Result<HashSet<String>> {
+        let recipients = self
+            .collection()
+            .distinct("email.to", doc! { "email.issue_id": issue_id }, None)
+            .await
+            .map_err(|e| repository::Error::QueryDb {
+                query_desc: format!("recipients of issue {}", issue_id),
+                source: Box::new(e),
+            })?;
+        Ok(recipients
+            .into_iter()
+            .filter_map(|to| to.as_str().map(str::to_owned))
+            .collect())
+    }
+}
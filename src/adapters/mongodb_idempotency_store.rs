@@ -0,0 +1,248 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use mongodb::{
+    bson::{doc, spec::BinarySubtype, Binary, Bson, Document},
+    error::{CommandError, ErrorKind, WriteError, WriteFailure},
+    Collection, Database,
+};
+
+use crate::{
+    delivery::after,
+    idempotency::{Begin, IdempotencyKey, IdempotencyStore, SavedResponse},
+    repository,
+};
+
+const COLLECTION: &str = "idempotency";
+const TTL_INDEX: &str = "created_at_ttl";
+const DUPLICATE_KEY: i32 = 11000;
+const INDEX_OPTIONS_CONFLICT: i32 = 85;
+
+#[derive(Clone)]
+pub(crate) struct MongoIdempotencyStore {
+    db: Database,
+}
+
+impl MongoIdempotencyStore {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.db.collection::<Document>(COLLECTION)
+    }
+
+    /// Saved responses are removed by Mongo `ttl` after their creation.
+    pub(crate) async fn ensure_ttl_index(&self, ttl: Duration) -> mongodb::error::Result<()> {
+        let ttl = ttl.as_secs() as i64;
+        let created = self
+            .db
+            .run_command(
+                doc! {
+                    "createIndexes": COLLECTION,
+                    "indexes": [{
+                        "key": { "created_at": 1 },
+                        "name": TTL_INDEX,
+                        "expireAfterSeconds": ttl,
+                    }],
+                },
+                None,
+            )
+            .await;
+        match created {
+            // The index is there with another `ttl`: change it in place
+            Err(e) if is_options_conflict(&e) => {
+                self.db
+                    .run_command(
+                        doc! {
+                            "collMod": COLLECTION,
+                            "index": { "name": TTL_INDEX, "expireAfterSeconds": ttl },
+                        },
+                        None,
+                    )
+                    .await?;
+                Ok(())
+            }
+            created => created.map(|_| ()),
+        }
+    }
+}
+
+fn id(key: &IdempotencyKey) -> Document {
+    doc! { "caller": &key.caller, "endpoint": &key.endpoint, "key": &key.key }
+}
+
+fn is_options_conflict(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Command(CommandError {
+            code: INDEX_OPTIONS_CONFLICT,
+            ..
+        })
+    )
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(WriteError {
+            code: DUPLICATE_KEY,
+            ..
+        }))
+    )
+}
+
+fn response_doc(response: &SavedResponse) -> Document {
+    let headers: Vec<Bson> = response
+        .headers
+        .iter()
+        .map(|(name, value)| vec![name.as_str(), value.as_str()].into())
+        .collect();
+    doc! {
+        "status": response.status as i32,
+        "headers": headers,
+        "body": Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: response.body.clone(),
+        },
+    }
+}
+
+fn saved_response(d: &Document) -> Option<SavedResponse> {
+    let headers = d
+        .get_array("headers")
+        .ok()?
+        .iter()
+        .filter_map(|header| match header {
+            Bson::Array(pair) => match pair.as_slice() {
+                [Bson::String(name), Bson::String(value)] => Some((name.clone(), value.clone())),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    Some(SavedResponse {
+        status: d.get_i32("status").ok()? as u16,
+        headers,
+        body: d.get_binary_generic("body").ok()?.clone(),
+    })
+}
+
+#[async_trait::async_trait]
+impl IdempotencyStore for MongoIdempotencyStore {
+    async fn begin(
+        &self,
+        key: &IdempotencyKey,
+        fingerprint: &str,
+        lock: Duration,
+    ) -> repository::Result<Begin> {
+        let query_err = |e: mongodb::error::Error| repository::Error::QueryDb {
+            query_desc: format!("idempotency key '{}'", key.key),
+            source: Box::new(e),
+        };
+        let now = Utc::now();
+        let inserted = self
+            .collection()
+            .insert_one(
+                doc! {
+                    "_id": id(key),
+                    "fingerprint": fingerprint,
+                    "created_at": now,
+                    "locked_until": after(now, lock),
+                },
+                None,
+            )
+            .await;
+        match inserted {
+            Ok(_) => return Ok(Begin::Started),
+            Err(e) if is_duplicate_key(&e) => {}
+            Err(e) => return Err(query_err(e)),
+        }
+        // Take over a request that never completed
+        let taken = self
+            .collection()
+            .find_one_and_update(
+                doc! {
+                    "_id": id(key),
+                    "fingerprint": fingerprint,
+                    "response": { "$exists": false },
+                    "locked_until": { "$lte": now },
+                },
+                doc! { "$set": { "locked_until": after(now, lock) } },
+                None,
+            )
+            .await
+            .map_err(query_err)?;
+        if taken.is_some() {
+            return Ok(Begin::Started);
+        }
+        let found = self
+            .collection()
+            .find_one(doc! { "_id": id(key) }, None)
+            .await
+            .map_err(query_err)?;
+        let mismatch = found
+            .as_ref()
+            .and_then(|d| d.get_str("fingerprint").ok())
+            .is_some_and(|saved| saved != fingerprint);
+        if mismatch {
+            return Ok(Begin::Mismatch);
+        }
+        Ok(found
+            .as_ref()
+            .and_then(|d| d.get_document("response").ok())
+            .and_then(saved_response)
+            .map(Begin::Completed)
+            .unwrap_or(Begin::InProgress))
+    }
+
+    async fn complete(
+        &self,
+        key: &IdempotencyKey,
+        response: &SavedResponse,
+    ) -> repository::Result<()> {
+        self.collection()
+            .update_one(
+                doc! { "_id": id(key) },
+                doc! { "$set": { "response": response_doc(response) } },
+                None,
+            )
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("idempotency key '{}'", key.key),
+                source: Box::new(e),
+            })?;
+        Ok(())
+    }
+
+    async fn release(&self, key: &IdempotencyKey) -> repository::Result<()> {
+        self.collection()
+            .delete_one(doc! { "_id": id(key) }, None)
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("idempotency key '{}'", key.key),
+                source: Box::new(e),
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn response_should_survive_the_round_trip() {
+        let response = SavedResponse {
+            status: 201,
+            headers: vec![
+                ("content-type".to_owned(), "text/plain".to_owned()),
+                ("x-custom".to_owned(), "a".to_owned()),
+                ("x-custom".to_owned(), "b".to_owned()),
+            ],
+            body: vec![0, 159, 146, 150],
+        };
+
+        assert_eq!(Some(response.clone()), saved_response(&response_doc(&response)));
+    }
+}
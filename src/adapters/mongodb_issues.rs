@@ -0,0 +1,319 @@
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Bson, Document},
+    options::FindOptions,
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    issues::{self, Content, Issue, Revision, Status},
+    lists, repository,
+};
+
+const COLLECTION: &str = "issues";
+
+#[derive(Clone)]
+pub(crate) struct MongoIssues {
+    db: Database,
+}
+
+impl MongoIssues {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.db.collection::<Document>(COLLECTION)
+    }
+
+    /// Apply `update` if the issue is still in `status`.
+    async fn update(&self, id: &str, status: Status, update: Document) -> repository::Result<bool> {
+        let id = match ObjectId::parse_str(id) {
+            Ok(id) => id,
+            Err(_) => return Ok(false),
+        };
+        let updated = self
+            .collection()
+            .update_one(doc! { "_id": id, "status": status.code() }, update, None)
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("issue {}", id),
+                source: Box::new(e),
+            })?;
+        Ok(updated.matched_count == 1)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RevisionDocument {
+    number: i32,
+    subject: String,
+    html: String,
+    text: String,
+    author: String,
+    created_at: bson::DateTime,
+}
+
+impl From<&Revision> for RevisionDocument {
+    fn from(r: &Revision) -> Self {
+        Self {
+            number: r.number as i32,
+            subject: r.content.subject.clone(),
+            html: r.content.html.clone(),
+            text: r.content.text.clone(),
+            author: r.author.clone(),
+            created_at: r.created_at.into(),
+        }
+    }
+}
+
+impl From<RevisionDocument> for Revision {
+    fn from(d: RevisionDocument) -> Self {
+        Self {
+            number: d.number as u32,
+            content: Content {
+                subject: d.subject,
+                html: d.html,
+                text: d.text,
+            },
+            author: d.author,
+            created_at: d.created_at.to_chrono(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct IssueDocument {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    /// Issues written before lists existed go to the default one
+    #[serde(default = "default_list")]
+    list: String,
+    status: String,
+    revisions: Vec<RevisionDocument>,
+    created_at: bson::DateTime,
+    updated_at: bson::DateTime,
+    #[serde(default)]
+    sent_at: Option<bson::DateTime>,
+}
+
+fn default_list() -> String {
+    lists::DEFAULT_LIST.to_owned()
+}
+
+impl IssueDocument {
+    fn into_issue(self) -> Option<Issue> {
+        Some(Issue {
+            id: self.id.map(ObjectId::to_hex).unwrap_or_default(),
+            list: self.list,
+            status: Status::from_code(&self.status)?,
+            revisions: self.revisions.into_iter().map(Revision::from).collect(),
+            created_at: self.created_at.to_chrono(),
+            updated_at: self.updated_at.to_chrono(),
+            sent_at: self.sent_at.map(|at| at.to_chrono()),
+        })
+    }
+}
+
+fn issue(d: Document) -> Result<Issue, Box<dyn std::error::Error + Send + Sync>> {
+    bson::from_document::<IssueDocument>(d)?
+        .into_issue()
+        .ok_or_else(|| "Unknown issue status".into())
+}
+
+#[async_trait::async_trait]
+impl issues::IssuesRepository for MongoIssues {
+    #[tracing::instrument(name = "Creating an issue", skip(self, revision))]
+    async fn create(&self, list: &str, revision: &Revision) -> repository::Result<Issue> {
+        let insert_err =
+            |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::InsertDb {
+                entry_desc: format!("issue '{}'", revision.content.subject),
+                source: e,
+            };
+        let now = Utc::now();
+        let mut document = IssueDocument {
+            id: None,
+            list: list.to_owned(),
+            status: Status::Draft.code().to_owned(),
+            revisions: vec![revision.into()],
+            created_at: now.into(),
+            updated_at: now.into(),
+            sent_at: None,
+        };
+        let inserted = self
+            .collection()
+            .insert_one(
+                bson::to_document(&document).map_err(|e| insert_err(Box::new(e)))?,
+                None,
+            )
+            .await
+            .map_err(|e| insert_err(Box::new(e)))?;
+        document.id = inserted.inserted_id.as_object_id();
+        document
+            .into_issue()
+            .ok_or_else(|| insert_err("Unknown issue status".into()))
+    }
+
+    async fn get(&self, id: &str) -> repository::Result<Option<Issue>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: format!("issue {}", id),
+            source: e,
+        };
+        let id = match ObjectId::parse_str(id) {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+        self.collection()
+            .find_one(doc! { "_id": id }, None)
+            .await
+            .map_err(|e| query_err(Box::new(e)))?
+            .map(|d| issue(d).map_err(query_err))
+            .transpose()
+    }
+
+    async fn list(&self) -> repository::Result<Vec<Issue>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: "all issues".to_owned(),
+            source: e,
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "updated_at": -1 })
+            .build();
+        let docs: Vec<_> = self
+            .collection()
+            .find(None, options)
+            .await
+            .map_err(|e| query_err(Box::new(e)))?
+            .try_collect()
+            .await
+            .map_err(|e| query_err(Box::new(e)))?;
+        docs.into_iter()
+            .map(|d| issue(d).map_err(query_err))
+            .collect()
+    }
+
+    #[tracing::instrument(name = "Revising an issue", skip(self, revision))]
+    async fn add_revision(
+        &self,
+        id: &str,
+        status: Status,
+        revision: &Revision,
+    ) -> repository::Result<bool> {
+        let revision = bson::to_document(&RevisionDocument::from(revision)).map_err(|e| {
+            repository::Error::UpdateDb {
+                entry_desc: format!("issue {}", id),
+                source: Box::new(e),
+            }
+        })?;
+        self.update(
+            id,
+            status,
+            doc! {
+                "$set": { "status": Status::Draft.code(), "updated_at": Utc::now() },
+                "$push": { "revisions": revision },
+            },
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "Changing an issue status", skip(self))]
+    async fn set_status(&self, id: &str, from: Status, to: Status) -> repository::Result<bool> {
+        let now = Utc::now();
+        let mut set = doc! { "status": to.code(), "updated_at": now };
+        if to == Status::Sent {
+            set.insert("sent_at", now);
+        }
+        self.update(id, from, doc! { "$set": set }).await
+    }
+
+    #[tracing::instrument(name = "Deleting an issue", skip(self))]
+    async fn delete(&self, id: &str, status: Status) -> repository::Result<bool> {
+        let id = match ObjectId::parse_str(id) {
+            Ok(id) => id,
+            Err(_) => return Ok(false),
+        };
+        let deleted = self
+            .collection()
+            .delete_one(doc! { "_id": id, "status": status.code() }, None)
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("issue {}", id),
+                source: Box::new(e),
+            })?;
+        Ok(deleted.deleted_count == 1)
+    }
+
+    async fn sent(&self, skip: u64, limit: u64) -> repository::Result<Vec<Issue>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: "sent issues".to_owned(),
+            source: e,
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "sent_at": -1, "_id": -1 })
+            .skip(skip)
+            .limit(limit as i64)
+            .build();
+        let docs: Vec<_> = self
+            .collection()
+            .find(doc! { "status": Status::Sent.code() }, options)
+            .await
+            .map_err(|e| query_err(Box::new(e)))?
+            .try_collect()
+            .await
+            .map_err(|e| query_err(Box::new(e)))?;
+        docs.into_iter()
+            .map(|d| issue(d).map_err(query_err))
+            .collect()
+    }
+
+    async fn count_sent(&self) -> repository::Result<u64> {
+        let count = self
+            .collection()
+            .count_documents(doc! { "status": Status::Sent.code() }, None)
+            .await
+            .map_err(|e| repository::Error::QueryDb {
+                query_desc: "sent issues count".to_owned(),
+                source: Box::new(e),
+            })?;
+        Ok(count as u64)
+    }
+
+    async fn sent_between(
+        &self,
+        list: &str,
+        after: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> repository::Result<Vec<Issue>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: format!("issues of {} sent between {} and {}", list, after, until),
+            source: e,
+        };
+        // Issues saved before lists existed belong to the default one
+        let on_list = if list == lists::DEFAULT_LIST {
+            doc! { "$in": [list, Bson::Null] }
+        } else {
+            doc! { "$eq": list }
+        };
+        let filter = doc! {
+            "list": on_list,
+            "status": Status::Sent.code(),
+            "sent_at": { "$gt": after, "$lte": until },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "sent_at": 1, "_id": 1 })
+            .build();
+        let docs: Vec<_> = self
+            .collection()
+            .find(filter, options)
+            .await
+            .map_err(|e| query_err(Box::new(e)))?
+            .try_collect()
+            .await
+            .map_err(|e| query_err(Box::new(e)))?;
+        docs.into_iter()
+            .map(|d| issue(d).map_err(query_err))
+            .collect()
+    }
+}
@@ -0,0 +1,219 @@
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{self, doc, Bson, Document},
+    error::{ErrorKind, WriteError, WriteFailure},
+    options::{FindOptions, UpdateOptions},
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    lists::{List, ListsRepository},
+    repository,
+};
+
+const COLLECTION: &str = "lists";
+const DUPLICATE_KEY: i32 = 11000;
+
+#[derive(Clone)]
+pub(crate) struct MongoLists {
+    db: Database,
+}
+
+impl MongoLists {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.db.collection::<Document>(COLLECTION)
+    }
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(WriteError {
+            code: DUPLICATE_KEY,
+            ..
+        }))
+    )
+}
+
+/// The slug is the `_id`.
+#[derive(Serialize, Deserialize)]
+struct ListDocument {
+    #[serde(rename = "_id")]
+    slug: String,
+    name: String,
+    #[serde(default)]
+    sender: Option<String>,
+    #[serde(default)]
+    tracking: bool,
+}
+
+impl From<&List> for ListDocument {
+    fn from(list: &List) -> Self {
+        Self {
+            slug: list.slug.clone(),
+            name: list.name.clone(),
+            sender: list.sender.clone(),
+            tracking: list.tracking,
+        }
+    }
+}
+
+impl From<ListDocument> for List {
+    fn from(d: ListDocument) -> Self {
+        Self {
+            slug: d.slug,
+            name: d.name,
+            sender: d.sender,
+            tracking: d.tracking,
+        }
+    }
+}
+
+fn sender(list: &List) -> Bson {
+    list.sender.as_deref().map(Bson::from).unwrap_or(Bson::Null)
+}
+
+fn list(d: Document) -> Result<List, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(bson::from_document::<ListDocument>(d)?.into())
+}
+
+#[async_trait::async_trait]
+impl ListsRepository for MongoLists {
+    #[tracing::instrument(name = "Creating a list", skip(self))]
+    async fn create(&self, list: &List) -> repository::Result<bool> {
+        let insert_err =
+            |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::InsertDb {
+                entry_desc: format!("list {}", list.slug),
+                source: e,
+            };
+        let document =
+            bson::to_document(&ListDocument::from(list)).map_err(|e| insert_err(Box::new(e)))?;
+        match self.collection().insert_one(document, None).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(insert_err(Box::new(e))),
+        }
+    }
+
+    async fn ensure(&self, list: &List) -> repository::Result<()> {
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.collection()
+            .update_one(
+                doc! { "_id": &list.slug },
+                doc! {
+                    "$setOnInsert": {
+                        "name": &list.name,
+                        "sender": sender(list),
+                        "tracking": list.tracking,
+                    }
+                },
+                options,
+            )
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("list {}", list.slug),
+                source: Box::new(e),
+            })?;
+        Ok(())
+    }
+
+    async fn get(&self, slug: &str) -> repository::Result<Option<List>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: format!("list {}", slug),
+            source: e,
+        };
+        self.collection()
+            .find_one(doc! { "_id": slug }, None)
+            .await
+            .map_err(|e| query_err(Box::new(e)))?
+            .map(|d| list(d).map_err(query_err))
+            .transpose()
+    }
+
+    async fn all(&self) -> repository::Result<Vec<List>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: "all lists".to_owned(),
+            source: e,
+        };
+        let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let docs: Vec<_> = self
+            .collection()
+            .find(None, options)
+            .await
+            .map_err(|e| query_err(Box::new(e)))?
+            .try_collect()
+            .await
+            .map_err(|e| query_err(Box::new(e)))?;
+        docs.into_iter()
+            .map(|d| list(d).map_err(query_err))
+            .collect()
+    }
+
+    #[tracing::instrument(name = "Updating a list", skip(self))]
+    async fn update(&self, list: &List) -> repository::Result<bool> {
+        let updated = self
+            .collection()
+            .update_one(
+                doc! { "_id": &list.slug },
+                doc! {
+                    "$set": {
+                        "name": &list.name,
+                        "sender": sender(list),
+                        "tracking": list.tracking,
+                    }
+                },
+                None,
+            )
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("list {}", list.slug),
+                source: Box::new(e),
+            })?;
+        Ok(updated.matched_count == 1)
+    }
+
+    async fn digest_sent_at(&self, slug: &str) -> repository::Result<Option<DateTime<Utc>>> {
+        let document = self
+            .collection()
+            .find_one(doc! { "_id": slug }, None)
+            .await
+            .map_err(|e| repository::Error::QueryDb {
+                query_desc: format!("digest of list {}", slug),
+                source: Box::new(e),
+            })?;
+        Ok(document.and_then(|d| {
+            d.get_datetime("digest_sent_at")
+                .ok()
+                .map(|at| at.to_chrono())
+        }))
+    }
+
+    #[tracing::instrument(name = "Recording a digest", skip(self))]
+    async fn digest_sent(
+        &self,
+        slug: &str,
+        previous: Option<DateTime<Utc>>,
+        at: DateTime<Utc>,
+    ) -> repository::Result<bool> {
+        let previous = previous.map(Bson::from).unwrap_or(Bson::Null);
+        let updated = self
+            .collection()
+            .update_one(
+                doc! { "_id": slug, "digest_sent_at": previous },
+                doc! { "$set": { "digest_sent_at": at } },
+                None,
+            )
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("digest of list {}", slug),
+                source: Box::new(e),
+            })?;
+        Ok(updated.matched_count == 1)
+    }
+}
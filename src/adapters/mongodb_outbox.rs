@@ -0,0 +1,247 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use mongodb::{
+    bson::{self, doc, Document},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    delivery,
+    outbox::{self, Claimed, DomainEvent, Event},
+    repository,
+};
+
+pub(super) const COLLECTION: &str = "outbox";
+
+/// Saved before the write it follows, not yet confirmed
+pub(super) const STAGED: &str = "staged";
+pub(super) const PENDING: &str = "pending";
+const IN_PROGRESS: &str = "in_progress";
+const DONE: &str = "done";
+const DEAD: &str = "dead";
+/// Staged for a write that never happened
+const DISCARDED: &str = "discarded";
+
+#[derive(Serialize, Deserialize)]
+struct EventDocument {
+    /// The event id
+    #[serde(rename = "_id")]
+    id: String,
+    event: DomainEvent,
+    status: String,
+    /// The write it follows is known to be saved
+    confirmed: bool,
+    attempts: i32,
+    next_attempt_at: bson::DateTime,
+    #[serde(default)]
+    lease_until: Option<bson::DateTime>,
+    /// The subscribers that are done with it
+    #[serde(default)]
+    handled: Vec<String>,
+    #[serde(default)]
+    last_error: Option<String>,
+    created_at: bson::DateTime,
+    updated_at: bson::DateTime,
+}
+
+impl From<EventDocument> for Claimed {
+    fn from(d: EventDocument) -> Self {
+        Self {
+            event: Event {
+                id: d.id,
+                event: d.event,
+            },
+            attempts: d.attempts as u32,
+            handled: d.handled,
+            unconfirmed: !d.confirmed,
+        }
+    }
+}
+
+/// `event` as saved with the write it follows. Saved before it, without a
+/// transaction, it's `STAGED` till it's `confirmed`.
+pub(super) fn event_doc(
+    event: &Event,
+    now: DateTime<Utc>,
+    confirmed: bool,
+) -> Result<Document, bson::ser::Error> {
+    bson::to_document(&EventDocument {
+        id: event.id.clone(),
+        event: event.event.clone(),
+        status: if confirmed { PENDING } else { STAGED }.to_owned(),
+        confirmed,
+        attempts: 0,
+        next_attempt_at: now.into(),
+        lease_until: None,
+        handled: Vec::new(),
+        last_error: None,
+        created_at: now.into(),
+        updated_at: now.into(),
+    })
+}
+
+#[derive(Clone)]
+pub(crate) struct MongoOutbox {
+    db: Database,
+}
+
+impl MongoOutbox {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.db.collection::<Document>(COLLECTION)
+    }
+
+    async fn claim_one(
+        &self,
+        filter: Document,
+        lease: Duration,
+    ) -> repository::Result<Option<Claimed>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: "claim an outbox event".to_owned(),
+            source: e,
+        };
+        let now = Utc::now();
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! { "next_attempt_at": 1 })
+            .return_document(ReturnDocument::After)
+            .build();
+        let claimed = self
+            .collection()
+            .find_one_and_update(
+                filter,
+                doc! {
+                    "$set": {
+                        "status": IN_PROGRESS,
+                        "lease_until": delivery::after(now, lease),
+                        "updated_at": now,
+                    },
+                    "$inc": { "attempts": 1 },
+                },
+                options,
+            )
+            .await
+            .map_err(|e| query_err(Box::new(e)))?;
+        claimed
+            .map(|d| {
+                bson::from_document::<EventDocument>(d)
+                    .map(Claimed::from)
+                    .map_err(|e| query_err(Box::new(e)))
+            })
+            .transpose()
+    }
+
+    /// Close `claimed` if nobody else claimed it in the meantime.
+    async fn close(&self, claimed: &Claimed, update: Document) -> repository::Result<()> {
+        self.collection()
+            .update_one(
+                doc! {
+                    "_id": &claimed.event.id,
+                    "status": IN_PROGRESS,
+                    "attempts": claimed.attempts as i32,
+                },
+                update,
+                None,
+            )
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("outbox event {}", claimed.event.id),
+                source: Box::new(e),
+            })?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl outbox::Outbox for MongoOutbox {
+    async fn claim(&self, lease: Duration) -> repository::Result<Option<Claimed>> {
+        let now = Utc::now();
+        // The request that saved an event has a lease on it too
+        let fresh = chrono::Duration::from_std(lease)
+            .ok()
+            .and_then(|lease| now.checked_sub_signed(lease))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let filter = doc! { "$or": [
+            {
+                "status": { "$in": [PENDING, STAGED] },
+                "next_attempt_at": { "$lte": now },
+                "created_at": { "$lte": fresh },
+            },
+            { "status": IN_PROGRESS, "lease_until": { "$lte": now } },
+        ] };
+        self.claim_one(filter, lease).await
+    }
+
+    async fn claim_id(&self, id: &str, lease: Duration) -> repository::Result<Option<Claimed>> {
+        self.claim_one(doc! { "_id": id, "status": PENDING }, lease)
+            .await
+    }
+
+    async fn handled(&self, claimed: &Claimed, subscriber: &str) -> repository::Result<()> {
+        self.collection()
+            .update_one(
+                doc! { "_id": &claimed.event.id },
+                doc! { "$addToSet": { "handled": subscriber } },
+                None,
+            )
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("outbox event {}", claimed.event.id),
+                source: Box::new(e),
+            })?;
+        Ok(())
+    }
+
+    async fn complete(&self, claimed: &Claimed) -> repository::Result<()> {
+        self.close(
+            claimed,
+            doc! { "$set": { "status": DONE, "updated_at": Utc::now() } },
+        )
+        .await
+    }
+
+    async fn retry(
+        &self,
+        claimed: &Claimed,
+        at: DateTime<Utc>,
+        error: &str,
+    ) -> repository::Result<()> {
+        // Still unconfirmed: the relay checks again whether the write happened
+        let status = if claimed.unconfirmed { STAGED } else { PENDING };
+        self.close(
+            claimed,
+            doc! { "$set": {
+                "status": status,
+                "next_attempt_at": at,
+                "last_error": error,
+                "updated_at": Utc::now(),
+            } },
+        )
+        .await
+    }
+
+    async fn dead_letter(&self, claimed: &Claimed, error: &str) -> repository::Result<()> {
+        self.close(
+            claimed,
+            doc! { "$set": {
+                "status": DEAD,
+                "last_error": error,
+                "updated_at": Utc::now(),
+            } },
+        )
+        .await
+    }
+
+    async fn discard(&self, claimed: &Claimed) -> repository::Result<()> {
+        self.close(
+            claimed,
+            doc! { "$set": { "status": DISCARDED, "updated_at": Utc::now() } },
+        )
+        .await
+    }
+}
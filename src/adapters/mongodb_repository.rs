@@ -1,38 +1,682 @@
 #[derive(Clone)]
 pub(crate) struct MongoUserRepository {
+    client: Client,
     db: Database,
+    /// Whether the server runs transactions: asked on the first write with an event
+    transactions: Arc<OnceCell<bool>>,
 }
 
 impl MongoUserRepository {
-    pub(crate) fn new(db: Database) -> Self {
-        Self { db }
+    pub(crate) fn new(client: Client, db: Database) -> Self {
+        Self {
+            client,
+            db,
+            transactions: Arc::default(),
+        }
+    }
+
+    /// Lowercase the emails saved before they were lowercased, then make them
+    /// unique. A subscriber whose email only differs by case from another's is
+    /// left as it is, and the index can't be created till they are merged.
+    pub(crate) async fn ensure_indexes(&self) -> Result<(), WriteError> {
+        let subscribers = self.db.collection::<Document>(SUBSCRIBERS);
+        let mut cursor = subscribers
+            .find(
+                doc! { "$expr": { "$ne": ["$email", { "$toLower": "$email" }] } },
+                FindOptions::builder()
+                    .projection(doc! { "email": 1 })
+                    .build(),
+            )
+            .await?;
+        let mut conflicts = 0;
+        while let Some(subscriber) = cursor.try_next().await? {
+            let email = subscriber.get_str("email")?;
+            let id = subscriber.get("_id").cloned().unwrap_or(Bson::Null);
+            let lowercase = email.to_lowercase();
+            if subscribers
+                .count_documents(doc! { "email": &lowercase }, None)
+                .await?
+                > 0
+            {
+                conflicts += 1;
+                continue;
+            }
+            subscribers
+                .update_one(
+                    doc! { "_id": id, "email": email },
+                    doc! { "$set": { "email": lowercase } },
+                    None,
+                )
+                .await?;
+        }
+        if conflicts > 0 {
+            warn!(
+                "{} subscribers have the email of another one in a different case",
+                conflicts
+            );
+        }
+        write(
+            &self.db,
+            doc! {
+                "createIndexes": SUBSCRIBERS,
+                "indexes": [{ "key": { "email": 1 }, "name": "email_1", "unique": true }],
+            },
+        )
+        .await
+    }
+
+    /// Run the write `commands` in order, with `event` in the outbox. Replica
+    /// sets and sharded clusters save them all in a transaction. A standalone
+    /// server has no transactions: the event is staged first and confirmed after,
+    /// so that it can't be missing if the writes are saved.
+    async fn update_with_event(
+        &self,
+        commands: Vec<Document>,
+        event: &outbox::Event,
+    ) -> Result<(), WriteError> {
+        if self.transactions().await? {
+            self.update_in_transaction(commands, event).await
+        } else {
+            self.update_staged(commands, event).await
+        }
+    }
+
+    /// Whether the server runs transactions: only replica sets and sharded
+    /// clusters do.
+    async fn transactions(&self) -> Result<bool, WriteError> {
+        if let Some(&transactions) = self.transactions.get() {
+            return Ok(transactions);
+        }
+        let reply = self.db.run_command(doc! { "isMaster": 1 }, None).await?;
+        let transactions =
+            reply.get_str("setName").is_ok() || reply.get_str("msg") == Ok("isdbgrid");
+        Ok(*self.transactions.get_or_init(|| transactions))
+    }
+
+    /// Concurrent writes to the same subscriber conflict: the transaction is
+    /// tried again, up to `TRANSACTION_ATTEMPTS` times.
+    async fn update_in_transaction(
+        &self,
+        mut commands: Vec<Document>,
+        event: &outbox::Event,
+    ) -> Result<(), WriteError> {
+        let pending = mongodb_outbox::event_doc(event, Utc::now(), true)?;
+        commands.insert(
+            0,
+            doc! { "insert": mongodb_outbox::COLLECTION, "documents": [pending] },
+        );
+        let mut attempts = 1;
+        loop {
+            match self.transaction(&commands).await {
+                Err(e) if attempts < TRANSACTION_ATTEMPTS && is_transient(e.as_ref()) => {
+                    attempts += 1
+                }
+                done => return done,
+            }
+        }
+    }
+
+    async fn transaction(&self, commands: &[Document]) -> Result<(), WriteError> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        // A failed write drops the session, which aborts the transaction
+        for command in commands {
+            write_in(&self.db, command.clone(), &mut session).await?;
+        }
+        session.commit_transaction().await?;
+        Ok(())
+    }
+
+    async fn update_staged(
+        &self,
+        commands: Vec<Document>,
+        event: &outbox::Event,
+    ) -> Result<(), WriteError> {
+        let staged = mongodb_outbox::event_doc(event, Utc::now(), false)?;
+        write(
+            &self.db,
+            doc! { "insert": mongodb_outbox::COLLECTION, "documents": [staged] },
+        )
+        .await?;
+        for command in commands {
+            write(&self.db, command).await?;
+        }
+        let confirm = doc! {
+            "q": { "_id": &event.id, "status": mongodb_outbox::STAGED },
+            "u": {
+                "$set": {
+                    "status": mongodb_outbox::PENDING,
+                    "confirmed": true,
+                    "updated_at": Utc::now(),
+                }
+            },
+        };
+        write(
+            &self.db,
+            doc! { "update": mongodb_outbox::COLLECTION, "updates": [confirm] },
+        )
+        .await
+    }
+}
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, Document},
+    error::TRANSIENT_TRANSACTION_ERROR,
+    options::{FindOneOptions, FindOptions},
+    Client, ClientSession, Database,
+};
+use once_cell::sync::OnceCell;
+use tracing::warn;
+
+use super::mongodb_outbox;
+use crate::{
+    email_events::{EmailEvent, SuppressionReason, SuppressionsRepository},
+    i18n::Locale,
+    lists::SubscriptionStatus,
+    outbox,
+    preferences::{Change, Frequency, Preferences, PreferencesRepository},
+    repository,
+    telemetry::pii,
+};
+
+const SUBSCRIBERS: &str = "subscriptions";
+
+type WriteError = Box<dyn std::error::Error + Send + Sync>;
+
+const TRANSACTION_ATTEMPTS: u32 = 3;
+
+/// Run a write command: its write errors don't fail it, they are in the reply.
+async fn write(db: &Database, command: Document) -> Result<(), WriteError> {
+    checked(db.run_command(command, None).await?)
+}
+
+/// Run a write command in `session`.
+async fn write_in(
+    db: &Database,
+    command: Document,
+    session: &mut ClientSession,
+) -> Result<(), WriteError> {
+    checked(db.run_command_with_session(command, None, session).await?)
+}
+
+/// Whether the transaction that failed with `e` can be tried again.
+fn is_transient(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<mongodb::error::Error>()
+        .is_some_and(|e| e.contains_label(TRANSIENT_TRANSACTION_ERROR))
+}
+
+fn checked(reply: Document) -> Result<(), WriteError> {
+    match reply.get_array("writeErrors") {
+        Ok(errors) if !errors.is_empty() => Err(format!("write errors: {:?}", errors).into()),
+        _ => Ok(()),
+    }
+}
+/// The audit trail of the preference changes
+pub(super) const CHANGES: &str = "preference_changes";
+pub(super) const EVENTS: &str = "email_events";
+/// `_id` is the address
+const SUPPRESSIONS: &str = "suppressed_emails";
+
+/// A subscriber has a `lists` array with the status on every list they joined.
+fn user_doc(user: &repository::User, list: &str) -> Document {
+    doc! {
+        "name": &user.name,
+        "email": &user.email,
+        "locale": user.locale.code(),
+        "lists": [membership(list, SubscriptionStatus::Subscribed)],
     }
 }
 
-use mongodb::{bson::doc, Database};
+fn membership(list: &str, status: SubscriptionStatus) -> Document {
+    doc! { "list": list, "status": status.code(), "updated_at": Utc::now() }
+}
 
-use crate::repository;
+/// The `(filter, update)` pairs that subscribe `email` to `list`, again if they
+/// left it: to be run in order.
+fn joining(email: &str, list: &str) -> Vec<(Document, Document)> {
+    let subscribed = SubscriptionStatus::Subscribed;
+    vec![
+        (
+            doc! { "email": email, "lists.list": { "$ne": list } },
+            doc! { "$push": { "lists": membership(list, subscribed) } },
+        ),
+        (
+            doc! { "email": email, "lists.list": list },
+            doc! {
+                "$set": {
+                    "lists.$.status": subscribed.code(),
+                    "lists.$.updated_at": Utc::now(),
+                }
+            },
+        ),
+    ]
+}
+
+/// The subscribers currently on `list`.
+fn on_list(list: &str) -> Document {
+    doc! {
+        "lists": {
+            "$elemMatch": { "list": list, "status": SubscriptionStatus::Subscribed.code() }
+        }
+    }
+}
+
+fn subscribed_to(d: &Document) -> HashSet<String> {
+    d.get_array("lists")
+        .map(|lists| {
+            lists
+                .iter()
+                .filter_map(|l| l.as_document())
+                .filter(|l| l.get_str("status").ok() == Some(SubscriptionStatus::Subscribed.code()))
+                .filter_map(|l| l.get_str("list").ok().map(str::to_owned))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The subscribers on `list` with `frequency` and not paused at `now`: the ones
+/// who never chose a frequency get issues immediately.
+fn recipients(list: &str, frequency: Frequency, now: DateTime<Utc>) -> Document {
+    let mut filter = on_list(list);
+    let weekly = Frequency::Weekly.code();
+    let frequency = match frequency {
+        Frequency::Immediate => Bson::Document(doc! { "$ne": weekly }),
+        Frequency::Weekly => Bson::from(weekly),
+    };
+    filter.insert("frequency", frequency);
+    filter.insert("paused_until", doc! { "$not": { "$gt": now } });
+    filter
+}
+
+/// Subscribers saved before localisation have no locale: they get the default one.
+fn locale(d: &Document) -> Locale {
+    d.get_str("locale")
+        .ok()
+        .and_then(Locale::from_tag)
+        .unwrap_or_default()
+}
+
+fn user(d: Document) -> Option<repository::User> {
+    Some(repository::User {
+        id: d.get_object_id("_id").ok().map(ObjectId::to_hex),
+        name: d.get_str("name").ok()?.to_owned(),
+        email: d.get_str("email").ok()?.to_owned(),
+        locale: locale(&d),
+    })
+}
+
+fn preferences(d: &Document) -> Option<Preferences> {
+    let mut lists: Vec<_> = subscribed_to(d).into_iter().collect();
+    lists.sort();
+    Some(Preferences {
+        name: d.get_str("name").ok()?.to_owned(),
+        locale: locale(d),
+        lists,
+        frequency: d
+            .get_str("frequency")
+            .ok()
+            .and_then(Frequency::from_code)
+            .unwrap_or_default(),
+        paused_until: d.get_datetime("paused_until").ok().map(|at| at.to_chrono()),
+    })
+}
 
 #[async_trait::async_trait]
 impl repository::UsersRepository for MongoUserRepository {
     #[tracing::instrument(
-        name = "Saving a new subscriber",
+        name = "Saving a subscriber",
         skip(self, user),
         fields(
-            name = %user.name,
-            email = %user.email,
+            name = %pii::name(&user.name),
+            email = %pii::email(&user.email),
+        )
+    )]
+    async fn subscribe(
+        &self,
+        user: repository::User,
+        lists: &[String],
+        event: Option<&outbox::Event>,
+    ) -> repository::Result<()> {
+        let update_err = |e: WriteError| repository::Error::UpdateDb {
+            entry_desc: format!("{:?}", &user),
+            source: e,
+        };
+        let mut updates = vec![doc! {
+            "q": { "email": &user.email },
+            "u": {
+                "$setOnInsert": {
+                    "name": &user.name,
+                    "locale": user.locale.code(),
+                    "lists": [],
+                }
+            },
+            "upsert": true,
+        }];
+        for list in lists {
+            updates.extend(
+                joining(&user.email, list)
+                    .into_iter()
+                    .map(|(filter, update)| doc! { "q": filter, "u": update }),
+            );
+        }
+        let update = doc! { "update": SUBSCRIBERS, "updates": updates, "ordered": true };
+        let written = match event {
+            Some(event) => self.update_with_event(vec![update], event).await,
+            None => write(&self.db, update).await,
+        };
+        written.map_err(update_err)
+    }
+
+    #[tracing::instrument(
+        name = "Saving a batch of subscribers",
+        skip(self, users),
+        fields(
+            size = users.len(),
+        )
+    )]
+    async fn create_many(
+        &self,
+        users: Vec<repository::User>,
+        list: &str,
+    ) -> repository::Result<()> {
+        if users.is_empty() {
+            return Ok(());
+        }
+        let docs = users.iter().map(|user| user_doc(user, list));
+        self.db
+            .collection::<Document>(SUBSCRIBERS)
+            .insert_many(docs, None)
+            .await
+            .map_err(|e| repository::Error::InsertDb {
+                entry_desc: format!("{} subscribers batch", users.len()),
+                source: Box::new(e),
+            })?;
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "Looking for existing subscribers",
+        skip(self, emails),
+        fields(
+            size = emails.len(),
+        )
+    )]
+    async fn subscribed_lists(
+        &self,
+        emails: &[String],
+    ) -> repository::Result<HashMap<String, HashSet<String>>> {
+        let query_err = |e: mongodb::error::Error| repository::Error::QueryDb {
+            query_desc: format!("existing emails among {} entries", emails.len()),
+            source: Box::new(e),
+        };
+        let options = FindOptions::builder()
+            .projection(doc! { "email": 1, "lists": 1, "_id": 0 })
+            .build();
+        let docs: Vec<_> = self
+            .db
+            .collection::<Document>(SUBSCRIBERS)
+            .find(doc! { "email": { "$in": emails } }, options)
+            .await
+            .map_err(query_err)?
+            .try_collect()
+            .await
+            .map_err(query_err)?;
+        Ok(docs
+            .into_iter()
+            .filter_map(|d| {
+                let email = d.get_str("email").ok()?.to_owned();
+                Some((email, subscribed_to(&d)))
+            })
+            .collect())
+    }
+
+    async fn email_of(&self, id: &str) -> repository::Result<Option<String>> {
+        let id = match ObjectId::parse_str(id) {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+        let options = FindOneOptions::builder()
+            .projection(doc! { "email": 1 })
+            .build();
+        let subscriber = self
+            .db
+            .collection::<Document>(SUBSCRIBERS)
+            .find_one(doc! { "_id": id }, options)
+            .await
+            .map_err(|e| repository::Error::QueryDb {
+                query_desc: format!("email of subscriber {}", id),
+                source: Box::new(e),
+            })?;
+        Ok(subscriber.and_then(|d| d.get_str("email").ok().map(str::to_owned)))
+    }
+
+    async fn count(&self, list: &str) -> repository::Result<u64> {
+        let count = self
+            .db
+            .collection::<Document>(SUBSCRIBERS)
+            .count_documents(on_list(list), None)
+            .await
+            .map_err(|e| repository::Error::QueryDb {
+                query_desc: format!("subscribers count of {}", list),
+                source: Box::new(e),
+            })?;
+        Ok(count as u64)
+    }
+
+    #[tracing::instrument(name = "Reading the recipients of a list", skip(self))]
+    async fn recipients(
+        &self,
+        list: &str,
+        frequency: Frequency,
+        now: DateTime<Utc>,
+    ) -> repository::Result<Vec<repository::User>> {
+        let query_err = |e: mongodb::error::Error| repository::Error::QueryDb {
+            query_desc: format!("{} recipients of {}", frequency.code(), list),
+            source: Box::new(e),
+        };
+        let docs: Vec<_> = self
+            .db
+            .collection::<Document>(SUBSCRIBERS)
+            .find(recipients(list, frequency, now), None)
+            .await
+            .map_err(query_err)?
+            .try_collect()
+            .await
+            .map_err(query_err)?;
+        Ok(docs.into_iter().filter_map(user).collect())
+    }
+
+    #[tracing::instrument(name = "Moving unlisted subscribers", skip(self))]
+    async fn adopt_unlisted(&self, list: &str) -> repository::Result<u64> {
+        let updated = self
+            .db
+            .collection::<Document>(SUBSCRIBERS)
+            .update_many(
+                doc! { "lists": { "$exists": false } },
+                doc! { "$set": { "lists": [membership(list, SubscriptionStatus::Subscribed)] } },
+                None,
+            )
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("unlisted subscribers to {}", list),
+                source: Box::new(e),
+            })?;
+        Ok(updated.modified_count as u64)
+    }
+}
+
+#[async_trait::async_trait]
+impl PreferencesRepository for MongoUserRepository {
+    async fn preferences(&self, email: &str) -> repository::Result<Option<Preferences>> {
+        let document = self
+            .db
+            .collection::<Document>(SUBSCRIBERS)
+            .find_one(doc! { "email": email }, None)
+            .await
+            .map_err(|e| repository::Error::QueryDb {
+                query_desc: format!("preferences of {}", pii::email(email)),
+                source: Box::new(e),
+            })?;
+        Ok(document.as_ref().and_then(preferences))
+    }
+
+    #[tracing::instrument(
+        name = "Saving preference changes",
+        skip(self, email, wanted, changes, event),
+        fields(
+            email = %pii::email(email),
         )
     )]
-    async fn create(&self, user: repository::User) -> repository::Result<()> {
-        let doc = doc! { "name": &user.name, "email": &user.email };
+    async fn change(
+        &self,
+        email: &str,
+        wanted: &Preferences,
+        changes: &[Change],
+        at: DateTime<Utc>,
+        event: &outbox::Event,
+    ) -> repository::Result<()> {
+        let paused_until = wanted.paused_until.map(Bson::from).unwrap_or(Bson::Null);
+        let mut updates = vec![doc! {
+            "q": { "email": email },
+            "u": {
+                "$set": {
+                    "name": &wanted.name,
+                    "frequency": wanted.frequency.code(),
+                    "paused_until": paused_until,
+                }
+            },
+        }];
+        if changes.iter().any(|change| change.field == "lists") {
+            for list in &wanted.lists {
+                updates.extend(
+                    joining(email, list)
+                        .into_iter()
+                        .map(|(filter, update)| doc! { "q": filter, "u": update }),
+                );
+            }
+            let subscribed = SubscriptionStatus::Subscribed.code();
+            updates.push(doc! {
+                "q": { "email": email },
+                "u": {
+                    "$set": {
+                        "lists.$[left].status": SubscriptionStatus::Unsubscribed.code(),
+                        "lists.$[left].updated_at": Utc::now(),
+                    }
+                },
+                "arrayFilters": [
+                    { "left.list": { "$nin": &wanted.lists }, "left.status": subscribed },
+                ],
+            });
+        }
+        let audit: Vec<_> = changes
+            .iter()
+            .map(|change| {
+                doc! {
+                    "email": email,
+                    "field": &change.field,
+                    "from": &change.from,
+                    "to": &change.to,
+                    "at": at,
+                }
+            })
+            .collect();
+        let commands = vec![
+            doc! { "update": SUBSCRIBERS, "updates": updates, "ordered": true },
+            doc! { "insert": CHANGES, "documents": audit },
+        ];
+        self.update_with_event(commands, event)
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("preferences of {}", pii::email(email)),
+                source: e,
+            })
+    }
+}
+
+#[async_trait::async_trait]
+impl SuppressionsRepository for MongoUserRepository {
+    #[tracing::instrument(name = "Recording an email event", skip(self))]
+    async fn record_event(&self, event: &EmailEvent) -> repository::Result<()> {
+        let description = event
+            .description
+            .as_ref()
+            .map(|description| Bson::from(description.as_str()))
+            .unwrap_or(Bson::Null);
         self.db
-            .collection("subscriptions")
-            .insert_one(doc, None)
+            .collection::<Document>(EVENTS)
+            .insert_one(
+                doc! {
+                    "email": &event.email,
+                    "type": event.kind.code(),
+                    "description": description,
+                    "occurred_at": event.occurred_at,
+                    "received_at": Utc::now(),
+                },
+                None,
+            )
             .await
             .map_err(|e| repository::Error::InsertDb {
-                entry_desc: format!("{:?}", &user),
+                entry_desc: format!("{:?}", event),
                 source: Box::new(e),
             })?;
         Ok(())
     }
+
+    #[tracing::instrument(
+        name = "Suppressing an address",
+        skip(self, email),
+        fields(
+            email = %pii::email(email),
+        )
+    )]
+    async fn suppress(
+        &self,
+        email: &str,
+        reason: SuppressionReason,
+        event: Option<&outbox::Event>,
+    ) -> repository::Result<()> {
+        let update = doc! {
+            "update": SUPPRESSIONS,
+            "updates": [{
+                "q": { "_id": email },
+                "u": { "$setOnInsert": { "reason": reason.code(), "suppressed_at": Utc::now() } },
+                "upsert": true,
+            }],
+        };
+        let written = match event {
+            Some(event) => self.update_with_event(vec![update], event).await,
+            None => write(&self.db, update).await,
+        };
+        written.map_err(|e| repository::Error::UpdateDb {
+            entry_desc: format!("suppression of {}", pii::email(email)),
+            source: e,
+        })
+    }
+
+    async fn suppressed(&self, emails: &[String]) -> repository::Result<HashSet<String>> {
+        let query_err = |e: mongodb::error::Error| repository::Error::QueryDb {
+            query_desc: format!("suppressed among {} emails", emails.len()),
+            source: Box::new(e),
+        };
+        let docs: Vec<_> = self
+            .db
+            .collection::<Document>(SUPPRESSIONS)
+            .find(doc! { "_id": { "$in": emails } }, None)
+            .await
+            .map_err(query_err)?
+            .try_collect()
+            .await
+            .map_err(query_err)?;
+        Ok(docs
+            .into_iter()
+            .filter_map(|d| d.get_str("_id").ok().map(str::to_owned))
+            .collect())
+    }
 }
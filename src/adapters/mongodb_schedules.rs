@@ -0,0 +1,237 @@
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Document},
+    error::{ErrorKind, WriteError, WriteFailure},
+    options::FindOptions,
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    repository,
+    scheduler::{Schedule, ScheduleStatus, Schedules},
+};
+
+const COLLECTION: &str = "schedules";
+const LEASES: &str = "leases";
+/// The `_id` of the scheduler lease document
+const LEASE: &str = "scheduler";
+const DUPLICATE_KEY: i32 = 11000;
+
+#[derive(Clone)]
+pub(crate) struct MongoSchedules {
+    db: Database,
+}
+
+impl MongoSchedules {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.db.collection::<Document>(COLLECTION)
+    }
+
+    async fn find(&self, filter: Document, desc: &str) -> repository::Result<Vec<Schedule>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: desc.to_owned(),
+            source: e,
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "publish_at": 1 })
+            .build();
+        let docs: Vec<_> = self
+            .collection()
+            .find(filter, options)
+            .await
+            .map_err(|e| query_err(Box::new(e)))?
+            .try_collect()
+            .await
+            .map_err(|e| query_err(Box::new(e)))?;
+        docs.into_iter()
+            .map(|d| schedule(d).map_err(query_err))
+            .collect()
+    }
+
+    /// Apply `set` to the schedule `id` if it's still pending.
+    async fn update_pending(&self, id: &str, set: Document) -> repository::Result<bool> {
+        let id = match ObjectId::parse_str(id) {
+            Ok(id) => id,
+            Err(_) => return Ok(false),
+        };
+        let updated = self
+            .collection()
+            .update_one(
+                doc! { "_id": id, "status": ScheduleStatus::Pending.code() },
+                doc! { "$set": set },
+                None,
+            )
+            .await
+            .map_err(|e| repository::Error::UpdateDb {
+                entry_desc: format!("schedule {}", id),
+                source: Box::new(e),
+            })?;
+        Ok(updated.matched_count == 1)
+    }
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(WriteError {
+            code: DUPLICATE_KEY,
+            ..
+        }))
+    )
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScheduleDocument {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    issue_id: String,
+    publish_at: bson::DateTime,
+    status: String,
+    #[serde(default)]
+    error: Option<String>,
+    created_at: bson::DateTime,
+    updated_at: bson::DateTime,
+}
+
+impl ScheduleDocument {
+    fn into_schedule(self) -> Option<Schedule> {
+        Some(Schedule {
+            id: self.id.map(ObjectId::to_hex).unwrap_or_default(),
+            issue_id: self.issue_id,
+            publish_at: self.publish_at.to_chrono(),
+            status: ScheduleStatus::from_code(&self.status)?,
+            error: self.error,
+            created_at: self.created_at.to_chrono(),
+            updated_at: self.updated_at.to_chrono(),
+        })
+    }
+}
+
+fn schedule(d: Document) -> Result<Schedule, Box<dyn std::error::Error + Send + Sync>> {
+    bson::from_document::<ScheduleDocument>(d)?
+        .into_schedule()
+        .ok_or_else(|| "Unknown schedule status".into())
+}
+
+#[async_trait::async_trait]
+impl Schedules for MongoSchedules {
+    #[tracing::instrument(name = "Scheduling an issue", skip(self))]
+    async fn create(
+        &self,
+        issue_id: &str,
+        publish_at: DateTime<Utc>,
+    ) -> repository::Result<Schedule> {
+        let insert_err =
+            |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::InsertDb {
+                entry_desc: format!("schedule of issue {}", issue_id),
+                source: e,
+            };
+        let now = Utc::now();
+        let mut document = ScheduleDocument {
+            id: None,
+            issue_id: issue_id.to_owned(),
+            publish_at: publish_at.into(),
+            status: ScheduleStatus::Pending.code().to_owned(),
+            error: None,
+            created_at: now.into(),
+            updated_at: now.into(),
+        };
+        let inserted = self
+            .collection()
+            .insert_one(
+                bson::to_document(&document).map_err(|e| insert_err(Box::new(e)))?,
+                None,
+            )
+            .await
+            .map_err(|e| insert_err(Box::new(e)))?;
+        document.id = inserted.inserted_id.as_object_id();
+        document
+            .into_schedule()
+            .ok_or_else(|| insert_err("Unknown schedule status".into()))
+    }
+
+    async fn pending(&self) -> repository::Result<Vec<Schedule>> {
+        self.find(
+            doc! { "status": ScheduleStatus::Pending.code() },
+            "pending schedules",
+        )
+        .await
+    }
+
+    async fn due(&self, now: DateTime<Utc>) -> repository::Result<Vec<Schedule>> {
+        self.find(
+            doc! { "status": ScheduleStatus::Pending.code(), "publish_at": { "$lte": now } },
+            "due schedules",
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "Rescheduling an issue", skip(self))]
+    async fn reschedule(&self, id: &str, publish_at: DateTime<Utc>) -> repository::Result<bool> {
+        self.update_pending(
+            id,
+            doc! { "publish_at": publish_at, "updated_at": Utc::now() },
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "Cancelling a schedule", skip(self))]
+    async fn cancel(&self, id: &str) -> repository::Result<bool> {
+        self.close(id, ScheduleStatus::Cancelled, None).await
+    }
+
+    async fn close(
+        &self,
+        id: &str,
+        status: ScheduleStatus,
+        error: Option<&str>,
+    ) -> repository::Result<bool> {
+        let mut set = doc! { "status": status.code(), "updated_at": Utc::now() };
+        if let Some(error) = error {
+            set.insert("error", error);
+        }
+        self.update_pending(id, set).await
+    }
+
+    async fn acquire_lease(
+        &self,
+        holder: &str,
+        now: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> repository::Result<bool> {
+        let query_err = |e: mongodb::error::Error| repository::Error::QueryDb {
+            query_desc: "scheduler lease".to_owned(),
+            source: Box::new(e),
+        };
+        let leases = self.db.collection::<Document>(LEASES);
+        let inserted = leases
+            .insert_one(
+                doc! { "_id": LEASE, "holder": holder, "until": until },
+                None,
+            )
+            .await;
+        match inserted {
+            Ok(_) => return Ok(true),
+            Err(e) if is_duplicate_key(&e) => {}
+            Err(e) => return Err(query_err(e)),
+        }
+        let renewed = leases
+            .update_one(
+                doc! {
+                    "_id": LEASE,
+                    "$or": [{ "holder": holder }, { "until": { "$lte": now } }],
+                },
+                doc! { "$set": { "holder": holder, "until": until } },
+                None,
+            )
+            .await
+            .map_err(query_err)?;
+        Ok(renewed.matched_count == 1)
+    }
+}
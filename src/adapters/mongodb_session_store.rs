@@ -0,0 +1,97 @@
+use async_session::{async_trait, Session, SessionStore};
+use mongodb::{
+    bson::{doc, Document},
+    options::UpdateOptions,
+    Collection, Database,
+};
+
+const COLLECTION: &str = "sessions";
+const TTL_INDEX: &str = "expires_at_ttl";
+
+/// Admin sessions: the cookie holds just the session id.
+#[derive(Clone)]
+pub(crate) struct MongoSessionStore {
+    db: Database,
+}
+
+impl std::fmt::Debug for MongoSessionStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MongoSessionStore")
+            .field("collection", &COLLECTION)
+            .finish()
+    }
+}
+
+impl MongoSessionStore {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.db.collection::<Document>(COLLECTION)
+    }
+
+    /// Expired sessions are removed by Mongo.
+    pub(crate) async fn ensure_ttl_index(&self) -> mongodb::error::Result<()> {
+        self.db
+            .run_command(
+                doc! {
+                    "createIndexes": COLLECTION,
+                    "indexes": [{
+                        "key": { "expires_at": 1 },
+                        "name": TTL_INDEX,
+                        "expireAfterSeconds": 0,
+                    }],
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for MongoSessionStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        let found = self
+            .collection()
+            .find_one(doc! { "_id": &id }, None)
+            .await?;
+        Ok(match found {
+            Some(d) => {
+                let session: Session = serde_json::from_str(d.get_str("session")?)?;
+                session.validate()
+            }
+            None => None,
+        })
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        let mut fields = doc! { "session": serde_json::to_string(&session)? };
+        if let Some(expiry) = session.expiry() {
+            fields.insert("expires_at", *expiry);
+        }
+        self.collection()
+            .update_one(
+                doc! { "_id": session.id() },
+                doc! { "$set": fields },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        session.reset_data_changed();
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        self.collection()
+            .delete_one(doc! { "_id": session.id() }, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        self.collection().delete_many(doc! {}, None).await?;
+        Ok(())
+    }
+}
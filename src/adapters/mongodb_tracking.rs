@@ -0,0 +1,53 @@
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Database,
+};
+
+use crate::{
+    repository,
+    tracking::{TrackingEvent, TrackingEvents},
+};
+
+pub(super) const COLLECTION: &str = "tracking_events";
+
+#[derive(Clone)]
+pub(crate) struct MongoTrackingEvents {
+    db: Database,
+}
+
+impl MongoTrackingEvents {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+}
+
+fn optional(value: &Option<String>) -> Bson {
+    value.as_deref().map(Bson::from).unwrap_or(Bson::Null)
+}
+
+#[async_trait::async_trait]
+impl TrackingEvents for MongoTrackingEvents {
+    #[tracing::instrument(name = "Recording a tracking event", skip(self))]
+    async fn record(&self, event: &TrackingEvent) -> repository::Result<()> {
+        self.db
+            .collection::<Document>(COLLECTION)
+            .insert_one(
+                doc! {
+                    "kind": event.kind.code(),
+                    "issue_id": &event.issue_id,
+                    "email": &event.email,
+                    "url": optional(&event.url),
+                    "user_agent": optional(&event.user_agent),
+                    "bot": event.bot,
+                    "at": event.at,
+                },
+                None,
+            )
+            .await
+            .map_err(|e| repository::Error::InsertDb {
+                entry_desc: format!("{:?}", event),
+                source: Box::new(e),
+            })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Document},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    delivery, repository,
+    webhooks::{self, Attempt, Delivery, Event, LogEntry},
+};
+
+const COLLECTION: &str = "webhook_deliveries";
+
+const PENDING: &str = "pending";
+const IN_PROGRESS: &str = "in_progress";
+const DONE: &str = "done";
+const DEAD: &str = "dead";
+
+#[derive(Clone)]
+pub(crate) struct MongoWebhookQueue {
+    db: Database,
+}
+
+impl MongoWebhookQueue {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.db.collection::<Document>(COLLECTION)
+    }
+
+    /// Close `delivery` with `status` if nobody else claimed it in the meantime,
+    /// adding `attempt` to its log.
+    async fn close(
+        &self,
+        delivery: &Delivery,
+        attempt: &Attempt,
+        status: &str,
+        mut set: Document,
+    ) -> repository::Result<()> {
+        let update_err =
+            |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::UpdateDb {
+                entry_desc: format!("webhook delivery {}", delivery.id),
+                source: e,
+            };
+        let id = ObjectId::parse_str(&delivery.id).map_err(|e| update_err(Box::new(e)))?;
+        let attempt = bson::to_document(&AttemptDocument::from(attempt))
+            .map_err(|e| update_err(Box::new(e)))?;
+        set.insert("status", status);
+        set.insert("updated_at", Utc::now());
+        self.collection()
+            .update_one(
+                doc! { "_id": id, "status": IN_PROGRESS, "attempts": delivery.attempts as i32 },
+                doc! { "$set": set, "$push": { "log": attempt } },
+                None,
+            )
+            .await
+            .map_err(|e| update_err(Box::new(e)))?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct AttemptDocument {
+    at: bson::DateTime,
+    #[serde(default)]
+    status: Option<i32>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl From<&Attempt> for AttemptDocument {
+    fn from(a: &Attempt) -> Self {
+        Self {
+            at: a.at.into(),
+            status: a.status.map(i32::from),
+            error: a.error.clone(),
+        }
+    }
+}
+
+impl From<AttemptDocument> for Attempt {
+    fn from(d: AttemptDocument) -> Self {
+        Self {
+            at: d.at.to_chrono(),
+            status: d.status.map(|status| status as u16),
+            error: d.error,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeliveryDocument {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    endpoint: String,
+    event: Event,
+    status: String,
+    attempts: i32,
+    next_attempt_at: bson::DateTime,
+    #[serde(default)]
+    lease_until: Option<bson::DateTime>,
+    #[serde(default)]
+    log: Vec<AttemptDocument>,
+    created_at: bson::DateTime,
+    updated_at: bson::DateTime,
+}
+
+impl DeliveryDocument {
+    fn id(&self) -> String {
+        self.id.map(ObjectId::to_hex).unwrap_or_default()
+    }
+}
+
+impl From<DeliveryDocument> for Delivery {
+    fn from(d: DeliveryDocument) -> Self {
+        Self {
+            id: d.id(),
+            attempts: d.attempts as u32,
+            endpoint: d.endpoint,
+            event: d.event,
+        }
+    }
+}
+
+impl From<DeliveryDocument> for LogEntry {
+    fn from(d: DeliveryDocument) -> Self {
+        Self {
+            id: d.id(),
+            event_id: d.event.id,
+            event_type: d.event.kind,
+            status: d.status,
+            attempts: d.log.into_iter().map(Attempt::from).collect(),
+            created_at: d.created_at.to_chrono(),
+            updated_at: d.updated_at.to_chrono(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl webhooks::WebhookQueue for MongoWebhookQueue {
+    #[tracing::instrument(name = "Enqueuing a webhook event", skip(self, event), fields(event = %event.id))]
+    async fn enqueue(&self, event: &Event, endpoints: &[String]) -> repository::Result<()> {
+        let insert_err =
+            |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::InsertDb {
+                entry_desc: format!("{:?}", event),
+                source: e,
+            };
+        let now = Utc::now();
+        let docs = endpoints
+            .iter()
+            .map(|endpoint| {
+                bson::to_document(&DeliveryDocument {
+                    id: None,
+                    endpoint: endpoint.clone(),
+                    event: event.clone(),
+                    status: PENDING.to_owned(),
+                    attempts: 0,
+                    next_attempt_at: now.into(),
+                    lease_until: None,
+                    log: Vec::new(),
+                    created_at: now.into(),
+                    updated_at: now.into(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| insert_err(Box::new(e)))?;
+        self.collection()
+            .insert_many(docs, None)
+            .await
+            .map_err(|e| insert_err(Box::new(e)))?;
+        Ok(())
+    }
+
+    async fn claim(&self, lease: Duration) -> repository::Result<Option<Delivery>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: "claim a webhook delivery".to_owned(),
+            source: e,
+        };
+        let now = Utc::now();
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! { "next_attempt_at": 1 })
+            .return_document(ReturnDocument::After)
+            .build();
+        let claimed = self
+            .collection()
+            .find_one_and_update(
+                doc! { "$or": [
+                    { "status": PENDING, "next_attempt_at": { "$lte": now } },
+                    { "status": IN_PROGRESS, "lease_until": { "$lte": now } },
+                ] },
+                doc! {
+                    "$set": {
+                        "status": IN_PROGRESS,
+                        "lease_until": delivery::after(now, lease),
+                        "updated_at": now,
+                    },
+                    "$inc": { "attempts": 1 },
+                },
+                options,
+            )
+            .await
+            .map_err(|e| query_err(Box::new(e)))?;
+        claimed
+            .map(|d| {
+                bson::from_document::<DeliveryDocument>(d)
+                    .map(Delivery::from)
+                    .map_err(|e| query_err(Box::new(e)))
+            })
+            .transpose()
+    }
+
+    async fn complete(&self, delivery: &Delivery, attempt: &Attempt) -> repository::Result<()> {
+        self.close(delivery, attempt, DONE, doc! {}).await
+    }
+
+    async fn retry(
+        &self,
+        delivery: &Delivery,
+        attempt: &Attempt,
+        at: DateTime<Utc>,
+    ) -> repository::Result<()> {
+        self.close(delivery, attempt, PENDING, doc! { "next_attempt_at": at })
+            .await
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, attempt: &Attempt) -> repository::Result<()> {
+        self.close(delivery, attempt, DEAD, doc! {}).await
+    }
+
+    async fn log(&self, endpoint: &str, limit: i64) -> repository::Result<Vec<LogEntry>> {
+        let query_err = |e: Box<dyn std::error::Error + Send + Sync>| repository::Error::QueryDb {
+            query_desc: format!("webhook deliveries to {}", endpoint),
+            source: e,
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .limit(limit)
+            .build();
+        let docs: Vec<_> = self
+            .collection()
+            .find(doc! { "endpoint": endpoint }, options)
+            .await
+            .map_err(|e| query_err(Box::new(e)))?
+            .try_collect()
+            .await
+            .map_err(|e| query_err(Box::new(e)))?;
+        docs.into_iter()
+            .map(|d| {
+                bson::from_document::<DeliveryDocument>(d)
+                    .map(LogEntry::from)
+                    .map_err(|e| query_err(Box::new(e)))
+            })
+            .collect()
+    }
+}
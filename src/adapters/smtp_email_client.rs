@@ -0,0 +1,488 @@
+//! Send emails through an SMTP relay (RFC 5321), e.g. the company mail server.
+//! Connections are secured by STARTTLS or implicit TLS and kept open in a pool
+//! for the next emails.
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_native_tls::{TlsConnector, TlsStream};
+use async_std::{net::TcpStream, sync::Mutex};
+use chrono::Utc;
+use futures::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use thiserror::Error;
+
+use crate::{
+    configuration::{Secret, SmtpAuth, SmtpSettings, SmtpTls},
+    email::{Email, EmailClient, SendError},
+    mime::Message,
+    telemetry::pii,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Servers drop idle clients: don't trust older connections
+const MAX_IDLE: Duration = Duration::from_secs(60);
+/// Reply classes: the first digit of the code
+const COMPLETION: u16 = 2;
+const INTERMEDIATE: u16 = 3;
+/// The server is closing the connection (e.g. idle timeout)
+const CLOSING: u16 = 421;
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Reply {
+    code: u16,
+    lines: Vec<String>,
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.lines.join(" "))
+    }
+}
+
+#[derive(Error, Debug)]
+enum SmtpError {
+    #[error("Connection failure: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("TLS failure: {0}")]
+    Tls(String),
+    #[error("Unexpected reply: '{0}'")]
+    Protocol(String),
+    #[error("{0} refused: {1}")]
+    Refused(&'static str, Reply),
+    #[error("{0}")]
+    Unsupported(&'static str),
+    #[error("Invalid address '{0}'")]
+    Address(String),
+}
+
+impl SmtpError {
+    /// The connection can't be used anymore: a pooled one was probably closed by
+    /// the server.
+    fn is_broken(&self) -> bool {
+        match self {
+            SmtpError::Io(_) | SmtpError::Protocol(_) => true,
+            SmtpError::Refused(_, reply) => reply.code == CLOSING,
+            _ => false,
+        }
+    }
+}
+
+impl From<SmtpError> for SendError {
+    fn from(e: SmtpError) -> Self {
+        match &e {
+            SmtpError::Refused(_, reply) if reply.code < 500 => SendError::Transient(e.to_string()),
+            SmtpError::Io(_) | SmtpError::Tls(_) | SmtpError::Protocol(_) => {
+                SendError::Transient(e.to_string())
+            }
+            _ => SendError::Permanent(e.to_string()),
+        }
+    }
+}
+
+/// `code`, whether it's the last line and the text of a reply line.
+fn parse_line(line: &str) -> Result<(u16, bool, &str), SmtpError> {
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+    let code = line
+        .get(..3)
+        .and_then(|code| code.parse::<u16>().ok())
+        .filter(|code| (200..600).contains(code))
+        .ok_or_else(|| SmtpError::Protocol(line.to_owned()))?;
+    match line.get(3..4) {
+        None => Ok((code, true, "")),
+        Some(" ") => Ok((code, true, &line[4..])),
+        Some("-") => Ok((code, false, &line[4..])),
+        Some(_) => Err(SmtpError::Protocol(line.to_owned())),
+    }
+}
+
+/// A line starting with `.` would end the data: double it (RFC 5321 4.5.2).
+fn dot_stuff(message: &str) -> String {
+    let mut stuffed = message.replace("\r\n.", "\r\n..");
+    if stuffed.starts_with('.') {
+        stuffed.insert(0, '.');
+    }
+    if !stuffed.ends_with("\r\n") {
+        stuffed.push_str("\r\n");
+    }
+    stuffed.push_str(".\r\n");
+    stuffed
+}
+
+/// Addresses end up in the commands: no line breaks or angle brackets.
+fn address(address: &str) -> Result<&str, SmtpError> {
+    if address.is_empty() || address.contains(&['\r', '\n', '<', '>'][..]) {
+        Err(SmtpError::Address(address.to_owned()))
+    } else {
+        Ok(address)
+    }
+}
+
+fn auth_plain(username: &str, password: &Secret<String>) -> String {
+    base64::encode(format!("\0{}\0{}", username, password.expose()))
+}
+
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+    /// The EHLO keywords with their parameters, e.g. `AUTH PLAIN LOGIN`
+    extensions: Vec<String>,
+    last_used: Instant,
+}
+
+impl Connection {
+    async fn open(settings: &SmtpSettings) -> Result<Self, SmtpError> {
+        let tcp = TcpStream::connect((settings.host.as_str(), settings.port())).await?;
+        let stream: Box<dyn Stream> = match settings.tls {
+            SmtpTls::Tls => Box::new(tls(&settings.host, tcp).await?),
+            SmtpTls::StartTls | SmtpTls::None => Box::new(tcp),
+        };
+        let mut connection = Self {
+            stream: BufReader::new(stream),
+            extensions: vec![],
+            last_used: Instant::now(),
+        };
+        connection.expect("Greeting", COMPLETION).await?;
+        connection.hello().await?;
+        if settings.tls == SmtpTls::StartTls {
+            if !connection.supports("STARTTLS") {
+                return Err(SmtpError::Unsupported(
+                    "The server doesn't support STARTTLS",
+                ));
+            }
+            connection
+                .execute("STARTTLS", "STARTTLS", COMPLETION)
+                .await?;
+            connection = connection.upgrade(&settings.host).await?;
+            // What the server told in plain text can't be trusted
+            connection.hello().await?;
+        }
+        if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+            connection
+                .authenticate(settings.auth, username, password)
+                .await?;
+        }
+        Ok(connection)
+    }
+
+    async fn upgrade(self, host: &str) -> Result<Self, SmtpError> {
+        if !self.stream.buffer().is_empty() {
+            // Commands injected before the handshake
+            return Err(SmtpError::Protocol("data before TLS handshake".to_owned()));
+        }
+        let stream: Box<dyn Stream> = Box::new(tls(host, self.stream.into_inner()).await?);
+        Ok(Self {
+            stream: BufReader::new(stream),
+            extensions: vec![],
+            last_used: Instant::now(),
+        })
+    }
+
+    async fn hello(&mut self) -> Result<(), SmtpError> {
+        let reply = self.execute("EHLO", "EHLO localhost", COMPLETION).await?;
+        self.extensions = reply
+            .lines
+            .into_iter()
+            .skip(1)
+            .map(|line| line.to_uppercase())
+            .collect();
+        Ok(())
+    }
+
+    fn supports(&self, keyword: &str) -> bool {
+        self.extensions
+            .iter()
+            .any(|line| line.split_whitespace().next() == Some(keyword))
+    }
+
+    /// `AUTH PLAIN LOGIN` or the obsolete `AUTH=PLAIN LOGIN`.
+    fn supports_auth(&self, mechanism: &str) -> bool {
+        self.extensions.iter().any(|line| {
+            let mut words = line.split([' ', '=']);
+            words.next() == Some("AUTH") && words.any(|word| word == mechanism)
+        })
+    }
+
+    async fn authenticate(
+        &mut self,
+        mechanism: Option<SmtpAuth>,
+        username: &str,
+        password: &Secret<String>,
+    ) -> Result<(), SmtpError> {
+        let mechanism = match mechanism {
+            Some(mechanism) => mechanism,
+            None if self.supports_auth("PLAIN") => SmtpAuth::Plain,
+            None if self.supports_auth("LOGIN") => SmtpAuth::Login,
+            None => return Err(SmtpError::Unsupported("No supported AUTH mechanism")),
+        };
+        match mechanism {
+            SmtpAuth::Plain => {
+                let command = format!("AUTH PLAIN {}", auth_plain(username, password));
+                self.execute("AUTH", &command, COMPLETION).await?;
+            }
+            SmtpAuth::Login => {
+                self.execute("AUTH", "AUTH LOGIN", INTERMEDIATE).await?;
+                self.execute("AUTH", &base64::encode(username), INTERMEDIATE)
+                    .await?;
+                self.execute("AUTH", &base64::encode(password.expose()), COMPLETION)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn deliver(&mut self, from: &str, to: &str, message: &str) -> Result<(), SmtpError> {
+        let to = address(to)?;
+        self.execute("MAIL FROM", &format!("MAIL FROM:<{}>", from), COMPLETION)
+            .await?;
+        self.execute("RCPT TO", &format!("RCPT TO:<{}>", to), COMPLETION)
+            .await?;
+        self.execute("DATA", "DATA", INTERMEDIATE).await?;
+        self.stream
+            .get_mut()
+            .write_all(dot_stuff(message).as_bytes())
+            .await?;
+        self.stream.get_mut().flush().await?;
+        self.expect("Message", COMPLETION).await?;
+        self.last_used = Instant::now();
+        Ok(())
+    }
+
+    /// Abort a refused transaction: the connection can be used again.
+    async fn reset(&mut self) -> Result<(), SmtpError> {
+        self.execute("RSET", "RSET", COMPLETION).await?;
+        Ok(())
+    }
+
+    /// Send `command` and read the reply: an error if it's not of the `expected`
+    /// class. `name` stands for the command in errors, that must not show
+    /// credentials.
+    async fn execute(
+        &mut self,
+        name: &'static str,
+        command: &str,
+        expected: u16,
+    ) -> Result<Reply, SmtpError> {
+        let writer = self.stream.get_mut();
+        writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        writer.flush().await?;
+        self.expect(name, expected).await
+    }
+
+    async fn expect(&mut self, name: &'static str, expected: u16) -> Result<Reply, SmtpError> {
+        let reply = self.reply().await?;
+        if reply.code / 100 == expected {
+            Ok(reply)
+        } else {
+            Err(SmtpError::Refused(name, reply))
+        }
+    }
+
+    async fn reply(&mut self) -> Result<Reply, SmtpError> {
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            let (code, last, text) = parse_line(&line)?;
+            lines.push(text.to_owned());
+            if last {
+                return Ok(Reply { code, lines });
+            }
+        }
+    }
+}
+
+async fn tls<S: AsyncRead + AsyncWrite + Unpin>(
+    host: &str,
+    stream: S,
+) -> Result<TlsStream<S>, SmtpError> {
+    TlsConnector::new()
+        .connect(host, stream)
+        .await
+        .map_err(|e| SmtpError::Tls(e.to_string()))
+}
+
+#[derive(Clone)]
+pub(crate) struct SmtpEmailClient {
+    settings: Arc<SmtpSettings>,
+    /// Idle connections
+    pool: Arc<Mutex<Vec<Connection>>>,
+    timeout: Duration,
+}
+
+impl SmtpEmailClient {
+    pub(crate) fn new(settings: &SmtpSettings) -> Self {
+        Self {
+            settings: Arc::new(settings.clone()),
+            pool: Arc::new(Mutex::new(vec![])),
+            timeout: settings.timeout.unwrap_or(DEFAULT_TIMEOUT),
+        }
+    }
+
+    /// A pooled connection, if any.
+    async fn pooled(&self) -> Option<Connection> {
+        let mut pool = self.pool.lock().await;
+        while let Some(connection) = pool.pop() {
+            if connection.last_used.elapsed() < MAX_IDLE {
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    async fn release(&self, connection: Connection) {
+        let mut pool = self.pool.lock().await;
+        if pool.len() < self.settings.pool_size {
+            pool.push(connection);
+        }
+    }
+
+    async fn deliver(&self, to: &str, message: &str) -> Result<(), SmtpError> {
+        let from = address(&self.settings.sender)?;
+        let mut connection = match self.pooled().await {
+            Some(mut connection) => match connection.deliver(from, to, message).await {
+                Err(e) if e.is_broken() => Connection::open(&self.settings).await?,
+                result => return self.done(connection, result).await,
+            },
+            None => Connection::open(&self.settings).await?,
+        };
+        let result = connection.deliver(from, to, message).await;
+        self.done(connection, result).await
+    }
+
+    /// Give the connection back to the pool, if it can still be used.
+    async fn done(
+        &self,
+        mut connection: Connection,
+        result: Result<(), SmtpError>,
+    ) -> Result<(), SmtpError> {
+        match &result {
+            Ok(()) => self.release(connection).await,
+            Err(e) if !e.is_broken() => {
+                if connection.reset().await.is_ok() {
+                    self.release(connection).await
+                }
+            }
+            Err(_) => {}
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailClient for SmtpEmailClient {
+    #[tracing::instrument(
+        name = "Sending an email over SMTP",
+        skip(self, email),
+        fields(
+            to = %pii::email(&email.to),
+        )
+    )]
+    async fn send(&self, email: &Email) -> Result<(), SendError> {
+        let domain = self.settings.sender.rsplit('@').next().unwrap_or_default();
+        let message_id = format!("{}@{}", uuid::Uuid::new_v4().to_simple(), domain);
+        // The envelope keeps the configured sender: it's the one receiving bounces
+        let message = Message {
+            from: email.from.as_deref().unwrap_or(&self.settings.sender),
+            email,
+            date: Utc::now(),
+            message_id: &message_id,
+        }
+        .format();
+        async_std::future::timeout(self.timeout, self.deliver(&email.to, &message))
+            .await
+            .map_err(|_| SendError::Transient(format!("no response in {:?}", self.timeout)))?
+            .map_err(SendError::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest(line, expected,
+        case::last("250 OK\r\n", (250, true, "OK")),
+        case::more("250-SIZE 1000\r\n", (250, false, "SIZE 1000")),
+        case::bare("250\r\n", (250, true, "")),
+    )]
+    fn should_parse_reply_lines(line: &str, expected: (u16, bool, &str)) {
+        assert_eq!(expected, parse_line(line).unwrap());
+    }
+
+    #[rstest(line,
+        case::no_code("Hello\r\n"),
+        case::bad_separator("250+OK\r\n"),
+        case::out_of_range("999 OK\r\n"),
+    )]
+    fn should_reject_invalid_reply_lines(line: &str) {
+        assert!(matches!(parse_line(line), Err(SmtpError::Protocol(_))));
+    }
+
+    #[test]
+    fn should_stuff_dots() {
+        assert_eq!(
+            "..first\r\nline\r\n..\r\n...end\r\n.\r\n",
+            dot_stuff(".first\r\nline\r\n.\r\n..end")
+        );
+    }
+
+    #[rstest(code, transient,
+        case::busy(450, true),
+        case::closing(421, true),
+        case::mailbox_unavailable(550, false),
+        case::bad_credentials(535, false),
+    )]
+    fn should_classify_refusals(code: u16, transient: bool) {
+        let error: SendError = SmtpError::Refused(
+            "RCPT TO",
+            Reply {
+                code,
+                lines: vec!["Refused".to_owned()],
+            },
+        )
+        .into();
+
+        assert_eq!(transient, matches!(error, SendError::Transient(_)));
+    }
+
+    #[test]
+    fn refusals_should_keep_the_connection() {
+        let refused = |code| {
+            SmtpError::Refused(
+                "RCPT TO",
+                Reply {
+                    code,
+                    lines: vec![],
+                },
+            )
+        };
+
+        assert!(!refused(550).is_broken());
+        assert!(refused(CLOSING).is_broken());
+        assert!(SmtpError::Protocol("garbage".to_owned()).is_broken());
+    }
+
+    #[test]
+    fn should_reject_addresses_that_inject_commands() {
+        assert!(address("antonio@gmail.com").is_ok());
+        assert!(address("antonio@gmail.com>\r\nRCPT TO:<other@gmail.com").is_err());
+    }
+
+    #[test]
+    fn plain_credentials_should_be_encoded() {
+        assert_eq!(
+            "AHVzZXIAcGFzc3dvcmQ=",
+            auth_plain("user", &"password".to_owned().into())
+        );
+    }
+}
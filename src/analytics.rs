@@ -0,0 +1,225 @@
+//! Delivery and engagement numbers of a sent issue. They are aggregated from the
+//! delivery queue, the provider events, the tracking events and the preference
+//! changes, then kept as a rollup: big lists don't aggregate on every request.
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    issues::{IssuesRepository, Status},
+    repository,
+    state::StateTrait,
+};
+
+/// How long a rollup is served before it's aggregated again
+const REFRESH_MINUTES: i64 = 5;
+/// How many links the report shows
+pub(crate) const TOP_LINKS: i64 = 10;
+
+/// Recipients who did something count once, however many times they did it.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct Totals {
+    pub(crate) recipients: u64,
+    pub(crate) delivered: u64,
+    /// Given up on after the retries
+    pub(crate) failed: u64,
+    /// Hard or soft, before the next issue of the list
+    pub(crate) bounced: u64,
+    pub(crate) unique_opens: u64,
+    pub(crate) unique_clicks: u64,
+    /// Left the list of the issue before its next issue
+    pub(crate) unsubscribes: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct LinkClicks {
+    pub(crate) url: String,
+    pub(crate) clicks: u64,
+    pub(crate) unique_clicks: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Hour {
+    pub(crate) hour: DateTime<Utc>,
+    pub(crate) opens: u64,
+    pub(crate) clicks: u64,
+}
+
+/// Opens and clicks of bots are left out.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Report {
+    pub(crate) issue_id: String,
+    pub(crate) list: String,
+    #[serde(flatten)]
+    pub(crate) totals: Totals,
+    /// The most clicked first
+    pub(crate) top_links: Vec<LinkClicks>,
+    /// The oldest first: hours without opens and clicks are left out
+    pub(crate) hours: Vec<Hour>,
+    pub(crate) computed_at: DateTime<Utc>,
+}
+
+impl Report {
+    fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        now < self.computed_at + Duration::minutes(REFRESH_MINUTES)
+    }
+
+    /// One `metric,key,value` row per number: the key is the url of the link or
+    /// the hour, if any.
+    pub(crate) fn write_csv(&self, w: impl std::io::Write) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(w);
+        writer.write_record(["metric", "key", "value"])?;
+        let totals = &self.totals;
+        for &(metric, value) in &[
+            ("recipients", totals.recipients),
+            ("delivered", totals.delivered),
+            ("failed", totals.failed),
+            ("bounced", totals.bounced),
+            ("unique_opens", totals.unique_opens),
+            ("unique_clicks", totals.unique_clicks),
+            ("unsubscribes", totals.unsubscribes),
+        ] {
+            writer.write_record([metric, "", &value.to_string()])?;
+        }
+        for link in &self.top_links {
+            writer.write_record(["link_clicks", &link.url, &link.clicks.to_string()])?;
+            writer.write_record([
+                "link_unique_clicks",
+                &link.url,
+                &link.unique_clicks.to_string(),
+            ])?;
+        }
+        for hour in &self.hours {
+            let key = hour.hour.to_rfc3339();
+            writer.write_record(["opens", &key, &hour.opens.to_string()])?;
+            writer.write_record(["clicks", &key, &hour.clicks.to_string()])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub(crate) enum AnalyticsError {
+    #[error("Issue not found")]
+    NotFound,
+    #[error("The issue was not sent yet")]
+    NotSent,
+    #[error("Repository failure: {0}")]
+    Repository(String),
+}
+
+impl From<repository::Error> for AnalyticsError {
+    fn from(e: repository::Error) -> Self {
+        AnalyticsError::Repository(format!("{:?}", e))
+    }
+}
+
+#[async_trait::async_trait]
+pub(crate) trait Analytics: Send + Sync {
+    /// The last rollup of issue `id`, if any.
+    async fn rollup(&self, id: &str) -> repository::Result<Option<Report>>;
+
+    /// Aggregate the numbers of issue `id` of `list`, with the bounces and the
+    /// unsubscribes between `since` and `until`, and keep them as its rollup.
+    async fn aggregate(
+        &self,
+        id: &str,
+        list: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> repository::Result<Report>;
+}
+
+/// The numbers of the sent issue `id` at `now`.
+#[tracing::instrument(name = "Reporting the analytics of an issue", skip(state))]
+pub(crate) async fn report<S: StateTrait>(
+    state: &S,
+    id: &str,
+    now: DateTime<Utc>,
+) -> Result<Report, AnalyticsError> {
+    let issue = state
+        .issues_repository()
+        .get(id)
+        .await?
+        .ok_or(AnalyticsError::NotFound)?;
+    let sent_at = match issue.sent_at {
+        Some(sent_at) if issue.status == Status::Sent => sent_at,
+        _ => return Err(AnalyticsError::NotSent),
+    };
+    if let Some(rollup) = state.analytics().rollup(id).await? {
+        if rollup.is_fresh(now) {
+            return Ok(rollup);
+        }
+    }
+    // Later bounces and unsubscribes are the next issue's
+    let until = state
+        .issues_repository()
+        .sent_between(&issue.list, sent_at, now)
+        .await?
+        .first()
+        .and_then(|next| next.sent_at)
+        .unwrap_or(now);
+    let report = state
+        .analytics()
+        .aggregate(&issue.id, &issue.list, sent_at, until, now)
+        .await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn rollup() -> Report {
+        Report {
+            issue_id: "5fd0".to_owned(),
+            list: "rust".to_owned(),
+            totals: Totals {
+                recipients: 3,
+                delivered: 2,
+                failed: 1,
+                unique_opens: 2,
+                unique_clicks: 1,
+                ..Totals::default()
+            },
+            top_links: vec![LinkClicks {
+                url: "https://example.com/?a=1,2".to_owned(),
+                clicks: 2,
+                unique_clicks: 1,
+            }],
+            hours: vec![Hour {
+                hour: Utc.ymd(2020, 12, 9).and_hms(10, 0, 0),
+                opens: 3,
+                clicks: 2,
+            }],
+            computed_at: Utc.ymd(2020, 12, 9).and_hms(12, 0, 0),
+        }
+    }
+
+    #[test]
+    fn rollups_should_be_refreshed() {
+        let computed_at = rollup().computed_at;
+
+        assert!(rollup().is_fresh(computed_at + Duration::minutes(4)));
+        assert!(!rollup().is_fresh(computed_at + Duration::minutes(5)));
+    }
+
+    #[test]
+    fn csv_should_have_a_row_per_number() {
+        let mut csv = Vec::new();
+
+        rollup().write_csv(&mut csv).unwrap();
+
+        let csv = String::from_utf8(csv).unwrap();
+        let rows: Vec<_> = csv.lines().collect();
+        assert_eq!(12, rows.len());
+        assert_eq!("metric,key,value", rows[0]);
+        assert_eq!("recipients,,3", rows[1]);
+        assert_eq!(r#"link_clicks,"https://example.com/?a=1,2",2"#, rows[8]);
+        assert_eq!("opens,2020-12-09T10:00:00+00:00,3", rows[10]);
+    }
+}
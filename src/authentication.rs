@@ -0,0 +1,192 @@
+//! Admin users log in the web pages with username and password: passwords are
+//! stored as Argon2id hashes.
+use rand::Rng;
+use thiserror::Error;
+
+use crate::{
+    configuration::{DatabaseSettings, Secret},
+    repository,
+    state::{State, StateTrait},
+};
+
+pub(crate) const MIN_PASSWORD_LENGTH: usize = 12;
+pub(crate) const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// Verified when the user doesn't exist, so that the response time doesn't tell
+/// which usernames are valid.
+const DUMMY_HASH: &str = "$argon2id$v=19$m=4096,t=3,p=1$c29tZXNhbHRzb21lc2FsdA$OPp3JJ0QKXhIO3ZGsF4d1XRYLBOpp8Y2TU+K0xFeP5c";
+
+#[derive(Error, Debug)]
+pub(crate) enum PasswordError {
+    #[error("Password should have {min} to {max} characters")]
+    Length { min: usize, max: usize },
+    #[error("Cannot hash password")]
+    Hash(#[from] argon2::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum CreateAdminError {
+    #[error("Invalid password: {0}")]
+    Password(String),
+    #[error("Cannot connect to the database: {0}")]
+    Connection(String),
+    #[error("Repository failure: {0}")]
+    Repository(String),
+}
+
+#[async_trait::async_trait]
+pub(crate) trait AdminUsersRepository: Send + Sync {
+    async fn password_hash(&self, username: &str) -> repository::Result<Option<String>>;
+
+    /// Create the user or change its password.
+    async fn save(&self, username: &str, password_hash: &str) -> repository::Result<()>;
+}
+
+pub(crate) fn check_password(password: &str) -> Result<(), PasswordError> {
+    let length = password.chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        return Err(PasswordError::Length {
+            min: MIN_PASSWORD_LENGTH,
+            max: MAX_PASSWORD_LENGTH,
+        });
+    }
+    Ok(())
+}
+
+fn config() -> argon2::Config<'static> {
+    argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        ..Default::default()
+    }
+}
+
+/// Hashing is slow on purpose: it runs on a blocking thread.
+pub(crate) async fn hash_password(password: &Secret<String>) -> Result<String, PasswordError> {
+    check_password(password.expose())?;
+    let password = password.clone();
+    async_std::task::spawn_blocking(move || {
+        let salt: [u8; 16] = rand::thread_rng().gen();
+        Ok(argon2::hash_encoded(
+            password.expose().as_bytes(),
+            &salt,
+            &config(),
+        )?)
+    })
+    .await
+}
+
+async fn verify_password(hash: String, password: &Secret<String>) -> bool {
+    let password = password.clone();
+    async_std::task::spawn_blocking(move || {
+        argon2::verify_encoded(&hash, password.expose().as_bytes()).unwrap_or_default()
+    })
+    .await
+}
+
+/// `true` if `username` exists and `password` is its password.
+pub(crate) async fn validate_credentials<R: AdminUsersRepository>(
+    repository: &R,
+    username: &str,
+    password: &Secret<String>,
+) -> repository::Result<bool> {
+    let hash = repository.password_hash(username).await?;
+    let known = hash.is_some();
+    let verified = verify_password(hash.unwrap_or_else(|| DUMMY_HASH.to_owned()), password).await;
+    Ok(known && verified)
+}
+
+/// Create the admin `username`, or reset its password if it already exists.
+pub async fn create_admin(
+    cfg: &DatabaseSettings,
+    username: &str,
+    password: &Secret<String>,
+) -> Result<(), CreateAdminError> {
+    let hash = hash_password(password)
+        .await
+        .map_err(|e| CreateAdminError::Password(e.to_string()))?;
+    let state = State::new(cfg)
+        .await
+        .map_err(|e| CreateAdminError::Connection(e.to_string()))?;
+    state
+        .admin_users()
+        .save(username, &hash)
+        .await
+        .map_err(|e| CreateAdminError::Repository(format!("{:?}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeAdminUsers(Mutex<HashMap<String, String>>);
+
+    #[async_trait::async_trait]
+    impl AdminUsersRepository for FakeAdminUsers {
+        async fn password_hash(&self, username: &str) -> repository::Result<Option<String>> {
+            Ok(self.0.lock().unwrap().get(username).cloned())
+        }
+
+        async fn save(&self, username: &str, password_hash: &str) -> repository::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(username.to_owned(), password_hash.to_owned());
+            Ok(())
+        }
+    }
+
+    fn secret(value: &str) -> Secret<String> {
+        value.to_owned().into()
+    }
+
+    #[async_std::test]
+    async fn should_validate_just_the_right_password() {
+        let users = FakeAdminUsers::default();
+        let hash = hash_password(&secret("correct horse battery"))
+            .await
+            .unwrap();
+        users.save("admin", &hash).await.unwrap();
+
+        assert!(
+            validate_credentials(&users, "admin", &secret("correct horse battery"))
+                .await
+                .unwrap()
+        );
+        assert!(
+            !validate_credentials(&users, "admin", &secret("wrong horse battery"))
+                .await
+                .unwrap()
+        );
+        assert!(
+            !validate_credentials(&users, "other", &secret("correct horse battery"))
+                .await
+                .unwrap()
+        );
+    }
+
+    #[async_std::test]
+    async fn should_salt_hashes() {
+        let password = secret("correct horse battery");
+
+        assert_ne!(
+            hash_password(&password).await.unwrap(),
+            hash_password(&password).await.unwrap()
+        );
+    }
+
+    #[async_std::test]
+    async fn should_reject_short_passwords() {
+        assert!(matches!(
+            hash_password(&secret("short")).await,
+            Err(PasswordError::Length { .. })
+        ));
+    }
+
+    #[test]
+    fn dummy_hash_should_be_valid() {
+        assert!(argon2::verify_encoded(DUMMY_HASH, b"anything").is_ok());
+    }
+}
@@ -0,0 +1,319 @@
+use std::path::{Path, PathBuf};
+
+use super::{
+    into_settings, resolve_secret_files, ConfigurationError, Settings, ENV_PREFIX, ENV_SEPARATOR,
+};
+
+pub const CONFIG_DIR_ENV: &str = "APP_CONFIG_DIR";
+pub const PROFILE_ENV: &str = "APP_ENVIRORMENT";
+pub const DEFAULT_PROFILE: &str = "local";
+const DEFAULT_CONFIG_DIR: &str = "configuration";
+const BASE: &str = "base";
+const LOCAL_OVERLAY: &str = "local";
+const EXTENSIONS: &[&str] = &["yaml", "yml", "toml", "json"];
+
+/// Load the settings by layering, from the lowest to the highest priority:
+///
+/// 1. `base` file
+/// 2. `<profile>` file
+/// 3. `base.local` and `<profile>.local` optional overlays (meant to be git ignored)
+/// 4. `APP__` environment variables
+///
+/// Every file can be YAML, TOML or JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigurationLoader {
+    dir: PathBuf,
+    profile: String,
+}
+
+impl ConfigurationLoader {
+    pub fn new(dir: impl Into<PathBuf>, profile: impl Into<String>) -> Self {
+        Self {
+            dir: dir.into(),
+            profile: profile.into(),
+        }
+    }
+
+    /// Use `APP_CONFIG_DIR` and `APP_ENVIRORMENT` if defined. Otherwise look for the
+    /// `configuration` directory in the current directory and then near the executable.
+    pub fn from_env() -> Self {
+        let dir = std::env::var_os(CONFIG_DIR_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(default_dir);
+        let profile = std::env::var(PROFILE_ENV)
+            .map(|p| p.to_lowercase())
+            .unwrap_or_else(|_| DEFAULT_PROFILE.to_owned());
+        Self::new(dir, profile)
+    }
+
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = profile.into();
+        self
+    }
+
+    pub fn config_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn profile_name(&self) -> &str {
+        &self.profile
+    }
+
+    /// All the profiles that have a file in the configuration directory.
+    pub fn profiles(&self) -> Result<Vec<String>, ConfigurationError> {
+        let entries = std::fs::read_dir(&self.dir)
+            .map_err(|e| ConfigurationError::Directory(self.dir.clone(), e))?;
+        let mut profiles: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| has_known_extension(path))
+            .filter_map(|path| path.file_stem()?.to_str().map(str::to_owned))
+            .filter(|stem| stem != BASE && !stem.ends_with(&format!(".{}", LOCAL_OVERLAY)))
+            .collect();
+        profiles.sort();
+        profiles.dedup();
+        Ok(profiles)
+    }
+
+    pub fn load(&self) -> Result<Settings, ConfigurationError> {
+        let mut settings = config::Config::default();
+
+        let base = self
+            .find(BASE)?
+            .ok_or_else(|| ConfigurationError::MissingBase(self.dir.clone()))?;
+        settings.merge(config::File::from(base))?;
+
+        let profile = self
+            .find(&self.profile)?
+            .ok_or_else(|| ConfigurationError::UnknownProfile {
+                profile: self.profile.clone(),
+                available: self.profiles().unwrap_or_default(),
+            })?;
+        settings.merge(config::File::from(profile))?;
+
+        for overlay in &[BASE, self.profile.as_str()] {
+            if let Some(path) = self.find(&format!("{}.{}", overlay, LOCAL_OVERLAY))? {
+                settings.merge(config::File::from(path))?;
+            }
+        }
+
+        // The prefix is `app_` because `config` appends a single `_`: in this way we take just
+        // the `APP__` variables and leave out the ones like `APP_ENVIRORMENT`
+        settings.merge(
+            config::Environment::with_prefix(&format!("{}_", ENV_PREFIX))
+                .separator(ENV_SEPARATOR),
+        )?;
+
+        resolve_secret_files(&mut settings)?;
+
+        into_settings(settings)
+    }
+
+    /// The most recent modification time among the configuration files: used to
+    /// detect changes.
+    pub fn last_modified(&self) -> Option<std::time::SystemTime> {
+        std::fs::read_dir(&self.dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| has_known_extension(&entry.path()))
+            .filter_map(|entry| entry.metadata().ok()?.modified().ok())
+            .max()
+    }
+
+    /// Look for the `name` file with any of the supported extensions.
+    fn find(&self, name: &str) -> Result<Option<PathBuf>, ConfigurationError> {
+        let mut found = EXTENSIONS
+            .iter()
+            .map(|ext| self.dir.join(format!("{}.{}", name, ext)))
+            .filter(|path| path.is_file());
+        match (found.next(), found.next()) {
+            (Some(first), Some(second)) => Err(ConfigurationError::Ambiguous(first, second)),
+            (first, _) => Ok(first),
+        }
+    }
+}
+
+impl Default for ConfigurationLoader {
+    fn default() -> Self {
+        Self::from_env()
+    }
+}
+
+fn has_known_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| EXTENSIONS.contains(&ext))
+        .unwrap_or_default()
+}
+
+fn default_dir() -> PathBuf {
+    let current = std::env::current_dir()
+        .expect("Cannot determine current directory")
+        .join(DEFAULT_CONFIG_DIR);
+    if current.is_dir() {
+        return current;
+    }
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(DEFAULT_CONFIG_DIR)))
+        .filter(|dir| dir.is_dir())
+        .unwrap_or(current)
+}
+
+#[cfg(test)]
+mod test {
+    use unindent::Unindent;
+
+    use super::*;
+
+    struct ConfigDir(PathBuf);
+
+    impl ConfigDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("z2p_config_{}", name));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn file(self, name: &str, content: &str) -> Self {
+            // `unindent()` doesn't count an unindented first line: it would flatten inline YAML
+            let content = if content.starts_with('\n') {
+                content.unindent()
+            } else {
+                content.to_owned()
+            };
+            std::fs::write(self.0.join(name), content).unwrap();
+            self
+        }
+
+        fn loader(&self, profile: &str) -> ConfigurationLoader {
+            ConfigurationLoader::new(&self.0, profile)
+        }
+    }
+
+    impl Drop for ConfigDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const BASE_YAML: &str = r#"
+        application:
+          host: 127.0.0.1
+          port: 8000
+        database:
+          host: localhost
+          port: 27017
+          username: mongo
+          password: password
+          name: z2p
+        "#;
+
+    #[test]
+    fn should_layer_profile_over_base() {
+        let dir = ConfigDir::new("layer")
+            .file("base.yaml", BASE_YAML)
+            .file("staging.yaml", "application:\n  port: 9000\n");
+
+        let settings = dir.loader("staging").load().unwrap();
+
+        assert_eq!(9000, settings.application.port);
+        assert_eq!("127.0.0.1", settings.application.host);
+    }
+
+    #[test]
+    fn should_apply_local_overlays_last() {
+        let dir = ConfigDir::new("overlay")
+            .file("base.yaml", BASE_YAML)
+            .file("test.yaml", "application:\n  port: 9000\n")
+            .file("base.local.yaml", "database:\n  name: mine\n")
+            .file("test.local.yaml", "application:\n  port: 9001\n");
+
+        let settings = dir.loader("test").load().unwrap();
+
+        assert_eq!(9001, settings.application.port);
+        assert_eq!("mine", settings.database.name);
+    }
+
+    #[test]
+    fn should_support_toml_and_json() {
+        let dir = ConfigDir::new("formats")
+            .file("base.yaml", BASE_YAML)
+            .file("toml.toml", "[application]\nport = 9002\n")
+            .file("json.json", r#"{ "application": { "port": 9003 } }"#);
+
+        assert_eq!(9002, dir.loader("toml").load().unwrap().application.port);
+        assert_eq!(9003, dir.loader("json").load().unwrap().application.port);
+    }
+
+    #[test]
+    fn should_report_available_profiles_if_the_required_one_is_missing() {
+        let dir = ConfigDir::new("unknown")
+            .file("base.yaml", BASE_YAML)
+            .file("staging.yaml", "")
+            .file("production.toml", "")
+            .file("staging.local.yaml", "")
+            .file("README.md", "");
+
+        match dir.loader("prod").load() {
+            Err(ConfigurationError::UnknownProfile { profile, available }) => {
+                assert_eq!("prod", profile);
+                assert_eq!(vec!["production", "staging"], available);
+            }
+            other => panic!("Should fail: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn last_modified_should_change_when_a_file_changes() {
+        let dir = ConfigDir::new("modified").file("base.yaml", BASE_YAML);
+        let loader = dir.loader("local");
+        let before = loader.last_modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+
+        let _dir = dir.file("local.yaml", "");
+
+        assert!(loader.last_modified().unwrap() > before);
+    }
+
+    #[test]
+    fn should_keep_tls_paths_as_they_are() {
+        let dir = ConfigDir::new("tls")
+            .file("base.yaml", BASE_YAML)
+            .file("ca.pem", "-----BEGIN CERTIFICATE-----\n")
+            .file("client.pem", "-----BEGIN CERTIFICATE-----\n");
+        let ca = dir.0.join("ca.pem").display().to_string();
+        let client = dir.0.join("client.pem").display().to_string();
+        let dir = dir.file(
+            "test.yaml",
+            &format!(
+                "database:\n  tls:\n    enabled: true\n    ca_path: {}\n    cert_key_path: {}\n",
+                ca, client
+            ),
+        );
+
+        let settings = dir.loader("test").load().unwrap();
+
+        assert_eq!(Some(true), settings.database.tls.enabled);
+        assert_eq!(Some(ca), settings.database.tls.ca_path);
+        assert_eq!(Some(client), settings.database.tls.cert_key_path);
+    }
+
+    #[test]
+    fn should_reject_ambiguous_files() {
+        let dir = ConfigDir::new("ambiguous")
+            .file("base.yaml", BASE_YAML)
+            .file("base.json", "{}")
+            .file("test.yaml", "");
+
+        assert!(matches!(
+            dir.loader("test").load(),
+            Err(ConfigurationError::Ambiguous(_, _))
+        ));
+    }
+}
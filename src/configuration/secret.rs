@@ -0,0 +1,56 @@
+use std::fmt;
+
+/// Wrap a value that must never end up in logs: both `Debug` and `Display` are redacted
+/// and the only way to read it is to call `expose()` deliberately.
+#[derive(serde::Deserialize, Default, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([REDACTED])")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_redact_debug_and_display() {
+        let secret = Secret::new("password".to_owned());
+
+        assert_eq!("Secret([REDACTED])", format!("{:?}", secret));
+        assert_eq!("[REDACTED]", format!("{}", secret));
+        assert_eq!("password", secret.expose());
+    }
+
+    #[test]
+    fn should_deserialize_as_the_inner_value() {
+        let secret: Secret<String> = serde_yaml::from_str("password").unwrap();
+
+        assert_eq!("password", secret.expose());
+    }
+}
@@ -0,0 +1,113 @@
+//! HMAC-SHA256 signatures with the secrets of the configuration. What is signed
+//! starts with a domain telling what it's for, so that a signature made for one
+//! use can't be passed off for another.
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use crate::configuration::Secret;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bytes a truncated signature keeps at least
+pub(crate) const MIN_TRUNCATED_LENGTH: usize = 10;
+
+fn mac(key: &Secret<String>, parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_varkey(key.expose().as_bytes()).expect("HMAC takes keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+/// The signature of `parts`, one after the other.
+pub(crate) fn sign(key: &Secret<String>, parts: &[&[u8]]) -> Vec<u8> {
+    mac(key, parts).finalize().into_bytes().to_vec()
+}
+
+/// Whether `signature` is the one of `parts`: compared in constant time.
+pub(crate) fn verify(key: &Secret<String>, parts: &[&[u8]], signature: &[u8]) -> bool {
+    mac(key, parts).verify(signature).is_ok()
+}
+
+/// Whether `signature` is the start of the one of `parts`: for signatures cut
+/// short to fit in a url. Shorter than `MIN_TRUNCATED_LENGTH` bytes they could
+/// be guessed, and never verify.
+pub(crate) fn verify_truncated(key: &Secret<String>, parts: &[&[u8]], signature: &[u8]) -> bool {
+    signature.len() >= MIN_TRUNCATED_LENGTH
+        && sign(key, parts)
+            .get(..signature.len())
+            .is_some_and(|expected| constant_time_eq(expected, signature))
+}
+
+/// Whether `a` and `b` are the same, in a time that doesn't tell where they differ.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A key of its own for `purpose`, derived from `key`: one secret can sign many
+/// things, or be used for other than signing, without giving them away.
+pub(crate) fn derive_key(key: &Secret<String>, purpose: &[u8]) -> Secret<String> {
+    hex::encode(sign(key, &[purpose])).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key() -> Secret<String> {
+        "a-secret-key".to_owned().into()
+    }
+
+    #[test]
+    fn signature_should_cover_every_part() {
+        let signature = sign(&key(), &[b"domain:", b"payload"]);
+
+        assert_eq!(32, signature.len());
+        assert_eq!(signature, sign(&key(), &[b"domain:payload"]));
+        assert!(verify(&key(), &[b"domain:", b"payload"], &signature));
+        assert!(!verify(&key(), &[b"domain:", b"other"], &signature));
+        assert!(!verify(
+            &"another-key".to_owned().into(),
+            &[b"domain:payload"],
+            &signature
+        ));
+        assert!(!verify(&key(), &[b"domain:payload"], &signature[..16]));
+    }
+
+    #[test]
+    fn truncated_signatures_should_be_long_enough() {
+        let signature = sign(&key(), &[b"domain:", b"payload"]);
+
+        assert!(verify_truncated(
+            &key(),
+            &[b"domain:payload"],
+            &signature[..12]
+        ));
+        assert!(verify_truncated(&key(), &[b"domain:payload"], &signature));
+        assert!(!verify_truncated(
+            &key(),
+            &[b"domain:other"],
+            &signature[..12]
+        ));
+        assert!(!verify_truncated(
+            &key(),
+            &[b"domain:payload"],
+            &signature[..4]
+        ));
+        assert!(!verify_truncated(
+            &key(),
+            &[b"domain:payload"],
+            &signature[1..13]
+        ));
+    }
+
+    #[test]
+    fn derived_keys_should_depend_on_the_purpose() {
+        let tenant = derive_key(&key(), b"tenant:rust");
+
+        assert_eq!(64, tenant.expose().len());
+        assert_ne!(tenant.expose(), derive_key(&key(), b"tenant:go").expose());
+        assert_ne!(key().expose(), tenant.expose());
+    }
+}
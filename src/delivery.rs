@@ -0,0 +1,552 @@
+//! Request handlers don't send emails: they put them in a persistent queue and
+//! a background worker delivers them, retrying transient failures with a jittered
+//! exponential backoff.
+use std::{collections::HashSet, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::{
+    configuration::DeliverySettings,
+    email::{Email, EmailClient, SendError},
+    email_events::SuppressionsRepository,
+    repository,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Job {
+    pub(crate) id: String,
+    pub(crate) email: Email,
+    /// Including the current one
+    pub(crate) attempts: u32,
+}
+
+/// Dead letters listed at a time by the admin endpoint
+pub(crate) const DEAD_LETTERS_PAGE_SIZE: u64 = 100;
+
+/// A job we gave up on.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct DeadLetter {
+    pub(crate) id: String,
+    pub(crate) to: String,
+    pub(crate) subject: String,
+    pub(crate) attempts: u32,
+    pub(crate) last_error: String,
+    pub(crate) failed_at: DateTime<Utc>,
+}
+
+#[async_trait::async_trait]
+pub(crate) trait DeliveryQueue: Send + Sync {
+    async fn enqueue(&self, email: Email) -> repository::Result<()>;
+
+    /// Take the oldest due job: no one else can claim it till `lease` expires.
+    async fn claim(&self, lease: Duration) -> repository::Result<Option<Job>>;
+
+    async fn complete(&self, job: &Job) -> repository::Result<()>;
+
+    /// Release the job to be claimed again not before `at`.
+    async fn retry(&self, job: &Job, at: DateTime<Utc>, error: &str) -> repository::Result<()>;
+
+    async fn dead_letter(&self, job: &Job, error: &str) -> repository::Result<()>;
+
+    /// At most `limit` dead letters after the first `skip`, most recent first.
+    async fn dead_letters(&self, skip: u64, limit: u64) -> repository::Result<Vec<DeadLetter>>;
+
+    /// The addresses an email of the stored issue `issue_id` was queued for.
+    async fn recipients_of(&self, issue_id: &str) -> repository::Result<HashSet<String>>;
+}
+
+/// `now + delay` that saturates instead of overflowing.
+pub(crate) fn after(now: DateTime<Utc>, delay: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(delay)
+        .ok()
+        .and_then(|delay| now.checked_add_signed(delay))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Backoff {
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max }
+    }
+
+    /// Delay after the `attempt`-th failure: it doubles every time up to `max`, then
+    /// up to half of it is cut off according to `jitter` (in `[0, 1]`) so that jobs
+    /// failed together are not retried together.
+    pub(crate) fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let factor = 2u32
+            .checked_pow(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let delay = self
+            .base
+            .checked_mul(factor)
+            .map_or(self.max, |delay| delay.min(self.max));
+        delay.mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    Sent,
+    Retry { at: DateTime<Utc>, error: String },
+    Dead { error: String },
+}
+
+pub(crate) struct Worker<Q: DeliveryQueue, C: EmailClient, U: SuppressionsRepository> {
+    queue: Q,
+    client: C,
+    suppressions: U,
+    settings: DeliverySettings,
+    backoff: Backoff,
+}
+
+impl<Q: DeliveryQueue, C: EmailClient, U: SuppressionsRepository + Send + Sync> Worker<Q, C, U> {
+    pub(crate) fn new(queue: Q, client: C, suppressions: U, settings: DeliverySettings) -> Self {
+        Self {
+            backoff: Backoff::new(settings.backoff_base, settings.backoff_max),
+            queue,
+            client,
+            suppressions,
+            settings,
+        }
+    }
+
+    pub(crate) async fn run(self) {
+        info!("Delivery worker started");
+        loop {
+            if !self.step().await {
+                async_std::task::sleep(self.settings.poll_interval).await;
+            }
+        }
+    }
+
+    /// Deliver the next due job, if any: return `false` if the queue is empty or
+    /// not reachable.
+    pub(crate) async fn step(&self) -> bool {
+        let job = match self.queue.claim(self.settings.lease).await {
+            Ok(Some(job)) => job,
+            Ok(None) => return false,
+            Err(e) => {
+                error!("Cannot claim a delivery job: {:?}", e);
+                return false;
+            }
+        };
+        self.deliver(&job).await;
+        true
+    }
+
+    #[tracing::instrument(
+        name = "Delivering a queued email",
+        skip(self, job),
+        fields(
+            job = %job.id,
+            attempt = job.attempts,
+        )
+    )]
+    async fn deliver(&self, job: &Job) -> Outcome {
+        let outcome = self.attempt(job).await;
+        let stored = match &outcome {
+            Outcome::Sent => self.queue.complete(job).await,
+            Outcome::Retry { at, error } => {
+                warn!(%at, "Delivery failed, will retry: {}", error);
+                self.queue.retry(job, *at, error).await
+            }
+            Outcome::Dead { error } => {
+                error!("Delivery failed, giving up: {}", error);
+                self.queue.dead_letter(job, error).await
+            }
+        };
+        if let Err(e) = stored {
+            error!("Cannot update the delivery job: {:?}", e);
+        }
+        outcome
+    }
+
+    async fn attempt(&self, job: &Job) -> Outcome {
+        if job.attempts > self.settings.max_attempts {
+            // The lease expired while the last attempt was running
+            return Outcome::Dead {
+                error: "too many attempts".to_owned(),
+            };
+        }
+        // Checked at every attempt: the address may bounce while the job waits
+        let suppressed = match self
+            .suppressions
+            .suppressed(std::slice::from_ref(&job.email.to))
+            .await
+        {
+            Ok(suppressed) => !suppressed.is_empty(),
+            Err(e) => {
+                let error = format!("cannot check the suppression list: {:?}", e);
+                return self.failure(job, SendError::Transient(error));
+            }
+        };
+        if suppressed {
+            return Outcome::Dead {
+                error: "the address is suppressed".to_owned(),
+            };
+        }
+        match self.client.send(&job.email).await {
+            Ok(()) => Outcome::Sent,
+            Err(e) => self.failure(job, e),
+        }
+    }
+
+    fn failure(&self, job: &Job, error: SendError) -> Outcome {
+        match error {
+            SendError::Transient(error) if job.attempts < self.settings.max_attempts => {
+                let delay = self.backoff.delay(job.attempts, rand::random());
+                Outcome::Retry {
+                    at: after(Utc::now(), delay),
+                    error,
+                }
+            }
+            e => Outcome::Dead {
+                error: e.to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{
+        email_events::{EmailEvent, SuppressionReason},
+        outbox,
+    };
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Call {
+        Complete(String),
+        Retry(String, String),
+        DeadLetter(String, String),
+    }
+
+    #[derive(Default)]
+    struct FakeQueue {
+        /// Claimed from the end
+        jobs: Mutex<Vec<Job>>,
+        calls: Mutex<Vec<Call>>,
+        dead: Mutex<Vec<DeadLetter>>,
+    }
+
+    impl FakeQueue {
+        fn with_job(attempts: u32) -> Self {
+            let queue = Self::default();
+            queue.jobs.lock().unwrap().push(Job {
+                id: "job".to_owned(),
+                email: Email {
+                    to: "antonio@gmail.com".to_owned(),
+                    subject: "Welcome!".to_owned(),
+                    html: "<p>Welcome!</p>".to_owned(),
+                    text: "Welcome!".to_owned(),
+                    from: None,
+                    issue_id: None,
+                },
+                attempts,
+            });
+            queue
+        }
+
+        fn calls(&self) -> Vec<Call> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl DeliveryQueue for FakeQueue {
+        async fn enqueue(&self, email: Email) -> repository::Result<()> {
+            let mut jobs = self.jobs.lock().unwrap();
+            let id = format!("job{}", jobs.len());
+            jobs.insert(
+                0,
+                Job {
+                    id,
+                    email,
+                    attempts: 1,
+                },
+            );
+            Ok(())
+        }
+
+        async fn claim(&self, _lease: Duration) -> repository::Result<Option<Job>> {
+            Ok(self.jobs.lock().unwrap().pop())
+        }
+
+        async fn complete(&self, job: &Job) -> repository::Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(Call::Complete(job.id.clone()));
+            Ok(())
+        }
+
+        async fn retry(
+            &self,
+            job: &Job,
+            _at: DateTime<Utc>,
+            error: &str,
+        ) -> repository::Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(Call::Retry(job.id.clone(), error.to_owned()));
+            Ok(())
+        }
+
+        async fn dead_letter(&self, job: &Job, error: &str) -> repository::Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(Call::DeadLetter(job.id.clone(), error.to_owned()));
+            self.dead.lock().unwrap().push(DeadLetter {
+                id: job.id.clone(),
+                to: job.email.to.clone(),
+                subject: job.email.subject.clone(),
+                attempts: job.attempts,
+                last_error: error.to_owned(),
+                failed_at: Utc::now(),
+            });
+            Ok(())
+        }
+
+        async fn dead_letters(&self, skip: u64, limit: u64) -> repository::Result<Vec<DeadLetter>> {
+            Ok(self
+                .dead
+                .lock()
+                .unwrap()
+                .iter()
+                .rev()
+                .skip(skip as usize)
+                .take(limit as usize)
+                .cloned()
+                .collect())
+        }
+
+        async fn recipients_of(&self, issue_id: &str) -> repository::Result<HashSet<String>> {
+            Ok(self
+                .jobs
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|job| job.email.issue_id.as_deref() == Some(issue_id))
+                .map(|job| job.email.to.clone())
+                .collect())
+        }
+    }
+
+    struct FakeClient(Result<(), SendError>);
+
+    #[async_trait::async_trait]
+    impl EmailClient for FakeClient {
+        async fn send(&self, _email: &Email) -> Result<(), SendError> {
+            self.0.clone()
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeSuppressions {
+        suppressed: Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl SuppressionsRepository for FakeSuppressions {
+        async fn record_event(&self, _event: &EmailEvent) -> repository::Result<()> {
+            Ok(())
+        }
+
+        async fn suppress(
+            &self,
+            email: &str,
+            _reason: SuppressionReason,
+            _event: Option<&outbox::Event>,
+        ) -> repository::Result<()> {
+            self.suppressed.lock().unwrap().push(email.to_owned());
+            Ok(())
+        }
+
+        async fn suppressed(&self, emails: &[String]) -> repository::Result<HashSet<String>> {
+            let suppressed = self.suppressed.lock().unwrap();
+            Ok(emails
+                .iter()
+                .filter(|e| suppressed.contains(e))
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn worker(
+        queue: FakeQueue,
+        result: Result<(), SendError>,
+    ) -> Worker<FakeQueue, FakeClient, FakeSuppressions> {
+        Worker::new(
+            queue,
+            FakeClient(result),
+            FakeSuppressions::default(),
+            DeliverySettings {
+                max_attempts: 3,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn transient() -> Result<(), SendError> {
+        Err(SendError::Transient("timeout".to_owned()))
+    }
+
+    #[rstest(attempt, expected,
+        case::first(1, 2),
+        case::second(2, 4),
+        case::third(3, 8),
+        case::capped(6, 60),
+        case::overflow(100, 60),
+    )]
+    fn backoff_should_double_up_to_max(attempt: u32, expected: u64) {
+        let backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(60));
+
+        assert_eq!(Duration::from_secs(expected), backoff.delay(attempt, 0.0));
+    }
+
+    #[test]
+    fn jitter_should_cut_up_to_half_of_the_delay() {
+        let backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(60));
+
+        assert_eq!(Duration::from_secs(4), backoff.delay(3, 1.0));
+        assert_eq!(Duration::from_secs(6), backoff.delay(3, 0.5));
+    }
+
+    #[async_std::test]
+    async fn should_complete_sent_jobs() {
+        let worker = worker(FakeQueue::with_job(1), Ok(()));
+
+        assert!(worker.step().await);
+
+        assert_eq!(vec![Call::Complete("job".to_owned())], worker.queue.calls());
+    }
+
+    #[async_std::test]
+    async fn should_retry_transient_failures() {
+        let worker = worker(FakeQueue::with_job(2), transient());
+
+        assert!(worker.step().await);
+
+        assert_eq!(
+            vec![Call::Retry("job".to_owned(), "timeout".to_owned())],
+            worker.queue.calls()
+        );
+    }
+
+    #[async_std::test]
+    async fn should_give_up_after_max_attempts() {
+        let worker = worker(FakeQueue::with_job(3), transient());
+
+        assert!(worker.step().await);
+
+        assert_eq!(
+            vec![Call::DeadLetter(
+                "job".to_owned(),
+                "Temporary delivery failure: timeout".to_owned()
+            )],
+            worker.queue.calls()
+        );
+    }
+
+    #[async_std::test]
+    async fn should_not_retry_permanent_failures() {
+        let worker = worker(
+            FakeQueue::with_job(1),
+            Err(SendError::Permanent("invalid address".to_owned())),
+        );
+
+        assert!(worker.step().await);
+
+        assert_eq!(
+            vec![Call::DeadLetter(
+                "job".to_owned(),
+                "Delivery rejected: invalid address".to_owned()
+            )],
+            worker.queue.calls()
+        );
+    }
+
+    #[async_std::test]
+    async fn should_not_send_to_suppressed_addresses() {
+        let worker = worker(FakeQueue::with_job(1), Ok(()));
+        worker
+            .suppressions
+            .suppress("antonio@gmail.com", SuppressionReason::HardBounce, None)
+            .await
+            .unwrap();
+
+        assert!(worker.step().await);
+
+        assert_eq!(
+            vec![Call::DeadLetter(
+                "job".to_owned(),
+                "the address is suppressed".to_owned()
+            )],
+            worker.queue.calls()
+        );
+    }
+
+    #[async_std::test]
+    async fn queued_emails_should_be_sent_in_order() {
+        let worker = worker(FakeQueue::default(), Ok(()));
+        let email = |to: &str| Email {
+            to: to.to_owned(),
+            subject: "Welcome!".to_owned(),
+            html: "<p>Welcome!</p>".to_owned(),
+            text: "Welcome!".to_owned(),
+            from: None,
+            issue_id: None,
+        };
+        worker
+            .queue
+            .enqueue(email("antonio@gmail.com"))
+            .await
+            .unwrap();
+        worker.queue.enqueue(email("luca@gmail.com")).await.unwrap();
+
+        assert!(worker.step().await);
+        assert!(worker.step().await);
+
+        assert_eq!(
+            vec![
+                Call::Complete("job0".to_owned()),
+                Call::Complete("job1".to_owned())
+            ],
+            worker.queue.calls()
+        );
+    }
+
+    #[async_std::test]
+    async fn dead_letters_should_keep_the_last_error() {
+        let worker = worker(FakeQueue::with_job(3), transient());
+
+        assert!(worker.step().await);
+
+        let dead = worker.queue.dead_letters(0, 10).await.unwrap();
+        assert_eq!(1, dead.len());
+        assert_eq!("antonio@gmail.com", dead[0].to);
+        assert_eq!("Temporary delivery failure: timeout", dead[0].last_error);
+    }
+
+    #[async_std::test]
+    async fn should_report_empty_queue() {
+        let worker = worker(FakeQueue::default(), Ok(()));
+
+        assert!(!worker.step().await);
+        assert!(worker.queue.calls().is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+//! The weekly digest: subscribers who chose it get the issues a list sent in the
+//! past week in a single email. The scheduler sends the digests that are due.
+use chrono::{DateTime, Duration, Utc};
+use tracing::{error, info};
+
+use crate::{
+    email_templates,
+    issues::{self, Issue, IssueError, IssuesRepository},
+    lists::{List, ListsRepository},
+    preferences::Frequency,
+    repository::User,
+    state::StateTrait,
+    tracking,
+};
+
+const PERIOD_DAYS: i64 = 7;
+
+/// Send the digests that are due at `now`: return how many lists sent one.
+pub(crate) async fn send_due<S: StateTrait>(state: &S, now: DateTime<Utc>) -> usize {
+    let lists = match state.lists().all().await {
+        Ok(lists) => lists,
+        Err(e) => {
+            error!("Cannot read the lists: {:?}", e);
+            return 0;
+        }
+    };
+    let mut sent = 0;
+    for list in &lists {
+        match send(state, list, now).await {
+            Ok(true) => sent += 1,
+            Ok(false) => {}
+            Err(e) => error!(list = %list.slug, "Cannot send the digest: {}", e),
+        }
+    }
+    sent
+}
+
+/// `issue` as `subscriber` gets it in the digest of `list`.
+fn issue<S: StateTrait>(
+    state: &S,
+    list: &List,
+    issue: &Issue,
+    subscriber: &User,
+) -> email_templates::Issue {
+    let content = &issue.current().content;
+    email_templates::Issue {
+        subject: content.subject.clone(),
+        html_content: tracking::html(state, list, &issue.id, subscriber, &content.html),
+        text_content: content.text.clone(),
+        preferences_link: None,
+    }
+}
+
+/// Send the digest of `list` if a week passed since the previous one: `false` if
+/// it was not due or there was nothing to send. The first one is due a week after
+/// the list is first seen.
+async fn send<S: StateTrait>(
+    state: &S,
+    list: &List,
+    now: DateTime<Utc>,
+) -> Result<bool, IssueError> {
+    let previous = state.lists().digest_sent_at(&list.slug).await?;
+    let since = match previous {
+        None => {
+            state.lists().digest_sent(&list.slug, None, now).await?;
+            return Ok(false);
+        }
+        Some(at) if now < at + Duration::days(PERIOD_DAYS) => return Ok(false),
+        Some(at) => at,
+    };
+    // Recorded before it's queued, like a published issue: it can't be sent twice
+    if !state.lists().digest_sent(&list.slug, previous, now).await? {
+        return Ok(false);
+    }
+    let sent = state
+        .issues_repository()
+        .sent_between(&list.slug, since, now)
+        .await?;
+    if sent.is_empty() {
+        return Ok(false);
+    }
+    let queued = issues::deliver(
+        state,
+        list,
+        Frequency::Weekly,
+        None,
+        |subscriber, preferences_link| email_templates::Digest {
+            list: list.name.clone(),
+            issues: sent
+                .iter()
+                .map(|sent| issue(state, list, sent, subscriber))
+                .collect(),
+            preferences_link,
+        },
+    )
+    .await?;
+    info!(
+        list = %list.slug,
+        "Digest of {} issues queued for {} subscribers",
+        sent.len(),
+        queued
+    );
+    Ok(true)
+}
@@ -0,0 +1,122 @@
+use thiserror::Error;
+
+use crate::i18n::Locale;
+
+const MAX_NAME_LENGTH: usize = 256;
+const FORBIDDEN_NAME_CHARACTERS: &[char] = &['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub(crate) enum ValidationError {
+    #[error("Name is empty")]
+    EmptyName,
+    #[error("Name is longer than {max} characters")]
+    NameTooLong { max: usize },
+    #[error("Name contains forbidden characters")]
+    ForbiddenNameCharacters,
+    /// The value is left out of the message: it ends up in the logs
+    #[error("Not a valid email")]
+    InvalidEmail(String),
+}
+
+impl ValidationError {
+    /// The error as told to the subscriber.
+    pub(crate) fn localized(&self, locale: Locale) -> String {
+        match self {
+            ValidationError::EmptyName => locale.t("validation.empty_name", &[]),
+            ValidationError::NameTooLong { max } => {
+                locale.t("validation.name_too_long", &[("max", &max.to_string())])
+            }
+            ValidationError::ForbiddenNameCharacters => {
+                locale.t("validation.forbidden_name_characters", &[])
+            }
+            ValidationError::InvalidEmail(email) => {
+                locale.t("validation.invalid_email", &[("email", email)])
+            }
+        }
+    }
+}
+
+pub(crate) fn parse_name(name: &str) -> Result<String, ValidationError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ValidationError::EmptyName);
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ValidationError::NameTooLong {
+            max: MAX_NAME_LENGTH,
+        });
+    }
+    if name.chars().any(|c| FORBIDDEN_NAME_CHARACTERS.contains(&c)) {
+        return Err(ValidationError::ForbiddenNameCharacters);
+    }
+    Ok(name.to_owned())
+}
+
+/// Emails are compared case insensitive, so we always store them lowercase.
+pub(crate) fn parse_email(email: &str) -> Result<String, ValidationError> {
+    let email = email.trim();
+    let invalid = || ValidationError::InvalidEmail(email.to_owned());
+    let mut parts = email.splitn(2, '@');
+    let (local, domain) = match (parts.next(), parts.next()) {
+        (Some(local), Some(domain)) => (local, domain),
+        _ => return Err(invalid()),
+    };
+    if local.is_empty()
+        || domain.contains('@')
+        || email.chars().any(char::is_whitespace)
+        || !domain.contains('.')
+        || domain.starts_with('.')
+        || domain.ends_with('.')
+    {
+        return Err(invalid());
+    }
+    Ok(email.to_lowercase())
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest(name, expected,
+        case::simple("Antonio", Ok("Antonio".to_owned())),
+        case::trimmed("  De Domenico ", Ok("De Domenico".to_owned())),
+        case::empty("", Err(ValidationError::EmptyName)),
+        case::only_spaces("   ", Err(ValidationError::EmptyName)),
+        case::too_long(&"a".repeat(257), Err(ValidationError::NameTooLong { max: 256 })),
+        case::forbidden("<script>", Err(ValidationError::ForbiddenNameCharacters)),
+    )]
+    fn parse_names(name: &str, expected: Result<String, ValidationError>) {
+        assert_eq!(expected, parse_name(name))
+    }
+
+    #[rstest(email, expected,
+        case::simple("antonio@gmail.com", Ok("antonio@gmail.com".to_owned())),
+        case::lowercase(" Antonio@GMail.com", Ok("antonio@gmail.com".to_owned())),
+        case::no_at("antonio.gmail.com", Err(ValidationError::InvalidEmail("antonio.gmail.com".to_owned()))),
+        case::no_local("@gmail.com", Err(ValidationError::InvalidEmail("@gmail.com".to_owned()))),
+        case::no_domain("antonio@", Err(ValidationError::InvalidEmail("antonio@".to_owned()))),
+        case::two_at("a@b@gmail.com", Err(ValidationError::InvalidEmail("a@b@gmail.com".to_owned()))),
+        case::spaces("an tonio@gmail.com", Err(ValidationError::InvalidEmail("an tonio@gmail.com".to_owned()))),
+        case::no_dot("antonio@gmail", Err(ValidationError::InvalidEmail("antonio@gmail".to_owned()))),
+    )]
+    fn parse_emails(email: &str, expected: Result<String, ValidationError>) {
+        assert_eq!(expected, parse_email(email))
+    }
+
+    #[test]
+    fn invalid_email_message_should_not_show_the_email() {
+        let error = parse_email("antonio.gmail.com").unwrap_err();
+
+        assert!(!error.to_string().contains("antonio"));
+    }
+
+    #[rstest(locale, expected,
+        case::english(Locale::En, "The name is longer than 256 characters."),
+        case::italian(Locale::It, "Il nome è più lungo di 256 caratteri."),
+    )]
+    fn errors_should_be_localized(locale: Locale, expected: &str) {
+        assert_eq!(expected, ValidationError::NameTooLong { max: 256 }.localized(locale))
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::telemetry::pii;
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Email {
+    pub(crate) to: String,
+    pub(crate) subject: String,
+    pub(crate) html: String,
+    pub(crate) text: String,
+    /// Instead of the configured sender, e.g. the sender of a list
+    #[serde(default)]
+    pub(crate) from: Option<String>,
+    /// The issue it carries, for the analytics
+    #[serde(default)]
+    pub(crate) issue_id: Option<String>,
+}
+
+/// Safe to log: the recipient is redacted.
+impl std::fmt::Debug for Email {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Email")
+            .field("to", &pii::email(&self.to))
+            .field("subject", &self.subject)
+            .finish()
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub(crate) enum SendError {
+    /// Worth a retry: e.g. timeouts, rate limits or server errors
+    #[error("Temporary delivery failure: {0}")]
+    Transient(String),
+    #[error("Delivery rejected: {0}")]
+    Permanent(String),
+}
+
+#[async_trait::async_trait]
+pub(crate) trait EmailClient: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), SendError>;
+}
+
+/// The configured transport, chosen at startup.
+pub(crate) type SharedEmailClient = Arc<dyn EmailClient>;
+
+#[async_trait::async_trait]
+impl<C: EmailClient + ?Sized> EmailClient for Arc<C> {
+    async fn send(&self, email: &Email) -> Result<(), SendError> {
+        (**self).send(email).await
+    }
+}
@@ -0,0 +1,302 @@
+//! Delivery events reported by the email provider: bounces, complaints and
+//! deliveries. They are posted to `/webhooks/email-events`, signed with the
+//! shared `email.webhook_secret`.
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tide::{Body, StatusCode};
+use tracing::warn;
+
+use crate::{configuration::Secret, crypto, outbox, repository, telemetry::pii};
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body
+pub(crate) const SIGNATURE_HEADER: &str = "X-Signature";
+const SIGNATURE_PREFIX: &str = "sha256=";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum BounceType {
+    /// Permanent, e.g. the mailbox doesn't exist
+    Hard,
+    /// Temporary, e.g. the mailbox is full
+    Soft,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum EventKind {
+    Delivery,
+    Bounce { bounce_type: BounceType },
+    /// The recipient marked the email as spam
+    Complaint,
+}
+
+impl EventKind {
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            EventKind::Delivery => "delivery",
+            EventKind::Bounce {
+                bounce_type: BounceType::Hard,
+            } => "hard_bounce",
+            EventKind::Bounce {
+                bounce_type: BounceType::Soft,
+            } => "soft_bounce",
+            EventKind::Complaint => "complaint",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+pub(crate) struct EmailEvent {
+    pub(crate) email: String,
+    #[serde(flatten)]
+    pub(crate) kind: EventKind,
+    #[serde(default = "Utc::now")]
+    pub(crate) occurred_at: DateTime<Utc>,
+    #[serde(default)]
+    pub(crate) description: Option<String>,
+}
+
+/// Safe to log: the address is redacted.
+impl std::fmt::Debug for EmailEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmailEvent")
+            .field("email", &pii::email(&self.email))
+            .field("kind", &self.kind)
+            .field("occurred_at", &self.occurred_at)
+            .finish()
+    }
+}
+
+/// Why an address doesn't receive our emails anymore.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SuppressionReason {
+    HardBounce,
+    Complaint,
+}
+
+impl SuppressionReason {
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            SuppressionReason::HardBounce => "hard_bounce",
+            SuppressionReason::Complaint => "complaint",
+        }
+    }
+}
+
+impl EmailEvent {
+    /// Hard bounces and complaints stop any further email.
+    pub(crate) fn suppression(&self) -> Option<SuppressionReason> {
+        match self.kind {
+            EventKind::Bounce {
+                bounce_type: BounceType::Hard,
+            } => Some(SuppressionReason::HardBounce),
+            EventKind::Complaint => Some(SuppressionReason::Complaint),
+            _ => None,
+        }
+    }
+}
+
+/// What the email provider told about the addresses. Suppressions are about
+/// addresses, not subscribers: a suppressed address gets nothing from any list.
+#[async_trait::async_trait]
+pub(crate) trait SuppressionsRepository {
+    /// Keep what the email provider told about an address.
+    async fn record_event(&self, event: &EmailEvent) -> repository::Result<()>;
+
+    /// Stop any further email to `email`: the first reason is kept. `event` goes
+    /// to the outbox with the suppression.
+    async fn suppress(
+        &self,
+        email: &str,
+        reason: SuppressionReason,
+        event: Option<&outbox::Event>,
+    ) -> repository::Result<()>;
+
+    /// Return the subset of `emails` that must not receive emails.
+    async fn suppressed(&self, emails: &[String]) -> repository::Result<HashSet<String>>;
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Events {
+    One(EmailEvent),
+    Many(Vec<EmailEvent>),
+}
+
+/// A single event or a batch: addresses are lowercase as the subscribers' ones.
+pub(crate) fn parse(body: &[u8]) -> Result<Vec<EmailEvent>, serde_json::Error> {
+    let events = match serde_json::from_slice(body)? {
+        Events::One(event) => vec![event],
+        Events::Many(events) => events,
+    };
+    Ok(events
+        .into_iter()
+        .map(|event| EmailEvent {
+            email: event.email.trim().to_lowercase(),
+            ..event
+        })
+        .collect())
+}
+
+/// The `X-Signature` value of `body`.
+#[cfg(test)]
+pub(crate) fn sign(secret: &Secret<String>, body: &[u8]) -> String {
+    format!(
+        "{}{}",
+        SIGNATURE_PREFIX,
+        hex::encode(crypto::sign(secret, &[body]))
+    )
+}
+
+pub(crate) fn verify(secret: &Secret<String>, body: &[u8], signature: &str) -> bool {
+    signature
+        .strip_prefix(SIGNATURE_PREFIX)
+        .and_then(|signature| hex::decode(signature).ok())
+        .map(|signature| crypto::verify(secret, &[body], &signature))
+        .unwrap_or_default()
+}
+
+/// Reject the requests that are not signed with the webhook secret: without a
+/// configured secret the endpoint is not reachable at all.
+#[derive(Clone)]
+pub(crate) struct SignatureMiddleware {
+    secret: Option<Secret<String>>,
+}
+
+impl SignatureMiddleware {
+    pub(crate) fn new(secret: Option<Secret<String>>) -> Self {
+        Self { secret }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for SignatureMiddleware {
+    async fn handle(
+        &self,
+        mut req: tide::Request<State>,
+        next: tide::Next<'_, State>,
+    ) -> tide::Result {
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => return Ok(StatusCode::NotFound.into()),
+        };
+        let body = req.take_body();
+        let mime = body.mime().clone();
+        let bytes = body.into_bytes().await?;
+        let signature = req
+            .header(SIGNATURE_HEADER)
+            .map(|values| values.as_str().to_owned())
+            .unwrap_or_default();
+        if !verify(secret, &bytes, &signature) {
+            warn!("Rejected email events with an invalid signature");
+            return Ok(StatusCode::Unauthorized.into());
+        }
+        let mut restored = Body::from_bytes(bytes);
+        restored.set_mime(mime);
+        req.set_body(restored);
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tide::http::{Method, Request, Response, Url};
+
+    use super::*;
+
+    fn secret() -> Secret<String> {
+        "webhook-secret".to_owned().into()
+    }
+
+    #[test]
+    fn should_parse_single_and_batched_events() {
+        let single = parse(br#"{"type": "complaint", "email": "Antonio@Gmail.com"}"#).unwrap();
+        let batch = parse(
+            br#"[
+                {"type": "bounce", "bounce_type": "hard", "email": "a@gmail.com",
+                 "occurred_at": "2020-12-01T10:00:00Z", "description": "No such user"},
+                {"type": "bounce", "bounce_type": "soft", "email": "b@gmail.com"},
+                {"type": "delivery", "email": "c@gmail.com"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!("antonio@gmail.com", single[0].email);
+        assert_eq!(Some(SuppressionReason::Complaint), single[0].suppression());
+        assert_eq!(
+            vec!["hard_bounce", "soft_bounce", "delivery"],
+            batch.iter().map(|e| e.kind.code()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some("No such user".to_owned()), batch[0].description);
+        assert_eq!(
+            vec![Some(SuppressionReason::HardBounce), None, None],
+            batch.iter().map(EmailEvent::suppression).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn should_reject_unknown_events() {
+        assert!(parse(br#"{"type": "open", "email": "a@gmail.com"}"#).is_err());
+        assert!(parse(br#"{"type": "bounce", "email": "a@gmail.com"}"#).is_err());
+    }
+
+    #[test]
+    fn signature_should_be_verified() {
+        let body = br#"{"type": "complaint", "email": "a@gmail.com"}"#;
+        let signature = sign(&secret(), body);
+
+        assert!(verify(&secret(), body, &signature));
+        assert!(!verify(&secret(), b"{}", &signature));
+        assert!(!verify(&"other".to_owned().into(), body, &signature));
+        assert!(!verify(&secret(), body, signature.trim_start_matches(SIGNATURE_PREFIX)));
+    }
+
+    fn app(secret: Option<Secret<String>>) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.at("/")
+            .with(SignatureMiddleware::new(secret))
+            .post(|mut req: tide::Request<()>| async move { req.body_string().await });
+        app
+    }
+
+    fn request(body: &str, signature: &str) -> Request {
+        let mut req = Request::new(Method::Post, Url::parse("https://example.com/").unwrap());
+        req.insert_header(SIGNATURE_HEADER, signature);
+        req.set_body(body);
+        req
+    }
+
+    #[async_std::test]
+    async fn signed_events_should_reach_the_handler() {
+        let body = r#"{"type": "delivery", "email": "a@gmail.com"}"#;
+        let signature = sign(&secret(), body.as_bytes());
+
+        let mut res: Response = app(Some(secret()))
+            .respond(request(body, &signature))
+            .await
+            .unwrap();
+
+        assert_eq!(StatusCode::Ok, res.status());
+        assert_eq!(body, res.body_string().await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn unsigned_events_should_be_rejected() {
+        let res: Response = app(Some(secret()))
+            .respond(request("{}", "sha256=00"))
+            .await
+            .unwrap();
+
+        assert_eq!(StatusCode::Unauthorized, res.status());
+    }
+
+    #[async_std::test]
+    async fn endpoint_should_be_disabled_without_secret() {
+        let res: Response = app(None).respond(request("{}", "")).await.unwrap();
+
+        assert_eq!(StatusCode::NotFound, res.status());
+    }
+}
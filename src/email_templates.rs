@@ -0,0 +1,443 @@
+//! Emails are rendered from named handlebars templates, each with an html and a
+//! plain text version. The defaults are embedded in the binary; a configured
+//! directory can override them.
+use std::path::{Path, PathBuf};
+
+use handlebars::{
+    no_escape, Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext,
+    RenderError, TemplateError,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::{email::Email, i18n::Locale};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Template {
+    Confirmation,
+    Welcome,
+    UnsubscribeReceipt,
+    Issue,
+    Digest,
+}
+
+impl Template {
+    pub(crate) const ALL: &'static [Template] = &[
+        Template::Confirmation,
+        Template::Welcome,
+        Template::UnsubscribeReceipt,
+        Template::Issue,
+        Template::Digest,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Template::Confirmation => "confirmation",
+            Template::Welcome => "welcome",
+            Template::UnsubscribeReceipt => "unsubscribe_receipt",
+            Template::Issue => "issue",
+            Template::Digest => "digest",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|template| template.name() == name)
+    }
+
+    /// The embedded `(html, text)` sources.
+    fn defaults(self) -> (&'static str, &'static str) {
+        match self {
+            Template::Confirmation => (
+                include_str!("../templates/emails/confirmation.html.hbs"),
+                include_str!("../templates/emails/confirmation.txt.hbs"),
+            ),
+            Template::Welcome => (
+                include_str!("../templates/emails/welcome.html.hbs"),
+                include_str!("../templates/emails/welcome.txt.hbs"),
+            ),
+            Template::UnsubscribeReceipt => (
+                include_str!("../templates/emails/unsubscribe_receipt.html.hbs"),
+                include_str!("../templates/emails/unsubscribe_receipt.txt.hbs"),
+            ),
+            Template::Issue => (
+                include_str!("../templates/emails/issue.html.hbs"),
+                include_str!("../templates/emails/issue.txt.hbs"),
+            ),
+            Template::Digest => (
+                include_str!("../templates/emails/digest.html.hbs"),
+                include_str!("../templates/emails/digest.txt.hbs"),
+            ),
+        }
+    }
+}
+
+/// The data a template is rendered with.
+pub(crate) trait EmailContext: Serialize {
+    const TEMPLATE: Template;
+
+    fn subject(&self, locale: Locale) -> String;
+
+    /// Data to preview the template with.
+    fn sample() -> Self;
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct Confirmation {
+    pub(crate) name: String,
+    pub(crate) confirmation_link: String,
+}
+
+impl EmailContext for Confirmation {
+    const TEMPLATE: Template = Template::Confirmation;
+
+    fn subject(&self, locale: Locale) -> String {
+        locale.t("confirmation.subject", &[])
+    }
+
+    fn sample() -> Self {
+        Self {
+            name: "Antonio".to_owned(),
+            confirmation_link: "https://example.com/subscriptions/confirm?token=sample".to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct Welcome {
+    pub(crate) name: String,
+    /// To the preference center, if enabled
+    pub(crate) preferences_link: Option<String>,
+}
+
+impl EmailContext for Welcome {
+    const TEMPLATE: Template = Template::Welcome;
+
+    fn subject(&self, locale: Locale) -> String {
+        locale.t("welcome.subject", &[])
+    }
+
+    fn sample() -> Self {
+        Self {
+            name: "Antonio".to_owned(),
+            preferences_link: Some("https://example.com/preferences?token=sample".to_owned()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct UnsubscribeReceipt {
+    pub(crate) name: String,
+}
+
+impl EmailContext for UnsubscribeReceipt {
+    const TEMPLATE: Template = Template::UnsubscribeReceipt;
+
+    fn subject(&self, locale: Locale) -> String {
+        locale.t("unsubscribe.subject", &[])
+    }
+
+    fn sample() -> Self {
+        Self {
+            name: "Antonio".to_owned(),
+        }
+    }
+}
+
+/// An issue written by the editors: its html content is trusted and not escaped.
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct Issue {
+    pub(crate) subject: String,
+    pub(crate) html_content: String,
+    pub(crate) text_content: String,
+    /// To the preference center of the recipient, if enabled
+    pub(crate) preferences_link: Option<String>,
+}
+
+impl EmailContext for Issue {
+    const TEMPLATE: Template = Template::Issue;
+
+    fn subject(&self, _locale: Locale) -> String {
+        self.subject.clone()
+    }
+
+    fn sample() -> Self {
+        Self {
+            subject: "Our first issue".to_owned(),
+            html_content: "<h1>Our first issue</h1><p>Lorem ipsum dolor sit amet.</p>".to_owned(),
+            text_content: "Our first issue\n\nLorem ipsum dolor sit amet.".to_owned(),
+            preferences_link: Some("https://example.com/preferences?token=sample".to_owned()),
+        }
+    }
+}
+
+/// The issues of a list sent in the past week, in a single email: like a single
+/// issue, their html content is trusted.
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct Digest {
+    /// The name of the list
+    pub(crate) list: String,
+    /// The oldest first
+    pub(crate) issues: Vec<Issue>,
+    pub(crate) preferences_link: Option<String>,
+}
+
+impl EmailContext for Digest {
+    const TEMPLATE: Template = Template::Digest;
+
+    fn subject(&self, locale: Locale) -> String {
+        locale.t("digest.subject", &[("list", &self.list)])
+    }
+
+    fn sample() -> Self {
+        Self {
+            list: "Newsletter".to_owned(),
+            issues: vec![Issue {
+                preferences_link: None,
+                ..Issue::sample()
+            }],
+            preferences_link: Some("https://example.com/preferences?token=sample".to_owned()),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum LoadError {
+    #[error("Cannot read template '{}'", .0.display())]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Invalid template '{0}'")]
+    Parse(String, #[source] Box<TemplateError>),
+}
+
+/// `{{t "key" name=value}}`: the message `key` in the locale of the email.
+struct Translate;
+
+impl HelperDef for Translate {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let key = h
+            .param(0)
+            .and_then(|key| key.value().as_str())
+            .ok_or_else(|| RenderError::new("t: the message key is mandatory"))?;
+        let locale = ctx
+            .data()
+            .get("locale")
+            .and_then(Value::as_str)
+            .and_then(Locale::from_tag)
+            .unwrap_or_default();
+        let args: Vec<(&str, String)> = h
+            .hash()
+            .iter()
+            .map(|(name, value)| {
+                let value = match value.value() {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                (*name, value)
+            })
+            .collect();
+        let args: Vec<(&str, &str)> = args
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        out.write(&r.get_escape_fn()(&locale.t(key, &args)))?;
+        Ok(())
+    }
+}
+
+fn registry() -> Handlebars<'static> {
+    let mut registry = Handlebars::new();
+    registry.register_helper("t", Box::new(Translate));
+    registry
+}
+
+pub(crate) struct EmailTemplates {
+    html: Handlebars<'static>,
+    text: Handlebars<'static>,
+}
+
+impl EmailTemplates {
+    /// Templates in `dir` win over the embedded ones.
+    pub(crate) fn load(dir: Option<&Path>) -> Result<Self, LoadError> {
+        let mut html = registry();
+        let mut text = registry();
+        text.register_escape_fn(no_escape);
+        for template in Template::ALL {
+            let (default_html, default_text) = template.defaults();
+            let name = template.name();
+            html.register_template_string(name, source(dir, name, "html", default_html)?)
+                .map_err(|e| LoadError::Parse(format!("{}.html", name), Box::new(e)))?;
+            text.register_template_string(name, source(dir, name, "txt", default_text)?)
+                .map_err(|e| LoadError::Parse(format!("{}.txt", name), Box::new(e)))?;
+        }
+        Ok(Self { html, text })
+    }
+
+    pub(crate) fn embedded() -> Self {
+        Self::load(None).expect("Valid embedded templates")
+    }
+
+    pub(crate) fn render<C: EmailContext>(
+        &self,
+        to: &str,
+        locale: Locale,
+        context: &C,
+    ) -> Result<Email, RenderError> {
+        let name = C::TEMPLATE.name();
+        let mut data = serde_json::to_value(context)?;
+        if let Value::Object(data) = &mut data {
+            data.insert("locale".to_owned(), json!(locale.code()));
+        }
+        Ok(Email {
+            to: to.to_owned(),
+            subject: context.subject(locale),
+            html: self.html.render(name, &data)?,
+            text: self.text.render(name, &data)?,
+            from: None,
+            issue_id: None,
+        })
+    }
+
+    /// `template` rendered with sample data.
+    pub(crate) fn preview(&self, template: Template, locale: Locale) -> Result<Email, RenderError> {
+        let to = "subscriber@example.com";
+        match template {
+            Template::Confirmation => self.render(to, locale, &Confirmation::sample()),
+            Template::Welcome => self.render(to, locale, &Welcome::sample()),
+            Template::UnsubscribeReceipt => self.render(to, locale, &UnsubscribeReceipt::sample()),
+            Template::Issue => self.render(to, locale, &Issue::sample()),
+            Template::Digest => self.render(to, locale, &Digest::sample()),
+        }
+    }
+}
+
+impl Default for EmailTemplates {
+    fn default() -> Self {
+        Self::embedded()
+    }
+}
+
+fn source(
+    dir: Option<&Path>,
+    name: &str,
+    extension: &str,
+    default: &'static str,
+) -> Result<String, LoadError> {
+    let path = match dir {
+        Some(dir) => dir.join(format!("{}.{}.hbs", name, extension)),
+        None => return Ok(default.to_owned()),
+    };
+    if !path.is_file() {
+        return Ok(default.to_owned());
+    }
+    std::fs::read_to_string(&path).map_err(|e| LoadError::Read(path, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_render_html_and_text() {
+        let email = EmailTemplates::embedded()
+            .render(
+                "antonio@gmail.com",
+                Locale::It,
+                &Welcome {
+                    name: "Antonio".to_owned(),
+                    preferences_link: None,
+                },
+            )
+            .unwrap();
+
+        assert_eq!("antonio@gmail.com", email.to);
+        assert_eq!("Benvenuto!", email.subject);
+        assert!(email.html.contains("<p>Ciao Antonio,</p>"));
+        assert!(email.text.starts_with("Ciao Antonio,\n"));
+    }
+
+    #[test]
+    fn should_escape_just_the_html() {
+        let email = EmailTemplates::embedded()
+            .render(
+                "antonio@gmail.com",
+                Locale::En,
+                &Confirmation {
+                    name: "Tom & <Jerry>".to_owned(),
+                    confirmation_link: "https://example.com/confirm?a=1&b=2".to_owned(),
+                },
+            )
+            .unwrap();
+
+        assert!(email.html.contains("Hi Tom &amp; &lt;Jerry&gt;,"));
+        assert!(email.html.contains("a&#x3D;1&amp;b&#x3D;2"));
+        assert!(email.text.contains("Hi Tom & <Jerry>,"));
+        assert!(email.text.contains("a=1&b=2"));
+    }
+
+    #[test]
+    fn issue_content_should_be_trusted() {
+        let email = EmailTemplates::embedded()
+            .preview(Template::Issue, Locale::En)
+            .unwrap();
+
+        assert_eq!("Our first issue", email.subject);
+        assert!(email.html.contains("<h1>Our first issue</h1>"));
+        assert!(email.text.contains("Lorem ipsum dolor sit amet."));
+    }
+
+    #[test]
+    fn all_templates_should_have_a_preview() {
+        let templates = EmailTemplates::embedded();
+
+        for template in Template::ALL {
+            for locale in Locale::ALL {
+                assert!(templates.preview(*template, *locale).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn directory_should_override_the_defaults() {
+        let dir = std::env::temp_dir().join(format!("z2p_email_templates_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("welcome.txt.hbs"), "Custom {{name}}").unwrap();
+
+        let templates = EmailTemplates::load(Some(&dir)).unwrap();
+        let email = templates
+            .render(
+                "antonio@gmail.com",
+                Locale::En,
+                &Welcome {
+                    name: "Antonio".to_owned(),
+                    preferences_link: None,
+                },
+            )
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!("Custom Antonio", email.text);
+        assert!(email.html.contains("Hi Antonio,"));
+    }
+
+    #[test]
+    fn invalid_template_should_be_reported() {
+        let dir = std::env::temp_dir().join(format!("z2p_bad_templates_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("issue.html.hbs"), "{{#if}}").unwrap();
+
+        let result = EmailTemplates::load(Some(&dir));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(result, Err(LoadError::Parse(name, _)) if name == "issue.html"));
+    }
+}
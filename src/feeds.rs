@@ -0,0 +1,280 @@
+//! RSS 2.0 and Atom feeds of the sent issues, with the validators that let feed
+//! readers poll them cheaply.
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::{configuration::ArchiveSettings, issues::Issue};
+
+pub(crate) const RSS_CONTENT_TYPE: &str = "application/rss+xml; charset=utf-8";
+pub(crate) const ATOM_CONTENT_TYPE: &str = "application/atom+xml; charset=utf-8";
+
+pub(crate) struct Feed<'a> {
+    pub(crate) settings: &'a ArchiveSettings,
+    /// Without the trailing slash
+    pub(crate) base_url: &'a str,
+    /// The most recent first
+    pub(crate) issues: &'a [Issue],
+}
+
+fn sent_at(issue: &Issue) -> DateTime<Utc> {
+    issue.sent_at.unwrap_or(issue.updated_at)
+}
+
+impl Feed<'_> {
+    /// When the most recent issue was sent.
+    pub(crate) fn updated(&self) -> Option<DateTime<Utc>> {
+        self.issues.iter().map(sent_at).max()
+    }
+
+    fn link(&self, issue: &Issue) -> String {
+        format!("{}/archive/{}", self.base_url, issue.slug())
+    }
+
+    pub(crate) fn rss(&self) -> String {
+        let mut xml = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+        xml.push_str(r#"<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom"><channel>"#);
+        xml.push_str(&format!(
+            "<title>{}</title><link>{}/archive</link><description>{}</description>",
+            escape(&self.settings.title),
+            escape(self.base_url),
+            escape(&self.settings.description),
+        ));
+        xml.push_str(&format!(
+            r#"<atom:link href="{}/feed.rss" rel="self" type="application/rss+xml"/>"#,
+            escape(self.base_url),
+        ));
+        if let Some(updated) = self.updated() {
+            xml.push_str(&format!(
+                "<lastBuildDate>{}</lastBuildDate>",
+                updated.to_rfc2822()
+            ));
+        }
+        for issue in self.issues {
+            let content = &issue.current().content;
+            let link = escape(&self.link(issue));
+            xml.push_str(&format!(
+                "<item><title>{}</title><link>{}</link><guid isPermaLink=\"true\">{}</guid>\
+                <pubDate>{}</pubDate><description>{}</description></item>",
+                escape(&content.subject),
+                link,
+                link,
+                sent_at(issue).to_rfc2822(),
+                escape(&content.html),
+            ));
+        }
+        xml.push_str("</channel></rss>");
+        xml
+    }
+
+    pub(crate) fn atom(&self) -> String {
+        // A feed must have a date: without issues it never changed
+        let updated = self.updated().unwrap_or_else(|| Utc.timestamp(0, 0));
+        let mut xml = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+        xml.push_str(r#"<feed xmlns="http://www.w3.org/2005/Atom">"#);
+        xml.push_str(&format!(
+            "<title>{}</title><subtitle>{}</subtitle><id>{}/archive</id><updated>{}</updated>",
+            escape(&self.settings.title),
+            escape(&self.settings.description),
+            escape(self.base_url),
+            rfc3339(updated),
+        ));
+        xml.push_str(&format!(
+            r#"<link href="{0}/archive"/><link rel="self" href="{0}/feed.atom"/>"#,
+            escape(self.base_url),
+        ));
+        xml.push_str(&format!(
+            "<author><name>{}</name></author>",
+            escape(&self.settings.title)
+        ));
+        for issue in self.issues {
+            let content = &issue.current().content;
+            let link = escape(&self.link(issue));
+            xml.push_str(&format!(
+                "<entry><title>{}</title><link href=\"{}\"/><id>{}</id><updated>{}</updated>\
+                <content type=\"html\">{}</content></entry>",
+                escape(&content.subject),
+                link,
+                link,
+                rfc3339(sent_at(issue)),
+                escape(&content.html),
+            ));
+        }
+        xml.push_str("</feed>");
+        xml
+    }
+}
+
+fn rfc3339(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Text and attribute values as XML character data.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A strong validator of `body`.
+pub(crate) fn etag(body: &str) -> String {
+    format!(
+        "\"{}\"",
+        hex::encode(&Sha256::digest(body.as_bytes())[..16])
+    )
+}
+
+/// The `Last-Modified` format, e.g. `Tue, 15 Nov 1994 08:12:31 GMT`.
+pub(crate) fn http_date(at: DateTime<Utc>) -> String {
+    at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether the client's copy, described by the conditional request headers, is
+/// still fresh: `If-None-Match` wins over `If-Modified-Since`.
+pub(crate) fn not_modified(
+    etag: &str,
+    last_modified: Option<DateTime<Utc>>,
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+) -> bool {
+    if let Some(tags) = if_none_match {
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    let since = if_modified_since.and_then(|since| DateTime::parse_from_rfc2822(since).ok());
+    match (last_modified, since) {
+        (Some(modified), Some(since)) => modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::issues::{Content, Revision, Status};
+
+    fn issue(subject: &str, html: &str) -> Issue {
+        let sent_at = Utc.ymd(2021, 1, 4).and_hms(8, 30, 0);
+        Issue {
+            id: "5ff2d3b8".to_owned(),
+            list: "rust".to_owned(),
+            status: Status::Sent,
+            revisions: vec![Revision {
+                number: 1,
+                content: Content {
+                    subject: subject.to_owned(),
+                    html: html.to_owned(),
+                    text: String::new(),
+                },
+                author: "antonio".to_owned(),
+                created_at: sent_at,
+            }],
+            created_at: sent_at,
+            updated_at: sent_at,
+            sent_at: Some(sent_at),
+        }
+    }
+
+    fn settings() -> ArchiveSettings {
+        ArchiveSettings {
+            title: "News & views".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn feed<'a>(settings: &'a ArchiveSettings, issues: &'a [Issue]) -> Feed<'a> {
+        Feed {
+            settings,
+            base_url: "https://news.example.com",
+            issues,
+        }
+    }
+
+    #[rstest(render,
+        case::rss(|feed: &Feed| feed.rss()),
+        case::atom(|feed: &Feed| feed.atom()),
+    )]
+    fn feeds_should_escape_content(render: fn(&Feed) -> String) {
+        let settings = settings();
+        let issues = [issue("Tom & Jerry", "<p>Hi!</p>")];
+
+        let xml = render(&feed(&settings, &issues));
+
+        assert!(xml.contains("News &amp; views"));
+        assert!(xml.contains("<title>Tom &amp; Jerry</title>"));
+        assert!(xml.contains("&lt;p&gt;Hi!&lt;/p&gt;"));
+        assert!(xml.contains("https://news.example.com/archive/tom-jerry-5ff2d3b8"));
+        assert!(!xml.contains("<p>"));
+    }
+
+    #[test]
+    fn rss_should_date_items_as_rfc2822() {
+        let settings = settings();
+        let issues = [issue("First", "")];
+
+        let xml = feed(&settings, &issues).rss();
+
+        assert!(xml.contains("<pubDate>Mon, 04 Jan 2021 08:30:00 +0000</pubDate>"));
+        assert!(xml.contains("<lastBuildDate>Mon, 04 Jan 2021 08:30:00 +0000</lastBuildDate>"));
+    }
+
+    #[test]
+    fn empty_atom_feed_should_have_a_date() {
+        let settings = settings();
+
+        let xml = feed(&settings, &[]).atom();
+
+        assert!(xml.contains("<updated>1970-01-01T00:00:00Z</updated>"));
+        assert!(!xml.contains("<entry>"));
+    }
+
+    #[rstest(if_none_match, if_modified_since, expected,
+        case::unconditional(None, None, false),
+        case::same_etag(Some(r#""abc""#), None, true),
+        case::weak_etag(Some(r#"W/"abc""#), None, true),
+        case::one_of_many(Some(r#""xyz", "abc""#), None, true),
+        case::any(Some("*"), None, true),
+        case::other_etag(Some(r#""xyz""#), None, false),
+        case::etag_wins(Some(r#""xyz""#), Some("Mon, 04 Jan 2021 09:00:00 GMT"), false),
+        case::not_modified_since(None, Some("Mon, 04 Jan 2021 08:30:00 GMT"), true),
+        case::modified_since(None, Some("Mon, 04 Jan 2021 08:29:59 GMT"), false),
+        case::invalid_date(None, Some("yesterday"), false),
+    )]
+    fn conditional_requests(
+        if_none_match: Option<&str>,
+        if_modified_since: Option<&str>,
+        expected: bool,
+    ) {
+        let last_modified = Utc.ymd(2021, 1, 4).and_hms(8, 30, 0);
+
+        assert_eq!(
+            expected,
+            not_modified(
+                r#""abc""#,
+                Some(last_modified),
+                if_none_match,
+                if_modified_since
+            )
+        );
+    }
+
+    #[test]
+    fn http_dates_should_be_gmt() {
+        assert_eq!(
+            "Mon, 04 Jan 2021 08:30:00 GMT",
+            http_date(Utc.ymd(2021, 1, 4).and_hms(8, 30, 0))
+        );
+    }
+}
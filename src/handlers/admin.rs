@@ -0,0 +1,109 @@
+use serde::Deserialize;
+use tide::{http::mime, Body, Request, Response, StatusCode};
+use tracing::error;
+
+use crate::{
+    delivery::{self, DeliveryQueue},
+    email_templates::Template,
+    i18n::Locale,
+    state::StateTrait,
+    telemetry,
+};
+
+pub(crate) async fn get_log_filter<S: StateTrait>(_req: Request<S>) -> tide::Result {
+    Ok(telemetry::current_filter()
+        .map(|filter| filter.into())
+        .unwrap_or_else(|e| {
+            error!("Cannot read log filter: {}", e);
+            StatusCode::ServiceUnavailable.into()
+        }))
+}
+
+#[tracing::instrument(name = "Changing log filter", skip(req))]
+pub(crate) async fn set_log_filter<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let directives = req.body_string().await?;
+    Ok(match telemetry::set_filter(directives.trim()) {
+        Ok(_) => StatusCode::Ok.into(),
+        Err(telemetry::FilterError::Parse(e)) => {
+            let mut res = Response::new(StatusCode::BadRequest);
+            res.set_body(format!("Invalid filter directives: {}", e));
+            res
+        }
+        Err(e) => {
+            error!("Cannot change log filter: {}", e);
+            StatusCode::ServiceUnavailable.into()
+        }
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct Pagination {
+    /// From 1
+    page: u64,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self { page: 1 }
+    }
+}
+
+/// The queued emails we gave up delivering, most recent first, a page at a time.
+pub(crate) async fn dead_letters<S: StateTrait>(req: Request<S>) -> tide::Result {
+    let Pagination { page } = req.query()?;
+    let skip = page.saturating_sub(1) * delivery::DEAD_LETTERS_PAGE_SIZE;
+    let dead_letters = req
+        .state()
+        .delivery_queue()
+        .dead_letters(skip, delivery::DEAD_LETTERS_PAGE_SIZE)
+        .await;
+    Ok(match dead_letters {
+        Ok(dead_letters) => Body::from_json(&dead_letters)?.into(),
+        Err(e) => {
+            error!("Cannot read dead letters: {:?}", e);
+            StatusCode::ServiceUnavailable.into()
+        }
+    })
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Preview {
+    /// `html` (default) or `text`
+    format: Option<String>,
+    lang: Option<String>,
+}
+
+/// An email template rendered with sample data.
+pub(crate) async fn preview_email_template<S: StateTrait>(req: Request<S>) -> tide::Result {
+    let template = match Template::from_name(req.param("name")?) {
+        Some(template) => template,
+        None => return Ok(StatusCode::NotFound.into()),
+    };
+    let preview: Preview = req.query()?;
+    let locale = Locale::negotiate(preview.lang.as_deref(), None);
+    let email = match req.state().email_templates().preview(template, locale) {
+        Ok(email) => email,
+        Err(e) => {
+            error!("Cannot render email template '{}': {}", template.name(), e);
+            return Ok(StatusCode::InternalServerError.into());
+        }
+    };
+    let mut res = Response::new(StatusCode::Ok);
+    match preview.format.as_deref() {
+        None | Some("html") => {
+            res.set_body(email.html);
+            res.set_content_type(mime::HTML);
+        }
+        Some("text") => {
+            res.set_body(email.text);
+            res.set_content_type(mime::PLAIN);
+        }
+        Some(format) => {
+            res = Response::new(StatusCode::BadRequest);
+            res.set_body(format!("Unknown format '{}': use html or text", format));
+        }
+    }
+    Ok(res)
+}
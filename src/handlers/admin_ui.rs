@@ -0,0 +1,260 @@
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use tide::{Request, StatusCode};
+use tracing::{error, info, warn};
+
+use crate::{
+    authentication::{self, AdminUsersRepository, MAX_PASSWORD_LENGTH, MIN_PASSWORD_LENGTH},
+    configuration::Secret,
+    issues::{self, Content, IssueError, Status},
+    lists::{self, ListsRepository, DEFAULT_LIST},
+    repository::UsersRepository,
+    state::StateTrait,
+    web::{self, FlashMessage, LOGIN_PATH},
+};
+
+const DASHBOARD_PATH: &str = "/admin/dashboard";
+const COMPOSE_PATH: &str = "/admin/issues/new";
+const PASSWORD_PATH: &str = "/admin/password";
+
+fn bad_form(mut e: tide::Error) -> tide::Error {
+    e.set_status(StatusCode::BadRequest);
+    e
+}
+
+pub(crate) async fn login_form<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    if web::logged_user(&req).is_some() {
+        return Ok(web::redirect(&req, DASHBOARD_PATH));
+    }
+    let page = web::page(&mut req, "Login", json!({}));
+    Ok(req.state().templates().render("login", &page))
+}
+
+#[derive(Deserialize)]
+struct Login {
+    username: String,
+    password: Secret<String>,
+}
+
+#[tracing::instrument(name = "Admin login", skip(req))]
+pub(crate) async fn login<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let form: Login = req.body_form().await.map_err(bad_form)?;
+    let valid = authentication::validate_credentials(
+        req.state().admin_users(),
+        &form.username,
+        &form.password,
+    )
+    .await;
+    match valid {
+        Ok(true) => {
+            info!("Admin logged in");
+            web::log_in(&mut req, &form.username);
+            Ok(web::redirect(&req, DASHBOARD_PATH))
+        }
+        Ok(false) => {
+            warn!("Wrong admin credentials");
+            Ok(web::redirect_with(
+                &req,
+                LOGIN_PATH,
+                FlashMessage::error("Wrong username or password"),
+            ))
+        }
+        Err(e) => {
+            error!("Cannot check admin credentials: {:?}", e);
+            Ok(StatusCode::ServiceUnavailable.into())
+        }
+    }
+}
+
+pub(crate) async fn logout<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    req.session_mut().destroy();
+    Ok(web::redirect_with(
+        &req,
+        LOGIN_PATH,
+        FlashMessage::info("You have logged out"),
+    ))
+}
+
+/// The subscribers of every list.
+async fn list_counts<S: StateTrait>(state: &S) -> Result<Vec<serde_json::Value>, String> {
+    let all = state.lists().all().await.map_err(|e| format!("{:?}", e))?;
+    let mut counts = Vec::with_capacity(all.len());
+    for list in all {
+        let subscribers = state
+            .users_repository()
+            .count(&list.slug)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        counts.push(json!({ "name": list.name, "subscribers": subscribers }));
+    }
+    Ok(counts)
+}
+
+pub(crate) async fn dashboard<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let lists = match list_counts(req.state()).await {
+        Ok(lists) => lists,
+        Err(e) => {
+            error!("Cannot count subscribers: {}", e);
+            return Ok(StatusCode::ServiceUnavailable.into());
+        }
+    };
+    let username = web::logged_user(&req);
+    let page = web::page(
+        &mut req,
+        "Dashboard",
+        json!({ "username": username, "lists": lists }),
+    );
+    Ok(req.state().templates().render("dashboard", &page))
+}
+
+pub(crate) async fn compose_form<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let lists = match req.state().lists().all().await {
+        Ok(lists) => lists,
+        Err(e) => {
+            error!("Cannot read lists: {:?}", e);
+            return Ok(StatusCode::ServiceUnavailable.into());
+        }
+    };
+    // The same for every submission of this page: it's published once
+    let idempotency_key = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    let page = web::page(
+        &mut req,
+        "New issue",
+        json!({ "lists": lists, "idempotency_key": idempotency_key }),
+    );
+    Ok(req.state().templates().render("compose", &page))
+}
+
+#[derive(Deserialize)]
+struct Issue {
+    subject: String,
+    html: String,
+    text: String,
+    #[serde(default = "default_list")]
+    list: String,
+}
+
+fn default_list() -> String {
+    DEFAULT_LIST.to_owned()
+}
+
+/// Store the issue, by the logged admin, and publish it to the subscribers of
+/// the chosen list: the delivery worker sends it.
+#[tracing::instrument(name = "Publishing an issue", skip(req))]
+pub(crate) async fn publish<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let issue: Issue = req.body_form().await.map_err(bad_form)?;
+    let author = web::logged_user(&req).unwrap_or_default();
+    let content = Content {
+        subject: issue.subject,
+        html: issue.html,
+        text: issue.text,
+    };
+    let queued = match create_and_publish(req.state(), &issue.list, content, &author).await {
+        Ok(queued) => queued,
+        Err(e @ IssueError::Invalid(_)) => {
+            return Ok(web::redirect_with(
+                &req,
+                COMPOSE_PATH,
+                FlashMessage::error(e.to_string()),
+            ));
+        }
+        Err(e) => {
+            error!("Cannot publish the issue: {}", e);
+            return Ok(web::redirect_with(
+                &req,
+                COMPOSE_PATH,
+                FlashMessage::error("Cannot publish the issue: try again later"),
+            ));
+        }
+    };
+    Ok(web::redirect_with(
+        &req,
+        DASHBOARD_PATH,
+        FlashMessage::info(format!("Issue queued for {} subscribers", queued)),
+    ))
+}
+
+/// The admin writing the issue approves it too.
+async fn create_and_publish<S: StateTrait>(
+    state: &S,
+    list: &str,
+    content: Content,
+    author: &str,
+) -> Result<usize, IssueError> {
+    let list = lists::get(state.lists(), list).await?;
+    let repository = state.issues_repository();
+    let issue = issues::create(repository, &list, content, author).await?;
+    for &status in &[Status::InReview, Status::Approved] {
+        issues::transition(repository, &issue.id, status).await?;
+    }
+    info!(id = %issue.id, "Issue created");
+    issues::publish(state, &issue.id).await
+}
+
+pub(crate) async fn password_form<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let page = web::page(
+        &mut req,
+        "Change password",
+        json!({ "min_length": MIN_PASSWORD_LENGTH, "max_length": MAX_PASSWORD_LENGTH }),
+    );
+    Ok(req.state().templates().render("password", &page))
+}
+
+#[derive(Deserialize)]
+struct ChangePassword {
+    current: Secret<String>,
+    new: Secret<String>,
+    confirm: Secret<String>,
+}
+
+#[tracing::instrument(name = "Changing admin password", skip(req))]
+pub(crate) async fn change_password<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let form: ChangePassword = req.body_form().await.map_err(bad_form)?;
+    let username = match web::logged_user(&req) {
+        Some(username) => username,
+        None => return Ok(web::redirect(&req, LOGIN_PATH)),
+    };
+    if form.new.expose() != form.confirm.expose() {
+        return Ok(web::redirect_with(
+            &req,
+            PASSWORD_PATH,
+            FlashMessage::error("The new passwords don't match"),
+        ));
+    }
+    let users = req.state().admin_users();
+    match authentication::validate_credentials(users, &username, &form.current).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(web::redirect_with(
+                &req,
+                PASSWORD_PATH,
+                FlashMessage::error("The current password is wrong"),
+            ))
+        }
+        Err(e) => {
+            error!("Cannot check admin credentials: {:?}", e);
+            return Ok(StatusCode::ServiceUnavailable.into());
+        }
+    }
+    let hash = match authentication::hash_password(&form.new).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            return Ok(web::redirect_with(
+                &req,
+                PASSWORD_PATH,
+                FlashMessage::error(e.to_string()),
+            ))
+        }
+    };
+    if let Err(e) = users.save(&username, &hash).await {
+        error!("Cannot save the new password: {:?}", e);
+        return Ok(StatusCode::ServiceUnavailable.into());
+    }
+    info!("Admin password changed");
+    Ok(web::redirect_with(
+        &req,
+        DASHBOARD_PATH,
+        FlashMessage::info("Your password has been changed"),
+    ))
+}
@@ -0,0 +1,56 @@
+use chrono::Utc;
+use serde::Deserialize;
+use tide::{Body, Request, Response, StatusCode};
+use tracing::error;
+
+use crate::{
+    analytics::{self, AnalyticsError},
+    state::StateTrait,
+};
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Export {
+    /// `json` (default) or `csv`
+    format: Option<String>,
+}
+
+/// The delivery and engagement numbers of a sent issue.
+pub(crate) async fn issue_analytics<S: StateTrait>(req: Request<S>) -> tide::Result {
+    let export: Export = req.query()?;
+    let report = match analytics::report(req.state(), req.param("id")?, Utc::now()).await {
+        Ok(report) => report,
+        Err(e) => {
+            let status = match &e {
+                AnalyticsError::NotFound => StatusCode::NotFound,
+                AnalyticsError::NotSent => StatusCode::Conflict,
+                AnalyticsError::Repository(_) => {
+                    error!("Cannot aggregate the analytics: {}", e);
+                    return Ok(StatusCode::ServiceUnavailable.into());
+                }
+            };
+            let mut res = Response::new(status);
+            res.set_body(e.to_string());
+            return Ok(res);
+        }
+    };
+    let mut res = Response::new(StatusCode::Ok);
+    match export.format.as_deref() {
+        None | Some("json") => res.set_body(Body::from_json(&report)?),
+        Some("csv") => {
+            let mut csv = Vec::new();
+            report.write_csv(&mut csv)?;
+            res.set_body(csv);
+            res.insert_header("Content-Type", "text/csv; charset=utf-8");
+            res.insert_header(
+                "Content-Disposition",
+                format!(r#"attachment; filename="issue-{}.csv""#, report.issue_id),
+            );
+        }
+        Some(format) => {
+            res = Response::new(StatusCode::BadRequest);
+            res.set_body(format!("Unknown format '{}': use json or csv", format));
+        }
+    }
+    Ok(res)
+}
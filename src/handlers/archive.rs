@@ -0,0 +1,165 @@
+use serde::Deserialize;
+use serde_json::json;
+use tide::{Request, Response, StatusCode};
+use tracing::error;
+
+use crate::{
+    feeds::{self, Feed, ATOM_CONTENT_TYPE, RSS_CONTENT_TYPE},
+    issues::{self, Issue, IssueError},
+    state::StateTrait,
+    web,
+};
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct Pagination {
+    /// From 1
+    page: u64,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self { page: 1 }
+    }
+}
+
+fn failure(e: IssueError) -> Response {
+    match e {
+        IssueError::NotFound => StatusCode::NotFound.into(),
+        e => {
+            error!("Cannot read the archive: {}", e);
+            StatusCode::ServiceUnavailable.into()
+        }
+    }
+}
+
+fn sent_on(issue: &Issue) -> String {
+    issue
+        .sent_at
+        .unwrap_or(issue.updated_at)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// The sent issues, the most recent first.
+pub(crate) async fn archive_page<S: StateTrait>(req: Request<S>) -> tide::Result {
+    let Pagination { page } = req.query()?;
+    let settings = req.state().archive();
+    let archive =
+        match issues::archive(req.state().issues_repository(), page, settings.page_size).await {
+            Ok(archive) => archive,
+            Err(e) => return Ok(failure(e)),
+        };
+    let issues: Vec<_> = archive
+        .issues
+        .iter()
+        .map(|issue| {
+            json!({
+                "subject": issue.current().content.subject,
+                "slug": issue.slug(),
+                "sent_on": sent_on(issue),
+            })
+        })
+        .collect();
+    let previous = if archive.page > 1 {
+        Some(archive.page - 1)
+    } else {
+        None
+    };
+    let next = if archive.page < archive.pages {
+        Some(archive.page + 1)
+    } else {
+        None
+    };
+    Ok(req.state().templates().render(
+        "archive",
+        &json!({
+            "base": web::base(&req),
+            "title": settings.title,
+            "description": settings.description,
+            "issues": issues,
+            "page": archive.page,
+            "pages": archive.pages,
+            "previous": previous,
+            "next": next,
+        }),
+    ))
+}
+
+pub(crate) async fn archive_issue<S: StateTrait>(req: Request<S>) -> tide::Result {
+    let slug = req.param("slug")?;
+    let issue = match issues::published(req.state().issues_repository(), slug).await {
+        Ok(issue) => issue,
+        Err(e) => return Ok(failure(e)),
+    };
+    let content = &issue.current().content;
+    Ok(req.state().templates().render(
+        "archive_issue",
+        &json!({
+            "base": web::base(&req),
+            "title": content.subject,
+            "archive_title": req.state().archive().title,
+            "sent_on": sent_on(&issue),
+            "html": content.html,
+        }),
+    ))
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Rss,
+    Atom,
+}
+
+pub(crate) async fn rss_feed<S: StateTrait>(req: Request<S>) -> tide::Result {
+    feed(req, Format::Rss).await
+}
+
+pub(crate) async fn atom_feed<S: StateTrait>(req: Request<S>) -> tide::Result {
+    feed(req, Format::Atom).await
+}
+
+/// The most recent issues: `304 Not Modified` if the client has them already.
+async fn feed<S: StateTrait>(req: Request<S>, format: Format) -> tide::Result {
+    let settings = req.state().archive();
+    let archive =
+        match issues::archive(req.state().issues_repository(), 1, settings.page_size).await {
+            Ok(archive) => archive,
+            Err(e) => return Ok(failure(e)),
+        };
+    let base_url = settings
+        .base_url
+        .clone()
+        .unwrap_or_else(|| req.url().origin().ascii_serialization());
+    let feed = Feed {
+        settings,
+        base_url: base_url.trim_end_matches('/'),
+        issues: &archive.issues,
+    };
+    let (body, content_type) = match format {
+        Format::Rss => (feed.rss(), RSS_CONTENT_TYPE),
+        Format::Atom => (feed.atom(), ATOM_CONTENT_TYPE),
+    };
+    let etag = feeds::etag(&body);
+    let last_modified = feed.updated();
+    let header = |name: &str| req.header(name).map(|values| values.as_str());
+    let fresh = feeds::not_modified(
+        &etag,
+        last_modified,
+        header("If-None-Match"),
+        header("If-Modified-Since"),
+    );
+    let mut res = if fresh {
+        Response::new(StatusCode::NotModified)
+    } else {
+        let mut res = Response::new(StatusCode::Ok);
+        res.set_body(body);
+        res
+    };
+    res.insert_header("Content-Type", content_type);
+    res.insert_header("ETag", etag);
+    if let Some(last_modified) = last_modified {
+        res.insert_header("Last-Modified", feeds::http_date(last_modified));
+    }
+    Ok(res)
+}
@@ -0,0 +1,46 @@
+use tide::{Request, StatusCode};
+use tracing::{error, info, warn};
+
+use crate::{
+    email_events::{self, SuppressionsRepository},
+    outbox::{self, DomainEvent, Event},
+    state::StateTrait,
+};
+
+/// Record the events posted by the email provider and suppress the addresses
+/// that hard bounced or complained.
+#[tracing::instrument(name = "Receiving email events", skip(req))]
+pub(crate) async fn email_events<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let body = req.body_bytes().await?;
+    let events = match email_events::parse(&body) {
+        Ok(events) => events,
+        Err(e) => {
+            warn!("Invalid email events: {}", e);
+            return Ok(StatusCode::BadRequest.into());
+        }
+    };
+    let suppressions = req.state().suppressions();
+    for event in &events {
+        if let Err(e) = suppressions.record_event(event).await {
+            error!("Cannot record {:?}: {:?}", event, e);
+            return Ok(StatusCode::ServiceUnavailable.into());
+        }
+        if let Some(reason) = event.suppression() {
+            let suppressed = Event::new(DomainEvent::Suppressed {
+                email: event.email.clone(),
+                reason,
+            });
+            let saved = suppressions
+                .suppress(&event.email, reason, Some(&suppressed))
+                .await;
+            if let Err(e) = saved {
+                error!("Cannot suppress the address of {:?}: {:?}", event, e);
+                return Ok(StatusCode::ServiceUnavailable.into());
+            }
+            info!(reason = reason.code(), "Address suppressed");
+            // The webhooks
+            outbox::relay_now(req.state(), &suppressed.id).await;
+        }
+    }
+    Ok(StatusCode::NoContent.into())
+}
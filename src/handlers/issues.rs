@@ -0,0 +1,178 @@
+use serde::Deserialize;
+use serde_json::json;
+use tide::{Body, Request, Response, StatusCode};
+use tracing::{error, info};
+
+use crate::{
+    issues::{self, Content, Issue, IssueError, IssuesRepository, Status},
+    lists::{self, DEFAULT_LIST},
+    state::StateTrait,
+    web,
+};
+
+/// A new issue or a new revision.
+#[derive(Deserialize)]
+struct Edit {
+    #[serde(flatten)]
+    content: Content,
+    author: String,
+    /// The slug of the list to send it to, when creating the issue: the default
+    /// list if missing
+    #[serde(default)]
+    list: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Transition {
+    status: Status,
+}
+
+fn bad_json(mut e: tide::Error) -> tide::Error {
+    e.set_status(StatusCode::BadRequest);
+    e
+}
+
+fn failure(e: IssueError) -> tide::Result {
+    let status = match &e {
+        IssueError::NotFound => StatusCode::NotFound,
+        IssueError::ReadOnly | IssueError::IllegalTransition { .. } | IssueError::Conflict => {
+            StatusCode::Conflict
+        }
+        IssueError::Invalid(_) => StatusCode::UnprocessableEntity,
+        IssueError::Repository(_) => {
+            error!("Cannot access issues: {}", e);
+            return Ok(StatusCode::ServiceUnavailable.into());
+        }
+        IssueError::Render(_) => {
+            error!("Cannot publish the issue: {}", e);
+            return Ok(StatusCode::InternalServerError.into());
+        }
+    };
+    let mut res = Response::new(status);
+    res.set_body(e.to_string());
+    Ok(res)
+}
+
+fn issue(status: StatusCode, issue: &Issue) -> tide::Result {
+    let mut res = Response::new(status);
+    res.set_body(Body::from_json(issue)?);
+    Ok(res)
+}
+
+/// The current revision of every issue, without the history.
+pub(crate) async fn list_issues<S: StateTrait>(req: Request<S>) -> tide::Result {
+    let issues = match req.state().issues_repository().list().await {
+        Ok(issues) => issues,
+        Err(e) => return failure(e.into()),
+    };
+    let summaries: Vec<_> = issues
+        .iter()
+        .map(|issue| {
+            let current = issue.current();
+            json!({
+                "id": issue.id,
+                "list": issue.list,
+                "status": issue.status,
+                "subject": current.content.subject,
+                "revision": current.number,
+                "author": current.author,
+                "updated_at": issue.updated_at,
+                "sent_at": issue.sent_at,
+            })
+        })
+        .collect();
+    Ok(Body::from_json(&summaries)?.into())
+}
+
+#[tracing::instrument(name = "Creating an issue", skip(req))]
+pub(crate) async fn create_issue<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let edit: Edit = req.body_json().await.map_err(bad_json)?;
+    let slug = edit.list.as_deref().unwrap_or(DEFAULT_LIST);
+    let list = match lists::get(req.state().lists(), slug).await {
+        Ok(list) => list,
+        Err(e) => return failure(e.into()),
+    };
+    let created = issues::create(
+        req.state().issues_repository(),
+        &list,
+        edit.content,
+        &edit.author,
+    )
+    .await;
+    match created {
+        Ok(created) => {
+            info!(id = %created.id, "Issue created");
+            let mut res = issue(StatusCode::Created, &created)?;
+            res.insert_header(
+                "Location",
+                format!("{}/admin/newsletter_issues/{}", web::base(&req), created.id),
+            );
+            Ok(res)
+        }
+        Err(e) => failure(e),
+    }
+}
+
+/// The issue with all its revisions.
+pub(crate) async fn get_issue<S: StateTrait>(req: Request<S>) -> tide::Result {
+    match issues::get(req.state().issues_repository(), req.param("id")?).await {
+        Ok(found) => issue(StatusCode::Ok, &found),
+        Err(e) => failure(e),
+    }
+}
+
+#[tracing::instrument(name = "Revising an issue", skip(req))]
+pub(crate) async fn update_issue<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let edit: Edit = req.body_json().await.map_err(bad_json)?;
+    let revised = issues::revise(
+        req.state().issues_repository(),
+        req.param("id")?,
+        edit.content,
+        &edit.author,
+    )
+    .await;
+    match revised {
+        Ok(revised) => issue(StatusCode::Ok, &revised),
+        Err(e) => failure(e),
+    }
+}
+
+#[tracing::instrument(name = "Deleting an issue", skip(req))]
+pub(crate) async fn delete_issue<S: StateTrait>(req: Request<S>) -> tide::Result {
+    match issues::delete(req.state().issues_repository(), req.param("id")?).await {
+        Ok(()) => Ok(StatusCode::NoContent.into()),
+        Err(e) => failure(e),
+    }
+}
+
+/// Move the issue through the workflow: it can only be sent by publishing it.
+#[tracing::instrument(name = "Changing an issue status", skip(req))]
+pub(crate) async fn set_issue_status<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let transition: Transition = req.body_json().await.map_err(bad_json)?;
+    if matches!(transition.status, Status::Sending | Status::Sent) {
+        let mut res = Response::new(StatusCode::Conflict);
+        res.set_body("Publish the issue to send it");
+        return Ok(res);
+    }
+    let moved = issues::transition(
+        req.state().issues_repository(),
+        req.param("id")?,
+        transition.status,
+    )
+    .await;
+    match moved {
+        Ok(moved) => {
+            info!(status = moved.status.code(), "Issue status changed");
+            issue(StatusCode::Ok, &moved)
+        }
+        Err(e) => failure(e),
+    }
+}
+
+/// Send an approved issue to the subscribers of its list.
+pub(crate) async fn publish_issue<S: StateTrait>(req: Request<S>) -> tide::Result {
+    match issues::publish(req.state(), req.param("id")?).await {
+        Ok(queued) => Ok(Body::from_json(&json!({ "queued": queued }))?.into()),
+        Err(e) => failure(e),
+    }
+}
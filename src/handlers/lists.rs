@@ -0,0 +1,87 @@
+use serde::Deserialize;
+use tide::{Body, Request, Response, StatusCode};
+use tracing::{error, info};
+
+use crate::{
+    lists::{self, List, ListError, ListsRepository},
+    state::StateTrait,
+    web,
+};
+
+/// The new name, sender and tracking of a list: the slug is in the path.
+#[derive(Deserialize)]
+struct Change {
+    name: String,
+    #[serde(default)]
+    sender: Option<String>,
+    #[serde(default)]
+    tracking: bool,
+}
+
+fn bad_json(mut e: tide::Error) -> tide::Error {
+    e.set_status(StatusCode::BadRequest);
+    e
+}
+
+fn failure(e: ListError) -> tide::Result {
+    let status = match &e {
+        ListError::NotFound | ListError::Unknown(_) => StatusCode::NotFound,
+        ListError::Exists => StatusCode::Conflict,
+        ListError::TooMany(_) | ListError::Full(_) => StatusCode::Forbidden,
+        ListError::Invalid(_) => StatusCode::UnprocessableEntity,
+        ListError::Repository(_) => {
+            error!("Cannot access lists: {}", e);
+            return Ok(StatusCode::ServiceUnavailable.into());
+        }
+    };
+    let mut res = Response::new(status);
+    res.set_body(e.to_string());
+    Ok(res)
+}
+
+fn list(status: StatusCode, list: &List) -> tide::Result {
+    let mut res = Response::new(status);
+    res.set_body(Body::from_json(list)?);
+    Ok(res)
+}
+
+/// Every list, sorted by slug.
+pub(crate) async fn list_lists<S: StateTrait>(req: Request<S>) -> tide::Result {
+    match req.state().lists().all().await {
+        Ok(all) => Ok(Body::from_json(&all)?.into()),
+        Err(e) => failure(e.into()),
+    }
+}
+
+#[tracing::instrument(name = "Creating a list", skip(req))]
+pub(crate) async fn create_list<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let new: List = req.body_json().await.map_err(bad_json)?;
+    let max = req.state().limits().max_lists;
+    match lists::create(req.state().lists(), new, max).await {
+        Ok(created) => {
+            info!(slug = %created.slug, "List created");
+            let mut res = list(StatusCode::Created, &created)?;
+            res.insert_header(
+                "Location",
+                format!("{}/admin/lists/{}", web::base(&req), created.slug),
+            );
+            Ok(res)
+        }
+        Err(e) => failure(e),
+    }
+}
+
+#[tracing::instrument(name = "Updating a list", skip(req))]
+pub(crate) async fn update_list<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let change: Change = req.body_json().await.map_err(bad_json)?;
+    let changed = List {
+        slug: req.param("slug")?.to_owned(),
+        name: change.name,
+        sender: change.sender,
+        tracking: change.tracking,
+    };
+    match lists::update(req.state().lists(), changed).await {
+        Ok(updated) => list(StatusCode::Ok, &updated),
+        Err(e) => failure(e),
+    }
+}
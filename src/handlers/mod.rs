@@ -1,7 +1,34 @@
+pub(crate) use admin::{dead_letters, get_log_filter, preview_email_template, set_log_filter};
+pub(crate) use admin_ui::{
+    change_password, compose_form, dashboard, login, login_form, logout, password_form, publish,
+};
+pub(crate) use analytics::issue_analytics;
+pub(crate) use archive::{archive_issue, archive_page, atom_feed, rss_feed};
+pub(crate) use email_events::email_events;
 pub(crate) use health_check::health_check;
+pub(crate) use issues::{
+    create_issue, delete_issue, get_issue, list_issues, publish_issue, set_issue_status,
+    update_issue,
+};
+pub(crate) use lists::{create_list, list_lists, update_list};
+pub(crate) use preferences::{preferences_page, update_preferences};
+pub(crate) use schedules::{cancel_schedule, create_schedule, list_schedules, reschedule};
 pub(crate) use subscriptions::subscriptions;
+pub(crate) use tracking::{track_click, track_open};
+pub(crate) use webhooks::webhook_deliveries;
 
+mod admin;
+mod admin_ui;
+mod analytics;
+mod archive;
+mod email_events;
 mod health_check;
+mod issues;
+mod lists;
+mod preferences;
+mod schedules;
 mod subscriptions;
 #[cfg(test)]
 pub mod test;
+mod tracking;
+mod webhooks;
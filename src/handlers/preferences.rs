@@ -0,0 +1,258 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use tide::{Request, Response, StatusCode};
+use tracing::{error, info};
+
+use super::subscriptions::accept_language;
+use crate::{
+    i18n::Locale,
+    lists::ListsRepository,
+    preferences::{self, Frequency, Preferences, PreferencesError, Update, MAX_PAUSE_WEEKS},
+    state::StateTrait,
+    web,
+};
+
+#[derive(Deserialize)]
+struct Link {
+    token: String,
+}
+
+/// `serde_urlencoded` can't collect repeated fields.
+#[derive(Default)]
+struct Form {
+    token: String,
+    name: String,
+    /// The slugs of the lists, one `list` field each
+    lists: Vec<String>,
+    frequency: Option<String>,
+    /// Empty to keep the current pause
+    pause_weeks: Option<String>,
+}
+
+impl Form {
+    fn parse(body: &str) -> Self {
+        let mut form = Self::default();
+        for (key, value) in form_urlencoded::parse(body.as_bytes()) {
+            match key.as_ref() {
+                "token" => form.token = value.into_owned(),
+                "name" => form.name = value.into_owned(),
+                "list" => form.lists.push(value.into_owned()),
+                "frequency" => form.frequency = Some(value.into_owned()),
+                "pause_weeks" => form.pause_weeks = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+        form
+    }
+
+    /// `None` if a field is not valid.
+    fn update(self, current: &Preferences) -> Option<Update> {
+        let frequency = match self.frequency {
+            Some(code) => Frequency::from_code(&code)?,
+            None => current.frequency,
+        };
+        let pause_weeks = match self.pause_weeks.as_deref().map(str::trim) {
+            None | Some("") => None,
+            Some(weeks) => Some(weeks.parse().ok()?),
+        };
+        Some(Update {
+            name: self.name,
+            lists: self.lists,
+            frequency,
+            pause_weeks,
+        })
+    }
+}
+
+/// A page with just a message, in the language of the subscriber.
+fn message<S: StateTrait>(
+    req: &Request<S>,
+    status: StatusCode,
+    locale: Locale,
+    text: &str,
+) -> Response {
+    let mut res = req.state().templates().render(
+        "message",
+        &json!({
+            "lang": locale.code(),
+            "title": locale.t("preferences.title", &[]),
+            "text": text,
+        }),
+    );
+    if res.status().is_success() {
+        res.set_status(status);
+    }
+    res.insert_header("Content-Language", locale.code());
+    res
+}
+
+fn failure<S: StateTrait>(req: &Request<S>, locale: Locale, e: PreferencesError) -> Response {
+    let status = match &e {
+        PreferencesError::NotFound => StatusCode::Forbidden,
+        PreferencesError::Repository(e) => {
+            error!("Cannot read the preferences: {}", e);
+            StatusCode::ServiceUnavailable
+        }
+        _ => StatusCode::UnprocessableEntity,
+    };
+    message(req, status, locale, &e.localized(locale))
+}
+
+/// The form with the `preferences` of the subscriber and every list.
+async fn form<S: StateTrait>(
+    req: &Request<S>,
+    status: StatusCode,
+    token: &str,
+    preferences: &Preferences,
+    notice: Option<String>,
+    error: Option<String>,
+) -> Response {
+    let locale = preferences.locale;
+    let lists = match req.state().lists().all().await {
+        Ok(lists) => lists,
+        Err(e) => {
+            error!("Cannot read the lists: {:?}", e);
+            return message(
+                req,
+                StatusCode::ServiceUnavailable,
+                locale,
+                &locale.t("preferences.failed", &[]),
+            );
+        }
+    };
+    let lists: Vec<_> = lists
+        .iter()
+        .map(|list| {
+            json!({
+                "slug": list.slug,
+                "name": list.name,
+                "subscribed": preferences.lists.contains(&list.slug),
+            })
+        })
+        .collect();
+    let paused = preferences
+        .paused_until
+        .filter(|_| preferences.is_paused(Utc::now()))
+        .map(|until| {
+            let date = until.format("%Y-%m-%d").to_string();
+            locale.t("preferences.paused_until", &[("date", &date)])
+        });
+    let label = |key: &str| locale.t(&format!("preferences.{}", key), &[]);
+    let mut res = req.state().templates().render(
+        "preferences",
+        &json!({
+            "lang": locale.code(),
+            "base": web::base(req),
+            "title": label("title"),
+            "labels": {
+                "name": label("name"),
+                "lists": label("lists"),
+                "frequency": label("frequency"),
+                "immediate": label("immediate"),
+                "weekly": label("weekly"),
+                "pause": label("pause"),
+                "save": label("save"),
+            },
+            "token": token,
+            "name": preferences.name,
+            "lists": lists,
+            "weekly": preferences.frequency == Frequency::Weekly,
+            "paused": paused,
+            "max_pause_weeks": MAX_PAUSE_WEEKS,
+            "notice": notice,
+            "error": error,
+        }),
+    );
+    if res.status().is_success() {
+        res.set_status(status);
+    }
+    res.insert_header("Content-Language", locale.code());
+    res
+}
+
+/// The preferences of the subscriber the signed `token` was made for.
+async fn verified<S: StateTrait>(
+    req: &Request<S>,
+    token: &str,
+    locale: Locale,
+) -> Result<(String, Preferences), Response> {
+    let links = match req.state().preference_links() {
+        Some(links) => links,
+        None => return Err(StatusCode::NotFound.into()),
+    };
+    let email = links.verify(token, Utc::now()).map_err(|e| {
+        info!("Preference center reached with a bad link: {}", e);
+        message(
+            req,
+            StatusCode::Forbidden,
+            locale,
+            &locale.t("preferences.invalid_link", &[]),
+        )
+    })?;
+    let preferences = preferences::get(req.state().preferences_repository(), &email)
+        .await
+        .map_err(|e| failure(req, locale, e))?;
+    Ok((email, preferences))
+}
+
+#[tracing::instrument(name = "Showing the preference center", skip(req))]
+pub(crate) async fn preferences_page<S: StateTrait>(req: Request<S>) -> tide::Result {
+    let locale = Locale::negotiate(None, accept_language(&req).as_deref());
+    let token = req
+        .query::<Link>()
+        .map(|link| link.token)
+        .unwrap_or_default();
+    let preferences = match verified(&req, &token, locale).await {
+        Ok((_, preferences)) => preferences,
+        Err(res) => return Ok(res),
+    };
+    Ok(form(&req, StatusCode::Ok, &token, &preferences, None, None).await)
+}
+
+#[tracing::instrument(name = "Updating the preferences", skip(req))]
+pub(crate) async fn update_preferences<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let locale = Locale::negotiate(None, accept_language(&req).as_deref());
+    let form_data = match req.body_string().await {
+        Ok(body) => Form::parse(&body),
+        Err(_) => {
+            return Ok(message(
+                &req,
+                StatusCode::BadRequest,
+                locale,
+                &locale.t("subscribe.invalid_form", &[]),
+            ))
+        }
+    };
+    let token = form_data.token.clone();
+    let (email, current) = match verified(&req, &token, locale).await {
+        Ok(verified) => verified,
+        Err(res) => return Ok(res),
+    };
+    let locale = current.locale;
+    let update = match form_data.update(&current) {
+        Some(update) => update,
+        None => {
+            return Ok(message(
+                &req,
+                StatusCode::BadRequest,
+                locale,
+                &locale.t("subscribe.invalid_form", &[]),
+            ))
+        }
+    };
+    match preferences::update(req.state(), &email, update, Utc::now()).await {
+        Ok(saved) => {
+            info!("Preferences saved");
+            let notice = locale.t("preferences.saved", &[]);
+            Ok(form(&req, StatusCode::Ok, &token, &saved, Some(notice), None).await)
+        }
+        Err(e) if e.is_invalid() => {
+            info!("Invalid preferences: {}", e);
+            let error = e.localized(locale);
+            let status = StatusCode::UnprocessableEntity;
+            Ok(form(&req, status, &token, &current, None, Some(error)).await)
+        }
+        Err(e) => Ok(failure(&req, locale, e)),
+    }
+}
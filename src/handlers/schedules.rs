@@ -0,0 +1,129 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tide::{Body, Request, Response, StatusCode};
+use tracing::{error, info};
+
+use crate::{
+    issues::{self, IssueError, Status},
+    scheduler::Schedules,
+    state::StateTrait,
+};
+
+#[derive(Deserialize)]
+struct NewSchedule {
+    issue_id: String,
+    publish_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct Reschedule {
+    publish_at: DateTime<Utc>,
+}
+
+fn bad_json(mut e: tide::Error) -> tide::Error {
+    e.set_status(StatusCode::BadRequest);
+    e
+}
+
+fn rejected(status: StatusCode, message: impl AsRef<str>) -> tide::Result {
+    let mut res = Response::new(status);
+    res.set_body(message.as_ref());
+    Ok(res)
+}
+
+fn in_the_past(publish_at: DateTime<Utc>) -> bool {
+    publish_at <= Utc::now()
+}
+
+/// The pending schedules, the next one first.
+pub(crate) async fn list_schedules<S: StateTrait>(req: Request<S>) -> tide::Result {
+    Ok(match req.state().schedules().pending().await {
+        Ok(pending) => Body::from_json(&pending)?.into(),
+        Err(e) => {
+            error!("Cannot read schedules: {:?}", e);
+            StatusCode::ServiceUnavailable.into()
+        }
+    })
+}
+
+/// Publish an issue at `publish_at`: it must be approved by then.
+#[tracing::instrument(name = "Scheduling an issue", skip(req))]
+pub(crate) async fn create_schedule<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let schedule: NewSchedule = req.body_json().await.map_err(bad_json)?;
+    if in_the_past(schedule.publish_at) {
+        return rejected(
+            StatusCode::UnprocessableEntity,
+            "publish_at should be in the future",
+        );
+    }
+    match issues::get(req.state().issues_repository(), &schedule.issue_id).await {
+        Ok(issue) if matches!(issue.status, Status::Sending | Status::Sent) => {
+            return rejected(StatusCode::Conflict, IssueError::ReadOnly.to_string())
+        }
+        Ok(_) => {}
+        Err(IssueError::NotFound) => {
+            return rejected(StatusCode::UnprocessableEntity, "Unknown issue")
+        }
+        Err(e) => {
+            error!("Cannot read the issue: {}", e);
+            return Ok(StatusCode::ServiceUnavailable.into());
+        }
+    }
+    match req
+        .state()
+        .schedules()
+        .create(&schedule.issue_id, schedule.publish_at)
+        .await
+    {
+        Ok(created) => {
+            info!(id = %created.id, "Issue scheduled");
+            let mut res = Response::new(StatusCode::Created);
+            res.set_body(Body::from_json(&created)?);
+            Ok(res)
+        }
+        Err(e) => {
+            error!("Cannot schedule the issue: {:?}", e);
+            Ok(StatusCode::ServiceUnavailable.into())
+        }
+    }
+}
+
+#[tracing::instrument(name = "Rescheduling an issue", skip(req))]
+pub(crate) async fn reschedule<S: StateTrait>(mut req: Request<S>) -> tide::Result {
+    let reschedule: Reschedule = req.body_json().await.map_err(bad_json)?;
+    if in_the_past(reschedule.publish_at) {
+        return rejected(
+            StatusCode::UnprocessableEntity,
+            "publish_at should be in the future",
+        );
+    }
+    Ok(
+        match req
+            .state()
+            .schedules()
+            .reschedule(req.param("id")?, reschedule.publish_at)
+            .await
+        {
+            Ok(true) => StatusCode::NoContent.into(),
+            Ok(false) => StatusCode::NotFound.into(),
+            Err(e) => {
+                error!("Cannot reschedule: {:?}", e);
+                StatusCode::ServiceUnavailable.into()
+            }
+        },
+    )
+}
+
+#[tracing::instrument(name = "Cancelling a schedule", skip(req))]
+pub(crate) async fn cancel_schedule<S: StateTrait>(req: Request<S>) -> tide::Result {
+    Ok(
+        match req.state().schedules().cancel(req.param("id")?).await {
+            Ok(true) => StatusCode::NoContent.into(),
+            Ok(false) => StatusCode::NotFound.into(),
+            Err(e) => {
+                error!("Cannot cancel the schedule: {:?}", e);
+                StatusCode::ServiceUnavailable.into()
+            }
+        },
+    )
+}
@@ -1,49 +1,240 @@
-use mongodb::bson::doc;
-use tide::{convert::Deserialize, Request, StatusCode};
+use serde_json::json;
+use tide::{Request, Response, StatusCode};
 use tracing::{error, info};
 
 use crate::{
+    domain::{parse_email, parse_name},
+    email_events::SuppressionsRepository,
+    i18n::Locale,
+    lists::{self, ListError},
+    outbox::{self, DomainEvent, Event},
     repository::{User, UsersRepository},
     state::StateTrait,
+    telemetry::pii,
 };
 
-#[derive(Deserialize, Debug)]
+/// Feature toggle to stop accepting new subscribers
+const SUBSCRIPTIONS_FEATURE: &str = "subscriptions";
+
+/// Missing fields are reported as validation errors.
+#[derive(Default)]
 struct Subscribe {
     name: String,
     email: String,
+    /// Overrides `Accept-Language`
+    lang: Option<String>,
+    /// The slugs of the lists, one `list` field each: the default list if none
+    lists: Vec<String>,
 }
 
-impl Into<User> for Subscribe {
-    fn into(self) -> User {
-        User {
-            name: self.name,
-            email: self.email,
+impl Subscribe {
+    /// `serde_urlencoded` can't collect repeated fields.
+    fn parse(body: &str) -> Self {
+        let mut form = Self::default();
+        for (key, value) in form_urlencoded::parse(body.as_bytes()) {
+            match key.as_ref() {
+                "name" => form.name = value.into_owned(),
+                "email" => form.email = value.into_owned(),
+                "lang" => form.lang = Some(value.into_owned()),
+                "list" => form.lists.push(value.into_owned()),
+                _ => {}
+            }
         }
+        form
+    }
+}
+
+impl std::fmt::Debug for Subscribe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscribe")
+            .field("name", &pii::name(&self.name))
+            .field("email", &pii::email(&self.email))
+            .field("lang", &self.lang)
+            .field("lists", &self.lists)
+            .finish()
+    }
+}
+
+pub(super) fn accept_language<S>(req: &Request<S>) -> Option<String> {
+    req.header("Accept-Language")
+        .map(|values| values.as_str().to_owned())
+}
+
+/// A page that tells the subscriber how it went, in their language.
+fn page<S: StateTrait>(
+    req: &Request<S>,
+    status: StatusCode,
+    locale: Locale,
+    text: &str,
+) -> Response {
+    let mut res = req.state().templates().render(
+        "message",
+        &json!({
+            "lang": locale.code(),
+            "title": locale.t("subscribe.title", &[]),
+            "text": text,
+        }),
+    );
+    if res.status().is_success() {
+        res.set_status(status);
     }
+    res.insert_header("Content-Language", locale.code());
+    res
 }
 
 #[tracing::instrument(name = "Adding a new subscriber", skip(req))]
 pub(crate) async fn subscriptions<S: StateTrait>(mut req: Request<S>) -> tide::Result {
-    let subscriber = req.body_form::<Subscribe>().await.map_err(|mut e| {
-        e.set_status(StatusCode::BadRequest);
-        e
-    })?;
-    let subscribe_result = req
+    let accept_language = accept_language(&req);
+    if !req.state().live_settings().is_enabled(SUBSCRIPTIONS_FEATURE) {
+        info!("Subscriptions are disabled");
+        let locale = Locale::negotiate(None, accept_language.as_deref());
+        return Ok(page(
+            &req,
+            StatusCode::ServiceUnavailable,
+            locale,
+            &locale.t("subscribe.unavailable", &[]),
+        ));
+    }
+    let form = match req.body_string().await {
+        Ok(body) => Subscribe::parse(&body),
+        Err(_) => {
+            let locale = Locale::negotiate(None, accept_language.as_deref());
+            return Ok(page(
+                &req,
+                StatusCode::BadRequest,
+                locale,
+                &locale.t("subscribe.invalid_form", &[]),
+            ));
+        }
+    };
+    let locale = Locale::negotiate(form.lang.as_deref(), accept_language.as_deref());
+    let subscriber = parse_name(&form.name).and_then(|name| {
+        parse_email(&form.email).map(|email| User {
+            id: None,
+            name,
+            email,
+            locale,
+        })
+    });
+    let subscriber = match subscriber {
+        Ok(subscriber) => subscriber,
+        Err(e) => {
+            info!("Invalid subscription: {}", e);
+            return Ok(page(
+                &req,
+                StatusCode::BadRequest,
+                locale,
+                &e.localized(locale),
+            ));
+        }
+    };
+    let lists = match lists::resolve(req.state().lists(), &form.lists).await {
+        Ok(lists) => lists,
+        Err(ListError::Unknown(slug)) => {
+            info!("Subscription to an unknown list");
+            return Ok(page(
+                &req,
+                StatusCode::UnprocessableEntity,
+                locale,
+                &locale.t("subscribe.unknown_list", &[("list", &slug)]),
+            ));
+        }
+        Err(e) => {
+            error!("Cannot read the lists: {}", e);
+            return Ok(page(
+                &req,
+                StatusCode::ServiceUnavailable,
+                locale,
+                &locale.t("subscribe.failed", &[]),
+            ));
+        }
+    };
+    match req
         .state()
-        .users_repository()
-        .create(subscriber.into())
-        .await;
-    Ok(match subscribe_result {
-        Ok(_) => {
-            info!("New subcriber saved");
-            StatusCode::Ok
+        .suppressions()
+        .suppressed(std::slice::from_ref(&subscriber.email))
+        .await
+    {
+        Ok(suppressed) if !suppressed.is_empty() => {
+            info!("Subscription of a suppressed address");
+            return Ok(page(
+                &req,
+                StatusCode::UnprocessableEntity,
+                locale,
+                &locale.t("subscribe.suppressed", &[]),
+            ));
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("Cannot check the suppression list: {:?}", e);
+            return Ok(page(
+                &req,
+                StatusCode::ServiceUnavailable,
+                locale,
+                &locale.t("subscribe.failed", &[]),
+            ));
+        }
+    }
+    let slugs: Vec<_> = lists.iter().map(|list| list.slug.clone()).collect();
+    let max = req.state().limits().max_subscribers;
+    match lists::check_room(
+        req.state().users_repository(),
+        &subscriber.email,
+        &slugs,
+        max,
+    )
+    .await
+    {
+        Ok(()) => {}
+        Err(ListError::Full(slug)) => {
+            info!("Subscription to a full list");
+            return Ok(page(
+                &req,
+                StatusCode::Forbidden,
+                locale,
+                &locale.t("subscribe.full_list", &[("list", &slug)]),
+            ));
         }
         Err(e) => {
-            error!("Failed to save suscriber: {:?}", e);
-            StatusCode::ServiceUnavailable
+            error!("Cannot count the subscribers: {}", e);
+            return Ok(page(
+                &req,
+                StatusCode::ServiceUnavailable,
+                locale,
+                &locale.t("subscribe.failed", &[]),
+            ));
         }
     }
-    .into())
+    let name = subscriber.name.clone();
+    let event = Event::new(DomainEvent::Subscribed {
+        email: subscriber.email.clone(),
+        name: subscriber.name.clone(),
+        locale,
+        lists: slugs.clone(),
+    });
+    let subscribed = req
+        .state()
+        .users_repository()
+        .subscribe(subscriber, &slugs, Some(&event))
+        .await;
+    if let Err(e) = subscribed {
+        error!("Failed to save suscriber: {:?}", e);
+        return Ok(page(
+            &req,
+            StatusCode::ServiceUnavailable,
+            locale,
+            &locale.t("subscribe.failed", &[]),
+        ));
+    }
+    info!("New subcriber saved");
+    // The welcome email and the webhooks
+    outbox::relay_now(req.state(), &event.id).await;
+    Ok(page(
+        &req,
+        StatusCode::Ok,
+        locale,
+        &locale.t("subscribe.confirmed", &[("name", &name)]),
+    ))
 }
 
 #[cfg(test)]
@@ -68,4 +259,26 @@ mod tests {
         assert_eq!(tide::StatusCode::ServiceUnavailable, res.status());
         Ok(())
     }
+
+    #[async_std::test]
+    async fn should_report_validation_errors_in_the_subscriber_language() -> tide::Result<()> {
+        let app = AppBuilder::from_dbcfg(&fake_db_settings())
+            .await
+            .post(subscriptions)
+            .take();
+
+        let url = Url::parse("https://example.com").unwrap();
+        let mut req = Request::new(Method::Post, url);
+        req.insert_header("Accept-Language", "it-IT,it;q=0.9,en;q=0.8");
+        req.set_body("name=De%20Domenico&email=antonio_de_domenico");
+        let mut res: Response = app.respond(req).await?;
+
+        assert_eq!(tide::StatusCode::BadRequest, res.status());
+        assert_eq!("it", res["Content-Language"].as_str());
+        assert!(res
+            .body_string()
+            .await?
+            .contains("non è un indirizzo email valido"));
+        Ok(())
+    }
 }
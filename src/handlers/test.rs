@@ -24,7 +24,7 @@ impl<S: 'static + StateTrait> AppBuilder<S> {
 
 impl AppBuilder<State> {
     pub async fn from_dbcfg(cfg: &DatabaseSettings) -> Self {
-        State::new(&cfg).await.unwrap().into()
+        State::new(cfg).await.unwrap().into()
     }
 }
 
@@ -37,10 +37,11 @@ impl From<State> for AppBuilder<State> {
 pub(crate) fn fake_db_settings() -> DatabaseSettings {
     DatabaseSettings {
         username: "mongo".to_string(),
-        password: "mongo".to_string(),
+        password: "mongo".to_string().into(),
         port: 12345,
         host: "localhost".to_string(),
         name: "no_name".to_string(),
         connection_timeout: Some(std::time::Duration::from_millis(10)),
+        ..Default::default()
     }
 }
@@ -0,0 +1,117 @@
+use chrono::Utc;
+use tide::{Body, Redirect, Request, Response, StatusCode};
+use tracing::{error, info};
+
+use crate::{
+    issues::{self, IssueError},
+    repository::UsersRepository,
+    state::StateTrait,
+    tracking::{self, Kind, Target, TrackingEvent, TrackingEvents},
+};
+
+/// A transparent 1x1 gif
+const PIXEL: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\xff\xff\xff\x00\x00\x00!\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+/// Headers that tell a prefetch from a reader
+const PREFETCH_HEADERS: &[&str] = &["Purpose", "Sec-Purpose", "X-Purpose", "X-Moz"];
+
+fn verified<S: StateTrait>(req: &Request<S>, kind: Kind, token: &str) -> Option<Target> {
+    let target = req.state().tracker()?.verify(kind, token);
+    if target.is_none() {
+        info!("Tracking link with an invalid token");
+    }
+    target
+}
+
+/// Record the event in the background: readers don't wait for it. Tokens only
+/// carry the id of the reader, the address is looked up here.
+fn record<S: StateTrait + 'static>(
+    req: &Request<S>,
+    kind: Kind,
+    target: Target,
+    url: Option<String>,
+) {
+    let user_agent = req
+        .header("User-Agent")
+        .map(|values| values.as_str().to_owned());
+    let prefetch = PREFETCH_HEADERS.iter().any(|&name| {
+        req.header(name)
+            .is_some_and(|values| values.as_str().contains("prefetch"))
+    });
+    let bot = tracking::is_bot(user_agent.as_deref(), prefetch);
+    let at = Utc::now();
+    let state = req.state().clone();
+    async_std::task::spawn(async move {
+        let email = match state
+            .users_repository()
+            .email_of(&target.recipient_id)
+            .await
+        {
+            Ok(Some(email)) => email,
+            Ok(None) => {
+                info!("Tracking event of a subscriber who is gone");
+                return;
+            }
+            Err(e) => {
+                error!("Cannot read the subscriber of the tracking event: {:?}", e);
+                return;
+            }
+        };
+        let event = TrackingEvent {
+            kind,
+            issue_id: target.issue_id,
+            email,
+            url,
+            user_agent,
+            bot,
+            at,
+        };
+        if let Err(e) = state.tracking_events().record(&event).await {
+            error!("Cannot record the tracking event: {:?}", e);
+        }
+    });
+}
+
+/// Send the reader where the link of the issue pointed to.
+pub(crate) async fn track_click<S: StateTrait + 'static>(req: Request<S>) -> tide::Result {
+    let target = match verified(&req, Kind::Click, req.param("token")?) {
+        Some(target) => target,
+        None => return Ok(StatusCode::NotFound.into()),
+    };
+    // The url is the one in the issue: a token can't point anywhere else
+    let url = match issues::get(req.state().issues_repository(), &target.issue_id).await {
+        Ok(issue) => target.link.and_then(|link| {
+            tracking::links(&issue.current().content.html)
+                .into_iter()
+                .nth(link)
+        }),
+        Err(IssueError::NotFound) => None,
+        Err(e) => {
+            error!("Cannot read the issue of a tracked link: {}", e);
+            return Ok(StatusCode::ServiceUnavailable.into());
+        }
+    };
+    let url = match url {
+        Some(url) => url,
+        None => return Ok(StatusCode::NotFound.into()),
+    };
+    record(&req, Kind::Click, target, Some(url.clone()));
+    Ok(Redirect::new(url).into())
+}
+
+/// The pixel at the end of a tracked issue.
+pub(crate) async fn track_open<S: StateTrait + 'static>(req: Request<S>) -> tide::Result {
+    let token = req.param("token")?.strip_suffix(".gif");
+    let target = match token.and_then(|token| verified(&req, Kind::Open, token)) {
+        Some(target) => target,
+        None => return Ok(StatusCode::NotFound.into()),
+    };
+    record(&req, Kind::Open, target, None);
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(Body::from_bytes(PIXEL.to_vec()));
+    res.insert_header("Content-Type", "image/gif");
+    res.insert_header(
+        "Cache-Control",
+        "no-store, no-cache, must-revalidate, private",
+    );
+    Ok(res)
+}
@@ -0,0 +1,29 @@
+use tide::{Body, Request, StatusCode};
+use tracing::error;
+
+use crate::{
+    state::StateTrait,
+    webhooks::{self, WebhookQueue},
+};
+
+/// The latest deliveries to an endpoint with all their attempts, the newest first.
+pub(crate) async fn webhook_deliveries<S: StateTrait>(req: Request<S>) -> tide::Result {
+    let endpoint = req.param("endpoint")?;
+    let state = req.state();
+    if !state.webhooks().endpoints.contains_key(endpoint) {
+        return Ok(StatusCode::NotFound.into());
+    }
+    Ok(
+        match state
+            .webhook_queue()
+            .log(endpoint, webhooks::LOG_SIZE)
+            .await
+        {
+            Ok(log) => Body::from_json(&log)?.into(),
+            Err(e) => {
+                error!("Cannot read the webhook deliveries: {:?}", e);
+                StatusCode::ServiceUnavailable.into()
+            }
+        },
+    )
+}
@@ -0,0 +1,139 @@
+//! Subscriber facing messages in the subscriber's language. The catalogs are
+//! embedded at compile time from `locales/<code>.json`.
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Locale {
+    #[default]
+    En,
+    It,
+}
+
+type Catalog = HashMap<String, String>;
+
+fn catalog(source: &str) -> Catalog {
+    serde_json::from_str(source).expect("Valid embedded catalog")
+}
+
+static CATALOGS: Lazy<HashMap<Locale, Catalog>> = Lazy::new(|| {
+    vec![
+        (Locale::En, catalog(include_str!("../locales/en.json"))),
+        (Locale::It, catalog(include_str!("../locales/it.json"))),
+    ]
+    .into_iter()
+    .collect()
+});
+
+impl Locale {
+    pub(crate) const ALL: &'static [Locale] = &[Locale::En, Locale::It];
+
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::It => "it",
+        }
+    }
+
+    /// Match a language tag (e.g. `it-IT`) by its primary language.
+    pub(crate) fn from_tag(tag: &str) -> Option<Self> {
+        let language = tag.trim().split('-').next()?.to_lowercase();
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|locale| locale.code() == language)
+    }
+
+    /// The preferred supported language of an `Accept-Language` header.
+    pub(crate) fn from_accept_language(header: &str) -> Option<Self> {
+        let mut ranges: Vec<(f32, Locale)> = header
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let locale = Self::from_tag(parts.next()?)?;
+                let quality = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .next()
+                    .map(|q| q.trim().parse().unwrap_or(0.0))
+                    .unwrap_or(1.0);
+                Some((quality, locale))
+            })
+            .filter(|(quality, _)| *quality > 0.0)
+            .collect();
+        // Stable: on equal quality the first listed wins
+        ranges.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        ranges.first().map(|(_, locale)| *locale)
+    }
+
+    /// An explicit choice (e.g. a `lang` form field) wins over the header.
+    pub(crate) fn negotiate(explicit: Option<&str>, accept_language: Option<&str>) -> Self {
+        explicit
+            .and_then(Self::from_tag)
+            .or_else(|| accept_language.and_then(Self::from_accept_language))
+            .unwrap_or_default()
+    }
+
+    /// The message `key` with its `{placeholder}`s replaced by `args`. Missing
+    /// messages fall back to the default locale and then to the key itself.
+    pub(crate) fn t(self, key: &str, args: &[(&str, &str)]) -> String {
+        let message = CATALOGS[&self]
+            .get(key)
+            .or_else(|| CATALOGS[&Locale::default()].get(key))
+            .map(String::as_str)
+            .unwrap_or(key);
+        args.iter()
+            .fold(message.to_owned(), |message, (name, value)| {
+                message.replace(&format!("{{{}}}", name), value)
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest(header, expected,
+        case::simple("it", Some(Locale::It)),
+        case::region("it-IT,en;q=0.5", Some(Locale::It)),
+        case::quality("en;q=0.4, it;q=0.9", Some(Locale::It)),
+        case::first_supported("fr-FR, it;q=0.8, en;q=0.7", Some(Locale::It)),
+        case::refused("it;q=0, en;q=0.1", Some(Locale::En)),
+        case::unsupported("fr, de", None),
+        case::wildcard("*", None),
+    )]
+    fn should_pick_the_preferred_supported_language(header: &str, expected: Option<Locale>) {
+        assert_eq!(expected, Locale::from_accept_language(header));
+    }
+
+    #[test]
+    fn explicit_choice_should_win_over_header() {
+        assert_eq!(Locale::It, Locale::negotiate(Some("it"), Some("en")));
+        assert_eq!(Locale::En, Locale::negotiate(Some("fr"), Some("en")));
+        assert_eq!(Locale::En, Locale::negotiate(None, None));
+    }
+
+    #[test]
+    fn should_replace_placeholders() {
+        assert_eq!(
+            "Ciao Antonio,",
+            Locale::It.t("email.greeting", &[("name", "Antonio")])
+        );
+        assert_eq!("unknown.key", Locale::It.t("unknown.key", &[]));
+    }
+
+    #[test]
+    fn catalogs_should_have_the_same_messages() {
+        let keys = |locale: &Locale| CATALOGS[locale].keys().cloned().collect::<BTreeSet<_>>();
+
+        for locale in Locale::ALL {
+            assert_eq!(keys(&Locale::default()), keys(locale), "{:?}", locale);
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! A request with an `Idempotency-Key` header is executed just once: retries with
+//! the same key (from the same caller) get the saved response back. Browsers
+//! can't send headers: forms carry the key in their `idempotency_key` field.
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tide::{Body, Response, StatusCode};
+use tracing::{error, info};
+
+use crate::{configuration::IdempotencySettings, repository, state::StateTrait};
+
+pub(crate) const HEADER: &str = "Idempotency-Key";
+const MAX_KEY_LENGTH: usize = 255;
+/// How often a duplicate checks whether the original request completed
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A key is meaningful just for the caller that sent it and for the endpoint it
+/// was sent to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct IdempotencyKey {
+    pub(crate) caller: String,
+    pub(crate) endpoint: String,
+    pub(crate) key: String,
+}
+
+impl IdempotencyKey {
+    fn from_request<S>(req: &tide::Request<S>, key: String) -> Self {
+        Self {
+            caller: caller(req),
+            endpoint: format!("{} {}", req.method(), req.url().path()),
+            key,
+        }
+    }
+}
+
+/// Authenticated callers are identified by their (hashed) credentials, the others
+/// by their IP: the port changes with each connection.
+fn caller<S>(req: &tide::Request<S>) -> String {
+    match req.header("Authorization") {
+        Some(authorization) => {
+            hex::encode(Sha256::digest(authorization.as_str().as_bytes()))
+        }
+        None => match req.remote() {
+            Some(remote) => remote
+                .parse::<SocketAddr>()
+                .map(|address| address.ip().to_string())
+                .unwrap_or_else(|_| remote.to_owned()),
+            None => "anonymous".to_owned(),
+        },
+    }
+}
+
+/// The body of `req`, which is read and put back.
+async fn body<S>(req: &mut tide::Request<S>) -> tide::Result<Vec<u8>> {
+    let body = req.take_body();
+    let mime = body.mime().clone();
+    let body = body.into_bytes().await?;
+    let mut restored = Body::from_bytes(body.clone());
+    restored.set_mime(mime);
+    req.set_body(restored);
+    Ok(body)
+}
+
+/// The hash of the body of `req`: a key can't be reused with another payload.
+async fn fingerprint<S>(req: &mut tide::Request<S>) -> tide::Result<String> {
+    Ok(hex::encode(Sha256::digest(&body(req).await?)))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct IdempotencyForm {
+    idempotency_key: Option<String>,
+}
+
+/// The key in the `idempotency_key` field of the form in the body of `req`.
+async fn form_key<S>(req: &mut tide::Request<S>) -> tide::Result<Option<String>> {
+    let body = body(req).await?;
+    Ok(Body::from_bytes(body)
+        .into_form::<IdempotencyForm>()
+        .await
+        .ok()
+        .and_then(|form| form.idempotency_key)
+        .map(|key| key.trim().to_owned()))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SavedResponse {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl SavedResponse {
+    /// Take a copy of `res`: its body is read and put back.
+    async fn save(res: &mut Response) -> tide::Result<Self> {
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        let body = body.into_bytes().await?;
+        let mut restored = Body::from_bytes(body.clone());
+        restored.set_mime(mime);
+        res.set_body(restored);
+        Ok(Self {
+            status: res.status().into(),
+            headers: res
+                .iter()
+                .flat_map(|(name, values)| {
+                    values
+                        .iter()
+                        .map(move |value| (name.as_str().to_owned(), value.as_str().to_owned()))
+                })
+                .collect(),
+            body,
+        })
+    }
+}
+
+impl From<SavedResponse> for Response {
+    fn from(saved: SavedResponse) -> Self {
+        let mut res = Response::new(saved.status);
+        for (name, value) in &saved.headers {
+            res.append_header(name.as_str(), value.as_str());
+        }
+        res.set_body(Body::from_bytes(saved.body));
+        res
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Begin {
+    /// The caller owns the key and should execute the request
+    Started,
+    /// Another request with the same key is running
+    InProgress,
+    Completed(SavedResponse),
+    /// The key was taken by a request with another payload
+    Mismatch,
+}
+
+#[async_trait::async_trait]
+pub(crate) trait IdempotencyStore: Send + Sync {
+    /// Take `key` for `lock` time, unless another request already did it: the
+    /// requests with the same key should have the same payload `fingerprint`.
+    async fn begin(
+        &self,
+        key: &IdempotencyKey,
+        fingerprint: &str,
+        lock: Duration,
+    ) -> repository::Result<Begin>;
+
+    async fn complete(
+        &self,
+        key: &IdempotencyKey,
+        response: &SavedResponse,
+    ) -> repository::Result<()>;
+
+    /// Forget `key`: the next request with it will be executed.
+    async fn release(&self, key: &IdempotencyKey) -> repository::Result<()>;
+}
+
+#[derive(Debug, Clone)]
+pub struct IdempotencyMiddleware {
+    settings: IdempotencySettings,
+    /// Whether the key can be in the `idempotency_key` field of a form too
+    forms: bool,
+}
+
+impl IdempotencyMiddleware {
+    pub fn new(settings: IdempotencySettings) -> Self {
+        Self {
+            settings,
+            forms: false,
+        }
+    }
+
+    /// For the forms of the admin pages: the key is in their `idempotency_key`
+    /// field, if not in the header.
+    pub(crate) fn for_forms(settings: IdempotencySettings) -> Self {
+        Self {
+            settings,
+            forms: true,
+        }
+    }
+
+    async fn key<S>(&self, req: &mut tide::Request<S>) -> tide::Result<Option<String>> {
+        if let Some(key) = req.header(HEADER) {
+            return Ok(Some(key.as_str().trim().to_owned()));
+        }
+        if self.forms {
+            return form_key(req).await;
+        }
+        Ok(None)
+    }
+
+    async fn begin<S: StateTrait>(
+        &self,
+        state: &S,
+        key: &IdempotencyKey,
+        fingerprint: &str,
+    ) -> tide::Result<Begin> {
+        let deadline = Instant::now() + self.settings.wait;
+        loop {
+            match state
+                .idempotency_store()
+                .begin(key, fingerprint, self.settings.lock)
+                .await
+                .map_err(store_error)?
+            {
+                Begin::InProgress if Instant::now() < deadline => {
+                    async_std::task::sleep(POLL_INTERVAL).await
+                }
+                begin => return Ok(begin),
+            }
+        }
+    }
+}
+
+fn store_error(e: repository::Error) -> tide::Error {
+    error!("Idempotency store failure: {:?}", e);
+    tide::Error::from_str(StatusCode::ServiceUnavailable, "Service unavailable")
+}
+
+fn bad_request(message: &str) -> Response {
+    let mut res = Response::new(StatusCode::BadRequest);
+    res.set_body(message);
+    res
+}
+
+#[async_trait::async_trait]
+impl<State: StateTrait + 'static> tide::Middleware<State> for IdempotencyMiddleware {
+    async fn handle(
+        &self,
+        mut req: tide::Request<State>,
+        next: tide::Next<'_, State>,
+    ) -> tide::Result {
+        let key = match self.key(&mut req).await? {
+            Some(key) => key,
+            None => return Ok(next.run(req).await),
+        };
+        if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+            return Ok(bad_request(&format!(
+                "{} should have 1 to {} characters",
+                HEADER, MAX_KEY_LENGTH
+            )));
+        }
+        let key = IdempotencyKey::from_request(&req, key);
+        let fingerprint = fingerprint(&mut req).await?;
+        let state = req.state().clone();
+        match self.begin(&state, &key, &fingerprint).await? {
+            Begin::Completed(saved) => {
+                info!("Replaying saved response");
+                Ok(saved.into())
+            }
+            Begin::InProgress => {
+                let mut res = Response::new(StatusCode::Conflict);
+                res.set_body("A request with the same idempotency key is in progress");
+                Ok(res)
+            }
+            Begin::Mismatch => {
+                let mut res = Response::new(StatusCode::UnprocessableEntity);
+                res.set_body("The idempotency key was used with another payload");
+                Ok(res)
+            }
+            Begin::Started => {
+                let mut res = next.run(req).await;
+                if res.status().is_server_error() {
+                    // Let the caller retry
+                    if let Err(e) = state.idempotency_store().release(&key).await {
+                        error!("Cannot release idempotency key: {:?}", e);
+                    }
+                    return Ok(res);
+                }
+                let saved = SavedResponse::save(&mut res).await?;
+                if let Err(e) = state.idempotency_store().complete(&key, &saved).await {
+                    error!("Cannot save response for replay: {:?}", e);
+                }
+                Ok(res)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tide::http::{Method, Request, Url};
+
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> tide::Request<()> {
+        let mut req = Request::new(Method::Post, Url::parse("https://example.com/subscriptions").unwrap());
+        if let Some(authorization) = authorization {
+            req.insert_header("Authorization", authorization);
+        }
+        req.into()
+    }
+
+    #[test]
+    fn key_should_be_scoped_to_caller_and_endpoint() {
+        let key = IdempotencyKey::from_request(&request(None), "abc".to_owned());
+
+        assert_eq!("anonymous", key.caller);
+        assert_eq!("POST /subscriptions", key.endpoint);
+        assert_eq!("abc", key.key);
+    }
+
+    fn from(peer_addr: &str) -> tide::Request<()> {
+        let mut req = Request::new(Method::Post, Url::parse("https://example.com/subscriptions").unwrap());
+        req.set_peer_addr(Some(peer_addr));
+        req.into()
+    }
+
+    #[test]
+    fn anonymous_caller_should_be_identified_by_ip() {
+        let first = IdempotencyKey::from_request(&from("10.0.0.1:50000"), "abc".to_owned());
+        let retry = IdempotencyKey::from_request(&from("10.0.0.1:50001"), "abc".to_owned());
+
+        assert_eq!("10.0.0.1", first.caller);
+        assert_eq!(first, retry);
+    }
+
+    #[async_std::test]
+    async fn fingerprint_should_leave_the_body_in_place() {
+        let mut req = request(None);
+        req.set_body("name=Antonio");
+        let mut other = request(None);
+        other.set_body("name=Luca");
+
+        let hash = fingerprint(&mut req).await.unwrap();
+
+        assert_eq!(64, hash.len());
+        assert_ne!(hash, fingerprint(&mut other).await.unwrap());
+        assert_eq!("name=Antonio", req.body_string().await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn form_key_should_leave_the_body_in_place() {
+        let mut req = request(None);
+        req.set_body("subject=Hi&idempotency_key=abc");
+        let mut without = request(None);
+        without.set_body("subject=Hi");
+
+        assert_eq!(Some("abc".to_owned()), form_key(&mut req).await.unwrap());
+        assert_eq!(None, form_key(&mut without).await.unwrap());
+        assert_eq!(
+            "subject=Hi&idempotency_key=abc",
+            req.body_string().await.unwrap()
+        );
+    }
+
+    #[test]
+    fn authenticated_caller_should_be_identified_by_hashed_credentials() {
+        let key = IdempotencyKey::from_request(&request(Some("Bearer token")), "abc".to_owned());
+
+        assert_eq!(64, key.caller.len());
+        assert!(!key.caller.contains("token"));
+        assert_ne!(
+            key.caller,
+            IdempotencyKey::from_request(&request(Some("Bearer other")), "abc".to_owned()).caller
+        );
+    }
+
+    #[async_std::test]
+    async fn replay_should_give_back_the_same_response() {
+        let mut res = Response::new(StatusCode::Created);
+        res.insert_header("X-Custom", "value");
+        res.set_body("payload");
+
+        let saved = SavedResponse::save(&mut res).await.unwrap();
+        let mut replayed: Response = saved.into();
+
+        assert_eq!(StatusCode::Created, replayed.status());
+        assert_eq!("value", replayed["X-Custom"].as_str());
+        assert_eq!(
+            res["Content-Type"].as_str(),
+            replayed["Content-Type"].as_str()
+        );
+        assert_eq!(
+            b"payload".to_vec(),
+            replayed.take_body().into_bytes().await.unwrap()
+        );
+        assert_eq!(
+            b"payload".to_vec(),
+            res.take_body().into_bytes().await.unwrap()
+        );
+    }
+}
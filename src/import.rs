@@ -0,0 +1,520 @@
+use std::{collections::HashMap, io::Read, path::Path};
+
+use thiserror::Error;
+
+use crate::{
+    configuration::DatabaseSettings,
+    domain::{parse_email, parse_name},
+    email_events::SuppressionsRepository,
+    i18n::Locale,
+    lists::{self, ListsRepository},
+    repository::{self, User, UsersRepository},
+    state::{State, StateTrait},
+};
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("Cannot read csv source")]
+    Csv(#[from] csv::Error),
+    #[error("Cannot connect to the database: {0}")]
+    Connection(String),
+    #[error("Repository failure: {0}")]
+    Repository(String),
+    #[error("Unknown list '{0}'")]
+    UnknownList(String),
+}
+
+impl From<repository::Error> for ImportError {
+    fn from(e: repository::Error) -> Self {
+        ImportError::Repository(format!("{:?}", e))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Accepted,
+    Duplicate(String),
+    Invalid(String),
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Accepted => "accepted",
+            Outcome::Duplicate(_) => "duplicate",
+            Outcome::Invalid(_) => "invalid",
+        }
+    }
+
+    fn reason(&self) -> &str {
+        match self {
+            Outcome::Accepted => "",
+            Outcome::Duplicate(reason) | Outcome::Invalid(reason) => reason,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowReport {
+    pub line: u64,
+    pub email: String,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub rows: Vec<RowReport>,
+}
+
+impl ImportReport {
+    pub fn accepted(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Accepted))
+    }
+
+    pub fn duplicated(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Duplicate(_)))
+    }
+
+    pub fn invalid(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Invalid(_)))
+    }
+
+    fn count(&self, predicate: impl Fn(&Outcome) -> bool) -> usize {
+        self.rows.iter().filter(|r| predicate(&r.outcome)).count()
+    }
+
+    pub fn write_csv(&self, w: impl std::io::Write) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(w);
+        writer.write_record(["line", "email", "outcome", "reason"])?;
+        for row in &self.rows {
+            writer.write_record([
+                &row.line.to_string(),
+                &row.email,
+                row.outcome.as_str(),
+                row.outcome.reason(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Row {
+    name: String,
+    email: String,
+}
+
+/// Import the subscribers from the csv file at `path` into `list`: the file should
+/// have a header with `name` and `email` columns.
+pub async fn import_csv(
+    cfg: &DatabaseSettings,
+    path: impl AsRef<Path>,
+    list: &str,
+    batch_size: usize,
+) -> Result<ImportReport, ImportError> {
+    let state = State::new(cfg)
+        .await
+        .map_err(|e| ImportError::Connection(e.to_string()))?;
+    lists::ensure_default(&state).await?;
+    if state.lists().get(list).await?.is_none() {
+        return Err(ImportError::UnknownList(list.to_owned()));
+    }
+    let file = std::fs::File::open(path).map_err(csv::Error::from)?;
+    import(state.users_repository(), file, list, batch_size).await
+}
+
+#[tracing::instrument(name = "Importing subscribers", skip(repository, source))]
+pub(crate) async fn import<R: UsersRepository + SuppressionsRepository>(
+    repository: &R,
+    source: impl Read,
+    list: &str,
+    batch_size: usize,
+) -> Result<ImportReport, ImportError> {
+    // Flexible so that a short row is reported as invalid instead of aborting the whole import
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(source);
+    let headers = reader.headers()?.clone();
+    let email_column = headers.iter().position(|h| h == "email");
+    let mut batch = Batch::new(repository, list, batch_size.max(1));
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record)? {
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let row = match record.deserialize::<Row>(Some(&headers)) {
+            Ok(row) => row,
+            Err(e) => {
+                let email = email_column.and_then(|c| record.get(c)).unwrap_or_default();
+                batch.reject(line, email, e.to_string());
+                continue;
+            }
+        };
+        let user = parse_name(&row.name).and_then(|name| {
+            parse_email(&row.email).map(|email| User {
+                id: None,
+                name,
+                email,
+                locale: Locale::default(),
+            })
+        });
+        match user {
+            Ok(user) => batch.push(line, user).await?,
+            Err(e) => batch.reject(line, &row.email, e.to_string()),
+        }
+    }
+    Ok(batch.finish().await?)
+}
+
+struct Batch<'r, R> {
+    repository: &'r R,
+    list: &'r str,
+    size: usize,
+    pending: Vec<(u64, User)>,
+    seen: HashMap<String, u64>,
+    report: ImportReport,
+}
+
+impl<'r, R: UsersRepository + SuppressionsRepository> Batch<'r, R> {
+    fn new(repository: &'r R, list: &'r str, size: usize) -> Self {
+        Self {
+            repository,
+            list,
+            size,
+            pending: Vec::with_capacity(size),
+            seen: Default::default(),
+            report: Default::default(),
+        }
+    }
+
+    fn reject(&mut self, line: u64, email: &str, reason: String) {
+        self.add(line, email.to_owned(), Outcome::Invalid(reason))
+    }
+
+    fn add(&mut self, line: u64, email: String, outcome: Outcome) {
+        self.report.rows.push(RowReport {
+            line,
+            email,
+            outcome,
+        })
+    }
+
+    async fn push(&mut self, line: u64, user: User) -> repository::Result<()> {
+        if let Some(first) = self.seen.get(&user.email) {
+            let reason = format!("Already present at line {}", first);
+            self.add(line, user.email, Outcome::Duplicate(reason));
+            return Ok(());
+        }
+        self.seen.insert(user.email.clone(), line);
+        self.pending.push((line, user));
+        if self.pending.len() >= self.size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> repository::Result<()> {
+        let emails: Vec<_> = self.pending.iter().map(|(_, u)| u.email.clone()).collect();
+        let known = self.repository.subscribed_lists(&emails).await?;
+        let suppressed = self.repository.suppressed(&emails).await?;
+        let mut accepted = Vec::with_capacity(self.pending.len());
+        for (line, user) in std::mem::take(&mut self.pending) {
+            if suppressed.contains(&user.email) {
+                let reason = "Suppressed after a hard bounce or a complaint".to_owned();
+                self.add(line, user.email, Outcome::Invalid(reason));
+                continue;
+            }
+            match known.get(&user.email) {
+                Some(lists) if lists.contains(self.list) => {
+                    let reason = "Already subscribed".to_owned();
+                    self.add(line, user.email, Outcome::Duplicate(reason));
+                }
+                // Subscribed to other lists: just join this one too
+                Some(_) => {
+                    self.add(line, user.email.clone(), Outcome::Accepted);
+                    self.repository
+                        .subscribe(user, &[self.list.to_owned()], None)
+                        .await?;
+                }
+                None => {
+                    self.add(line, user.email.clone(), Outcome::Accepted);
+                    accepted.push(user);
+                }
+            }
+        }
+        self.repository.create_many(accepted, self.list).await
+    }
+
+    async fn finish(mut self) -> repository::Result<ImportReport> {
+        if !self.pending.is_empty() {
+            self.flush().await?;
+        }
+        self.report.rows.sort_by_key(|r| r.line);
+        Ok(self.report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Mutex,
+    };
+
+    use chrono::{DateTime, Utc};
+    use unindent::Unindent;
+
+    use super::*;
+    use crate::{
+        email_events::{EmailEvent, SuppressionReason},
+        lists::DEFAULT_LIST,
+        outbox,
+        preferences::Frequency,
+    };
+
+    /// Subscriptions as (email, list) pairs.
+    #[derive(Default)]
+    struct FakeRepository {
+        users: Mutex<Vec<(String, String)>>,
+        batches: Mutex<Vec<usize>>,
+        suppressed: Mutex<Vec<String>>,
+    }
+
+    impl FakeRepository {
+        fn with_emails(list: &str, emails: &[&str]) -> Self {
+            let users = emails
+                .iter()
+                .map(|&e| (e.to_owned(), list.to_owned()))
+                .collect();
+            Self {
+                users: Mutex::new(users),
+                ..Default::default()
+            }
+        }
+
+        fn users(&self) -> Vec<(String, String)> {
+            self.users.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl UsersRepository for FakeRepository {
+        async fn subscribe(
+            &self,
+            user: User,
+            lists: &[String],
+            _event: Option<&outbox::Event>,
+        ) -> repository::Result<()> {
+            let mut users = self.users.lock().unwrap();
+            users.extend(lists.iter().map(|l| (user.email.clone(), l.clone())));
+            Ok(())
+        }
+
+        async fn create_many(&self, users: Vec<User>, list: &str) -> repository::Result<()> {
+            self.batches.lock().unwrap().push(users.len());
+            self.users
+                .lock()
+                .unwrap()
+                .extend(users.into_iter().map(|u| (u.email, list.to_owned())));
+            Ok(())
+        }
+
+        async fn subscribed_lists(
+            &self,
+            emails: &[String],
+        ) -> repository::Result<HashMap<String, HashSet<String>>> {
+            let mut known: HashMap<_, HashSet<_>> = HashMap::new();
+            for (email, list) in self.users() {
+                if emails.contains(&email) {
+                    known.entry(email).or_default().insert(list);
+                }
+            }
+            Ok(known)
+        }
+
+        // Ids are not kept
+        async fn email_of(&self, _id: &str) -> repository::Result<Option<String>> {
+            Ok(None)
+        }
+
+        async fn count(&self, list: &str) -> repository::Result<u64> {
+            Ok(self.users().iter().filter(|(_, l)| l == list).count() as u64)
+        }
+
+        // Names, frequencies and pauses are not kept
+        async fn recipients(
+            &self,
+            list: &str,
+            _frequency: Frequency,
+            _now: DateTime<Utc>,
+        ) -> repository::Result<Vec<User>> {
+            Ok(self
+                .users()
+                .into_iter()
+                .filter(|(_, l)| l == list)
+                .map(|(email, _)| User {
+                    id: None,
+                    name: String::new(),
+                    email,
+                    locale: Locale::En,
+                })
+                .collect())
+        }
+
+        async fn adopt_unlisted(&self, _list: &str) -> repository::Result<u64> {
+            // Every pair has its list
+            Ok(0)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl SuppressionsRepository for FakeRepository {
+        async fn record_event(&self, _event: &EmailEvent) -> repository::Result<()> {
+            Ok(())
+        }
+
+        async fn suppress(
+            &self,
+            email: &str,
+            _reason: SuppressionReason,
+            _event: Option<&outbox::Event>,
+        ) -> repository::Result<()> {
+            self.suppressed.lock().unwrap().push(email.to_owned());
+            Ok(())
+        }
+
+        async fn suppressed(&self, emails: &[String]) -> repository::Result<HashSet<String>> {
+            let suppressed = self.suppressed.lock().unwrap();
+            Ok(emails
+                .iter()
+                .filter(|e| suppressed.contains(e))
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn outcomes(report: &ImportReport) -> Vec<(u64, &str)> {
+        report
+            .rows
+            .iter()
+            .map(|r| (r.line, r.outcome.as_str()))
+            .collect()
+    }
+
+    #[async_std::test]
+    async fn should_report_every_row() {
+        let csv = r#"
+            name,email
+            Antonio,antonio@gmail.com
+            ,empty@gmail.com
+            Michele,not an email
+            Antonio Again,ANTONIO@gmail.com
+            Known,known@gmail.com
+            Mario,mario@gmail.com
+            "#
+        .unindent();
+        let repository = FakeRepository::with_emails(DEFAULT_LIST, &["known@gmail.com"]);
+
+        let report = import(&repository, csv.as_bytes(), DEFAULT_LIST, 2)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                (2, "accepted"),
+                (3, "invalid"),
+                (4, "invalid"),
+                (5, "duplicate"),
+                (6, "duplicate"),
+                (7, "accepted"),
+            ],
+            outcomes(&report)
+        );
+        assert_eq!((2, 2, 2), (report.accepted(), report.duplicated(), report.invalid()));
+    }
+
+    #[async_std::test]
+    async fn should_insert_in_batches() {
+        let csv = "name,email\na,a@x.it\nb,b@x.it\nc,c@x.it\nd,d@x.it\ne,e@x.it\n";
+        let repository = FakeRepository::default();
+
+        import(&repository, csv.as_bytes(), DEFAULT_LIST, 2)
+            .await
+            .unwrap();
+
+        assert_eq!(vec![2, 2, 1], *repository.batches.lock().unwrap());
+        assert_eq!(5, repository.count(DEFAULT_LIST).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn subscribers_of_other_lists_should_join_the_list() {
+        let csv = "name,email
+a,a@x.it
+b,b@x.it
+";
+        let repository = FakeRepository::with_emails("rust", &["a@x.it"]);
+
+        let report = import(&repository, csv.as_bytes(), DEFAULT_LIST, 10)
+            .await
+            .unwrap();
+
+        assert_eq!(vec![(2, "accepted"), (3, "accepted")], outcomes(&report));
+        assert_eq!(vec![1], *repository.batches.lock().unwrap());
+        assert_eq!(2, repository.count(DEFAULT_LIST).await.unwrap());
+        assert_eq!(1, repository.count("rust").await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn should_not_import_suppressed_addresses() {
+        let csv = "name,email\na,a@x.it\nb,b@x.it\n";
+        let repository = FakeRepository::default();
+        repository
+            .suppress("b@x.it", SuppressionReason::Complaint, None)
+            .await
+            .unwrap();
+
+        let report = import(&repository, csv.as_bytes(), DEFAULT_LIST, 10)
+            .await
+            .unwrap();
+
+        assert_eq!(vec![(2, "accepted"), (3, "invalid")], outcomes(&report));
+        assert_eq!(
+            vec![("a@x.it".to_owned(), DEFAULT_LIST.to_owned())],
+            repository.users()
+        );
+    }
+
+    #[async_std::test]
+    async fn should_mark_rows_with_missing_columns_as_invalid() {
+        let csv = "name,email\nonly_name\n";
+
+        let report = import(&FakeRepository::default(), csv.as_bytes(), DEFAULT_LIST, 10)
+            .await
+            .unwrap();
+
+        assert_eq!(vec![(2, "invalid")], outcomes(&report));
+    }
+
+    #[test]
+    fn should_write_report_as_csv() {
+        let report = ImportReport {
+            rows: vec![
+                RowReport {
+                    line: 2,
+                    email: "a@x.it".to_owned(),
+                    outcome: Outcome::Accepted,
+                },
+                RowReport {
+                    line: 3,
+                    email: "a@x.it".to_owned(),
+                    outcome: Outcome::Duplicate("Already present at line 2".to_owned()),
+                },
+            ],
+        };
+        let mut out = Vec::new();
+
+        report.write_csv(&mut out).unwrap();
+
+        assert_eq!(
+            "line,email,outcome,reason\n2,a@x.it,accepted,\n3,a@x.it,duplicate,Already present at line 2\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
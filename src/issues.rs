@@ -0,0 +1,799 @@
+//! Newsletter issues: editors write a draft, submit it for review and publish it
+//! once approved. Every change is a new revision, the history is never rewritten,
+//! and a sent issue can't be changed anymore.
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::{
+    delivery::DeliveryQueue,
+    email::Email,
+    email_events::SuppressionsRepository,
+    email_templates::{self, EmailContext},
+    lists::{self, List, ListError},
+    preferences::{self, Frequency},
+    repository::{self, User, UsersRepository},
+    state::StateTrait,
+    tracking,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Status {
+    Draft,
+    InReview,
+    Approved,
+    /// Being queued: it goes back to approved if it can't be
+    Sending,
+    Sent,
+}
+
+impl Status {
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            Status::Draft => "draft",
+            Status::InReview => "in_review",
+            Status::Approved => "approved",
+            Status::Sending => "sending",
+            Status::Sent => "sent",
+        }
+    }
+
+    pub(crate) fn from_code(code: &str) -> Option<Self> {
+        [
+            Status::Draft,
+            Status::InReview,
+            Status::Approved,
+            Status::Sending,
+            Status::Sent,
+        ]
+        .iter()
+        .copied()
+        .find(|status| status.code() == code)
+    }
+
+    /// Reviewers can send an issue back to draft; nothing leaves `sent`.
+    pub(crate) fn can_become(self, next: Status) -> bool {
+        matches!(
+            (self, next),
+            (Status::Draft, Status::InReview)
+                | (Status::InReview, Status::Draft)
+                | (Status::InReview, Status::Approved)
+                | (Status::Approved, Status::Draft)
+                | (Status::Approved, Status::Sending)
+                | (Status::Sending, Status::Approved)
+                | (Status::Sending, Status::Sent)
+        )
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// What the editors write.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Content {
+    pub(crate) subject: String,
+    pub(crate) html: String,
+    pub(crate) text: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Revision {
+    /// From 1
+    pub(crate) number: u32,
+    #[serde(flatten)]
+    pub(crate) content: Content,
+    pub(crate) author: String,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+impl Revision {
+    fn new(number: u32, content: Content, author: &str) -> Result<Self, IssueError> {
+        if content.subject.trim().is_empty() {
+            return Err(IssueError::Invalid("The subject is mandatory".to_owned()));
+        }
+        if author.trim().is_empty() {
+            return Err(IssueError::Invalid("The author is mandatory".to_owned()));
+        }
+        Ok(Self {
+            number,
+            content,
+            author: author.trim().to_owned(),
+            created_at: Utc::now(),
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Issue {
+    pub(crate) id: String,
+    /// The slug of the list it's sent to
+    pub(crate) list: String,
+    pub(crate) status: Status,
+    /// Oldest first: never empty
+    pub(crate) revisions: Vec<Revision>,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) updated_at: DateTime<Utc>,
+    pub(crate) sent_at: Option<DateTime<Utc>>,
+}
+
+impl Issue {
+    pub(crate) fn current(&self) -> &Revision {
+        self.revisions
+            .last()
+            .expect("An issue has at least a revision")
+    }
+
+    /// Where the archive shows the issue: it doesn't change once the issue is
+    /// sent, because the subject can't change anymore.
+    pub(crate) fn slug(&self) -> String {
+        let words: Vec<_> = self
+            .current()
+            .content
+            .subject
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_ascii_lowercase)
+            .collect();
+        if words.is_empty() {
+            format!("issue-{}", self.id)
+        } else {
+            format!("{}-{}", words.join("-"), self.id)
+        }
+    }
+}
+
+/// A page of the archive of sent issues.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ArchivePage {
+    /// The most recent first
+    pub(crate) issues: Vec<Issue>,
+    /// From 1
+    pub(crate) page: u64,
+    /// At least 1, even if nothing was sent yet
+    pub(crate) pages: u64,
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub(crate) enum IssueError {
+    #[error("Issue not found")]
+    NotFound,
+    #[error("A sent issue cannot be changed")]
+    ReadOnly,
+    #[error("An issue cannot go from {from} to {to}")]
+    IllegalTransition { from: Status, to: Status },
+    #[error("The issue was changed in the meantime: reload it and try again")]
+    Conflict,
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Cannot render the issue: {0}")]
+    Render(String),
+    #[error("Repository failure: {0}")]
+    Repository(String),
+}
+
+impl From<repository::Error> for IssueError {
+    fn from(e: repository::Error) -> Self {
+        IssueError::Repository(format!("{:?}", e))
+    }
+}
+
+impl From<ListError> for IssueError {
+    fn from(e: ListError) -> Self {
+        match e {
+            ListError::Repository(e) => IssueError::Repository(e),
+            e => IssueError::Invalid(e.to_string()),
+        }
+    }
+}
+
+/// Updates take the state the issue was read in and return `false`, without
+/// changing anything, if it's not in that state anymore.
+#[async_trait::async_trait]
+pub(crate) trait IssuesRepository: Send + Sync {
+    async fn create(&self, list: &str, revision: &Revision) -> repository::Result<Issue>;
+
+    async fn get(&self, id: &str) -> repository::Result<Option<Issue>>;
+
+    /// Most recently updated first.
+    async fn list(&self) -> repository::Result<Vec<Issue>>;
+
+    /// Append `revision` and bring the issue back to draft.
+    async fn add_revision(
+        &self,
+        id: &str,
+        status: Status,
+        revision: &Revision,
+    ) -> repository::Result<bool>;
+
+    async fn set_status(&self, id: &str, from: Status, to: Status) -> repository::Result<bool>;
+
+    async fn delete(&self, id: &str, status: Status) -> repository::Result<bool>;
+
+    /// Sent issues, the most recently sent first.
+    async fn sent(&self, skip: u64, limit: u64) -> repository::Result<Vec<Issue>>;
+
+    async fn count_sent(&self) -> repository::Result<u64>;
+
+    /// Issues of `list` sent after `after` and not after `until`, the oldest first.
+    async fn sent_between(
+        &self,
+        list: &str,
+        after: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> repository::Result<Vec<Issue>>;
+}
+
+async fn existing<R: IssuesRepository>(repository: &R, id: &str) -> Result<Issue, IssueError> {
+    repository.get(id).await?.ok_or(IssueError::NotFound)
+}
+
+async fn writable<R: IssuesRepository>(repository: &R, id: &str) -> Result<Issue, IssueError> {
+    let issue = existing(repository, id).await?;
+    if matches!(issue.status, Status::Sending | Status::Sent) {
+        return Err(IssueError::ReadOnly);
+    }
+    Ok(issue)
+}
+
+pub(crate) async fn create<R: IssuesRepository>(
+    repository: &R,
+    list: &List,
+    content: Content,
+    author: &str,
+) -> Result<Issue, IssueError> {
+    let revision = Revision::new(1, content, author)?;
+    Ok(repository.create(&list.slug, &revision).await?)
+}
+
+pub(crate) async fn get<R: IssuesRepository>(
+    repository: &R,
+    id: &str,
+) -> Result<Issue, IssueError> {
+    existing(repository, id).await
+}
+
+/// A reviewed or approved issue goes back to draft: the new content was not
+/// reviewed yet.
+pub(crate) async fn revise<R: IssuesRepository>(
+    repository: &R,
+    id: &str,
+    content: Content,
+    author: &str,
+) -> Result<Issue, IssueError> {
+    let issue = writable(repository, id).await?;
+    let revision = Revision::new(issue.current().number + 1, content, author)?;
+    if !repository.add_revision(id, issue.status, &revision).await? {
+        return Err(IssueError::Conflict);
+    }
+    existing(repository, id).await
+}
+
+pub(crate) async fn transition<R: IssuesRepository>(
+    repository: &R,
+    id: &str,
+    to: Status,
+) -> Result<Issue, IssueError> {
+    let issue = writable(repository, id).await?;
+    if !issue.status.can_become(to) {
+        return Err(IssueError::IllegalTransition {
+            from: issue.status,
+            to,
+        });
+    }
+    if !repository.set_status(id, issue.status, to).await? {
+        return Err(IssueError::Conflict);
+    }
+    existing(repository, id).await
+}
+
+pub(crate) async fn delete<R: IssuesRepository>(
+    repository: &R,
+    id: &str,
+) -> Result<(), IssueError> {
+    let issue = writable(repository, id).await?;
+    if !repository.delete(id, issue.status).await? {
+        return Err(IssueError::Conflict);
+    }
+    Ok(())
+}
+
+/// The `page`-th page of sent issues, `page_size` issues per page.
+pub(crate) async fn archive<R: IssuesRepository>(
+    repository: &R,
+    page: u64,
+    page_size: u64,
+) -> Result<ArchivePage, IssueError> {
+    let pages = repository.count_sent().await?.div_ceil(page_size).max(1);
+    if page == 0 || page > pages {
+        return Err(IssueError::NotFound);
+    }
+    let issues = repository.sent((page - 1) * page_size, page_size).await?;
+    Ok(ArchivePage {
+        issues,
+        page,
+        pages,
+    })
+}
+
+/// The sent issue with the given `slug`: the others are not public.
+pub(crate) async fn published<R: IssuesRepository>(
+    repository: &R,
+    slug: &str,
+) -> Result<Issue, IssueError> {
+    let id = slug.rsplit('-').next().unwrap_or_default();
+    match repository.get(id).await? {
+        Some(issue) if issue.status == Status::Sent && issue.slug() == slug => Ok(issue),
+        _ => Err(IssueError::NotFound),
+    }
+}
+
+/// Send an approved issue to the subscribers of its list. It's marked as sending
+/// before it's queued, so that concurrent publications can't send it twice, and
+/// as sent once it's queued. If queueing fails part way it's left sending: it's
+/// published again by resuming it.
+#[tracing::instrument(name = "Publishing a stored issue", skip(state))]
+pub(crate) async fn publish<S: StateTrait>(state: &S, id: &str) -> Result<usize, IssueError> {
+    let issue = existing(state.issues_repository(), id).await?;
+    if issue.status == Status::Sending {
+        return resume(state, id).await;
+    }
+    // An unknown list must not leave the issue marked as sending
+    let list = lists::get(state.lists(), &issue.list).await?;
+    let issue = transition(state.issues_repository(), id, Status::Sending).await?;
+    let queued = match queue(state, &list, &issue).await {
+        Ok(queued) => queued,
+        Err(e) => {
+            if nothing_queued(state, id).await {
+                // It can be published again
+                let repository = state.issues_repository();
+                if let Err(e) = repository
+                    .set_status(id, Status::Sending, Status::Approved)
+                    .await
+                {
+                    error!("Cannot bring the issue back to approved: {:?}", e);
+                }
+            }
+            return Err(e);
+        }
+    };
+    mark_sent(state, id).await?;
+    Ok(queued)
+}
+
+/// Finish the publication of an issue left sending, e.g. by a database failure:
+/// the subscribers who have it already are skipped.
+#[tracing::instrument(name = "Resuming the publication of an issue", skip(state))]
+async fn resume<S: StateTrait>(state: &S, id: &str) -> Result<usize, IssueError> {
+    let issue = existing(state.issues_repository(), id).await?;
+    if issue.status != Status::Sending {
+        return Err(IssueError::IllegalTransition {
+            from: issue.status,
+            to: Status::Sent,
+        });
+    }
+    let list = lists::get(state.lists(), &issue.list).await?;
+    let queued = queue(state, &list, &issue).await?;
+    mark_sent(state, id).await?;
+    Ok(queued)
+}
+
+/// Whether no subscriber got the issue `id` yet: `false` if it can't be told.
+async fn nothing_queued<S: StateTrait>(state: &S, id: &str) -> bool {
+    match state.delivery_queue().recipients_of(id).await {
+        Ok(recipients) => recipients.is_empty(),
+        Err(e) => {
+            error!("Cannot tell whether the issue was queued: {:?}", e);
+            false
+        }
+    }
+}
+
+async fn queue<S: StateTrait>(state: &S, list: &List, issue: &Issue) -> Result<usize, IssueError> {
+    let content = &issue.current().content;
+    deliver(
+        state,
+        list,
+        Frequency::Immediate,
+        Some(&issue.id),
+        |subscriber, preferences_link| email_templates::Issue {
+            subject: content.subject.clone(),
+            html_content: tracking::html(state, list, &issue.id, subscriber, &content.html),
+            text_content: content.text.clone(),
+            preferences_link,
+        },
+    )
+    .await
+}
+
+async fn mark_sent<S: StateTrait>(state: &S, id: &str) -> Result<(), IssueError> {
+    if !state
+        .issues_repository()
+        .set_status(id, Status::Sending, Status::Sent)
+        .await?
+    {
+        return Err(IssueError::Conflict);
+    }
+    Ok(())
+}
+
+/// Queue an email for every subscriber of `list` who gets issues with `frequency`,
+/// is not paused and whose address is not suppressed, from the sender of the
+/// list: return how many emails were queued. `issue_id` is the stored issue they
+/// carry, if a single one: the subscribers who have it already are skipped.
+/// `compose` makes the email given the subscriber and the link to their
+/// preference center. It stops at the first email it can't render or queue: the
+/// ones queued before stay queued.
+pub(crate) async fn deliver<S, C>(
+    state: &S,
+    list: &List,
+    frequency: Frequency,
+    issue_id: Option<&str>,
+    compose: impl Fn(&User, Option<String>) -> C,
+) -> Result<usize, IssueError>
+where
+    S: StateTrait,
+    C: EmailContext,
+{
+    let now = Utc::now();
+    let subscribers = state
+        .users_repository()
+        .recipients(&list.slug, frequency, now)
+        .await?;
+    let emails: Vec<_> = subscribers.iter().map(|s| s.email.clone()).collect();
+    let suppressed = state.suppressions().suppressed(&emails).await?;
+    let delivered = match issue_id {
+        Some(issue_id) => state.delivery_queue().recipients_of(issue_id).await?,
+        None => HashSet::new(),
+    };
+    let mut queued = 0;
+    for subscriber in subscribers {
+        if suppressed.contains(&subscriber.email) || delivered.contains(&subscriber.email) {
+            continue;
+        }
+        let context = compose(
+            &subscriber,
+            preferences::link(state, &subscriber.email, now),
+        );
+        let email = state
+            .email_templates()
+            .render(&subscriber.email, subscriber.locale, &context)
+            .map_err(|e| IssueError::Render(e.to_string()))?;
+        let email = Email {
+            from: list.sender.clone(),
+            issue_id: issue_id.map(str::to_owned),
+            ..email
+        };
+        state.delivery_queue().enqueue(email).await?;
+        queued += 1;
+    }
+    info!("Issue queued for {} subscribers", queued);
+    Ok(queued)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    /// Only one issue, with id `1`.
+    #[derive(Default)]
+    struct FakeIssues {
+        issue: Mutex<Option<Issue>>,
+    }
+
+    impl FakeIssues {
+        fn with_status(status: Status) -> Self {
+            let issues = Self::default();
+            let revision = Revision::new(1, content("First"), "antonio").unwrap();
+            *issues.issue.lock().unwrap() = Some(Issue {
+                id: "1".to_owned(),
+                list: lists::DEFAULT_LIST.to_owned(),
+                status,
+                revisions: vec![revision],
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+                sent_at: None,
+            });
+            issues
+        }
+
+        fn issue(&self) -> Option<Issue> {
+            self.issue.lock().unwrap().clone()
+        }
+
+        /// Apply `update` if the issue is in `status`.
+        fn update(&self, status: Status, update: impl FnOnce(&mut Option<Issue>)) -> bool {
+            let mut issue = self.issue.lock().unwrap();
+            match issue.as_ref() {
+                Some(i) if i.status == status => {
+                    update(&mut issue);
+                    true
+                }
+                _ => false,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IssuesRepository for FakeIssues {
+        async fn create(&self, list: &str, revision: &Revision) -> repository::Result<Issue> {
+            let issue = Issue {
+                id: "1".to_owned(),
+                list: list.to_owned(),
+                status: Status::Draft,
+                revisions: vec![revision.clone()],
+                created_at: revision.created_at,
+                updated_at: revision.created_at,
+                sent_at: None,
+            };
+            *self.issue.lock().unwrap() = Some(issue.clone());
+            Ok(issue)
+        }
+
+        async fn get(&self, id: &str) -> repository::Result<Option<Issue>> {
+            Ok(self.issue().filter(|issue| issue.id == id))
+        }
+
+        async fn list(&self) -> repository::Result<Vec<Issue>> {
+            Ok(self.issue().into_iter().collect())
+        }
+
+        async fn add_revision(
+            &self,
+            _id: &str,
+            status: Status,
+            revision: &Revision,
+        ) -> repository::Result<bool> {
+            Ok(self.update(status, |issue| {
+                let issue = issue.as_mut().unwrap();
+                issue.status = Status::Draft;
+                issue.revisions.push(revision.clone());
+            }))
+        }
+
+        async fn set_status(
+            &self,
+            _id: &str,
+            from: Status,
+            to: Status,
+        ) -> repository::Result<bool> {
+            Ok(self.update(from, |issue| issue.as_mut().unwrap().status = to))
+        }
+
+        async fn delete(&self, _id: &str, status: Status) -> repository::Result<bool> {
+            Ok(self.update(status, |issue| *issue = None))
+        }
+
+        async fn sent(&self, skip: u64, limit: u64) -> repository::Result<Vec<Issue>> {
+            Ok(self
+                .issue()
+                .filter(|issue| issue.status == Status::Sent)
+                .into_iter()
+                .skip(skip as usize)
+                .take(limit as usize)
+                .collect())
+        }
+
+        async fn count_sent(&self) -> repository::Result<u64> {
+            Ok(self.sent(0, 1).await?.len() as u64)
+        }
+
+        async fn sent_between(
+            &self,
+            list: &str,
+            after: DateTime<Utc>,
+            until: DateTime<Utc>,
+        ) -> repository::Result<Vec<Issue>> {
+            Ok(self
+                .issue()
+                .filter(|issue| issue.status == Status::Sent && issue.list == list)
+                .filter(|issue| issue.sent_at.is_some_and(|at| after < at && at <= until))
+                .into_iter()
+                .collect())
+        }
+    }
+
+    fn newsletter() -> List {
+        List {
+            slug: lists::DEFAULT_LIST.to_owned(),
+            name: "Newsletter".to_owned(),
+            sender: None,
+            tracking: false,
+        }
+    }
+
+    fn content(subject: &str) -> Content {
+        Content {
+            subject: subject.to_owned(),
+            html: format!("<p>{}</p>", subject),
+            text: subject.to_owned(),
+        }
+    }
+
+    #[rstest(from, to, legal,
+        case::submit(Status::Draft, Status::InReview, true),
+        case::reject(Status::InReview, Status::Draft, true),
+        case::approve(Status::InReview, Status::Approved, true),
+        case::reopen(Status::Approved, Status::Draft, true),
+        case::send(Status::Approved, Status::Sending, true),
+        case::sent(Status::Sending, Status::Sent, true),
+        case::not_sent(Status::Sending, Status::Approved, true),
+        case::skip_sending(Status::Approved, Status::Sent, false),
+        case::approve_draft(Status::Draft, Status::Approved, false),
+        case::send_draft(Status::Draft, Status::Sent, false),
+        case::send_in_review(Status::InReview, Status::Sent, false),
+        case::same(Status::Draft, Status::Draft, false),
+        case::unsend(Status::Sent, Status::Draft, false),
+    )]
+    fn transitions(from: Status, to: Status, legal: bool) {
+        assert_eq!(legal, from.can_become(to));
+    }
+
+    #[rstest(code, expected,
+        case::draft("draft", Some(Status::Draft)),
+        case::in_review("in_review", Some(Status::InReview)),
+        case::unknown("published", None),
+    )]
+    fn status_from_code(code: &str, expected: Option<Status>) {
+        assert_eq!(expected, Status::from_code(code));
+    }
+
+    #[async_std::test]
+    async fn revisions_should_keep_the_history() {
+        let issues = FakeIssues::default();
+        create(&issues, &newsletter(), content("First"), "antonio")
+            .await
+            .unwrap();
+
+        let issue = revise(&issues, "1", content("Second"), " michele ")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec![(1, "First", "antonio"), (2, "Second", "michele")],
+            issue
+                .revisions
+                .iter()
+                .map(|r| (r.number, r.content.subject.as_str(), r.author.as_str()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[async_std::test]
+    async fn revising_an_approved_issue_should_bring_it_back_to_draft() {
+        let issues = FakeIssues::with_status(Status::Approved);
+
+        let issue = revise(&issues, "1", content("Fixed"), "antonio")
+            .await
+            .unwrap();
+
+        assert_eq!(Status::Draft, issue.status);
+    }
+
+    #[async_std::test]
+    async fn sent_issue_should_be_read_only() {
+        let issues = FakeIssues::with_status(Status::Sent);
+
+        assert_eq!(
+            Err(IssueError::ReadOnly),
+            revise(&issues, "1", content("Late"), "antonio").await
+        );
+        assert_eq!(
+            Err(IssueError::ReadOnly),
+            transition(&issues, "1", Status::Draft).await
+        );
+        assert_eq!(Err(IssueError::ReadOnly), delete(&issues, "1").await);
+        assert_eq!(Some(Status::Sent), issues.issue().map(|i| i.status));
+    }
+
+    #[async_std::test]
+    async fn issue_being_sent_should_be_read_only() {
+        let issues = FakeIssues::with_status(Status::Sending);
+
+        assert_eq!(
+            Err(IssueError::ReadOnly),
+            revise(&issues, "1", content("Late"), "antonio").await
+        );
+        assert_eq!(
+            Err(IssueError::ReadOnly),
+            transition(&issues, "1", Status::Draft).await
+        );
+        assert_eq!(Some(Status::Sending), issues.issue().map(|i| i.status));
+    }
+
+    #[async_std::test]
+    async fn illegal_transition_should_be_rejected() {
+        let issues = FakeIssues::with_status(Status::Draft);
+
+        assert_eq!(
+            Err(IssueError::IllegalTransition {
+                from: Status::Draft,
+                to: Status::Sent
+            }),
+            transition(&issues, "1", Status::Sent).await
+        );
+        assert_eq!(Some(Status::Draft), issues.issue().map(|i| i.status));
+    }
+
+    #[rstest(subject, author,
+        case::subject(" ", "antonio"),
+        case::author("Subject", ""),
+    )]
+    fn should_require_subject_and_author(subject: &str, author: &str) {
+        let created = async_std::task::block_on(create(
+            &FakeIssues::default(),
+            &newsletter(),
+            content(subject),
+            author,
+        ));
+
+        assert!(matches!(created, Err(IssueError::Invalid(_))));
+    }
+
+    #[rstest(subject, expected,
+        case::words("Issue #1: what's new?", "issue-1-what-s-new-1"),
+        case::case_and_spaces("  Hello   World ", "hello-world-1"),
+        case::no_ascii("Ciao è già qui", "ciao-gi-qui-1"),
+        case::only_symbols("!!!", "issue-1"),
+    )]
+    fn slugs_should_be_url_safe(subject: &str, expected: &str) {
+        let issues = FakeIssues::with_status(Status::Sent);
+        let mut issue = issues.issue().unwrap();
+        issue.revisions[0].content.subject = subject.to_owned();
+
+        assert_eq!(expected, issue.slug());
+    }
+
+    #[rstest(status, slug, found,
+        case::sent(Status::Sent, "first-1", true),
+        case::approved(Status::Approved, "first-1", false),
+        case::other_subject(Status::Sent, "second-1", false),
+        case::other_id(Status::Sent, "first-2", false),
+    )]
+    fn only_sent_issues_should_be_published(status: Status, slug: &str, found: bool) {
+        let issues = FakeIssues::with_status(status);
+
+        let published = async_std::task::block_on(published(&issues, slug));
+
+        assert_eq!(found, published.is_ok());
+    }
+
+    #[rstest(status, page, expected,
+        case::first(Status::Sent, 1, Ok((1, 1))),
+        case::empty(Status::Draft, 1, Ok((0, 1))),
+        case::past_the_end(Status::Sent, 2, Err(IssueError::NotFound)),
+        case::zero(Status::Sent, 0, Err(IssueError::NotFound)),
+    )]
+    fn archive_should_be_paginated(
+        status: Status,
+        page: u64,
+        expected: Result<(usize, u64), IssueError>,
+    ) {
+        let issues = FakeIssues::with_status(status);
+
+        let archive = async_std::task::block_on(archive(&issues, page, 10));
+
+        assert_eq!(
+            expected,
+            archive.map(|archive| (archive.issues.len(), archive.pages))
+        );
+    }
+
+    #[async_std::test]
+    async fn unknown_issue_should_not_be_found() {
+        let issues = FakeIssues::with_status(Status::Draft);
+
+        assert_eq!(Err(IssueError::NotFound), get(&issues, "2").await);
+    }
+}
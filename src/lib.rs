@@ -1,10 +1,34 @@
 pub(crate) mod adapters;
+mod analytics;
+pub mod authentication;
 pub mod configuration;
+mod crypto;
+pub(crate) mod delivery;
+mod digest;
+mod domain;
+pub(crate) mod email;
+mod email_events;
+mod email_templates;
+mod feeds;
 pub(crate) mod handlers;
+mod i18n;
+mod idempotency;
+pub(crate) mod issues;
+pub mod import;
+pub(crate) mod lists;
 mod middleware;
+mod mime;
+mod outbox;
+pub(crate) mod preferences;
+pub mod reload;
 pub(crate) mod repository;
+pub(crate) mod scheduler;
 mod startup;
 pub(crate) mod state;
 pub mod telemetry;
+mod tenants;
+pub(crate) mod tracking;
+mod web;
+mod webhooks;
 
 pub use startup::run;
@@ -0,0 +1,434 @@
+//! Mailing lists: every newsletter we run is a list with its own sender, and a
+//! subscriber can be on many of them.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+
+use crate::{
+    domain::parse_email,
+    repository::{self, UsersRepository},
+    state::StateTrait,
+};
+
+/// Where subscribers go if they don't choose, and where the ones saved before
+/// lists existed are.
+pub(crate) const DEFAULT_LIST: &str = "newsletter";
+const MAX_SLUG_LENGTH: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct List {
+    pub(crate) slug: String,
+    /// Shown to the subscribers
+    pub(crate) name: String,
+    /// The `From` of its emails, e.g. `Rust weekly <rust@example.com>`: if missing
+    /// the configured sender
+    #[serde(default)]
+    pub(crate) sender: Option<String>,
+    /// Whether opens and clicks of its issues are tracked: off unless asked for
+    #[serde(default)]
+    pub(crate) tracking: bool,
+}
+
+impl List {
+    fn default_list() -> Self {
+        Self {
+            slug: DEFAULT_LIST.to_owned(),
+            name: "Newsletter".to_owned(),
+            sender: None,
+            tracking: false,
+        }
+    }
+}
+
+/// The status of a subscriber on a list.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SubscriptionStatus {
+    Subscribed,
+    Unsubscribed,
+}
+
+impl SubscriptionStatus {
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            SubscriptionStatus::Subscribed => "subscribed",
+            SubscriptionStatus::Unsubscribed => "unsubscribed",
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub(crate) enum ListError {
+    #[error("List not found")]
+    NotFound,
+    #[error("Unknown list '{0}'")]
+    Unknown(String),
+    #[error("A list with this slug already exists")]
+    Exists,
+    #[error("{0}")]
+    Invalid(String),
+    #[error("There can be {0} lists at most")]
+    TooMany(u64),
+    #[error("List '{0}' is full")]
+    Full(String),
+    #[error("Repository failure: {0}")]
+    Repository(String),
+}
+
+impl From<repository::Error> for ListError {
+    fn from(e: repository::Error) -> Self {
+        ListError::Repository(format!("{:?}", e))
+    }
+}
+
+#[async_trait::async_trait]
+pub(crate) trait ListsRepository: Send + Sync {
+    /// `false` if the slug is taken.
+    async fn create(&self, list: &List) -> repository::Result<bool>;
+
+    /// Create `list` if its slug is free, leave the existing one alone otherwise.
+    async fn ensure(&self, list: &List) -> repository::Result<()>;
+
+    async fn get(&self, slug: &str) -> repository::Result<Option<List>>;
+
+    /// Sorted by slug.
+    async fn all(&self) -> repository::Result<Vec<List>>;
+
+    /// Change name, sender and tracking: `false` if there is no such list.
+    async fn update(&self, list: &List) -> repository::Result<bool>;
+
+    /// When the last weekly digest of `slug` was sent, if ever.
+    async fn digest_sent_at(&self, slug: &str) -> repository::Result<Option<DateTime<Utc>>>;
+
+    /// Record that the digest of `slug` was sent `at` if the previous one was sent
+    /// `previous`: `false` if someone else recorded it in the meantime.
+    async fn digest_sent(
+        &self,
+        slug: &str,
+        previous: Option<DateTime<Utc>>,
+        at: DateTime<Utc>,
+    ) -> repository::Result<bool>;
+}
+
+/// Lowercase ASCII letters, digits and dashes: it goes in forms and urls.
+fn valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LENGTH
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// `address` or `Display name <address>`, on a single line: it becomes a header.
+fn valid_sender(sender: &str) -> bool {
+    if sender.contains(&['\r', '\n'][..]) {
+        return false;
+    }
+    let address = match (sender.rfind('<'), sender.strip_suffix('>')) {
+        (Some(start), Some(rest)) => &rest[start + 1..],
+        (None, None) => sender,
+        _ => return false,
+    };
+    parse_email(address).is_ok()
+}
+
+fn validated(list: List) -> Result<List, ListError> {
+    if !valid_slug(&list.slug) {
+        return Err(ListError::Invalid(format!(
+            "The slug should be at most {} lowercase letters, digits or dashes",
+            MAX_SLUG_LENGTH
+        )));
+    }
+    if list.name.trim().is_empty() {
+        return Err(ListError::Invalid("The name is mandatory".to_owned()));
+    }
+    let sender = list
+        .sender
+        .map(|sender| sender.trim().to_owned())
+        .filter(|sender| !sender.is_empty());
+    if let Some(sender) = &sender {
+        if !valid_sender(sender) {
+            return Err(ListError::Invalid(format!(
+                "'{}' is not a valid sender",
+                sender
+            )));
+        }
+    }
+    Ok(List {
+        name: list.name.trim().to_owned(),
+        sender,
+        ..list
+    })
+}
+
+/// Create `list` if there are less than `max` lists.
+pub(crate) async fn create<R: ListsRepository>(
+    repository: &R,
+    list: List,
+    max: Option<u64>,
+) -> Result<List, ListError> {
+    let list = validated(list)?;
+    if let Some(max) = max {
+        if repository.all().await?.len() as u64 >= max {
+            return Err(ListError::TooMany(max));
+        }
+    }
+    if !repository.create(&list).await? {
+        return Err(ListError::Exists);
+    }
+    Ok(list)
+}
+
+pub(crate) async fn update<R: ListsRepository>(
+    repository: &R,
+    list: List,
+) -> Result<List, ListError> {
+    let list = validated(list)?;
+    if !repository.update(&list).await? {
+        return Err(ListError::NotFound);
+    }
+    Ok(list)
+}
+
+pub(crate) async fn get<R: ListsRepository>(repository: &R, slug: &str) -> Result<List, ListError> {
+    repository
+        .get(slug)
+        .await?
+        .ok_or_else(|| ListError::Unknown(slug.to_owned()))
+}
+
+/// The lists with the given slugs, in the same order: the default list if there
+/// are none.
+pub(crate) async fn resolve<R: ListsRepository>(
+    repository: &R,
+    slugs: &[String],
+) -> Result<Vec<List>, ListError> {
+    if slugs.is_empty() {
+        return Ok(vec![get(repository, DEFAULT_LIST).await?]);
+    }
+    let mut lists = Vec::with_capacity(slugs.len());
+    for slug in slugs {
+        if lists.iter().any(|list: &List| &list.slug == slug) {
+            continue;
+        }
+        lists.push(get(repository, slug).await?);
+    }
+    Ok(lists)
+}
+
+/// Fail on the first of `slugs` that `email` is not on yet and already has `max`
+/// subscribers.
+pub(crate) async fn check_room<R: UsersRepository>(
+    users: &R,
+    email: &str,
+    slugs: &[String],
+    max: Option<u64>,
+) -> Result<(), ListError> {
+    let max = match max {
+        Some(max) => max,
+        None => return Ok(()),
+    };
+    let subscribed = users
+        .subscribed_lists(&[email.to_owned()])
+        .await?
+        .remove(email)
+        .unwrap_or_default();
+    for slug in slugs.iter().filter(|&slug| !subscribed.contains(slug)) {
+        if users.count(slug).await? >= max {
+            return Err(ListError::Full(slug.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Create the default list, if missing, and move there the subscribers saved
+/// before lists existed.
+pub(crate) async fn ensure_default<S: StateTrait>(state: &S) -> repository::Result<()> {
+    state.lists().ensure(&List::default_list()).await?;
+    let adopted = state
+        .users_repository()
+        .adopt_unlisted(DEFAULT_LIST)
+        .await?;
+    if adopted > 0 {
+        info!("{} subscribers moved to the default list", adopted);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use rstest::rstest;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeLists {
+        lists: Mutex<Vec<List>>,
+        digests: Mutex<HashMap<String, DateTime<Utc>>>,
+    }
+
+    impl FakeLists {
+        fn with(slugs: &[&str]) -> Self {
+            let lists = slugs
+                .iter()
+                .map(|&slug| List {
+                    slug: slug.to_owned(),
+                    name: slug.to_uppercase(),
+                    sender: None,
+                    tracking: false,
+                })
+                .collect();
+            Self {
+                lists: Mutex::new(lists),
+                ..Default::default()
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ListsRepository for FakeLists {
+        async fn create(&self, list: &List) -> repository::Result<bool> {
+            let mut lists = self.lists.lock().unwrap();
+            if lists.iter().any(|l| l.slug == list.slug) {
+                return Ok(false);
+            }
+            lists.push(list.clone());
+            Ok(true)
+        }
+
+        async fn ensure(&self, list: &List) -> repository::Result<()> {
+            self.create(list).await.map(|_| ())
+        }
+
+        async fn get(&self, slug: &str) -> repository::Result<Option<List>> {
+            let lists = self.lists.lock().unwrap();
+            Ok(lists.iter().find(|l| l.slug == slug).cloned())
+        }
+
+        async fn all(&self) -> repository::Result<Vec<List>> {
+            Ok(self.lists.lock().unwrap().clone())
+        }
+
+        async fn update(&self, list: &List) -> repository::Result<bool> {
+            let mut lists = self.lists.lock().unwrap();
+            match lists.iter_mut().find(|l| l.slug == list.slug) {
+                Some(existing) => {
+                    *existing = list.clone();
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+
+        async fn digest_sent_at(&self, slug: &str) -> repository::Result<Option<DateTime<Utc>>> {
+            Ok(self.digests.lock().unwrap().get(slug).copied())
+        }
+
+        async fn digest_sent(
+            &self,
+            slug: &str,
+            previous: Option<DateTime<Utc>>,
+            at: DateTime<Utc>,
+        ) -> repository::Result<bool> {
+            if self.get(slug).await?.is_none() {
+                return Ok(false);
+            }
+            let mut digests = self.digests.lock().unwrap();
+            if digests.get(slug).copied() != previous {
+                return Ok(false);
+            }
+            digests.insert(slug.to_owned(), at);
+            Ok(true)
+        }
+    }
+
+    fn list(slug: &str, name: &str, sender: Option<&str>) -> List {
+        List {
+            slug: slug.to_owned(),
+            name: name.to_owned(),
+            sender: sender.map(str::to_owned),
+            tracking: false,
+        }
+    }
+
+    #[rstest(slug, valid,
+        case::simple("rust-weekly", true),
+        case::digits("news2021", true),
+        case::empty("", false),
+        case::uppercase("Rust", false),
+        case::spaces("rust weekly", false),
+        case::slash("rust/weekly", false),
+        case::too_long(&"a".repeat(65), false),
+    )]
+    fn slugs(slug: &str, valid: bool) {
+        assert_eq!(valid, valid_slug(slug));
+    }
+
+    #[rstest(sender, valid,
+        case::address("rust@example.com", true),
+        case::display_name("Rust weekly <rust@example.com>", true),
+        case::not_an_address("Rust weekly", false),
+        case::unclosed("Rust weekly <rust@example.com", false),
+        case::header_injection("rust@example.com\r\nBcc: all@example.com", false),
+    )]
+    fn senders(sender: &str, valid: bool) {
+        assert_eq!(valid, valid_sender(sender));
+    }
+
+    #[async_std::test]
+    async fn should_create_lists_with_a_free_slug() {
+        let lists = FakeLists::with(&["rust"]);
+
+        let created = create(&lists, list("go", " Go news ", Some(" ")), None).await;
+
+        assert_eq!(Ok(list("go", "Go news", None)), created);
+        assert_eq!(
+            Err(ListError::Exists),
+            create(&lists, list("rust", "Rust", None), None).await
+        );
+    }
+
+    #[async_std::test]
+    async fn should_not_create_more_lists_than_allowed() {
+        let lists = FakeLists::with(&[DEFAULT_LIST, "rust"]);
+
+        assert_eq!(
+            Err(ListError::TooMany(2)),
+            create(&lists, list("go", "Go", None), Some(2)).await
+        );
+        assert!(create(&lists, list("go", "Go", None), Some(3))
+            .await
+            .is_ok());
+    }
+
+    #[async_std::test]
+    async fn should_not_update_unknown_lists() {
+        let lists = FakeLists::with(&["rust"]);
+
+        assert_eq!(
+            Err(ListError::NotFound),
+            update(&lists, list("go", "Go", None)).await
+        );
+    }
+
+    #[rstest(slugs, expected,
+        case::none(&[], Ok(vec!["newsletter"])),
+        case::some(&["rust", "go"], Ok(vec!["rust", "go"])),
+        case::repeated(&["rust", "rust"], Ok(vec!["rust"])),
+        case::unknown(&["rust", "java"], Err(ListError::Unknown("java".to_owned()))),
+    )]
+    fn should_resolve_lists(slugs: &[&str], expected: Result<Vec<&str>, ListError>) {
+        let lists = FakeLists::with(&[DEFAULT_LIST, "rust", "go"]);
+        let slugs: Vec<_> = slugs.iter().map(|&s| s.to_owned()).collect();
+
+        let resolved = async_std::task::block_on(resolve(&lists, &slugs));
+
+        assert_eq!(
+            expected.map(|slugs| slugs.into_iter().map(str::to_owned).collect::<Vec<_>>()),
+            resolved.map(|lists| lists.into_iter().map(|l| l.slug).collect())
+        );
+    }
+}
@@ -1,15 +1,134 @@
-use z2p::telemetry::{get_subscriber, init_subscriber};
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+use z2p::{
+    configuration::ConfigurationLoader,
+    telemetry::{get_subscriber, init_subscriber, set_filter},
+};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "app", about = "Zero to production newsletter")]
+struct Opt {
+    /// Configuration directory [env: APP_CONFIG_DIR]
+    #[structopt(long, global = true, parse(from_os_str))]
+    config_dir: Option<PathBuf>,
+    /// Configuration profile, e.g. local, staging or production [env: APP_ENVIRORMENT]
+    #[structopt(long, global = true)]
+    profile: Option<String>,
+    /// The tenant `import` and `create-admin` work on: the default publication if
+    /// missing
+    #[structopt(long, global = true)]
+    tenant: Option<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Start the web server (default)
+    Serve,
+    /// Import subscribers from a csv file with `name` and `email` columns and
+    /// print a per row report on stdout
+    Import {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// The slug of the list they subscribe to
+        #[structopt(long, default_value = "newsletter")]
+        list: String,
+        #[structopt(long, default_value = "500")]
+        batch_size: usize,
+    },
+    /// Load and validate the configuration, report all the problems and exit
+    CheckConfig,
+    /// Create an admin user for the web pages or reset its password: the password
+    /// is read from stdin [env: APP_ADMIN_PASSWORD]
+    CreateAdmin { username: String },
+}
 
 #[cfg(not(tarpaulin_include))]
 #[async_std::main]
 async fn main() -> tide::Result<()> {
-    init_subscriber(get_subscriber("z2p", "info"));
-
-    let configs = z2p::configuration::get_configuration().expect("Failed to read configuration");
-    let host = format!("{}:{}", configs.application.host, configs.application.port);
-    z2p::run(configs.database)
-        .await
-        .listen(host)
-        .await
-        .map_err(|e| e.into())
+    let opt = Opt::from_args();
+    let mut loader = ConfigurationLoader::from_env();
+    if let Some(dir) = opt.config_dir {
+        loader = loader.dir(dir);
+    }
+    if let Some(profile) = opt.profile {
+        loader = loader.profile(profile);
+    }
+    let configs = match loader.load() {
+        Ok(configs) => configs,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let (subscriber, _guard) = get_subscriber("z2p", &configs.telemetry);
+    init_subscriber(subscriber);
+    if let Some(directives) = &configs.runtime.log_filter {
+        set_filter(directives)?;
+    }
+    let publication = match &opt.tenant {
+        Some(slug) => match configs.tenant(slug) {
+            Some(publication) => publication,
+            None => {
+                eprintln!("Unknown tenant '{}'", slug);
+                std::process::exit(1);
+            }
+        },
+        None => configs.clone(),
+    };
+    match opt.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            let host = format!("{}:{}", configs.application.host, configs.application.port);
+            let watch_interval = configs.runtime.watch_interval;
+            let app = z2p::run(configs).await;
+            let live = app.state().live().clone();
+            #[cfg(unix)]
+            z2p::reload::reload_on_sighup(live.clone(), loader.clone())?;
+            if let Some(interval) = watch_interval {
+                z2p::reload::watch_files(live, loader, interval);
+            }
+            app.listen(host).await.map_err(|e| e.into())
+        }
+        Command::Import {
+            path,
+            list,
+            batch_size,
+        } => {
+            let report =
+                z2p::import::import_csv(&publication.database, path, &list, batch_size).await?;
+            report.write_csv(std::io::stdout())?;
+            eprintln!(
+                "Imported {} subscribers: {} duplicated and {} invalid rows",
+                report.accepted(),
+                report.duplicated(),
+                report.invalid()
+            );
+            Ok(())
+        }
+        Command::CreateAdmin { username } => {
+            let password = match std::env::var("APP_ADMIN_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => {
+                    eprintln!("Password for '{}':", username);
+                    let mut line = String::new();
+                    std::io::stdin().read_line(&mut line)?;
+                    line.trim_end_matches(&['\r', '\n'][..]).to_owned()
+                }
+            };
+            z2p::authentication::create_admin(&publication.database, &username, &password.into())
+                .await?;
+            eprintln!("Admin '{}' saved", username);
+            Ok(())
+        }
+        Command::CheckConfig => {
+            println!(
+                "Configuration '{}' in '{}' is valid",
+                loader.profile_name(),
+                loader.config_dir().display()
+            );
+            Ok(())
+        }
+    }
 }
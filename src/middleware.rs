@@ -1,3 +1,5 @@
+use crate::{configuration::Secret, crypto::constant_time_eq, state::StateTrait};
+
 #[derive(Debug, Default, Clone)]
 pub struct TraceUuidMiddleware;
 
@@ -20,3 +22,120 @@ impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for TraceUuid
         Ok(next.run(req).await)
     }
 }
+
+/// Abort the requests that take longer than the `request_timeout` runtime setting.
+#[derive(Debug, Default, Clone)]
+pub struct RequestTimeoutMiddleware;
+
+impl RequestTimeoutMiddleware {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: StateTrait + 'static> tide::Middleware<State> for RequestTimeoutMiddleware {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let timeout = match req.state().live_settings().current().request_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(next.run(req).await),
+        };
+        match async_std::future::timeout(timeout, next.run(req)).await {
+            Ok(response) => Ok(response),
+            Err(_) => {
+                tracing::warn!("Request timed out after {:?}", timeout);
+                Ok(tide::StatusCode::ServiceUnavailable.into())
+            }
+        }
+    }
+}
+
+/// Guard the admin endpoints with a bearer token: without a configured token they
+/// are not reachable at all.
+#[derive(Debug, Clone)]
+pub struct AdminTokenMiddleware {
+    token: Option<Secret<String>>,
+}
+
+impl AdminTokenMiddleware {
+    pub fn new(token: Option<Secret<String>>) -> Self {
+        Self { token }
+    }
+
+    fn is_authorized<State>(&self, req: &tide::Request<State>) -> bool {
+        let token = match &self.token {
+            Some(token) => token.expose(),
+            None => return false,
+        };
+        req.header("Authorization")
+            .and_then(|values| values.as_str().strip_prefix("Bearer "))
+            .map(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for AdminTokenMiddleware {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        if self.token.is_none() {
+            return Ok(tide::StatusCode::NotFound.into());
+        }
+        if !self.is_authorized(&req) {
+            tracing::warn!("Unauthorized admin request");
+            return Ok(tide::StatusCode::Unauthorized.into());
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tide::http::{Method, Request, Url};
+
+    use super::*;
+
+    fn admin_app(token: Option<&str>) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.at("/")
+            .with(AdminTokenMiddleware::new(token.map(|t| t.to_owned().into())))
+            .get(|_| async { Ok("secret") });
+        app
+    }
+
+    fn request(authorization: Option<&str>) -> Request {
+        let mut req = Request::new(Method::Get, Url::parse("https://example.com/").unwrap());
+        if let Some(authorization) = authorization {
+            req.insert_header("Authorization", authorization);
+        }
+        req
+    }
+
+    #[async_std::test]
+    async fn admin_should_accept_the_right_token() {
+        let app = admin_app(Some("token"));
+
+        let res: tide::http::Response = app.respond(request(Some("Bearer token"))).await.unwrap();
+
+        assert_eq!(tide::StatusCode::Ok, res.status());
+    }
+
+    #[async_std::test]
+    async fn admin_should_reject_wrong_or_missing_token() {
+        let app = admin_app(Some("token"));
+
+        let wrong: tide::http::Response = app.respond(request(Some("Bearer other"))).await.unwrap();
+        let missing: tide::http::Response = app.respond(request(None)).await.unwrap();
+
+        assert_eq!(tide::StatusCode::Unauthorized, wrong.status());
+        assert_eq!(tide::StatusCode::Unauthorized, missing.status());
+    }
+
+    #[async_std::test]
+    async fn admin_should_be_disabled_without_token() {
+        let app = admin_app(None);
+
+        let res: tide::http::Response = app.respond(request(Some("Bearer token"))).await.unwrap();
+
+        assert_eq!(tide::StatusCode::NotFound, res.status());
+    }
+}
@@ -0,0 +1,191 @@
+//! Internet messages (RFC 5322) with the text and the html versions of an email as
+//! the parts of a `multipart/alternative` body. Bodies are quoted-printable and
+//! non ASCII subjects are encoded words, so the message is plain 7 bit ASCII.
+use chrono::{DateTime, Utc};
+
+use crate::email::Email;
+
+/// Quoted-printable lines can't be longer than this, soft line break included
+const MAX_LINE_LEN: usize = 76;
+/// Input bytes of an encoded word: 60 base64 chars plus `=?utf-8?B?` and `?=`
+const ENCODED_WORD_BYTES: usize = 45;
+
+pub(crate) struct Message<'a> {
+    pub(crate) from: &'a str,
+    pub(crate) email: &'a Email,
+    pub(crate) date: DateTime<Utc>,
+    pub(crate) message_id: &'a str,
+}
+
+impl Message<'_> {
+    /// The message with CRLF line endings.
+    pub(crate) fn format(&self) -> String {
+        // Quoted-printable never writes `=_`: the parts can't contain the boundary
+        let boundary = format!("=_z2p_{}", uuid::Uuid::new_v4().to_simple());
+        let mut message = String::new();
+        for (name, value) in &[
+            ("From", self.from.to_owned()),
+            ("To", self.email.to.clone()),
+            ("Subject", encode_header(&self.email.subject)),
+            ("Date", self.date.to_rfc2822()),
+            ("Message-ID", format!("<{}>", self.message_id)),
+            ("MIME-Version", "1.0".to_owned()),
+            (
+                "Content-Type",
+                format!("multipart/alternative; boundary=\"{}\"", boundary),
+            ),
+        ] {
+            message.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // The last part is the preferred one
+        for (content_type, body) in &[
+            ("text/plain", &self.email.text),
+            ("text/html", &self.email.html),
+        ] {
+            message.push_str(&format!("\r\n--{}\r\n", boundary));
+            message.push_str(&format!(
+                "Content-Type: {}; charset=utf-8\r\n",
+                content_type
+            ));
+            message.push_str("Content-Transfer-Encoding: quoted-printable\r\n\r\n");
+            message.push_str(&quoted_printable(body));
+        }
+        message.push_str(&format!("\r\n--{}--\r\n", boundary));
+        message
+    }
+}
+
+/// RFC 2045 quoted-printable, with CRLF line endings.
+fn quoted_printable(body: &str) -> String {
+    let body = body.replace("\r\n", "\n");
+    body.split('\n')
+        .map(quoted_printable_line)
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+fn quoted_printable_line(line: &str) -> String {
+    let bytes = line.as_bytes();
+    let mut encoded = String::new();
+    let mut len = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        let last = i == bytes.len() - 1;
+        let token = match byte {
+            // Trailing white space would be stripped in transit
+            b' ' | b'\t' if !last => (*byte as char).to_string(),
+            b'!'..=b'<' | b'>'..=b'~' => (*byte as char).to_string(),
+            _ => format!("={:02X}", byte),
+        };
+        // Room for the `=` of a soft line break, unless it's the last token
+        let room = if last { MAX_LINE_LEN } else { MAX_LINE_LEN - 1 };
+        if len + token.len() > room {
+            encoded.push_str("=\r\n");
+            len = 0;
+        }
+        len += token.len();
+        encoded.push_str(&token);
+    }
+    encoded
+}
+
+/// A header value as is if it's printable ASCII, otherwise as RFC 2047 encoded
+/// words on folded lines.
+fn encode_header(value: &str) -> String {
+    let printable = value.bytes().all(|b| (b' '..=b'~').contains(&b));
+    if printable && !value.contains("=?") {
+        return value.to_owned();
+    }
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in value.chars() {
+        // Don't split a multibyte char between two words
+        if chunk.len() + c.len_utf8() > ENCODED_WORD_BYTES {
+            words.push(encoded_word(&chunk));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    if !chunk.is_empty() {
+        words.push(encoded_word(&chunk));
+    }
+    words.join("\r\n ")
+}
+
+fn encoded_word(text: &str) -> String {
+    format!("=?utf-8?B?{}?=", base64::encode(text))
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest(body, expected,
+        case::plain("Hello, World!", "Hello, World!"),
+        case::equal("a=b", "a=3Db"),
+        case::utf8("Perché", "Perch=C3=A9"),
+        case::trailing_space("end \nnext", "end=20\r\nnext"),
+        case::crlf("one\r\ntwo", "one\r\ntwo"),
+    )]
+    fn should_encode_quoted_printable(body: &str, expected: &str) {
+        assert_eq!(expected, quoted_printable(body));
+    }
+
+    #[test]
+    fn long_lines_should_be_soft_broken() {
+        let encoded = quoted_printable(&"é".repeat(100));
+
+        assert!(encoded.lines().all(|line| line.len() <= MAX_LINE_LEN));
+        assert_eq!(600, encoded.replace("=\r\n", "").len());
+    }
+
+    #[rstest(subject, expected,
+        case::ascii("Welcome!", "Welcome!"),
+        case::utf8("Perché?", "=?utf-8?B?UGVyY2jDqT8=?="),
+        case::new_line("Hi\r\nBcc: x@example.com", "=?utf-8?B?SGkNCkJjYzogeEBleGFtcGxlLmNvbQ==?="),
+    )]
+    fn should_encode_headers(subject: &str, expected: &str) {
+        assert_eq!(expected, encode_header(subject));
+    }
+
+    #[test]
+    fn long_headers_should_be_folded() {
+        let encoded = encode_header(&"è".repeat(40));
+
+        assert_eq!(2, encoded.split("\r\n ").count());
+        assert!(encoded.split("\r\n ").all(|word| word.len() <= 75));
+    }
+
+    #[test]
+    fn message_should_have_both_versions() {
+        let email = Email {
+            to: "antonio@gmail.com".to_owned(),
+            subject: "Benvenuto!".to_owned(),
+            html: "<p>Ciao</p>".to_owned(),
+            text: "Ciao".to_owned(),
+            from: None,
+            issue_id: None,
+        };
+        let message = Message {
+            from: "newsletter@example.com",
+            email: &email,
+            date: Utc::now(),
+            message_id: "id@example.com",
+        }
+        .format();
+        let boundary = message
+            .split("boundary=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+
+        assert!(message.starts_with("From: newsletter@example.com\r\nTo: antonio@gmail.com\r\n"));
+        assert!(message.contains("Message-ID: <id@example.com>\r\n"));
+        assert!(message.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(message.contains("\r\n\r\n<p>Ciao</p>\r\n"));
+        assert_eq!(3, message.matches(&format!("--{}", boundary)).count());
+        assert!(message.ends_with(&format!("\r\n--{}--\r\n", boundary)));
+        assert!(message.is_ascii());
+    }
+}
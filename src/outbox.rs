@@ -0,0 +1,361 @@
+//! Side effects of repository writes, e.g. the welcome email and the webhooks of
+//! a new subscriber, of changed preferences and of suppressed addresses. The event is staged in the outbox before the write it follows
+//! and confirmed after it, then a relay hands it to the in-process subscribers and
+//! marks it done: side effects happen at least once, even if the process dies
+//! right after the write.
+//!
+//! The request that saved an event relays it straight away; the background relay
+//! picks up the ones it left behind.
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    configuration::OutboxSettings,
+    delivery::{self, Backoff, DeliveryQueue},
+    email::Email,
+    email_events::{SuppressionReason, SuppressionsRepository},
+    email_templates::Welcome,
+    i18n::Locale,
+    lists,
+    preferences::{self, Change, PreferencesRepository},
+    repository::{self, UsersRepository},
+    state::StateTrait,
+    webhooks::{self, EventType},
+};
+
+/// What happened.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum DomainEvent {
+    Subscribed {
+        email: String,
+        name: String,
+        locale: Locale,
+        lists: Vec<String>,
+    },
+    /// From the preference center: `lists` are the ones they are on now
+    PreferencesChanged {
+        email: String,
+        changes: Vec<Change>,
+        lists: Vec<String>,
+    },
+    /// Hard bounced or complained: nothing is sent to them anymore
+    Suppressed {
+        email: String,
+        reason: SuppressionReason,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Event {
+    pub(crate) id: String,
+    pub(crate) event: DomainEvent,
+}
+
+impl Event {
+    pub(crate) fn new(event: DomainEvent) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            event,
+        }
+    }
+}
+
+/// An event taken by a relay.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Claimed {
+    pub(crate) event: Event,
+    /// Including the current one
+    pub(crate) attempts: u32,
+    /// The subscribers that are done with it
+    pub(crate) handled: Vec<String>,
+    /// The write it follows was never confirmed: the process may have died
+    /// before it
+    pub(crate) unconfirmed: bool,
+}
+
+#[async_trait::async_trait]
+pub(crate) trait Outbox: Send + Sync {
+    /// Take the oldest due event: no one else can claim it till `lease` expires.
+    async fn claim(&self, lease: Duration) -> repository::Result<Option<Claimed>>;
+
+    /// Take the event `id` if it's waiting, even if not due yet.
+    async fn claim_id(&self, id: &str, lease: Duration) -> repository::Result<Option<Claimed>>;
+
+    /// `subscriber` is done with `claimed`: it won't get it again on a retry.
+    async fn handled(&self, claimed: &Claimed, subscriber: &str) -> repository::Result<()>;
+
+    async fn complete(&self, claimed: &Claimed) -> repository::Result<()>;
+
+    /// Release the event to be claimed again not before `at`.
+    async fn retry(
+        &self,
+        claimed: &Claimed,
+        at: DateTime<Utc>,
+        error: &str,
+    ) -> repository::Result<()>;
+
+    async fn dead_letter(&self, claimed: &Claimed, error: &str) -> repository::Result<()>;
+
+    /// Drop an unconfirmed event whose write didn't happen.
+    async fn discard(&self, claimed: &Claimed) -> repository::Result<()>;
+}
+
+/// Reacts to the events in the outbox. It may get an event more than once.
+#[async_trait::async_trait]
+pub(crate) trait Subscriber<S: StateTrait>: Send + Sync {
+    /// Kept in the event once handled: don't rename it.
+    fn name(&self) -> &'static str;
+
+    async fn handle(&self, state: &S, event: &DomainEvent) -> Result<(), String>;
+}
+
+/// Queue the welcome email of new subscribers.
+struct WelcomeEmail;
+
+#[async_trait::async_trait]
+impl<S: StateTrait> Subscriber<S> for WelcomeEmail {
+    fn name(&self) -> &'static str {
+        "welcome_email"
+    }
+
+    async fn handle(&self, state: &S, event: &DomainEvent) -> Result<(), String> {
+        let (email, name, locale, lists) = match event {
+            DomainEvent::Subscribed {
+                email,
+                name,
+                locale,
+                lists,
+            } => (email, name, locale, lists),
+            _ => return Ok(()),
+        };
+        let sender = match lists.first() {
+            Some(slug) => {
+                lists::get(state.lists(), slug)
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .sender
+            }
+            None => None,
+        };
+        let welcome = Welcome {
+            name: name.clone(),
+            preferences_link: preferences::link(state, email, Utc::now()),
+        };
+        let welcome = match state.email_templates().render(email, *locale, &welcome) {
+            Ok(welcome) => welcome,
+            Err(e) => {
+                // Retrying won't fix the templates
+                error!("Cannot render the welcome email: {}", e);
+                return Ok(());
+            }
+        };
+        let welcome = Email {
+            from: sender,
+            ..welcome
+        };
+        state
+            .delivery_queue()
+            .enqueue(welcome)
+            .await
+            .map_err(|e| format!("cannot enqueue the welcome email: {:?}", e))
+    }
+}
+
+/// Post the subscriber events to the webhook endpoints.
+struct Webhooks;
+
+#[async_trait::async_trait]
+impl<S: StateTrait> Subscriber<S> for Webhooks {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    async fn handle(&self, state: &S, event: &DomainEvent) -> Result<(), String> {
+        let (kind, data) = match event {
+            DomainEvent::Subscribed {
+                email,
+                name,
+                locale,
+                lists,
+            } => (
+                EventType::SubscriberCreated,
+                serde_json::json!({
+                    "email": email,
+                    "name": name,
+                    "locale": locale.code(),
+                    "lists": lists,
+                }),
+            ),
+            // Leaving every list is the end of the subscription
+            DomainEvent::PreferencesChanged { email, lists, .. } if lists.is_empty() => (
+                EventType::SubscriberDeleted,
+                serde_json::json!({ "email": email, "reason": "left_all_lists" }),
+            ),
+            DomainEvent::PreferencesChanged { email, changes, .. } => (
+                EventType::SubscriberUpdated,
+                serde_json::json!({ "email": email, "changes": changes }),
+            ),
+            DomainEvent::Suppressed { email, reason } => (
+                EventType::SubscriberDeleted,
+                serde_json::json!({ "email": email, "reason": reason.code() }),
+            ),
+        };
+        webhooks::enqueue(state, kind, data)
+            .await
+            .map_err(|e| format!("cannot enqueue the webhook event: {:?}", e))
+    }
+}
+
+fn subscribers<S: StateTrait>() -> Vec<Box<dyn Subscriber<S>>> {
+    vec![Box::new(WelcomeEmail), Box::new(Webhooks)]
+}
+
+/// Relay the event `id` now, if nobody else took it: for the request that saved it.
+pub(crate) async fn relay_now<S: StateTrait>(state: &S, id: &str) {
+    let settings = state.outbox_settings();
+    let relay = Relay::new(state.clone(), settings.clone());
+    match state.outbox().claim_id(id, settings.lease).await {
+        Ok(Some(claimed)) => relay.dispatch(&claimed).await,
+        Ok(None) => {}
+        Err(e) => error!("Cannot claim the outbox event: {:?}", e),
+    }
+}
+
+pub(crate) struct Relay<S: StateTrait> {
+    state: S,
+    settings: OutboxSettings,
+    backoff: Backoff,
+    subscribers: Vec<Box<dyn Subscriber<S>>>,
+}
+
+impl<S: StateTrait> Relay<S> {
+    pub(crate) fn new(state: S, settings: OutboxSettings) -> Self {
+        Self {
+            backoff: Backoff::new(settings.backoff_base, settings.backoff_max),
+            subscribers: subscribers(),
+            state,
+            settings,
+        }
+    }
+
+    pub(crate) async fn run(self) {
+        info!("Outbox relay started");
+        loop {
+            if !self.step().await {
+                async_std::task::sleep(self.settings.poll_interval).await;
+            }
+        }
+    }
+
+    /// Relay the next due event, if any: return `false` if the outbox is empty or
+    /// not reachable.
+    pub(crate) async fn step(&self) -> bool {
+        let claimed = match self.state.outbox().claim(self.settings.lease).await {
+            Ok(Some(claimed)) => claimed,
+            Ok(None) => return false,
+            Err(e) => {
+                error!("Cannot claim an outbox event: {:?}", e);
+                return false;
+            }
+        };
+        self.dispatch(&claimed).await;
+        true
+    }
+
+    #[tracing::instrument(
+        name = "Relaying an outbox event",
+        skip(self, claimed),
+        fields(
+            event = %claimed.event.id,
+            attempt = claimed.attempts,
+        )
+    )]
+    async fn dispatch(&self, claimed: &Claimed) {
+        let outbox = self.state.outbox();
+        if claimed.unconfirmed {
+            match self.happened(&claimed.event.event).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("Discarding an event whose write didn't happen");
+                    if let Err(e) = outbox.discard(claimed).await {
+                        error!("Cannot discard the outbox event: {:?}", e);
+                    }
+                    return;
+                }
+                Err(e) => return self.failed(claimed, &e).await,
+            }
+        }
+        for subscriber in &self.subscribers {
+            let name = subscriber.name();
+            if claimed.handled.iter().any(|handled| handled == name) {
+                continue;
+            }
+            if let Err(e) = subscriber.handle(&self.state, &claimed.event.event).await {
+                return self.failed(claimed, &format!("{}: {}", name, e)).await;
+            }
+            if let Err(e) = outbox.handled(claimed, name).await {
+                // It may get the event again: subscribers are ready for that
+                warn!(
+                    subscriber = name,
+                    "Cannot record the handled event: {:?}", e
+                );
+            }
+        }
+        if let Err(e) = outbox.complete(claimed).await {
+            error!("Cannot complete the outbox event: {:?}", e);
+        }
+    }
+
+    /// Whether the write the event follows is there: the subscriber is on every
+    /// list they asked for, has the preferences they chose or is suppressed.
+    async fn happened(&self, event: &DomainEvent) -> Result<bool, String> {
+        let state = &self.state;
+        match event {
+            DomainEvent::Subscribed { email, lists, .. } => {
+                let known = state
+                    .users_repository()
+                    .subscribed_lists(std::slice::from_ref(email))
+                    .await
+                    .map_err(|e| format!("cannot look for the subscriber: {:?}", e))?;
+                Ok(match known.get(email) {
+                    Some(subscribed) => lists.iter().all(|list| subscribed.contains(list)),
+                    None => false,
+                })
+            }
+            DomainEvent::PreferencesChanged { email, changes, .. } => {
+                let current = state
+                    .preferences_repository()
+                    .preferences(email)
+                    .await
+                    .map_err(|e| format!("cannot read the preferences: {:?}", e))?;
+                Ok(current.is_some_and(|current| preferences::applied(&current, changes)))
+            }
+            DomainEvent::Suppressed { email, .. } => state
+                .suppressions()
+                .suppressed(std::slice::from_ref(email))
+                .await
+                .map(|suppressed| suppressed.contains(email))
+                .map_err(|e| format!("cannot read the suppressions: {:?}", e)),
+        }
+    }
+
+    async fn failed(&self, claimed: &Claimed, error: &str) {
+        let outbox = self.state.outbox();
+        let stored = if claimed.attempts < self.settings.max_attempts {
+            let delay = self.backoff.delay(claimed.attempts, rand::random());
+            let at = delivery::after(Utc::now(), delay);
+            warn!(%at, "Outbox event failed, will retry: {}", error);
+            outbox.retry(claimed, at, error).await
+        } else {
+            error!("Outbox event failed, giving up: {}", error);
+            outbox.dead_letter(claimed, error).await
+        };
+        if let Err(e) = stored {
+            error!("Cannot update the outbox event: {:?}", e);
+        }
+    }
+}
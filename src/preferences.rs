@@ -0,0 +1,499 @@
+//! The preference center: subscribers reach it through a signed link in our
+//! emails and change their name, lists, frequency or take a break. Every change
+//! is kept in an audit trail.
+use std::time::Duration;
+
+use chrono::{DateTime, SubsecRound, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    configuration::{PreferencesSettings, Secret},
+    crypto,
+    domain::{parse_name, ValidationError},
+    i18n::Locale,
+    lists::{self, ListError},
+    outbox::{self, DomainEvent, Event},
+    repository,
+    state::StateTrait,
+};
+
+/// Keep link signatures apart from anything else signed with the same key
+const DOMAIN: &[u8] = b"preferences:";
+pub(crate) const MAX_PAUSE_WEEKS: u32 = 52;
+
+/// How issues reach a subscriber.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Frequency {
+    /// As soon as they are published
+    #[default]
+    Immediate,
+    /// The issues of the week in a single email
+    Weekly,
+}
+
+impl Frequency {
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            Frequency::Immediate => "immediate",
+            Frequency::Weekly => "weekly",
+        }
+    }
+
+    pub(crate) fn from_code(code: &str) -> Option<Self> {
+        [Frequency::Immediate, Frequency::Weekly]
+            .iter()
+            .copied()
+            .find(|frequency| frequency.code() == code)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Preferences {
+    pub(crate) name: String,
+    pub(crate) locale: Locale,
+    /// The slugs of the lists they are subscribed to, sorted
+    pub(crate) lists: Vec<String>,
+    pub(crate) frequency: Frequency,
+    /// Nothing is sent till then
+    pub(crate) paused_until: Option<DateTime<Utc>>,
+}
+
+impl Preferences {
+    pub(crate) fn is_paused(&self, now: DateTime<Utc>) -> bool {
+        self.paused_until.is_some_and(|until| until > now)
+    }
+}
+
+/// What the subscriber asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Update {
+    pub(crate) name: String,
+    pub(crate) lists: Vec<String>,
+    pub(crate) frequency: Frequency,
+    /// `None` keeps the current pause, `Some(0)` resumes delivery
+    pub(crate) pause_weeks: Option<u32>,
+}
+
+/// An entry of the audit trail.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Change {
+    pub(crate) field: String,
+    pub(crate) from: String,
+    pub(crate) to: String,
+}
+
+/// The preferences of the subscribers, by address.
+#[async_trait::async_trait]
+pub(crate) trait PreferencesRepository {
+    async fn preferences(&self, email: &str) -> repository::Result<Option<Preferences>>;
+
+    /// Save `wanted`, subscribed to its lists and unsubscribed from the others,
+    /// and append `changes` to the audit trail of `email`. `event` goes to the
+    /// outbox with them.
+    async fn change(
+        &self,
+        email: &str,
+        wanted: &Preferences,
+        changes: &[Change],
+        at: DateTime<Utc>,
+        event: &outbox::Event,
+    ) -> repository::Result<()>;
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub(crate) enum LinkError {
+    #[error("Invalid link")]
+    Invalid,
+    #[error("Expired link")]
+    Expired,
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub(crate) enum PreferencesError {
+    #[error("Subscriber not found")]
+    NotFound,
+    #[error(transparent)]
+    InvalidName(#[from] ValidationError),
+    #[error("Unknown list '{0}'")]
+    UnknownList(String),
+    #[error("List '{0}' is full")]
+    FullList(String),
+    #[error("Delivery can be paused for {max} weeks at most")]
+    PauseTooLong { max: u32 },
+    #[error("Repository failure: {0}")]
+    Repository(String),
+}
+
+impl From<repository::Error> for PreferencesError {
+    fn from(e: repository::Error) -> Self {
+        PreferencesError::Repository(format!("{:?}", e))
+    }
+}
+
+impl From<ListError> for PreferencesError {
+    fn from(e: ListError) -> Self {
+        match e {
+            ListError::Unknown(slug) => PreferencesError::UnknownList(slug),
+            ListError::Full(slug) => PreferencesError::FullList(slug),
+            e => PreferencesError::Repository(e.to_string()),
+        }
+    }
+}
+
+impl PreferencesError {
+    /// Whether the subscriber asked for something we can't do.
+    pub(crate) fn is_invalid(&self) -> bool {
+        matches!(
+            self,
+            PreferencesError::InvalidName(_)
+                | PreferencesError::UnknownList(_)
+                | PreferencesError::FullList(_)
+                | PreferencesError::PauseTooLong { .. }
+        )
+    }
+
+    /// The error as told to the subscriber.
+    pub(crate) fn localized(&self, locale: Locale) -> String {
+        match self {
+            PreferencesError::InvalidName(e) => e.localized(locale),
+            PreferencesError::UnknownList(slug) => {
+                locale.t("subscribe.unknown_list", &[("list", slug)])
+            }
+            PreferencesError::FullList(slug) => locale.t("subscribe.full_list", &[("list", slug)]),
+            PreferencesError::PauseTooLong { max } => {
+                locale.t("preferences.pause_too_long", &[("max", &max.to_string())])
+            }
+            PreferencesError::NotFound => locale.t("preferences.invalid_link", &[]),
+            PreferencesError::Repository(_) => locale.t("preferences.failed", &[]),
+        }
+    }
+}
+
+/// Signs and checks the links to the preference center.
+#[derive(Clone)]
+pub(crate) struct Links {
+    key: Secret<String>,
+    base_url: Option<String>,
+    ttl: Duration,
+}
+
+impl Links {
+    pub(crate) fn new(key: Secret<String>, settings: &PreferencesSettings) -> Self {
+        Self {
+            key,
+            base_url: settings
+                .base_url
+                .as_ref()
+                .map(|url| url.trim_end_matches('/').to_owned()),
+            ttl: settings.link_ttl,
+        }
+    }
+
+    /// The address and the expiration time, signed: safe to put in a url.
+    pub(crate) fn token(&self, email: &str, now: DateTime<Utc>) -> String {
+        let expires_at = now.timestamp() + self.ttl.as_secs() as i64;
+        let payload = format!("{}\n{}", expires_at, email);
+        let signature = crypto::sign(&self.key, &[DOMAIN, payload.as_bytes()]);
+        format!(
+            "{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// The link for `email`: `None` without a configured base url.
+    pub(crate) fn url(&self, email: &str, now: DateTime<Utc>) -> Option<String> {
+        let base_url = self.base_url.as_ref()?;
+        Some(format!(
+            "{}/preferences?token={}",
+            base_url,
+            self.token(email, now)
+        ))
+    }
+
+    /// The address the token was made for.
+    pub(crate) fn verify(&self, token: &str, now: DateTime<Utc>) -> Result<String, LinkError> {
+        let decode = |part: &str| base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok();
+        let mut parts = token.splitn(2, '.');
+        let payload = parts.next().and_then(decode).ok_or(LinkError::Invalid)?;
+        let signature = parts.next().and_then(decode).ok_or(LinkError::Invalid)?;
+        if !crypto::verify(&self.key, &[DOMAIN, &payload], &signature) {
+            return Err(LinkError::Invalid);
+        }
+        let payload = String::from_utf8(payload).map_err(|_| LinkError::Invalid)?;
+        let mut fields = payload.splitn(2, '\n');
+        let expires_at = fields
+            .next()
+            .and_then(|at| at.parse().ok())
+            .and_then(|at| Utc.timestamp_opt(at, 0).single())
+            .ok_or(LinkError::Invalid)?;
+        let email = fields.next().ok_or(LinkError::Invalid)?;
+        if expires_at <= now {
+            return Err(LinkError::Expired);
+        }
+        Ok(email.to_owned())
+    }
+}
+
+fn paused_until(at: Option<DateTime<Utc>>) -> String {
+    at.map(|at| at.to_rfc3339()).unwrap_or_default()
+}
+
+/// The audited fields of `preferences`, as the audit trail shows them.
+fn fields(preferences: &Preferences) -> [(&'static str, String); 4] {
+    [
+        ("name", preferences.name.clone()),
+        ("lists", preferences.lists.join(",")),
+        ("frequency", preferences.frequency.code().to_owned()),
+        ("paused_until", paused_until(preferences.paused_until)),
+    ]
+}
+
+/// What changes from `current` to `wanted`, field by field.
+fn changes(current: &Preferences, wanted: &Preferences) -> Vec<Change> {
+    fields(current)
+        .iter()
+        .zip(fields(wanted).iter())
+        .filter(|((_, from), (_, to))| from != to)
+        .map(|((field, from), (_, to))| Change {
+            field: (*field).to_owned(),
+            from: from.clone(),
+            to: to.clone(),
+        })
+        .collect()
+}
+
+/// Whether `current` has every change of `changes`.
+pub(crate) fn applied(current: &Preferences, changes: &[Change]) -> bool {
+    let fields = fields(current);
+    changes.iter().all(|change| {
+        fields
+            .iter()
+            .any(|(field, value)| *field == change.field && *value == change.to)
+    })
+}
+
+/// `current` once `update` is applied: the lists must exist.
+async fn apply<S: StateTrait>(
+    state: &S,
+    current: &Preferences,
+    update: Update,
+    now: DateTime<Utc>,
+) -> Result<Preferences, PreferencesError> {
+    let name = parse_name(&update.name)?;
+    let mut slugs = Vec::with_capacity(update.lists.len());
+    for slug in update.lists {
+        slugs.push(lists::get(state.lists(), &slug).await?.slug);
+    }
+    slugs.sort();
+    slugs.dedup();
+    let paused_until = match update.pause_weeks {
+        None => current.paused_until,
+        Some(0) => None,
+        // To the second: the audit trail and the database keep the same time
+        Some(weeks) if weeks <= MAX_PAUSE_WEEKS => {
+            Some((now + chrono::Duration::weeks(weeks.into())).trunc_subsecs(0))
+        }
+        Some(_) => {
+            return Err(PreferencesError::PauseTooLong {
+                max: MAX_PAUSE_WEEKS,
+            })
+        }
+    };
+    Ok(Preferences {
+        name,
+        locale: current.locale,
+        lists: slugs,
+        frequency: update.frequency,
+        paused_until,
+    })
+}
+
+/// The link to the preference center of `email`, if enabled.
+pub(crate) fn link<S: StateTrait>(state: &S, email: &str, now: DateTime<Utc>) -> Option<String> {
+    state.preference_links()?.url(email, now)
+}
+
+pub(crate) async fn get<R: PreferencesRepository>(
+    repository: &R,
+    email: &str,
+) -> Result<Preferences, PreferencesError> {
+    repository
+        .preferences(email)
+        .await?
+        .ok_or(PreferencesError::NotFound)
+}
+
+/// Save what changed and record it in the audit trail.
+#[tracing::instrument(name = "Updating preferences", skip(state, email, update))]
+pub(crate) async fn update<S: StateTrait>(
+    state: &S,
+    email: &str,
+    update: Update,
+    now: DateTime<Utc>,
+) -> Result<Preferences, PreferencesError> {
+    let users = state.preferences_repository();
+    let current = get(users, email).await?;
+    let wanted = apply(state, &current, update, now).await?;
+    let max = state.limits().max_subscribers;
+    lists::check_room(state.users_repository(), email, &wanted.lists, max).await?;
+    let changes = changes(&current, &wanted);
+    if changes.is_empty() {
+        return Ok(wanted);
+    }
+    let event = Event::new(DomainEvent::PreferencesChanged {
+        email: email.to_owned(),
+        changes: changes.clone(),
+        lists: wanted.lists.clone(),
+    });
+    users.change(email, &wanted, &changes, now, &event).await?;
+    // The webhooks
+    outbox::relay_now(state, &event.id).await;
+    Ok(wanted)
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn links(ttl: u64) -> Links {
+        let settings = PreferencesSettings {
+            base_url: Some("https://news.example.com/".to_owned()),
+            link_ttl: Duration::from_secs(ttl),
+        };
+        Links::new("a-secret-key".to_owned().into(), &settings)
+    }
+
+    fn preferences() -> Preferences {
+        Preferences {
+            name: "Antonio".to_owned(),
+            locale: Locale::It,
+            lists: vec!["newsletter".to_owned()],
+            frequency: Frequency::Immediate,
+            paused_until: None,
+        }
+    }
+
+    #[test]
+    fn links_should_carry_the_address() {
+        let now = Utc::now();
+        let links = links(3600);
+
+        let url = links.url("antonio@gmail.com", now).unwrap();
+
+        let token = url
+            .strip_prefix("https://news.example.com/preferences?token=")
+            .unwrap();
+        assert_eq!(Ok("antonio@gmail.com".to_owned()), links.verify(token, now));
+    }
+
+    fn other_address(token: &str) -> String {
+        let signature = &token[token.find('.').unwrap()..];
+        let payload = "9999999999\nmichele@gmail.com";
+        format!(
+            "{}{}",
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD),
+            signature
+        )
+    }
+
+    fn no_signature(token: &str) -> String {
+        token.split('.').next().unwrap().to_owned()
+    }
+
+    fn garbage(_token: &str) -> String {
+        "not a token".to_owned()
+    }
+
+    #[rstest(tamper,
+        case::other_address(other_address),
+        case::no_signature(no_signature),
+        case::garbage(garbage),
+    )]
+    fn forged_links_should_be_invalid(tamper: fn(&str) -> String) {
+        let now = Utc::now();
+        let links = links(3600);
+
+        let token = tamper(&links.token("antonio@gmail.com", now));
+
+        assert_eq!(Err(LinkError::Invalid), links.verify(&token, now));
+    }
+
+    #[test]
+    fn links_should_expire() {
+        let now = Utc::now();
+        let links = links(3600);
+
+        let token = links.token("antonio@gmail.com", now);
+
+        assert_eq!(
+            Err(LinkError::Expired),
+            links.verify(&token, now + chrono::Duration::hours(1))
+        );
+    }
+
+    #[test]
+    fn other_keys_should_not_verify_links() {
+        let now = Utc::now();
+        let token = links(3600).token("antonio@gmail.com", now);
+        let settings = PreferencesSettings::default();
+        let other = Links::new("another-secret-key".to_owned().into(), &settings);
+
+        assert_eq!(Err(LinkError::Invalid), other.verify(&token, now));
+    }
+
+    #[test]
+    fn only_changed_fields_should_be_audited() {
+        let current = preferences();
+        let wanted = Preferences {
+            lists: vec!["newsletter".to_owned(), "rust".to_owned()],
+            frequency: Frequency::Weekly,
+            ..preferences()
+        };
+
+        assert_eq!(
+            vec![
+                Change {
+                    field: "lists".to_owned(),
+                    from: "newsletter".to_owned(),
+                    to: "newsletter,rust".to_owned(),
+                },
+                Change {
+                    field: "frequency".to_owned(),
+                    from: "immediate".to_owned(),
+                    to: "weekly".to_owned(),
+                },
+            ],
+            changes(&current, &wanted)
+        );
+        assert!(changes(&current, &preferences()).is_empty());
+    }
+
+    #[test]
+    fn changes_should_be_applied_once_saved() {
+        let current = preferences();
+        let wanted = Preferences {
+            name: "Antonio De Domenico".to_owned(),
+            lists: Vec::new(),
+            ..preferences()
+        };
+        let changes = changes(&current, &wanted);
+
+        assert!(applied(&wanted, &changes));
+        assert!(!applied(&current, &changes));
+        assert!(applied(&current, &[]));
+    }
+
+    #[rstest(code, expected,
+        case::immediate("immediate", Some(Frequency::Immediate)),
+        case::weekly("weekly", Some(Frequency::Weekly)),
+        case::unknown("daily", None),
+    )]
+    fn frequency_from_code(code: &str, expected: Option<Frequency>) {
+        assert_eq!(expected, Frequency::from_code(code));
+    }
+}
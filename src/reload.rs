@@ -0,0 +1,136 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::{
+    configuration::{ConfigurationError, ConfigurationLoader, RuntimeSettings},
+    telemetry::{self, FilterError},
+};
+
+#[derive(Error, Debug)]
+pub enum ReloadError {
+    #[error(transparent)]
+    Configuration(#[from] ConfigurationError),
+    #[error(transparent)]
+    Filter(#[from] FilterError),
+}
+
+/// The runtime settings shared by all requests: every change swaps the whole
+/// snapshot, so a request never sees a half applied configuration.
+#[derive(Clone, Default, Debug)]
+pub struct LiveSettings(Arc<RwLock<Arc<RuntimeSettings>>>);
+
+impl LiveSettings {
+    pub fn new(settings: RuntimeSettings) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(settings))))
+    }
+
+    pub fn current(&self) -> Arc<RuntimeSettings> {
+        self.0.read().expect("Poisoned live settings").clone()
+    }
+
+    pub fn is_enabled(&self, feature: &str) -> bool {
+        self.current().is_enabled(feature)
+    }
+
+    /// Apply the log filter, if changed, and then swap the settings.
+    pub fn update(&self, settings: RuntimeSettings) -> Result<(), FilterError> {
+        let current = self.current();
+        if settings.log_filter != current.log_filter {
+            if let Some(directives) = &settings.log_filter {
+                telemetry::set_filter(directives)?;
+            }
+        }
+        *self.0.write().expect("Poisoned live settings") = Arc::new(settings);
+        Ok(())
+    }
+}
+
+/// Reload the configuration and apply just the runtime section: all the other
+/// settings need a restart.
+#[tracing::instrument(name = "Reloading configuration", skip(live, loader))]
+pub fn reload(live: &LiveSettings, loader: &ConfigurationLoader) -> Result<(), ReloadError> {
+    let settings = loader.load()?;
+    live.update(settings.runtime)?;
+    info!("Runtime settings reloaded");
+    Ok(())
+}
+
+fn reload_or_log(live: &LiveSettings, loader: &ConfigurationLoader) {
+    if let Err(e) = reload(live, loader) {
+        error!("Cannot reload configuration: {}", e);
+    }
+}
+
+/// Check configuration files every `interval` and reload them when something changed.
+pub fn watch_files(
+    live: LiveSettings,
+    loader: ConfigurationLoader,
+    interval: Duration,
+) -> async_std::task::JoinHandle<()> {
+    async_std::task::spawn(async move {
+        let mut last = loader.last_modified();
+        loop {
+            async_std::task::sleep(interval).await;
+            let modified = loader.last_modified();
+            if modified != last {
+                last = modified;
+                reload_or_log(&live, &loader);
+            }
+        }
+    })
+}
+
+/// Reload configuration on `SIGHUP`.
+#[cfg(unix)]
+pub fn reload_on_sighup(live: LiveSettings, loader: ConfigurationLoader) -> std::io::Result<()> {
+    let signals = signal_hook::iterator::Signals::new([signal_hook::SIGHUP])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            reload_or_log(&live, &loader);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_swap_settings() {
+        let live = LiveSettings::default();
+        let before = live.current();
+        let mut features = std::collections::HashMap::new();
+        features.insert("subscriptions".to_owned(), false);
+
+        live.update(RuntimeSettings {
+            features,
+            request_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(!live.is_enabled("subscriptions"));
+        assert_eq!(Some(Duration::from_secs(1)), live.current().request_timeout);
+        assert_eq!(None, before.request_timeout);
+    }
+
+    #[test]
+    fn clones_should_share_settings() {
+        let live = LiveSettings::default();
+        let other = live.clone();
+
+        live.update(RuntimeSettings {
+            request_timeout: Some(Duration::from_secs(3)),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(Some(Duration::from_secs(3)), other.current().request_timeout);
+    }
+}
@@ -1,21 +1,88 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
 use thiserror::Error;
+
+use crate::{i18n::Locale, outbox, preferences::Frequency, telemetry::pii};
 pub(crate) type Result<T> = std::result::Result<T, Error>;
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum Error {
     #[error("Cannot insert entry '{entry_desc}'")]
     InsertDb {
         entry_desc: String,
         #[source]
-        source: Box<dyn std::error::Error>,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("Cannot execute query '{query_desc}'")]
+    QueryDb {
+        query_desc: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("Cannot update entry '{entry_desc}'")]
+    UpdateDb {
+        entry_desc: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
     },
 }
-#[derive(Debug)]
 pub(crate) struct User {
+    /// `None` till it's saved
+    pub(crate) id: Option<String>,
     pub(crate) name: String,
     pub(crate) email: String,
+    /// The language of the pages and emails we send
+    pub(crate) locale: Locale,
 }
 
+/// Safe to log: personal data is redacted.
+impl std::fmt::Debug for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("User")
+            .field("name", &pii::name(&self.name))
+            .field("email", &pii::email(&self.email))
+            .field("locale", &self.locale)
+            .finish()
+    }
+}
+
+/// Subscribers and the lists they are on.
 #[async_trait::async_trait]
 pub(crate) trait UsersRepository {
-    async fn create(&self, user: User) -> Result<()>;
+    /// Subscribe `user` to `lists`: an existing subscriber keeps name and locale
+    /// and is subscribed again to the lists they left. `event` goes to the outbox
+    /// with the subscriber: it's relayed only if they are saved.
+    async fn subscribe(
+        &self,
+        user: User,
+        lists: &[String],
+        event: Option<&outbox::Event>,
+    ) -> Result<()>;
+
+    /// Insert all the new subscribers `users`, on `list`, in a single round trip.
+    async fn create_many(&self, users: Vec<User>, list: &str) -> Result<()>;
+
+    /// The lists each known address among `emails` is subscribed to.
+    async fn subscribed_lists(&self, emails: &[String])
+        -> Result<HashMap<String, HashSet<String>>>;
+
+    /// The address of the subscriber `id`: `None` if there's no such subscriber.
+    async fn email_of(&self, id: &str) -> Result<Option<String>>;
+
+    /// Subscribers on `list`.
+    async fn count(&self, list: &str) -> Result<u64>;
+
+    /// Subscribers on `list` who get issues with `frequency` and are not paused
+    /// at `now`.
+    async fn recipients(
+        &self,
+        list: &str,
+        frequency: Frequency,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<User>>;
+
+    /// Subscribe to `list` the subscribers saved before lists existed: return how
+    /// many they were.
+    async fn adopt_unlisted(&self, list: &str) -> Result<u64>;
 }
@@ -0,0 +1,247 @@
+//! Issues scheduled for a future time. Every instance can run the ticker, but only
+//! the holder of the lease document publishes: the others wait for it to expire.
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::{
+    configuration::{MissedSchedules, SchedulerSettings},
+    delivery::after,
+    digest,
+    issues::{self, IssueError},
+    repository,
+    state::StateTrait,
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ScheduleStatus {
+    Pending,
+    Published,
+    /// Found too late, with the `skip` policy
+    Missed,
+    Failed,
+    Cancelled,
+}
+
+impl ScheduleStatus {
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            ScheduleStatus::Pending => "pending",
+            ScheduleStatus::Published => "published",
+            ScheduleStatus::Missed => "missed",
+            ScheduleStatus::Failed => "failed",
+            ScheduleStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub(crate) fn from_code(code: &str) -> Option<Self> {
+        [
+            ScheduleStatus::Pending,
+            ScheduleStatus::Published,
+            ScheduleStatus::Missed,
+            ScheduleStatus::Failed,
+            ScheduleStatus::Cancelled,
+        ]
+        .iter()
+        .copied()
+        .find(|status| status.code() == code)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Schedule {
+    pub(crate) id: String,
+    pub(crate) issue_id: String,
+    pub(crate) publish_at: DateTime<Utc>,
+    pub(crate) status: ScheduleStatus,
+    pub(crate) error: Option<String>,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) updated_at: DateTime<Utc>,
+}
+
+/// Only pending schedules can be changed: updates return `false` otherwise.
+#[async_trait::async_trait]
+pub(crate) trait Schedules: Send + Sync {
+    async fn create(
+        &self,
+        issue_id: &str,
+        publish_at: DateTime<Utc>,
+    ) -> repository::Result<Schedule>;
+
+    /// Pending schedules, the next one first.
+    async fn pending(&self) -> repository::Result<Vec<Schedule>>;
+
+    /// Pending schedules with `publish_at` not after `now`, the oldest first.
+    async fn due(&self, now: DateTime<Utc>) -> repository::Result<Vec<Schedule>>;
+
+    async fn reschedule(&self, id: &str, publish_at: DateTime<Utc>) -> repository::Result<bool>;
+
+    async fn cancel(&self, id: &str) -> repository::Result<bool>;
+
+    async fn close(
+        &self,
+        id: &str,
+        status: ScheduleStatus,
+        error: Option<&str>,
+    ) -> repository::Result<bool>;
+
+    /// Take the lease, or renew it, for `holder` till `until`: `false` if someone
+    /// else holds it.
+    async fn acquire_lease(
+        &self,
+        holder: &str,
+        now: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> repository::Result<bool>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Publish,
+    Skip,
+}
+
+fn action(publish_at: DateTime<Utc>, now: DateTime<Utc>, settings: &SchedulerSettings) -> Action {
+    let missed = after(publish_at, settings.grace) < now;
+    match (missed, settings.missed) {
+        (true, MissedSchedules::Skip) => Action::Skip,
+        _ => Action::Publish,
+    }
+}
+
+pub(crate) struct Scheduler<S: StateTrait> {
+    state: S,
+    settings: SchedulerSettings,
+    /// This instance
+    holder: String,
+}
+
+impl<S: StateTrait> Scheduler<S> {
+    pub(crate) fn new(state: S, settings: SchedulerSettings) -> Self {
+        Self {
+            state,
+            settings,
+            holder: uuid::Uuid::new_v4().to_simple().to_string(),
+        }
+    }
+
+    pub(crate) async fn run(self) {
+        info!(holder = %self.holder, "Scheduler started");
+        loop {
+            self.tick().await;
+            async_std::task::sleep(self.settings.tick).await;
+        }
+    }
+
+    /// Handle the due schedules, then the due digests, if this instance holds the
+    /// lease: return how many schedules were due.
+    pub(crate) async fn tick(&self) -> usize {
+        let schedules = self.state.schedules();
+        let now = Utc::now();
+        match schedules
+            .acquire_lease(&self.holder, now, after(now, self.settings.lease))
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => return 0,
+            Err(e) => {
+                error!("Cannot acquire the scheduler lease: {:?}", e);
+                return 0;
+            }
+        }
+        let due = match schedules.due(now).await {
+            Ok(due) => due,
+            Err(e) => {
+                error!("Cannot read due schedules: {:?}", e);
+                return 0;
+            }
+        };
+        for schedule in &due {
+            self.fire(schedule, now).await;
+        }
+        digest::send_due(&self.state, now).await;
+        due.len()
+    }
+
+    #[tracing::instrument(
+        name = "Firing a schedule",
+        skip(self, schedule, now),
+        fields(
+            schedule = %schedule.id,
+            issue = %schedule.issue_id,
+        )
+    )]
+    async fn fire(&self, schedule: &Schedule, now: DateTime<Utc>) {
+        let (status, error) = match action(schedule.publish_at, now, &self.settings) {
+            Action::Skip => {
+                warn!(publish_at = %schedule.publish_at, "Schedule missed");
+                (ScheduleStatus::Missed, None)
+            }
+            Action::Publish => match issues::publish(&self.state, &schedule.issue_id).await {
+                Ok(queued) => {
+                    info!("Scheduled issue queued for {} subscribers", queued);
+                    (ScheduleStatus::Published, None)
+                }
+                Err(IssueError::Repository(e)) => {
+                    warn!("Cannot publish the scheduled issue, will retry: {}", e);
+                    return;
+                }
+                Err(e) => {
+                    error!("Cannot publish the scheduled issue: {}", e);
+                    (ScheduleStatus::Failed, Some(e.to_string()))
+                }
+            },
+        };
+        if let Err(e) = self
+            .state
+            .schedules()
+            .close(&schedule.id, status, error.as_deref())
+            .await
+        {
+            error!("Cannot close the schedule: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest(late, missed, expected,
+        case::on_time(0, MissedSchedules::Skip, Action::Publish),
+        case::within_grace(60, MissedSchedules::Skip, Action::Publish),
+        case::missed_skip(3600, MissedSchedules::Skip, Action::Skip),
+        case::missed_publish(3600, MissedSchedules::Publish, Action::Publish),
+    )]
+    fn missed_schedules_should_follow_the_policy(
+        late: i64,
+        missed: MissedSchedules,
+        expected: Action,
+    ) {
+        let now = Utc::now();
+        let settings = SchedulerSettings {
+            missed,
+            grace: Duration::from_secs(600),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            expected,
+            action(now - chrono::Duration::seconds(late), now, &settings)
+        );
+    }
+
+    #[rstest(code, expected,
+        case::pending("pending", Some(ScheduleStatus::Pending)),
+        case::cancelled("cancelled", Some(ScheduleStatus::Cancelled)),
+        case::unknown("done", None),
+    )]
+    fn status_from_code(code: &str, expected: Option<ScheduleStatus>) {
+        assert_eq!(expected, ScheduleStatus::from_code(code));
+    }
+}
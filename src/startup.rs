@@ -1,11 +1,288 @@
-use crate::{configuration::DatabaseSettings, handlers::*, state::State};
+use tide::sessions::SessionMiddleware;
+use tracing::warn;
 
-pub async fn run(cfg: DatabaseSettings) -> tide::Server<State> {
-    let state = State::new(&cfg).await.unwrap();
+use crate::{
+    adapters::{http_email_client::HttpEmailClient, smtp_email_client::SmtpEmailClient},
+    configuration::{EmailTransport, IdempotencySettings, Secret, Settings},
+    delivery::Worker,
+    email::SharedEmailClient,
+    email_events::SignatureMiddleware,
+    email_templates::EmailTemplates,
+    handlers::*,
+    idempotency::IdempotencyMiddleware,
+    lists,
+    middleware::{AdminTokenMiddleware, RequestTimeoutMiddleware, TraceUuidMiddleware},
+    outbox::Relay,
+    preferences::Links,
+    reload::LiveSettings,
+    scheduler::Scheduler,
+    state::{State, StateTrait},
+    tenants::TenantMiddleware,
+    tracking::Tracker,
+    web::{
+        CsrfMiddleware, FlashMiddleware, LoginRequired, SecureCookiesMiddleware, SESSION_COOKIE,
+    },
+    webhooks,
+};
+
+pub async fn run(settings: Settings) -> tide::Server<State> {
+    // Shared: a reload reaches every publication
+    let live = LiveSettings::new(settings.runtime.clone());
+    let mut tenants = TenantMiddleware::default();
+    for (slug, tenant) in &settings.tenants {
+        let tenant_settings = settings.for_tenant(slug, tenant);
+        let tenant_server = server(
+            tenant_settings,
+            Some(slug),
+            live.clone(),
+            Default::default(),
+        )
+        .await;
+        tenants.add(slug, &tenant.hosts, tenant_server);
+    }
+    server(settings, None, live, tenants).await
+}
+
+/// Everything a publication needs: its state, background tasks and routes. The
+/// requests `tenants` take are not served here.
+async fn server(
+    settings: Settings,
+    tenant: Option<&str>,
+    live: LiveSettings,
+    tenants: TenantMiddleware<State>,
+) -> tide::Server<State> {
+    let email_templates = EmailTemplates::load(settings.email_templates.dir.as_deref())
+        .expect("Cannot load email templates");
+    let email_client = email_client(&settings);
+    let state = State::new(&settings.database)
+        .await
+        .unwrap()
+        .with_live_settings(live)
+        .with_email_templates(email_templates)
+        .with_archive(settings.archive.clone())
+        .with_limits(settings.limits.clone())
+        .with_webhooks(settings.webhooks.clone())
+        .with_outbox(settings.outbox.clone());
+    let state = match &settings.application.secret_key {
+        Some(secret_key) => {
+            state.with_preference_links(Links::new(secret_key.clone(), &settings.preferences))
+        }
+        None => state,
+    };
+    let state = match (
+        &settings.application.secret_key,
+        &settings.tracking.base_url,
+    ) {
+        (Some(secret_key), Some(base_url)) => {
+            state.with_tracker(Tracker::new(secret_key.clone(), base_url))
+        }
+        _ => state,
+    };
+    let state = match email_client {
+        Some(email_client) => state.with_email_client(email_client),
+        None => {
+            warn!("No email client configured: queued emails won't be delivered");
+            state
+        }
+    };
+    if settings.delivery.worker {
+        if let Some(email_client) = state.email_client() {
+            let worker = Worker::new(
+                state.delivery_queue().clone(),
+                email_client.clone(),
+                state.suppressions().clone(),
+                settings.delivery,
+            );
+            async_std::task::spawn(worker.run());
+        }
+    }
+    if settings.webhooks.worker && !settings.webhooks.endpoints.is_empty() {
+        let worker = webhooks::Worker::new(state.webhook_queue().clone(), settings.webhooks);
+        async_std::task::spawn(worker.run());
+    }
+    if settings.outbox.relay {
+        let relay = Relay::new(state.clone(), settings.outbox);
+        async_std::task::spawn(relay.run());
+    }
+    if settings.scheduler.enabled {
+        let scheduler = Scheduler::new(state.clone(), settings.scheduler);
+        async_std::task::spawn(scheduler.run());
+    }
+    if let Err(e) = state
+        .idempotency_store()
+        .ensure_ttl_index(settings.idempotency.ttl)
+        .await
+    {
+        warn!("Cannot create the idempotency TTL index: {}", e);
+    }
+    if let Err(e) = state.users_repository().ensure_indexes().await {
+        warn!("Cannot make the subscriber emails unique: {}", e);
+    }
+    if let Err(e) = state.delivery_queue().ensure_indexes().await {
+        warn!("Cannot create the delivery queue indexes: {}", e);
+    }
+    if let Err(e) = state.analytics().ensure_indexes().await {
+        warn!("Cannot create the analytics indexes: {}", e);
+    }
+    if let Err(e) = lists::ensure_default(&state).await {
+        warn!("Cannot create the default list: {:?}", e);
+    }
+    let admin_token = settings.application.admin_token;
+    let webhook_secret = settings.email.webhook_secret.clone();
+    let admin_ui = match &settings.application.secret_key {
+        Some(secret_key) => {
+            if let Err(e) = state.session_store().ensure_ttl_index().await {
+                warn!("Cannot create the sessions TTL index: {}", e);
+            }
+            Some(admin_ui(
+                state.clone(),
+                secret_key.clone(),
+                tenant,
+                settings.application.secure_cookies,
+                settings.idempotency.clone(),
+            ))
+        }
+        None => {
+            warn!("No secret key configured: admin web pages and preference center are disabled");
+            None
+        }
+    };
+    let preference_center = state.preference_links().is_some();
+    let tracking = state.tracker().is_some();
     let mut app = tide::with_state(state);
+    if !tenants.is_empty() {
+        app.with(tenants);
+    }
     app.with(tide_tracing::TraceMiddleware::new());
-    app.with(crate::middleware::TraceUuidMiddleware::new());
+    app.with(TraceUuidMiddleware::new());
+    app.with(RequestTimeoutMiddleware::new());
     app.at("/health_check").get(health_check);
-    app.at("/subscriptions").post(subscriptions);
+    app.at("/subscriptions")
+        .with(IdempotencyMiddleware::new(settings.idempotency.clone()))
+        .post(subscriptions);
+    if preference_center {
+        app.at("/preferences")
+            .get(preferences_page)
+            .post(update_preferences);
+    }
+    if tracking {
+        app.at("/t/c/:token").get(track_click);
+        app.at("/t/o/:token").get(track_open);
+    }
+    app.at("/archive").get(archive_page);
+    app.at("/archive/:slug").get(archive_issue);
+    app.at("/feed.rss").get(rss_feed);
+    app.at("/feed.atom").get(atom_feed);
+    app.at("/webhooks/email-events")
+        .with(SignatureMiddleware::new(webhook_secret))
+        .post(email_events);
+    app.at("/admin/log_filter")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .get(get_log_filter)
+        .put(set_log_filter);
+    app.at("/admin/delivery_queue/dead_letters")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .get(dead_letters);
+    app.at("/admin/webhooks/:endpoint/deliveries")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .get(webhook_deliveries);
+    app.at("/admin/newsletter_issues")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .get(list_issues)
+        .post(create_issue);
+    app.at("/admin/newsletter_issues/:id")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .get(get_issue)
+        .put(update_issue)
+        .delete(delete_issue);
+    app.at("/admin/newsletter_issues/:id/status")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .put(set_issue_status);
+    app.at("/admin/newsletter_issues/:id/publish")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .with(IdempotencyMiddleware::new(settings.idempotency))
+        .post(publish_issue);
+    app.at("/admin/newsletter_issues/:id/analytics")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .get(issue_analytics);
+    app.at("/admin/lists")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .get(list_lists)
+        .post(create_list);
+    app.at("/admin/lists/:slug")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .put(update_list);
+    app.at("/admin/schedules")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .get(list_schedules)
+        .post(create_schedule);
+    app.at("/admin/schedules/:id")
+        .with(AdminTokenMiddleware::new(admin_token.clone()))
+        .put(reschedule)
+        .delete(cancel_schedule);
+    app.at("/admin/email_templates/:name/preview")
+        .with(AdminTokenMiddleware::new(admin_token))
+        .get(preview_email_template);
+    if let Some(admin_ui) = admin_ui {
+        app.at("/admin").nest(admin_ui);
+    }
     app
 }
+
+/// The transport selected by `email.transport`.
+fn email_client(settings: &Settings) -> Option<SharedEmailClient> {
+    match settings.email.transport {
+        EmailTransport::Http => settings.email_client.as_ref().map(|email_client| {
+            let client = HttpEmailClient::new(email_client).expect("Invalid email client");
+            std::sync::Arc::new(client) as SharedEmailClient
+        }),
+        EmailTransport::Smtp => settings
+            .email
+            .smtp
+            .as_ref()
+            .map(|smtp| std::sync::Arc::new(SmtpEmailClient::new(smtp)) as SharedEmailClient),
+    }
+}
+
+/// The admin web pages, nested under `/admin`.
+fn admin_ui(
+    state: State,
+    secret_key: Secret<String>,
+    tenant: Option<&str>,
+    secure_cookies: bool,
+    idempotency: IdempotencySettings,
+) -> tide::Server<State> {
+    // Tenants reached through their path prefix share the host with the others
+    let cookie_name = match tenant {
+        Some(slug) => format!("{}.{}", SESSION_COOKIE, slug),
+        None => SESSION_COOKIE.to_owned(),
+    };
+    let sessions = SessionMiddleware::new(
+        state.session_store().clone(),
+        secret_key.expose().as_bytes(),
+    )
+    .with_cookie_name(cookie_name)
+    .without_save_unchanged();
+    let mut ui = tide::with_state(state);
+    if secure_cookies {
+        ui.with(SecureCookiesMiddleware::new());
+    }
+    ui.with(sessions);
+    ui.with(FlashMiddleware::new(secret_key));
+    ui.with(CsrfMiddleware::new());
+    ui.at("/login").get(login_form).post(login);
+    ui.at("/logout").post(logout);
+    ui.at("/dashboard")
+        .with(LoginRequired::new())
+        .get(dashboard);
+    ui.at("/issues/new")
+        .with(LoginRequired::new())
+        .with(IdempotencyMiddleware::for_forms(idempotency))
+        .get(compose_form)
+        .post(publish);
+    ui.at("/password")
+        .with(LoginRequired::new())
+        .get(password_form)
+        .post(change_password);
+    ui
+}
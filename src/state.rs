@@ -1,38 +1,316 @@
+use std::sync::Arc;
+
 use crate::{
-    adapters::mongodb_repository::MongoUserRepository, configuration::DatabaseSettings, repository,
+    adapters::{
+        mongodb_admin_users::MongoAdminUsers, mongodb_analytics::MongoAnalytics,
+        mongodb_delivery_queue::MongoDeliveryQueue,
+        mongodb_idempotency_store::MongoIdempotencyStore, mongodb_issues::MongoIssues,
+        mongodb_lists::MongoLists, mongodb_outbox::MongoOutbox,
+        mongodb_repository::MongoUserRepository, mongodb_schedules::MongoSchedules,
+        mongodb_session_store::MongoSessionStore, mongodb_tracking::MongoTrackingEvents,
+        mongodb_webhooks::MongoWebhookQueue,
+    },
+    analytics, authentication,
+    configuration::{
+        ArchiveSettings, DatabaseSettings, LimitSettings, OutboxSettings, WebhookSettings,
+    },
+    delivery,
+    email::SharedEmailClient,
+    email_events,
+    email_templates::EmailTemplates,
+    idempotency, issues, lists, outbox,
+    preferences::{self, Links},
+    reload::LiveSettings,
+    repository, scheduler,
+    tracking::{self, Tracker},
+    web::Templates,
+    webhooks,
 };
 
 #[derive(Clone)]
 pub struct State {
     users_repository: MongoUserRepository,
+    lists: MongoLists,
+    delivery_queue: MongoDeliveryQueue,
+    idempotency_store: MongoIdempotencyStore,
+    issues_repository: MongoIssues,
+    schedules: MongoSchedules,
+    admin_users: MongoAdminUsers,
+    session_store: MongoSessionStore,
+    tracking_events: MongoTrackingEvents,
+    analytics: MongoAnalytics,
+    webhook_queue: MongoWebhookQueue,
+    outbox: MongoOutbox,
+    templates: Arc<Templates>,
+    email_templates: Arc<EmailTemplates>,
+    email_client: Option<SharedEmailClient>,
+    live_settings: LiveSettings,
+    archive: Arc<ArchiveSettings>,
+    limits: Arc<LimitSettings>,
+    webhooks: Arc<WebhookSettings>,
+    outbox_settings: Arc<OutboxSettings>,
+    preference_links: Option<Arc<Links>>,
+    tracker: Option<Arc<Tracker>>,
 }
 
 pub(crate) trait StateTrait: Clone + Send + Sync {
     type UserRepository: repository::UsersRepository;
+    type Preferences: preferences::PreferencesRepository;
+    type Suppressions: email_events::SuppressionsRepository;
+    type Lists: lists::ListsRepository;
+    type DeliveryQueue: delivery::DeliveryQueue;
+    type IdempotencyStore: idempotency::IdempotencyStore;
+    type IssuesRepository: issues::IssuesRepository;
+    type Schedules: scheduler::Schedules;
+    type AdminUsers: authentication::AdminUsersRepository;
+    type TrackingEvents: tracking::TrackingEvents;
+    type Analytics: analytics::Analytics;
+    type WebhookQueue: webhooks::WebhookQueue;
+    type Outbox: outbox::Outbox;
 
     fn users_repository(&self) -> &Self::UserRepository;
+
+    fn preferences_repository(&self) -> &Self::Preferences;
+
+    fn suppressions(&self) -> &Self::Suppressions;
+
+    fn lists(&self) -> &Self::Lists;
+
+    fn delivery_queue(&self) -> &Self::DeliveryQueue;
+
+    fn idempotency_store(&self) -> &Self::IdempotencyStore;
+
+    fn issues_repository(&self) -> &Self::IssuesRepository;
+
+    fn schedules(&self) -> &Self::Schedules;
+
+    fn admin_users(&self) -> &Self::AdminUsers;
+
+    fn tracking_events(&self) -> &Self::TrackingEvents;
+
+    fn analytics(&self) -> &Self::Analytics;
+
+    fn webhook_queue(&self) -> &Self::WebhookQueue;
+
+    fn outbox(&self) -> &Self::Outbox;
+
+    fn templates(&self) -> &Templates;
+
+    fn email_templates(&self) -> &EmailTemplates;
+
+    /// How emails are sent, if configured.
+    fn email_client(&self) -> Option<&SharedEmailClient>;
+
+    fn live_settings(&self) -> &LiveSettings;
+
+    fn archive(&self) -> &ArchiveSettings;
+
+    /// What the publication may grow to.
+    fn limits(&self) -> &LimitSettings;
+
+    /// Where the subscriber events are posted.
+    fn webhooks(&self) -> &WebhookSettings;
+
+    /// How the side effects of the writes are relayed.
+    fn outbox_settings(&self) -> &OutboxSettings;
+
+    /// Signs the links to the preference center, if enabled.
+    fn preference_links(&self) -> Option<&Links>;
+
+    /// Signs the tracking links, if configured.
+    fn tracker(&self) -> Option<&Tracker>;
 }
 
 impl StateTrait for State {
     type UserRepository = MongoUserRepository;
+    type Preferences = MongoUserRepository;
+    type Suppressions = MongoUserRepository;
+    type Lists = MongoLists;
+    type DeliveryQueue = MongoDeliveryQueue;
+    type IdempotencyStore = MongoIdempotencyStore;
+    type IssuesRepository = MongoIssues;
+    type Schedules = MongoSchedules;
+    type AdminUsers = MongoAdminUsers;
+    type TrackingEvents = MongoTrackingEvents;
+    type Analytics = MongoAnalytics;
+    type WebhookQueue = MongoWebhookQueue;
+    type Outbox = MongoOutbox;
 
     fn users_repository(&self) -> &Self::UserRepository {
         &self.users_repository
     }
+
+    fn preferences_repository(&self) -> &Self::Preferences {
+        &self.users_repository
+    }
+
+    fn suppressions(&self) -> &Self::Suppressions {
+        &self.users_repository
+    }
+
+    fn lists(&self) -> &Self::Lists {
+        &self.lists
+    }
+
+    fn delivery_queue(&self) -> &Self::DeliveryQueue {
+        &self.delivery_queue
+    }
+
+    fn idempotency_store(&self) -> &Self::IdempotencyStore {
+        &self.idempotency_store
+    }
+
+    fn issues_repository(&self) -> &Self::IssuesRepository {
+        &self.issues_repository
+    }
+
+    fn schedules(&self) -> &Self::Schedules {
+        &self.schedules
+    }
+
+    fn admin_users(&self) -> &Self::AdminUsers {
+        &self.admin_users
+    }
+
+    fn tracking_events(&self) -> &Self::TrackingEvents {
+        &self.tracking_events
+    }
+
+    fn analytics(&self) -> &Self::Analytics {
+        &self.analytics
+    }
+
+    fn webhook_queue(&self) -> &Self::WebhookQueue {
+        &self.webhook_queue
+    }
+
+    fn outbox(&self) -> &Self::Outbox {
+        &self.outbox
+    }
+
+    fn templates(&self) -> &Templates {
+        &self.templates
+    }
+
+    fn email_templates(&self) -> &EmailTemplates {
+        &self.email_templates
+    }
+
+    fn email_client(&self) -> Option<&SharedEmailClient> {
+        self.email_client.as_ref()
+    }
+
+    fn live_settings(&self) -> &LiveSettings {
+        &self.live_settings
+    }
+
+    fn archive(&self) -> &ArchiveSettings {
+        &self.archive
+    }
+
+    fn limits(&self) -> &LimitSettings {
+        &self.limits
+    }
+
+    fn webhooks(&self) -> &WebhookSettings {
+        &self.webhooks
+    }
+
+    fn outbox_settings(&self) -> &OutboxSettings {
+        &self.outbox_settings
+    }
+
+    fn preference_links(&self) -> Option<&Links> {
+        self.preference_links.as_deref()
+    }
+
+    fn tracker(&self) -> Option<&Tracker> {
+        self.tracker.as_deref()
+    }
 }
 
 impl State {
     pub async fn new(cfg: &DatabaseSettings) -> tide::Result<Self> {
-        let client_options = mongodb::options::ClientOptions::parse(&cfg.connection_string())
-            .await
-            .map(|mut opts| {
-                opts.server_selection_timeout = cfg.connection_timeout;
-                opts.connect_timeout = cfg.connection_timeout;
-                opts
-            })?;
+        let client_options = cfg.client_options().await?;
         let mongo = mongodb::Client::with_options(client_options)?;
+        let db = mongo.database(&cfg.name);
         Ok(Self {
-            users_repository: MongoUserRepository::new(mongo.database(&cfg.name)),
+            users_repository: MongoUserRepository::new(mongo.clone(), db.clone()),
+            lists: MongoLists::new(db.clone()),
+            delivery_queue: MongoDeliveryQueue::new(db.clone()),
+            idempotency_store: MongoIdempotencyStore::new(db.clone()),
+            issues_repository: MongoIssues::new(db.clone()),
+            schedules: MongoSchedules::new(db.clone()),
+            admin_users: MongoAdminUsers::new(db.clone()),
+            session_store: MongoSessionStore::new(db.clone()),
+            tracking_events: MongoTrackingEvents::new(db.clone()),
+            analytics: MongoAnalytics::new(db.clone()),
+            webhook_queue: MongoWebhookQueue::new(db.clone()),
+            outbox: MongoOutbox::new(db),
+            templates: Arc::new(Templates::new()),
+            email_templates: Arc::new(EmailTemplates::embedded()),
+            email_client: None,
+            live_settings: Default::default(),
+            archive: Default::default(),
+            limits: Default::default(),
+            webhooks: Default::default(),
+            outbox_settings: Default::default(),
+            preference_links: None,
+            tracker: None,
         })
     }
+
+    pub fn with_live_settings(mut self, live_settings: LiveSettings) -> Self {
+        self.live_settings = live_settings;
+        self
+    }
+
+    pub(crate) fn with_email_templates(mut self, email_templates: EmailTemplates) -> Self {
+        self.email_templates = Arc::new(email_templates);
+        self
+    }
+
+    pub(crate) fn with_archive(mut self, archive: ArchiveSettings) -> Self {
+        self.archive = Arc::new(archive);
+        self
+    }
+
+    pub(crate) fn with_limits(mut self, limits: LimitSettings) -> Self {
+        self.limits = Arc::new(limits);
+        self
+    }
+
+    pub(crate) fn with_webhooks(mut self, webhooks: WebhookSettings) -> Self {
+        self.webhooks = Arc::new(webhooks);
+        self
+    }
+
+    pub(crate) fn with_outbox(mut self, settings: OutboxSettings) -> Self {
+        self.outbox_settings = Arc::new(settings);
+        self
+    }
+
+    pub(crate) fn with_preference_links(mut self, links: Links) -> Self {
+        self.preference_links = Some(Arc::new(links));
+        self
+    }
+
+    pub(crate) fn with_tracker(mut self, tracker: Tracker) -> Self {
+        self.tracker = Some(Arc::new(tracker));
+        self
+    }
+
+    pub(crate) fn with_email_client(mut self, email_client: SharedEmailClient) -> Self {
+        self.email_client = Some(email_client);
+        self
+    }
+
+    pub(crate) fn session_store(&self) -> &MongoSessionStore {
+        &self.session_store
+    }
+
+    /// Settings that can change without restart.
+    pub fn live(&self) -> &LiveSettings {
+        &self.live_settings
+    }
 }
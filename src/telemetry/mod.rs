@@ -0,0 +1,181 @@
+use once_cell::sync::OnceCell;
+use thiserror::Error;
+use tracing::subscriber::set_global_default;
+use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
+use tracing_subscriber::{
+    filter::ParseError, fmt, layer::SubscriberExt, reload, EnvFilter, Registry,
+};
+
+use crate::configuration::{LogFormat, TelemetrySettings};
+
+pub mod pii;
+mod sampling;
+mod sink;
+
+pub use sink::{LogSink, MemorySink, TelemetryGuard};
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Handle to change the filter of the first created subscriber: the one that
+/// is installed as global default.
+static FILTER: OnceCell<FilterHandle> = OnceCell::new();
+
+#[derive(Error, Debug)]
+pub enum FilterError {
+    #[error("Invalid filter directives")]
+    Parse(#[from] ParseError),
+    #[error("Cannot reload filter")]
+    Reload(#[from] reload::Error),
+    #[error("No subscriber installed")]
+    NotInstalled,
+}
+
+/// Build the subscriber described by `settings`: `RUST_LOG`, if defined, overrides
+/// the configured levels.
+pub fn get_subscriber(
+    name: &str,
+    settings: &TelemetrySettings,
+) -> (impl tracing::Subscriber + Send + Sync, TelemetryGuard) {
+    let (sink, guard) = LogSink::from_settings(&settings.output);
+    (subscriber_with_sink(name, settings, sink), guard)
+}
+
+/// Like `get_subscriber()` but write to the given `sink`.
+pub fn subscriber_with_sink(
+    name: &str,
+    settings: &TelemetrySettings,
+    sink: impl Into<LogSink>,
+) -> impl tracing::Subscriber + Send + Sync {
+    let sink = sink.into();
+    pii::configure(&settings.pii);
+    let env_filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(settings.directives()));
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    let _ = FILTER.set(handle);
+    let sampling = settings.sampling.as_ref().map(sampling::SamplingLayer::new);
+    let ansi = sink.is_terminal();
+    let (json, pretty, compact) = match settings.format {
+        LogFormat::Json => (Some(BunyanFormattingLayer::new(name.into(), sink)), None, None),
+        LogFormat::Pretty => (
+            None,
+            Some(fmt::layer().pretty().with_ansi(ansi).with_writer(sink)),
+            None,
+        ),
+        LogFormat::Compact => (
+            None,
+            None,
+            Some(fmt::layer().compact().with_ansi(ansi).with_writer(sink)),
+        ),
+    };
+    Registry::default()
+        .with(env_filter)
+        .with(sampling)
+        .with(JsonStorageLayer)
+        .with(json)
+        .with(pretty)
+        .with(compact)
+}
+
+pub fn init_subscriber(subscriber: impl tracing::Subscriber + Sync + Send) {
+    tracing_log::LogTracer::init().expect("Failed to set logger");
+    set_global_default(subscriber).expect("Failed to set subscriber");
+}
+
+/// Replace the filter directives at runtime.
+pub fn set_filter(directives: &str) -> Result<(), FilterError> {
+    let filter = EnvFilter::try_new(directives)?;
+    FILTER
+        .get()
+        .ok_or(FilterError::NotInstalled)?
+        .reload(filter)?;
+    tracing::info!(directives, "Log filter changed");
+    Ok(())
+}
+
+pub fn current_filter() -> Result<String, FilterError> {
+    Ok(FILTER
+        .get()
+        .ok_or(FilterError::NotInstalled)?
+        .with_current(|filter| filter.to_string())?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::configuration::SamplingSettings;
+
+    fn capture(settings: TelemetrySettings, f: impl FnOnce()) -> MemorySink {
+        let sink = MemorySink::new();
+        let subscriber = subscriber_with_sink("test", &settings, sink.clone());
+        tracing::subscriber::with_default(subscriber, f);
+        sink
+    }
+
+    #[rstest(format, expected,
+        case::json(LogFormat::Json, r#""msg":"hello world""#),
+        case::pretty(LogFormat::Pretty, "hello world"),
+        case::compact(LogFormat::Compact, "hello world"),
+    )]
+    fn should_write_in_the_configured_format(format: LogFormat, expected: &str) {
+        let settings = TelemetrySettings {
+            format,
+            ..Default::default()
+        };
+
+        let logs = capture(settings, || tracing::info!("hello world"));
+
+        assert!(logs.contents().contains(expected), "{}", logs.contents());
+    }
+
+    #[test]
+    fn should_apply_per_target_levels() {
+        let mut targets = BTreeMap::new();
+        targets.insert("noisy".to_owned(), "error".to_owned());
+        let settings = TelemetrySettings {
+            format: LogFormat::Compact,
+            targets,
+            ..Default::default()
+        };
+
+        let logs = capture(settings, || {
+            tracing::info!(target: "noisy", "filtered");
+            tracing::error!(target: "noisy", "kept");
+            tracing::info!("also kept");
+        });
+
+        assert!(!logs.contents().contains("filtered"));
+        assert!(logs.contents().contains("kept"));
+        assert!(logs.contents().contains("also kept"));
+    }
+
+    #[test]
+    fn should_sample_configured_spans() {
+        let settings = TelemetrySettings {
+            sampling: Some(SamplingSettings {
+                spans: vec!["sampled".to_owned()],
+                rate: 3,
+            }),
+            ..Default::default()
+        };
+
+        let logs = capture(settings, || {
+            for _ in 0..6 {
+                tracing::info_span!("sampled").in_scope(|| {});
+                tracing::info_span!("always").in_scope(|| {});
+            }
+        });
+
+        let count = |name: &str| {
+            logs.lines()
+                .iter()
+                .filter(|l| l.contains(&format!(r#""msg":"[{} - START]""#, name)))
+                .count()
+        };
+        assert_eq!(2, count("SAMPLED"));
+        assert_eq!(6, count("ALWAYS"));
+    }
+}
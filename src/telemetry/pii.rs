@@ -0,0 +1,153 @@
+//! Personal data (subscribers' names and emails) must not reach the logs as is:
+//! wrap them with [`email()`] or [`name()`] and they will be rendered as configured
+//! by `telemetry.pii`. Till it's configured they are masked.
+use std::fmt;
+
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+
+use crate::configuration::{PiiMode, PiiSettings};
+
+static SETTINGS: OnceCell<PiiSettings> = OnceCell::new();
+
+const HASH_LEN: usize = 16;
+
+/// Set how personal data is rendered: just the first call has effect.
+pub fn configure(settings: &PiiSettings) {
+    let _ = SETTINGS.set(settings.clone());
+}
+
+fn settings() -> &'static PiiSettings {
+    static DEFAULT: OnceCell<PiiSettings> = OnceCell::new();
+    SETTINGS.get().unwrap_or_else(|| DEFAULT.get_or_init(Default::default))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Email,
+    Name,
+}
+
+/// Personal data that renders itself according to the configured `PiiMode`.
+#[derive(Clone, Copy)]
+pub struct Redacted<'a> {
+    value: &'a str,
+    kind: Kind,
+}
+
+pub fn email(value: &str) -> Redacted<'_> {
+    Redacted {
+        value,
+        kind: Kind::Email,
+    }
+}
+
+pub fn name(value: &str) -> Redacted<'_> {
+    Redacted {
+        value,
+        kind: Kind::Name,
+    }
+}
+
+impl<'a> Redacted<'a> {
+    fn render(&self, settings: &PiiSettings) -> String {
+        match settings.mode {
+            PiiMode::Plain => self.value.to_owned(),
+            PiiMode::Mask => match self.kind {
+                Kind::Email => mask_email(self.value),
+                Kind::Name => mask(self.value),
+            },
+            PiiMode::Hash => hash(settings.salt.expose(), self.value),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Redacted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(settings()))
+    }
+}
+
+impl<'a> fmt::Debug for Redacted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.render(settings()))
+    }
+}
+
+fn mask(value: &str) -> String {
+    match value.chars().next() {
+        Some(first) => format!("{}***", first),
+        None => String::new(),
+    }
+}
+
+fn mask_email(value: &str) -> String {
+    match value.rfind('@') {
+        Some(at) => format!("{}{}", mask(&value[..at]), &value[at..]),
+        None => mask(value),
+    }
+}
+
+/// Stable across restarts: the same value always gives the same hash, so logs
+/// about the same subscriber can be correlated.
+fn hash(salt: &str, value: &str) -> String {
+    let digest = Sha256::new()
+        .chain(salt.as_bytes())
+        .chain(value.as_bytes())
+        .finalize();
+    let mut encoded = hex::encode(digest);
+    encoded.truncate(HASH_LEN);
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn settings(mode: PiiMode) -> PiiSettings {
+        PiiSettings {
+            mode,
+            salt: "salt".to_owned().into(),
+        }
+    }
+
+    #[rstest(value, mode, expected,
+        case::plain(email("antonio@gmail.com"), PiiMode::Plain, "antonio@gmail.com"),
+        case::mask_email(email("antonio@gmail.com"), PiiMode::Mask, "a***@gmail.com"),
+        case::mask_invalid_email(email("antonio"), PiiMode::Mask, "a***"),
+        case::mask_name(name("De Domenico"), PiiMode::Mask, "D***"),
+        case::mask_empty(name(""), PiiMode::Mask, ""),
+    )]
+    fn render(value: Redacted, mode: PiiMode, expected: &str) {
+        assert_eq!(expected, value.render(&settings(mode)));
+    }
+
+    #[test]
+    fn hash_should_be_stable_and_salted() {
+        let hashed = email("antonio@gmail.com").render(&settings(PiiMode::Hash));
+
+        assert_eq!(HASH_LEN, hashed.len());
+        assert_eq!(
+            hashed,
+            email("antonio@gmail.com").render(&settings(PiiMode::Hash))
+        );
+        assert_ne!(hashed, email("other@gmail.com").render(&settings(PiiMode::Hash)));
+        assert_ne!(
+            hashed,
+            email("antonio@gmail.com").render(&PiiSettings {
+                mode: PiiMode::Hash,
+                salt: "other".to_owned().into()
+            })
+        );
+    }
+
+    #[test]
+    fn should_mask_by_default() {
+        assert_eq!(
+            "a***@gmail.com",
+            email("antonio@gmail.com").render(&Default::default())
+        );
+    }
+}
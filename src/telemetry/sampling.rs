@@ -0,0 +1,46 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::{subscriber::Interest, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::configuration::SamplingSettings;
+
+/// Enable just one every `rate` of the configured spans.
+pub(crate) struct SamplingLayer {
+    spans: Vec<String>,
+    rate: u64,
+    counter: AtomicU64,
+}
+
+impl SamplingLayer {
+    pub(crate) fn new(settings: &SamplingSettings) -> Self {
+        Self {
+            spans: settings.spans.clone(),
+            rate: u64::from(settings.rate.max(1)),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    fn is_sampled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span() && self.spans.iter().any(|name| name == metadata.name())
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SamplingLayer {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if self.is_sampled(metadata) {
+            // Ask every time
+            Interest::sometimes()
+        } else {
+            Interest::always()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        !self.is_sampled(metadata)
+            || self
+                .counter
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.rate)
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::configuration::{LogOutput, LogRotation};
+
+/// Where the logs go.
+#[derive(Clone)]
+pub enum LogSink {
+    Stdout,
+    Stderr,
+    /// Written by a background thread: never blocks the caller
+    NonBlocking(tracing_appender::non_blocking::NonBlocking),
+    Memory(MemorySink),
+}
+
+/// Keep it alive till the end of the program: when dropped the pending logs are
+/// flushed.
+#[must_use]
+#[derive(Default)]
+pub struct TelemetryGuard(#[allow(dead_code)] Option<WorkerGuard>);
+
+impl LogSink {
+    pub fn from_settings(output: &LogOutput) -> (Self, TelemetryGuard) {
+        match output {
+            LogOutput::Stdout => (LogSink::Stdout, Default::default()),
+            LogOutput::Stderr => (LogSink::Stderr, Default::default()),
+            LogOutput::File {
+                directory,
+                prefix,
+                rotation,
+            } => {
+                let appender = match rotation {
+                    LogRotation::Minutely => rolling::minutely(directory, prefix),
+                    LogRotation::Hourly => rolling::hourly(directory, prefix),
+                    LogRotation::Daily => rolling::daily(directory, prefix),
+                    LogRotation::Never => rolling::never(directory, prefix),
+                };
+                let (writer, guard) = tracing_appender::non_blocking(appender);
+                (LogSink::NonBlocking(writer), TelemetryGuard(Some(guard)))
+            }
+        }
+    }
+
+    pub(crate) fn is_terminal(&self) -> bool {
+        matches!(self, LogSink::Stdout | LogSink::Stderr)
+    }
+}
+
+impl MakeWriter for LogSink {
+    type Writer = Box<dyn Write>;
+
+    fn make_writer(&self) -> Self::Writer {
+        match self {
+            LogSink::Stdout => Box::new(std::io::stdout()),
+            LogSink::Stderr => Box::new(std::io::stderr()),
+            LogSink::NonBlocking(writer) => Box::new(writer.make_writer()),
+            LogSink::Memory(sink) => Box::new(sink.make_writer()),
+        }
+    }
+}
+
+impl From<MemorySink> for LogSink {
+    fn from(sink: MemorySink) -> Self {
+        LogSink::Memory(sink)
+    }
+}
+
+/// Collect logs in memory: tests use it to assert on them.
+#[derive(Clone, Default, Debug)]
+pub struct MemorySink(Arc<Mutex<Vec<u8>>>);
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().expect("Poisoned memory sink")).to_string()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.contents().lines().map(str::to_owned).collect()
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().expect("Poisoned memory sink").clear()
+    }
+}
+
+impl MakeWriter for MemorySink {
+    type Writer = MemoryWriter;
+
+    fn make_writer(&self) -> Self::Writer {
+        MemoryWriter(self.0.clone())
+    }
+}
+
+pub struct MemoryWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .map_err(|_| std::io::Error::other("Poisoned memory sink"))?
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
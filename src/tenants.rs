@@ -0,0 +1,153 @@
+//! Several publications in one deployment. Each tenant is served by a server of
+//! its own, whose state is built on the tenant's database: its repositories can't
+//! read what belongs to the others. Requests reach a tenant through one of its
+//! hosts or under `/tenants/<slug>`, everything else is the default publication's.
+use std::collections::{HashMap, HashSet};
+
+use tide::{http, Middleware, Next, Request};
+
+/// Where the tenants are reached when they have no host of their own
+const PATH_PREFIX: &str = "/tenants/";
+
+/// Where the server of a tenant is mounted, as the client sees it: empty when it's
+/// reached through one of its hosts. Handlers find it in the request extensions
+/// and prepend it to the paths they send back.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct BasePath(pub(crate) String);
+
+#[derive(Debug, PartialEq)]
+struct Route {
+    slug: String,
+    base: String,
+    /// Inside the tenant
+    path: String,
+}
+
+/// Which tenant a request belongs to.
+#[derive(Default, Debug)]
+struct Routes {
+    /// Lowercase hosts to slugs
+    hosts: HashMap<String, String>,
+    slugs: HashSet<String>,
+}
+
+impl Routes {
+    fn add(&mut self, slug: &str, hosts: &[String]) {
+        for host in hosts {
+            self.hosts.insert(host.to_lowercase(), slug.to_owned());
+        }
+        self.slugs.insert(slug.to_owned());
+    }
+
+    /// The tenant of a request for `path` on `host`: `None` for the default
+    /// publication.
+    fn resolve(&self, host: Option<&str>, path: &str) -> Option<Route> {
+        if let Some(slug) = host.and_then(|host| self.hosts.get(&without_port(host).to_lowercase()))
+        {
+            return Some(Route {
+                slug: slug.clone(),
+                base: String::new(),
+                path: path.to_owned(),
+            });
+        }
+        let rest = path.strip_prefix(PATH_PREFIX)?;
+        let (slug, inner) = match rest.find('/') {
+            Some(end) => (&rest[..end], &rest[end..]),
+            None => (rest, "/"),
+        };
+        if !self.slugs.contains(slug) {
+            return None;
+        }
+        Some(Route {
+            slug: slug.to_owned(),
+            base: format!("{}{}", PATH_PREFIX, slug),
+            path: inner.to_owned(),
+        })
+    }
+}
+
+fn without_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(colon) if host[colon + 1..].chars().all(|c| c.is_ascii_digit()) => &host[..colon],
+        _ => host,
+    }
+}
+
+/// Hand the requests of the tenants over to their servers.
+pub(crate) struct TenantMiddleware<State> {
+    routes: Routes,
+    servers: HashMap<String, tide::Server<State>>,
+}
+
+impl<State> Default for TenantMiddleware<State> {
+    fn default() -> Self {
+        Self {
+            routes: Routes::default(),
+            servers: HashMap::new(),
+        }
+    }
+}
+
+impl<State> TenantMiddleware<State> {
+    pub(crate) fn add(&mut self, slug: &str, hosts: &[String], server: tide::Server<State>) {
+        self.routes.add(slug, hosts);
+        self.servers.insert(slug.to_owned(), server);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TenantMiddleware<State> {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let route = match self.routes.resolve(req.host(), req.url().path()) {
+            Some(route) => route,
+            None => return Ok(next.run(req).await),
+        };
+        let mut req: http::Request = req.into();
+        req.url_mut().set_path(&route.path);
+        req.ext_mut().insert(BasePath(route.base));
+        tracing::debug!(tenant = %route.slug, "Tenant request");
+        self.servers[&route.slug].respond(req).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn routes() -> Routes {
+        let mut routes = Routes::default();
+        routes.add("acme", &["news.acme.com".to_owned()]);
+        routes.add("globex", &[]);
+        routes
+    }
+
+    #[rstest(host, path, expected,
+        case::by_host(Some("news.acme.com"), "/archive", Some(("acme", "", "/archive"))),
+        case::by_host_with_port(Some("News.Acme.com:8000"), "/", Some(("acme", "", "/"))),
+        case::by_prefix(Some("127.0.0.1:8000"), "/tenants/globex/archive", Some(("globex", "/tenants/globex", "/archive"))),
+        case::prefix_only(None, "/tenants/globex", Some(("globex", "/tenants/globex", "/"))),
+        case::host_first(Some("news.acme.com"), "/tenants/globex/archive", Some(("acme", "", "/tenants/globex/archive"))),
+        case::unknown_prefix(None, "/tenants/initech/archive", None),
+        case::default(Some("news.example.com"), "/archive", None),
+    )]
+    fn requests_should_reach_their_tenant(
+        host: Option<&str>,
+        path: &str,
+        expected: Option<(&str, &str, &str)>,
+    ) {
+        assert_eq!(
+            expected.map(|(slug, base, path)| Route {
+                slug: slug.to_owned(),
+                base: base.to_owned(),
+                path: path.to_owned(),
+            }),
+            routes().resolve(host, path)
+        );
+    }
+}
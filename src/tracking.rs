@@ -0,0 +1,398 @@
+//! Open and click tracking, for the lists that enable it: the links of an issue go
+//! through a redirect and a pixel reports the opens. Tokens are short and signed:
+//! they carry the ids of the issue and of the reader and the index of the link,
+//! never a url, so they can't be turned into an open redirect and don't show the
+//! address of the reader to whoever sees them.
+use std::convert::TryFrom;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    configuration::Secret,
+    crypto,
+    lists::List,
+    repository::{self, User},
+    state::StateTrait,
+    telemetry::pii,
+};
+
+/// Lowercase parts of the user agents of crawlers, link scanners and prefetchers
+const BOT_AGENTS: &[&str] = &[
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "preview",
+    "prefetch",
+    "facebookexternalhit",
+    "headless",
+    "curl",
+    "wget",
+    "python",
+    "barracuda",
+    "proofpoint",
+    "mimecast",
+];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Kind {
+    Open,
+    Click,
+}
+
+impl Kind {
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            Kind::Open => "open",
+            Kind::Click => "click",
+        }
+    }
+
+    /// Keeps the tokens of a kind from working for the other one
+    fn domain(self) -> &'static [u8] {
+        match self {
+            Kind::Open => b"tracking:open:",
+            Kind::Click => b"tracking:click:",
+        }
+    }
+}
+
+/// What a token stands for.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Target {
+    pub(crate) issue_id: String,
+    /// The id of the subscriber
+    pub(crate) recipient_id: String,
+    /// Which web link of the issue a click follows: `None` for opens
+    pub(crate) link: Option<usize>,
+}
+
+#[derive(Clone, PartialEq)]
+pub(crate) struct TrackingEvent {
+    pub(crate) kind: Kind,
+    pub(crate) issue_id: String,
+    pub(crate) email: String,
+    pub(crate) url: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    /// From a crawler, a link scanner or a prefetch rather than from a reader
+    pub(crate) bot: bool,
+    pub(crate) at: DateTime<Utc>,
+}
+
+/// Safe to log: personal data is redacted.
+impl std::fmt::Debug for TrackingEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrackingEvent")
+            .field("kind", &self.kind)
+            .field("issue_id", &self.issue_id)
+            .field("email", &pii::email(&self.email))
+            .field("url", &self.url)
+            .field("user_agent", &self.user_agent)
+            .field("bot", &self.bot)
+            .field("at", &self.at)
+            .finish()
+    }
+}
+
+#[async_trait::async_trait]
+pub(crate) trait TrackingEvents: Send + Sync {
+    async fn record(&self, event: &TrackingEvent) -> repository::Result<()>;
+}
+
+/// Whether a request comes from a bot rather than a reader: `prefetch` tells if
+/// it says it's a prefetch.
+pub(crate) fn is_bot(user_agent: Option<&str>, prefetch: bool) -> bool {
+    let user_agent = match user_agent.map(str::trim) {
+        Some(user_agent) if !user_agent.is_empty() => user_agent.to_ascii_lowercase(),
+        _ => return true,
+    };
+    prefetch || BOT_AGENTS.iter().any(|bot| user_agent.contains(bot))
+}
+
+/// Bytes of the signature at the end of each token: too many to be guessed
+const SIGNATURE_LENGTH: usize = 12;
+
+/// Makes and checks the tracking tokens.
+#[derive(Clone)]
+pub(crate) struct Tracker {
+    key: Secret<String>,
+    base_url: String,
+}
+
+impl Tracker {
+    pub(crate) fn new(key: Secret<String>, base_url: &str) -> Self {
+        Self {
+            // The secret key signs other things too: sign with a key of our own
+            key: crypto::derive_key(&key, b"tracking:key"),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    /// `None` if `target` doesn't fit in a token: the link is left untracked.
+    pub(crate) fn token(&self, kind: Kind, target: &Target) -> Option<String> {
+        let mut token = payload(target)?;
+        let mut signature = crypto::sign(&self.key, &[kind.domain(), &token]);
+        signature.truncate(SIGNATURE_LENGTH);
+        token.extend(signature);
+        Some(base64::encode_config(token, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// `None` if `token` was not made by us for `kind`.
+    pub(crate) fn verify(&self, kind: Kind, token: &str) -> Option<Target> {
+        let token = base64::decode_config(token, base64::URL_SAFE_NO_PAD).ok()?;
+        let payload_length = token.len().checked_sub(SIGNATURE_LENGTH)?;
+        let (payload, signature) = token.split_at(payload_length);
+        if !crypto::verify_truncated(&self.key, &[kind.domain(), payload], signature) {
+            return None;
+        }
+        let mut rest = payload;
+        let issue_id = take_id(&mut rest)?;
+        let recipient_id = take_id(&mut rest)?;
+        let link = match (kind, rest) {
+            (Kind::Open, []) => None,
+            (Kind::Click, &[high, low]) => Some(usize::from(u16::from_be_bytes([high, low]))),
+            _ => return None,
+        };
+        Some(Target {
+            issue_id,
+            recipient_id,
+            link,
+        })
+    }
+
+    /// `html` with every web link going through the click redirect and the open
+    /// pixel at the end. Left as it is if the ids don't fit in a token.
+    pub(crate) fn track(&self, html: &str, issue_id: &str, recipient_id: &str) -> String {
+        let target = |link: Option<usize>| Target {
+            issue_id: issue_id.to_owned(),
+            recipient_id: recipient_id.to_owned(),
+            link,
+        };
+        let open = match self.token(Kind::Open, &target(None)) {
+            Some(open) => open,
+            None => return html.to_owned(),
+        };
+        let mut index = 0;
+        let mut tracked = rewrite_links(html, |url| {
+            let link = self.token(Kind::Click, &target(Some(index)));
+            index += 1;
+            match link {
+                Some(token) => format!("{}/t/c/{}", self.base_url, token),
+                None => url.to_owned(),
+            }
+        });
+        tracked.push_str(&format!(
+            r#"<img src="{}/t/o/{}.gif" width="1" height="1" alt="">"#,
+            self.base_url, open
+        ));
+        tracked
+    }
+}
+
+/// The ids of `target`, each after its length, and the index of the link: ids
+/// are hex and take half the bytes.
+fn payload(target: &Target) -> Option<Vec<u8>> {
+    let mut payload = Vec::new();
+    for id in &[&target.issue_id, &target.recipient_id] {
+        let id = hex::decode(id).ok()?;
+        payload.push(u8::try_from(id.len()).ok()?);
+        payload.extend(id);
+    }
+    if let Some(link) = target.link {
+        payload.extend(&u16::try_from(link).ok()?.to_be_bytes());
+    }
+    Some(payload)
+}
+
+/// The id at the start of `rest`, which is left with what follows.
+fn take_id(rest: &mut &[u8]) -> Option<String> {
+    let (&length, tail) = rest.split_first()?;
+    if tail.len() < usize::from(length) {
+        return None;
+    }
+    let (id, tail) = tail.split_at(usize::from(length));
+    *rest = tail;
+    Some(hex::encode(id))
+}
+
+/// The web links of `html`, in the order `Tracker::track` numbers them.
+pub(crate) fn links(html: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    rewrite_links(html, |url| {
+        links.push(url.to_owned());
+        String::new()
+    });
+    links
+}
+
+/// `html` with the `http` and `https` urls of the `href` attributes replaced by
+/// `link` of the unescaped url.
+fn rewrite_links(html: &str, mut link: impl FnMut(&str) -> String) -> String {
+    const HREF: &str = "href=";
+    let mut rewritten = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(HREF) {
+        let (before, after) = rest.split_at(start + HREF.len());
+        rewritten.push_str(before);
+        rest = after;
+        let quote = match rest.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => quote,
+            _ => continue,
+        };
+        let end = match rest[1..].find(quote) {
+            Some(end) => end + 1,
+            None => continue,
+        };
+        let url = rest[1..end].trim().replace("&amp;", "&");
+        let lowercase = url.to_ascii_lowercase();
+        if lowercase.starts_with("http://") || lowercase.starts_with("https://") {
+            rewritten.push(quote);
+            rewritten.push_str(&link(&url));
+            rewritten.push(quote);
+            rest = &rest[end + 1..];
+        }
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+/// The html of issue `issue_id` for `recipient`: tracked if `list` asks for it,
+/// tracking is configured and the recipient is saved.
+pub(crate) fn html<S: StateTrait>(
+    state: &S,
+    list: &List,
+    issue_id: &str,
+    recipient: &User,
+    html: &str,
+) -> String {
+    match (state.tracker(), &recipient.id) {
+        (Some(tracker), Some(id)) if list.tracking => tracker.track(html, issue_id, id),
+        _ => html.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn tracker() -> Tracker {
+        Tracker::new(
+            "a-secret-key".to_owned().into(),
+            "https://news.example.com/",
+        )
+    }
+
+    const ISSUE_ID: &str = "5fd0a1b2c3d4e5f6a7b8c9d0";
+    const RECIPIENT_ID: &str = "5fc9e8d7c6b5a4f3e2d1c0b9";
+
+    fn click() -> Target {
+        Target {
+            issue_id: ISSUE_ID.to_owned(),
+            recipient_id: RECIPIENT_ID.to_owned(),
+            link: Some(3),
+        }
+    }
+
+    #[test]
+    fn tokens_should_carry_the_target() {
+        let tracker = tracker();
+
+        let token = tracker.token(Kind::Click, &click()).unwrap();
+
+        assert_eq!(Some(click()), tracker.verify(Kind::Click, &token));
+    }
+
+    #[test]
+    fn tokens_should_be_short() {
+        let token = tracker().token(Kind::Click, &click()).unwrap();
+
+        // Two ObjectIds and their lengths, the link and the signature: 40 bytes
+        assert_eq!(54, token.len());
+    }
+
+    #[test]
+    fn tokens_should_not_redirect_elsewhere() {
+        let tracker = tracker();
+        let token = tracker.token(Kind::Click, &click()).unwrap();
+        let mut tampered = base64::decode_config(&token, base64::URL_SAFE_NO_PAD).unwrap();
+        // The index of the link
+        tampered[27] ^= 1;
+        let tampered = base64::encode_config(tampered, base64::URL_SAFE_NO_PAD);
+        let other_key = Tracker::new("another-key".to_owned().into(), "https://example.com");
+
+        assert_eq!(None, tracker.verify(Kind::Click, &tampered));
+        assert_eq!(None, other_key.verify(Kind::Click, &token));
+        assert_eq!(None, tracker.verify(Kind::Click, "garbage"));
+    }
+
+    #[test]
+    fn open_tokens_should_not_be_click_tokens() {
+        let tracker = tracker();
+        let open = Target {
+            link: None,
+            ..click()
+        };
+
+        let token = tracker.token(Kind::Open, &open).unwrap();
+
+        assert_eq!(Some(open), tracker.verify(Kind::Open, &token));
+        assert_eq!(None, tracker.verify(Kind::Click, &token));
+    }
+
+    #[test]
+    fn ids_that_are_not_hex_should_not_be_tracked() {
+        let target = Target {
+            recipient_id: "antonio@gmail.com".to_owned(),
+            ..click()
+        };
+
+        assert_eq!(None, tracker().token(Kind::Click, &target));
+    }
+
+    #[test]
+    fn web_links_should_be_rewritten() {
+        let html = r#"<a href="https://example.com/?a=1&amp;b=2">A</a> <a href='mailto:a@b.it'>B</a> <a href=x>C</a>"#;
+
+        let rewritten = rewrite_links(html, |url| format!("[{}]", url));
+
+        assert_eq!(
+            r#"<a href="[https://example.com/?a=1&b=2]">A</a> <a href='mailto:a@b.it'>B</a> <a href=x>C</a>"#,
+            rewritten
+        );
+        assert_eq!(vec!["https://example.com/?a=1&b=2"], links(html));
+    }
+
+    #[test]
+    fn tracked_html_should_link_to_us() {
+        let tracker = tracker();
+        let html = r#"<a href="https://example.com">A</a> <a href="http://example.org">B</a>"#;
+
+        let tracked = tracker.track(html, ISSUE_ID, RECIPIENT_ID);
+
+        assert!(tracked.starts_with(r#"<a href="https://news.example.com/t/c/"#));
+        assert!(tracked.contains(r#"<img src="https://news.example.com/t/o/"#));
+        let targets: Vec<_> = links(&tracked)
+            .iter()
+            .filter_map(|link| link.strip_prefix("https://news.example.com/t/c/"))
+            .map(|token| tracker.verify(Kind::Click, token).unwrap().link)
+            .collect();
+        assert_eq!(vec![Some(0), Some(1)], targets);
+        assert_eq!(html, tracker.track(html, ISSUE_ID, "not-an-id"));
+    }
+
+    #[rstest(user_agent, prefetch, bot,
+        case::mail_client(Some("Mozilla/5.0 Thunderbird/78.6.0"), false, false),
+        case::crawler(Some("Mozilla/5.0 (compatible; Googlebot/2.1)"), false, true),
+        case::scanner(Some("Barracuda Sentinel (EE)"), false, true),
+        case::prefetch(Some("Mozilla/5.0 (iPhone)"), true, true),
+        case::missing(None, false, true),
+        case::empty(Some(" "), false, true),
+    )]
+    fn bots_should_be_flagged(user_agent: Option<&str>, prefetch: bool, bot: bool) {
+        assert_eq!(bot, is_bot(user_agent, prefetch));
+    }
+}
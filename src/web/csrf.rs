@@ -0,0 +1,147 @@
+//! Every admin form carries the session's CSRF token in the `csrf_token` field:
+//! posts without it are rejected.
+use rand::Rng;
+use serde::Deserialize;
+use tide::{http::Method, Body, StatusCode};
+use tracing::warn;
+
+use crate::crypto::constant_time_eq;
+
+const SESSION_KEY: &str = "csrf_token";
+
+/// The token of the current session: created on first use.
+pub(crate) fn token<State>(req: &mut tide::Request<State>) -> String {
+    if let Some(token) = req.session().get::<String>(SESSION_KEY) {
+        return token;
+    }
+    let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    req.session_mut()
+        .insert(SESSION_KEY, &token)
+        .expect("Serializable token");
+    token
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct CsrfForm {
+    csrf_token: String,
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct CsrfMiddleware;
+
+impl CsrfMiddleware {
+    pub(crate) fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for CsrfMiddleware {
+    async fn handle(
+        &self,
+        mut req: tide::Request<State>,
+        next: tide::Next<'_, State>,
+    ) -> tide::Result {
+        if req.method() != Method::Post {
+            return Ok(next.run(req).await);
+        }
+        let body = req.take_body();
+        let mime = body.mime().clone();
+        let bytes = body.into_bytes().await?;
+        let given = Body::from_bytes(bytes.clone())
+            .into_form::<CsrfForm>()
+            .await
+            .map(|form| form.csrf_token)
+            .unwrap_or_default();
+        let expected = req.session().get::<String>(SESSION_KEY);
+        let valid = match &expected {
+            Some(expected) => constant_time_eq(given.as_bytes(), expected.as_bytes()),
+            None => false,
+        };
+        if !valid {
+            warn!("Rejected a form without a valid CSRF token");
+            return Ok(StatusCode::Forbidden.into());
+        }
+        let mut restored = Body::from_bytes(bytes);
+        restored.set_mime(mime);
+        req.set_body(restored);
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tide::{
+        http::{Request, Response, Url},
+        sessions::{MemoryStore, SessionMiddleware},
+    };
+
+    use super::*;
+
+    fn app() -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(SessionMiddleware::new(
+            MemoryStore::new(),
+            b"0123456789abcdef0123456789abcdef",
+        ));
+        app.at("/form")
+            .get(|mut req: tide::Request<()>| async move { Ok(token(&mut req)) });
+        app.at("/form")
+            .with(CsrfMiddleware::new())
+            .post(|mut req: tide::Request<()>| async move { req.body_string().await });
+        app
+    }
+
+    async fn post(app: &tide::Server<()>, cookie: &str, body: String) -> Response {
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("https://example.com/form").unwrap(),
+        );
+        req.insert_header("Cookie", cookie);
+        req.set_body(body);
+        req.set_content_type(tide::http::mime::FORM);
+        app.respond(req).await.unwrap()
+    }
+
+    async fn session(app: &tide::Server<()>) -> (String, String) {
+        let mut res: Response = app
+            .respond(Request::new(
+                Method::Get,
+                Url::parse("https://example.com/form").unwrap(),
+            ))
+            .await
+            .unwrap();
+        let cookie = res["Set-Cookie"]
+            .as_str()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+        (cookie, res.body_string().await.unwrap())
+    }
+
+    #[async_std::test]
+    async fn should_accept_the_session_token_and_keep_the_body() {
+        let app = app();
+        let (cookie, token) = session(&app).await;
+        let body = format!("title=Hello&csrf_token={}", token);
+
+        let mut res = post(&app, &cookie, body.clone()).await;
+
+        assert_eq!(StatusCode::Ok, res.status());
+        assert_eq!(body, res.body_string().await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn should_reject_wrong_or_missing_token() {
+        let app = app();
+        let (cookie, _) = session(&app).await;
+
+        let wrong = post(&app, &cookie, "title=Hello&csrf_token=abc".to_owned()).await;
+        let missing = post(&app, &cookie, "title=Hello".to_owned()).await;
+
+        assert_eq!(StatusCode::Forbidden, wrong.status());
+        assert_eq!(StatusCode::Forbidden, missing.status());
+    }
+}
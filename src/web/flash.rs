@@ -0,0 +1,209 @@
+//! One shot messages shown on the page after a redirect, e.g. "Issue queued".
+//! They travel in a cookie signed with the application secret key.
+use serde::{Deserialize, Serialize};
+use tide::http::cookies::{Cookie, SameSite};
+
+use crate::{configuration::Secret, crypto};
+
+const COOKIE: &str = "z2p.flash";
+/// Keep flash signatures apart from anything else signed with the same key
+const DOMAIN: &[u8] = b"flash:";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Level {
+    Info,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct FlashMessage {
+    pub(crate) level: Level,
+    pub(crate) text: String,
+}
+
+impl FlashMessage {
+    pub(crate) fn info(text: impl Into<String>) -> Self {
+        Self {
+            level: Level::Info,
+            text: text.into(),
+        }
+    }
+
+    pub(crate) fn error(text: impl Into<String>) -> Self {
+        Self {
+            level: Level::Error,
+            text: text.into(),
+        }
+    }
+}
+
+/// The message received with the request: handlers find it in the request
+/// extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct IncomingFlash(pub(crate) FlashMessage);
+
+fn sign(key: &Secret<String>, message: &FlashMessage) -> String {
+    let payload = serde_json::to_vec(message).expect("Serializable message");
+    let signature = crypto::sign(key, &[DOMAIN, &payload]);
+    format!("{}.{}", hex::encode(&payload), hex::encode(signature))
+}
+
+fn verify(key: &Secret<String>, value: &str) -> Option<FlashMessage> {
+    let mut parts = value.splitn(2, '.');
+    let payload = hex::decode(parts.next()?).ok()?;
+    let signature = hex::decode(parts.next()?).ok()?;
+    if !crypto::verify(key, &[DOMAIN, &payload], &signature) {
+        return None;
+    }
+    serde_json::from_slice(&payload).ok()
+}
+
+/// Read the incoming flash message and write the one the handler put in the
+/// response extensions.
+#[derive(Clone)]
+pub(crate) struct FlashMiddleware {
+    key: Secret<String>,
+}
+
+impl FlashMiddleware {
+    pub(crate) fn new(key: Secret<String>) -> Self {
+        Self { key }
+    }
+
+    /// Scoped to the admin pages of the server mounted on `base`.
+    fn cookie(&self, value: String, base: &str, secure: bool) -> Cookie<'static> {
+        Cookie::build(COOKIE, value)
+            .path(format!("{}/admin", base))
+            .http_only(true)
+            .secure(secure)
+            .same_site(SameSite::Lax)
+            .finish()
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for FlashMiddleware {
+    async fn handle(
+        &self,
+        mut req: tide::Request<State>,
+        next: tide::Next<'_, State>,
+    ) -> tide::Result {
+        let secure = req.url().scheme() == "https";
+        let base = super::base(&req).to_owned();
+        let received = req.cookie(COOKIE).is_some();
+        if let Some(message) = req
+            .cookie(COOKIE)
+            .and_then(|cookie| verify(&self.key, cookie.value()))
+        {
+            req.set_ext(IncomingFlash(message));
+        }
+        let mut res = next.run(req).await;
+        if let Some(message) = res.ext::<FlashMessage>().cloned() {
+            res.insert_cookie(self.cookie(sign(&self.key, &message), &base, secure));
+        } else if received && !res.status().is_redirection() {
+            // Shown: don't show it again
+            res.remove_cookie(self.cookie(String::new(), &base, secure));
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tide::http::{Method, Request, Response, Url};
+
+    use super::*;
+
+    fn key() -> Secret<String> {
+        "0123456789abcdef0123456789abcdef".to_owned().into()
+    }
+
+    #[test]
+    fn signed_message_should_be_verified() {
+        let message = FlashMessage::info("Issue queued");
+
+        assert_eq!(
+            Some(message.clone()),
+            verify(&key(), &sign(&key(), &message))
+        );
+    }
+
+    #[test]
+    fn tampered_message_should_be_rejected() {
+        let signed = sign(&key(), &FlashMessage::info("Issue queued"));
+        let signature = signed.split('.').nth(1).unwrap();
+        let forged = format!(
+            "{}.{}",
+            hex::encode(serde_json::to_vec(&FlashMessage::error("Forged")).unwrap()),
+            signature
+        );
+        let other_key: Secret<String> = "fedcba9876543210fedcba9876543210".to_owned().into();
+
+        assert_eq!(None, verify(&key(), &forged));
+        assert_eq!(None, verify(&other_key, &signed));
+        assert_eq!(None, verify(&key(), "garbage"));
+    }
+
+    #[async_std::test]
+    async fn message_should_reach_the_next_page() {
+        let mut app = tide::new();
+        app.with(FlashMiddleware::new(key()));
+        app.at("/admin/send").post(|_| async {
+            let mut res: tide::Response = tide::Redirect::see_other("/admin/show").into();
+            res.insert_ext(FlashMessage::info("Sent"));
+            Ok(res)
+        });
+        app.at("/admin/show")
+            .get(|req: tide::Request<()>| async move {
+                Ok(req
+                    .ext::<IncomingFlash>()
+                    .map(|flash| flash.0.text.clone())
+                    .unwrap_or_default())
+            });
+
+        let sent: Response = app
+            .respond(Request::new(
+                Method::Post,
+                Url::parse("https://example.com/admin/send").unwrap(),
+            ))
+            .await
+            .unwrap();
+        let set_cookie = sent["Set-Cookie"].as_str().to_owned();
+        let cookie = set_cookie.split(';').next().unwrap();
+        let mut show = Request::new(
+            Method::Get,
+            Url::parse("https://example.com/admin/show").unwrap(),
+        );
+        show.insert_header("Cookie", cookie);
+        let mut shown: Response = app.respond(show).await.unwrap();
+
+        assert!(set_cookie.contains("Secure"));
+        assert!(set_cookie.contains("HttpOnly"));
+        assert_eq!("Sent", shown.body_string().await.unwrap());
+        assert!(shown["Set-Cookie"].as_str().starts_with("z2p.flash=;"));
+    }
+
+    #[async_std::test]
+    async fn cookie_should_be_scoped_to_the_admin_pages_of_the_tenant() {
+        let mut app = tide::new();
+        app.with(FlashMiddleware::new(key()));
+        app.at("/admin/send").post(|_| async {
+            let mut res: tide::Response = tide::Redirect::see_other("/admin/show").into();
+            res.insert_ext(FlashMessage::info("Sent"));
+            Ok(res)
+        });
+        let mut send = Request::new(
+            Method::Post,
+            Url::parse("https://example.com/admin/send").unwrap(),
+        );
+        send.ext_mut()
+            .insert(crate::tenants::BasePath("/tenants/globex".to_owned()));
+
+        let sent: Response = app.respond(send).await.unwrap();
+
+        assert!(sent["Set-Cookie"]
+            .as_str()
+            .contains("Path=/tenants/globex/admin"));
+    }
+}
@@ -0,0 +1,115 @@
+//! Server rendered admin pages: cookie sessions, signed flash messages and CSRF
+//! tokens on every form.
+use serde_json::{json, Value};
+use tide::Redirect;
+
+use crate::tenants::BasePath;
+
+pub(crate) mod csrf;
+pub(crate) mod flash;
+pub(crate) mod templates;
+
+pub(crate) use csrf::CsrfMiddleware;
+pub(crate) use flash::{FlashMessage, FlashMiddleware};
+pub(crate) use templates::Templates;
+
+pub(crate) const SESSION_COOKIE: &str = "z2p.sid";
+pub(crate) const LOGIN_PATH: &str = "/admin/login";
+const USER_KEY: &str = "username";
+
+/// The admin logged in the current session, if any.
+pub(crate) fn logged_user<State>(req: &tide::Request<State>) -> Option<String> {
+    req.session().get(USER_KEY)
+}
+
+/// Bind the session to `username`: the session id changes to prevent fixation.
+pub(crate) fn log_in<State>(req: &mut tide::Request<State>, username: &str) {
+    let session = req.session_mut();
+    session.regenerate();
+    session
+        .insert(USER_KEY, username)
+        .expect("Serializable username");
+}
+
+/// Template data shared by all the pages, merged with the page's own `data`.
+pub(crate) fn page<State>(req: &mut tide::Request<State>, title: &str, data: Value) -> Value {
+    let mut context = json!({
+        "title": title,
+        "base": base(req),
+        "logged_in": logged_user(req).is_some(),
+        "csrf_token": csrf::token(req),
+        "flash": req.ext::<flash::IncomingFlash>().map(|flash| &flash.0),
+    });
+    if let (Some(context), Value::Object(data)) = (context.as_object_mut(), data) {
+        context.extend(data);
+    }
+    context
+}
+
+/// Where this server is mounted, to prepend to the paths sent to the client:
+/// empty unless it's a tenant reached through its path prefix.
+pub(crate) fn base<State>(req: &tide::Request<State>) -> &str {
+    req.ext::<BasePath>().map_or("", |base| base.0.as_str())
+}
+
+/// Redirect to `path` on this server.
+pub(crate) fn redirect<State>(req: &tide::Request<State>, path: &str) -> tide::Response {
+    Redirect::see_other(format!("{}{}", base(req), path)).into()
+}
+
+/// Redirect to `path` on this server showing `message` there.
+pub(crate) fn redirect_with<State>(
+    req: &tide::Request<State>,
+    path: &str,
+    message: FlashMessage,
+) -> tide::Response {
+    let mut res = redirect(req, path);
+    res.insert_ext(message);
+    res
+}
+
+/// Send anonymous users to the login page.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct LoginRequired;
+
+impl LoginRequired {
+    pub(crate) fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for LoginRequired {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        if logged_user(&req).is_none() {
+            return Ok(redirect(&req, LOGIN_PATH));
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// Cookies get the `Secure` flag just for https requests: behind a TLS
+/// terminating proxy the requests look like plain http, so mark them as https.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SecureCookiesMiddleware;
+
+impl SecureCookiesMiddleware {
+    pub(crate) fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for SecureCookiesMiddleware {
+    async fn handle(
+        &self,
+        mut req: tide::Request<State>,
+        next: tide::Next<'_, State>,
+    ) -> tide::Result {
+        let url: &mut tide::http::Url = AsMut::<tide::http::Request>::as_mut(&mut req).url_mut();
+        if url.scheme() == "http" {
+            url.set_scheme("https").expect("http can become https");
+        }
+        Ok(next.run(req).await)
+    }
+}
@@ -0,0 +1,114 @@
+use handlebars::Handlebars;
+use serde::Serialize;
+use tide::{http::mime, Response, StatusCode};
+use tracing::error;
+
+const PARTIALS: &[(&str, &str)] = &[
+    ("header", include_str!("../../templates/admin/header.hbs")),
+    ("footer", include_str!("../../templates/admin/footer.hbs")),
+];
+
+const PAGES: &[(&str, &str)] = &[
+    ("login", include_str!("../../templates/admin/login.hbs")),
+    (
+        "dashboard",
+        include_str!("../../templates/admin/dashboard.hbs"),
+    ),
+    ("compose", include_str!("../../templates/admin/compose.hbs")),
+    (
+        "password",
+        include_str!("../../templates/admin/password.hbs"),
+    ),
+    // Subscriber facing
+    ("message", include_str!("../../templates/pages/message.hbs")),
+    ("archive", include_str!("../../templates/pages/archive.hbs")),
+    (
+        "archive_issue",
+        include_str!("../../templates/pages/archive_issue.hbs"),
+    ),
+    (
+        "preferences",
+        include_str!("../../templates/pages/preferences.hbs"),
+    ),
+];
+
+/// The html pages, embedded in the binary.
+pub(crate) struct Templates(Handlebars<'static>);
+
+impl Templates {
+    pub(crate) fn new() -> Self {
+        let mut registry = Handlebars::new();
+        for (name, partial) in PARTIALS {
+            registry
+                .register_partial(name, partial)
+                .expect("Valid embedded partial");
+        }
+        for (name, page) in PAGES {
+            registry
+                .register_template_string(name, page)
+                .expect("Valid embedded template");
+        }
+        Self(registry)
+    }
+
+    pub(crate) fn render(&self, page: &str, data: &impl Serialize) -> Response {
+        match self.0.render(page, data) {
+            Ok(html) => {
+                let mut res = Response::new(StatusCode::Ok);
+                res.set_body(html);
+                res.set_content_type(mime::HTML);
+                res
+            }
+            Err(e) => {
+                error!("Cannot render '{}': {}", page, e);
+                StatusCode::InternalServerError.into()
+            }
+        }
+    }
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[async_std::test]
+    async fn pages_should_escape_values() {
+        let mut res = Templates::new().render(
+            "dashboard",
+            &json!({
+                "title": "Dashboard",
+                "logged_in": true,
+                "csrf_token": "token",
+                "username": "<script>",
+                "lists": [{ "name": "<b>Rust</b>", "subscribers": 42 }],
+            }),
+        );
+        let html = res.take_body().into_string().await.unwrap();
+
+        assert_eq!(StatusCode::Ok, res.status());
+        assert!(html.contains("<strong>42</strong>"));
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(html.contains("&lt;b&gt;Rust&lt;/b&gt;"));
+        assert!(html.contains(r#"value="token""#));
+    }
+
+    #[test]
+    fn all_pages_should_render() {
+        let templates = Templates::new();
+
+        for (page, _) in PAGES {
+            assert_eq!(
+                StatusCode::Ok,
+                templates.render(page, &json!({ "title": page })).status()
+            );
+        }
+    }
+}
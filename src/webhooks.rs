@@ -0,0 +1,414 @@
+//! Subscriber lifecycle events posted to other systems, e.g. a CRM. Each event
+//! becomes a delivery per interested endpoint in a persistent queue: a background
+//! worker posts it signed and retries failures with a jittered exponential backoff,
+//! so endpoints get it at least once. Every attempt is kept in the delivery log of
+//! the endpoint.
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+use crate::{
+    configuration::{Secret, WebhookEndpointSettings, WebhookSettings},
+    crypto,
+    delivery::{self, Backoff},
+    repository,
+    state::StateTrait,
+};
+
+/// The id of the event: the same on every attempt, receivers drop the ones they
+/// have already seen
+pub(crate) const ID_HEADER: &str = "X-Webhook-Id";
+/// Unix seconds when the attempt was made
+pub(crate) const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+/// `sha256=` followed by the hex HMAC-SHA256 of `<timestamp>.<body>`
+pub(crate) const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+const SIGNATURE_PREFIX: &str = "sha256=";
+/// Deliveries shown in the log of an endpoint
+pub(crate) const LOG_SIZE: i64 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub(crate) enum EventType {
+    #[serde(rename = "subscriber.created")]
+    SubscriberCreated,
+    #[serde(rename = "subscriber.updated")]
+    SubscriberUpdated,
+    /// They left every list or their address was suppressed: they get nothing anymore
+    #[serde(rename = "subscriber.deleted")]
+    SubscriberDeleted,
+}
+
+impl EventType {
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            EventType::SubscriberCreated => "subscriber.created",
+            EventType::SubscriberUpdated => "subscriber.updated",
+            EventType::SubscriberDeleted => "subscriber.deleted",
+        }
+    }
+
+    pub(crate) fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "subscriber.created" => Some(EventType::SubscriberCreated),
+            "subscriber.updated" => Some(EventType::SubscriberUpdated),
+            "subscriber.deleted" => Some(EventType::SubscriberDeleted),
+            _ => None,
+        }
+    }
+}
+
+/// The body of a delivery.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Event {
+    pub(crate) id: String,
+    #[serde(rename = "type")]
+    pub(crate) kind: EventType,
+    pub(crate) occurred_at: DateTime<Utc>,
+    pub(crate) data: Value,
+}
+
+impl Event {
+    pub(crate) fn new(kind: EventType, data: Value, now: DateTime<Utc>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind,
+            occurred_at: now,
+            data,
+        }
+    }
+}
+
+/// An event on its way to an endpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Delivery {
+    pub(crate) id: String,
+    /// Its name in the settings
+    pub(crate) endpoint: String,
+    pub(crate) event: Event,
+    /// Including the current one
+    pub(crate) attempts: u32,
+}
+
+/// How an attempt went: `status` is missing if the endpoint didn't answer.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Attempt {
+    pub(crate) at: DateTime<Utc>,
+    pub(crate) status: Option<u16>,
+    pub(crate) error: Option<String>,
+}
+
+impl Attempt {
+    fn failed(at: DateTime<Utc>, status: Option<u16>, error: String) -> Self {
+        Self {
+            at,
+            status,
+            error: Some(error),
+        }
+    }
+}
+
+/// A delivery as shown in the log of its endpoint.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct LogEntry {
+    pub(crate) id: String,
+    pub(crate) event_id: String,
+    pub(crate) event_type: EventType,
+    /// `pending`, `in_progress`, `done` or `dead`
+    pub(crate) status: String,
+    pub(crate) attempts: Vec<Attempt>,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) updated_at: DateTime<Utc>,
+}
+
+#[async_trait::async_trait]
+pub(crate) trait WebhookQueue: Send + Sync {
+    /// A delivery of `event` to each of `endpoints`.
+    async fn enqueue(&self, event: &Event, endpoints: &[String]) -> repository::Result<()>;
+
+    /// Take the oldest due delivery: no one else can claim it till `lease` expires.
+    async fn claim(&self, lease: Duration) -> repository::Result<Option<Delivery>>;
+
+    async fn complete(&self, delivery: &Delivery, attempt: &Attempt) -> repository::Result<()>;
+
+    /// Release the delivery to be claimed again not before `at`.
+    async fn retry(
+        &self,
+        delivery: &Delivery,
+        attempt: &Attempt,
+        at: DateTime<Utc>,
+    ) -> repository::Result<()>;
+
+    async fn dead_letter(&self, delivery: &Delivery, attempt: &Attempt) -> repository::Result<()>;
+
+    /// The latest `limit` deliveries to `endpoint`, the newest first.
+    async fn log(&self, endpoint: &str, limit: i64) -> repository::Result<Vec<LogEntry>>;
+}
+
+/// Queue an event for the endpoints that want it.
+pub(crate) async fn enqueue<S: StateTrait>(
+    state: &S,
+    kind: EventType,
+    data: Value,
+) -> repository::Result<()> {
+    let endpoints: Vec<_> = state
+        .webhooks()
+        .endpoints
+        .iter()
+        .filter(|(_, endpoint)| wants(endpoint, kind))
+        .map(|(name, _)| name.clone())
+        .collect();
+    if endpoints.is_empty() {
+        return Ok(());
+    }
+    let event = Event::new(kind, data, Utc::now());
+    state.webhook_queue().enqueue(&event, &endpoints).await
+}
+
+fn wants(endpoint: &WebhookEndpointSettings, kind: EventType) -> bool {
+    endpoint.events.is_empty() || endpoint.events.iter().any(|e| e == kind.code())
+}
+
+/// The `X-Webhook-Signature` value of `body` sent at `timestamp`: the timestamp
+/// is signed too, so that an old delivery can't be replayed as a new one.
+pub(crate) fn sign(secret: &Secret<String>, timestamp: i64, body: &[u8]) -> String {
+    let signature = crypto::sign(secret, &[timestamp.to_string().as_bytes(), b".", body]);
+    format!("{}{}", SIGNATURE_PREFIX, hex::encode(signature))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    Delivered,
+    Retry { at: DateTime<Utc> },
+    Dead,
+}
+
+/// What to do after `attempt`, the `attempts`-th: every failure is retried, the
+/// endpoint may be down for a while.
+fn outcome(
+    attempt: &Attempt,
+    attempts: u32,
+    max_attempts: u32,
+    backoff: &Backoff,
+    jitter: f64,
+) -> Outcome {
+    if attempt.error.is_none() {
+        Outcome::Delivered
+    } else if attempts < max_attempts {
+        Outcome::Retry {
+            at: delivery::after(attempt.at, backoff.delay(attempts, jitter)),
+        }
+    } else {
+        Outcome::Dead
+    }
+}
+
+pub(crate) struct Worker<Q: WebhookQueue> {
+    queue: Q,
+    http: surf::Client,
+    settings: WebhookSettings,
+    backoff: Backoff,
+}
+
+impl<Q: WebhookQueue> Worker<Q> {
+    pub(crate) fn new(queue: Q, settings: WebhookSettings) -> Self {
+        Self {
+            backoff: Backoff::new(settings.backoff_base, settings.backoff_max),
+            http: surf::Client::new(),
+            queue,
+            settings,
+        }
+    }
+
+    pub(crate) async fn run(self) {
+        info!("Webhook worker started");
+        loop {
+            if !self.step().await {
+                async_std::task::sleep(self.settings.poll_interval).await;
+            }
+        }
+    }
+
+    /// Post the next due delivery, if any: return `false` if the queue is empty or
+    /// not reachable.
+    pub(crate) async fn step(&self) -> bool {
+        let delivery = match self.queue.claim(self.settings.lease).await {
+            Ok(Some(delivery)) => delivery,
+            Ok(None) => return false,
+            Err(e) => {
+                error!("Cannot claim a webhook delivery: {:?}", e);
+                return false;
+            }
+        };
+        self.deliver(&delivery).await;
+        true
+    }
+
+    #[tracing::instrument(
+        name = "Posting a webhook",
+        skip(self, delivery),
+        fields(
+            endpoint = %delivery.endpoint,
+            event = %delivery.event.id,
+            attempt = delivery.attempts,
+        )
+    )]
+    async fn deliver(&self, delivery: &Delivery) {
+        let endpoint = self.settings.endpoints.get(&delivery.endpoint);
+        let (attempt, outcome) = match endpoint {
+            // The lease expired while the last attempt was running
+            _ if delivery.attempts > self.settings.max_attempts => (
+                Attempt::failed(Utc::now(), None, "too many attempts".to_owned()),
+                Outcome::Dead,
+            ),
+            Some(endpoint) => {
+                let attempt = self.attempt(endpoint, &delivery.event).await;
+                let outcome = outcome(
+                    &attempt,
+                    delivery.attempts,
+                    self.settings.max_attempts,
+                    &self.backoff,
+                    rand::random(),
+                );
+                (attempt, outcome)
+            }
+            // Removed from the settings after the event was queued
+            None => (
+                Attempt::failed(Utc::now(), None, "unknown endpoint".to_owned()),
+                Outcome::Dead,
+            ),
+        };
+        let stored = match outcome {
+            Outcome::Delivered => self.queue.complete(delivery, &attempt).await,
+            Outcome::Retry { at } => {
+                warn!(%at, "Webhook failed, will retry: {:?}", attempt.error);
+                self.queue.retry(delivery, &attempt, at).await
+            }
+            Outcome::Dead => {
+                error!("Webhook failed, giving up: {:?}", attempt.error);
+                self.queue.dead_letter(delivery, &attempt).await
+            }
+        };
+        if let Err(e) = stored {
+            error!("Cannot update the webhook delivery: {:?}", e);
+        }
+    }
+
+    async fn attempt(&self, endpoint: &WebhookEndpointSettings, event: &Event) -> Attempt {
+        let at = Utc::now();
+        let body = match serde_json::to_vec(event) {
+            Ok(body) => body,
+            Err(e) => return Attempt::failed(at, None, e.to_string()),
+        };
+        let request = self
+            .http
+            .post(&endpoint.url)
+            .header("Content-Type", "application/json")
+            .header(ID_HEADER, event.id.as_str())
+            .header(TIMESTAMP_HEADER, at.timestamp().to_string())
+            .header(
+                SIGNATURE_HEADER,
+                sign(&endpoint.secret, at.timestamp(), &body),
+            )
+            .body(body)
+            .send();
+        match async_std::future::timeout(self.settings.timeout, request).await {
+            Err(_) => Attempt::failed(at, None, "timed out".to_owned()),
+            Ok(Err(e)) => Attempt::failed(at, None, e.to_string()),
+            Ok(Ok(response)) => {
+                let status = response.status();
+                if status.is_success() {
+                    Attempt {
+                        at,
+                        status: Some(status.into()),
+                        error: None,
+                    }
+                } else {
+                    Attempt::failed(at, Some(status.into()), status.to_string())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn secret() -> Secret<String> {
+        "s3cr3t".to_owned().into()
+    }
+
+    #[test]
+    fn event_types_should_round_trip() {
+        for kind in &[
+            EventType::SubscriberCreated,
+            EventType::SubscriberUpdated,
+            EventType::SubscriberDeleted,
+        ] {
+            assert_eq!(Some(*kind), EventType::from_code(kind.code()));
+            assert_eq!(
+                serde_json::json!(kind.code()),
+                serde_json::to_value(kind).unwrap()
+            );
+        }
+        assert_eq!(None, EventType::from_code("subscriber.exploded"));
+    }
+
+    #[test]
+    fn signature_should_cover_the_timestamp_and_the_body() {
+        let signature = sign(&secret(), 1_600_000_000, b"{}");
+
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(7 + 64, signature.len());
+        assert_eq!(signature, sign(&secret(), 1_600_000_000, b"{}"));
+        assert_ne!(signature, sign(&secret(), 1_600_000_001, b"{}"));
+        assert_ne!(signature, sign(&secret(), 1_600_000_000, b"[]"));
+        assert_ne!(
+            signature,
+            sign(&"other".to_owned().into(), 1_600_000_000, b"{}")
+        );
+    }
+
+    #[rstest(events, wanted,
+        case::all(&[], true),
+        case::listed(&["subscriber.deleted", "subscriber.created"], true),
+        case::not_listed(&["subscriber.deleted"], false),
+    )]
+    fn endpoints_should_get_the_events_they_want(events: &[&str], wanted: bool) {
+        let endpoint = WebhookEndpointSettings {
+            url: "https://crm.example.com/hooks".to_owned(),
+            secret: secret(),
+            events: events.iter().map(|e| e.to_string()).collect(),
+        };
+
+        assert_eq!(wanted, wants(&endpoint, EventType::SubscriberCreated));
+    }
+
+    #[rstest(status, error, attempts, expected,
+        case::delivered(Some(204), None, 1, Outcome::Delivered),
+        case::server_error(Some(503), Some("503"), 1, Outcome::Retry { at: "2020-01-01T00:00:01Z".parse().unwrap() }),
+        case::unreachable(None, Some("connection refused"), 2, Outcome::Retry { at: "2020-01-01T00:00:02Z".parse().unwrap() }),
+        case::client_error(Some(400), Some("400"), 2, Outcome::Retry { at: "2020-01-01T00:00:02Z".parse().unwrap() }),
+        case::last_attempt(Some(503), Some("503"), 3, Outcome::Dead),
+    )]
+    fn failures_should_be_retried_till_the_last_attempt(
+        status: Option<u16>,
+        error: Option<&str>,
+        attempts: u32,
+        expected: Outcome,
+    ) {
+        let attempt = Attempt {
+            at: "2020-01-01T00:00:00Z".parse().unwrap(),
+            status,
+            error: error.map(str::to_owned),
+        };
+        let backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+        assert_eq!(expected, outcome(&attempt, attempts, 3, &backoff, 0.0));
+    }
+}
@@ -0,0 +1,204 @@
+use rstest::rstest;
+use std::net::SocketAddr;
+
+pub mod utils;
+
+use utils::{app, App};
+
+mod admin_ui {
+    use super::*;
+
+    use mongodb::bson::Document;
+    use surf::Response;
+
+    const PASSWORD: &str = "a-very-strong-password";
+
+    fn url(address: &SocketAddr, path: &str) -> String {
+        format!("http://{}{}", address, path)
+    }
+
+    fn session_cookie(response: &Response) -> String {
+        response
+            .header("Set-Cookie")
+            .and_then(|values| {
+                values
+                    .iter()
+                    .map(|value| value.as_str())
+                    .find(|value| value.starts_with("z2p.sid="))
+            })
+            .and_then(|cookie| cookie.split(';').next())
+            .expect("No session cookie")
+            .to_owned()
+    }
+
+    fn csrf_token(html: &str) -> String {
+        let start = html
+            .find(r#"name="csrf_token" value=""#)
+            .expect("No csrf token")
+            + r#"name="csrf_token" value=""#.len();
+        html[start..].split('"').next().unwrap().to_owned()
+    }
+
+    /// The session cookie and the CSRF token from the login page.
+    async fn login_page(address: &SocketAddr) -> (String, String) {
+        let mut response = surf::get(url(address, "/admin/login"))
+            .await
+            .expect("Failed to execute request.");
+        let html = response.body_string().await.unwrap();
+        (session_cookie(&response), csrf_token(&html))
+    }
+
+    async fn post_login(address: &SocketAddr, cookie: &str, body: String) -> Response {
+        surf::post(url(address, "/admin/login"))
+            .header("Cookie", cookie)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    #[rstest]
+    async fn should_send_anonymous_users_to_login(app: App) {
+        let response = surf::get(url(&app.address, "/admin/dashboard"))
+            .await
+            .expect("Failed to execute request.");
+
+        assert_eq!(303, response.status());
+        assert_eq!("/admin/login", response["Location"].as_str());
+    }
+
+    #[rstest]
+    async fn should_show_the_dashboard_after_login(app: App) {
+        z2p::authentication::create_admin(&app.db_cfg, "editor", &PASSWORD.to_owned().into())
+            .await
+            .unwrap();
+        let (cookie, token) = login_page(&app.address).await;
+
+        let response = post_login(
+            &app.address,
+            &cookie,
+            format!("username=editor&password={}&csrf_token={}", PASSWORD, token),
+        )
+        .await;
+        assert_eq!(303, response.status());
+        assert_eq!("/admin/dashboard", response["Location"].as_str());
+
+        let mut dashboard = surf::get(url(&app.address, "/admin/dashboard"))
+            .header("Cookie", session_cookie(&response))
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(200, dashboard.status());
+        assert!(dashboard
+            .body_string()
+            .await
+            .unwrap()
+            .contains("Welcome editor"));
+    }
+
+    #[rstest]
+    async fn should_reject_login_without_csrf_token(app: App) {
+        z2p::authentication::create_admin(&app.db_cfg, "editor", &PASSWORD.to_owned().into())
+            .await
+            .unwrap();
+        let (cookie, _) = login_page(&app.address).await;
+
+        let response = post_login(
+            &app.address,
+            &cookie,
+            format!("username=editor&password={}", PASSWORD),
+        )
+        .await;
+
+        assert_eq!(403, response.status());
+    }
+
+    /// The session cookie of a logged admin and the issue form.
+    async fn compose_page(app: &App) -> (String, String) {
+        z2p::authentication::create_admin(&app.db_cfg, "editor", &PASSWORD.to_owned().into())
+            .await
+            .unwrap();
+        let (cookie, token) = login_page(&app.address).await;
+        let login = post_login(
+            &app.address,
+            &cookie,
+            format!("username=editor&password={}&csrf_token={}", PASSWORD, token),
+        )
+        .await;
+        let cookie = session_cookie(&login);
+        let mut compose = surf::get(url(&app.address, "/admin/issues/new"))
+            .header("Cookie", cookie.as_str())
+            .await
+            .expect("Failed to execute request.");
+        let html = compose.body_string().await.unwrap();
+        (cookie, html)
+    }
+
+    fn idempotency_key(html: &str) -> String {
+        let start = html
+            .find(r#"name="idempotency_key" value=""#)
+            .expect("No idempotency key")
+            + r#"name="idempotency_key" value=""#.len();
+        html[start..].split('"').next().unwrap().to_owned()
+    }
+
+    async fn post_issue(app: &App, cookie: &str, body: String) -> Response {
+        surf::post(url(&app.address, "/admin/issues/new"))
+            .header("Cookie", cookie)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    #[rstest]
+    async fn should_store_the_published_issue(app: App) {
+        let (cookie, html) = compose_page(&app).await;
+
+        let response = post_issue(
+            &app,
+            &cookie,
+            format!(
+                "subject=Issue%20%231&html=%3Cp%3EHi%3C%2Fp%3E&text=Hi&csrf_token={}",
+                csrf_token(&html)
+            ),
+        )
+        .await;
+
+        assert_eq!(303, response.status());
+        assert_eq!("/admin/dashboard", response["Location"].as_str());
+        let issue = app
+            .db
+            .collection::<Document>("issues")
+            .find_one(None, None)
+            .await
+            .unwrap()
+            .expect("No issue stored");
+        assert_eq!(Ok("sent"), issue.get_str("status"));
+        assert_eq!(Ok("newsletter"), issue.get_str("list"));
+    }
+
+    #[rstest]
+    async fn should_publish_the_issue_form_once(app: App) {
+        let (cookie, html) = compose_page(&app).await;
+        let body = format!(
+            "subject=Issue%20%231&html=%3Cp%3EHi%3C%2Fp%3E&text=Hi&csrf_token={}&idempotency_key={}",
+            csrf_token(&html),
+            idempotency_key(&html)
+        );
+
+        let first = post_issue(&app, &cookie, body.clone()).await;
+        let again = post_issue(&app, &cookie, body).await;
+
+        assert_eq!(303, first.status());
+        assert_eq!(303, again.status());
+        let issues = app
+            .db
+            .collection::<Document>("issues")
+            .count_documents(None, None)
+            .await
+            .unwrap();
+        assert_eq!(1, issues);
+    }
+}
@@ -0,0 +1,209 @@
+use rstest::rstest;
+use std::sync::Arc;
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, App};
+
+mod analytics {
+    use super::*;
+
+    use chrono::Utc;
+    use mongodb::bson::{doc, Bson, Document};
+    use serde_json::{json, Value};
+
+    const TOKEN: &str = "admin-token";
+    const READERS: &[&str] = &["antonio@gmail.com", "maria@gmail.com", "luca@gmail.com"];
+
+    fn app(db_container: Arc<docker::Container>) -> App {
+        let mut cfg = configurations();
+        cfg.application.admin_token = Some(TOKEN.to_owned().into());
+        spawn_app(cfg, db_container)
+    }
+
+    async fn send(request: surf::RequestBuilder) -> (u16, Value) {
+        let mut response = request
+            .header("Authorization", format!("Bearer {}", TOKEN))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        let body = response.body_string().await.unwrap();
+        (
+            response.status().into(),
+            serde_json::from_str(&body).unwrap_or(Value::String(body)),
+        )
+    }
+
+    fn url(app: &App, path: &str) -> String {
+        format!("http://{}/admin/newsletter_issues{}", app.address, path)
+    }
+
+    async fn insert(app: &App, collection: &str, doc: Document) {
+        app.db
+            .collection::<Document>(collection)
+            .insert_one(doc, None)
+            .await
+            .unwrap();
+    }
+
+    /// A new issue of the `rust` list, in `status`: return its id.
+    async fn issue(app: &App, status: &str) -> String {
+        let (_, created) = send(surf::post(url(app, "")).body(json!({
+            "subject": "Rust news",
+            "html": "<p>Hi!</p>",
+            "text": "Hi!",
+            "author": "antonio",
+            "list": "rust",
+        })))
+        .await;
+        let id = created["id"].as_str().unwrap().to_owned();
+        if status != "draft" {
+            for next in &["in_review", "approved"] {
+                let request =
+                    surf::put(url(app, &format!("/{}/status", id))).body(json!({ "status": next }));
+                assert_eq!(200, send(request).await.0);
+            }
+        }
+        if status == "sent" {
+            let (status, _) = send(surf::post(url(app, &format!("/{}/publish", id)))).await;
+            assert_eq!(200, status);
+        }
+        id
+    }
+
+    /// Send an issue of `rust` to the readers, then: Antonio opens it twice and
+    /// clicks a link twice, Maria's link scanner opens it and she leaves the list,
+    /// Luca's mailbox bounces it.
+    async fn sent_issue(app: &App) -> String {
+        insert(app, "lists", doc! { "_id": "rust", "name": "Rust" }).await;
+        for email in READERS {
+            let subscriber = doc! {
+                "name": "Reader",
+                "email": *email,
+                "locale": "en",
+                "lists": [{ "list": "rust", "status": "subscribed" }],
+            };
+            insert(app, "subscriptions", subscriber).await;
+        }
+        let id = issue(app, "sent").await;
+        let queue = app.db.collection::<Document>("issue_delivery_queue");
+        for (email, status) in READERS.iter().zip(&["done", "done", "dead"]) {
+            queue
+                .update_one(
+                    doc! { "email.to": *email },
+                    doc! { "$set": { "status": *status } },
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+        let now = Utc::now();
+        let event = |kind: &str, email: &str, url: Option<&str>, bot: bool| {
+            doc! {
+                "kind": kind,
+                "issue_id": &id,
+                "email": email,
+                "url": url.map_or(Bson::Null, Bson::from),
+                "user_agent": "Mozilla/5.0",
+                "bot": bot,
+                "at": now,
+            }
+        };
+        let post = Some("https://example.com/post");
+        for event in [
+            event("open", READERS[0], None, false),
+            event("open", READERS[0], None, false),
+            event("click", READERS[0], post, false),
+            event("click", READERS[0], post, false),
+            event("open", READERS[1], None, true),
+            event("click", READERS[1], post, true),
+        ] {
+            insert(app, "tracking_events", event).await;
+        }
+        let change = doc! {
+            "email": READERS[1],
+            "field": "lists",
+            "from": "newsletter,rust",
+            "to": "newsletter",
+            "at": now,
+        };
+        insert(app, "preference_changes", change).await;
+        let bounce = doc! {
+            "email": READERS[2],
+            "type": "hard_bounce",
+            "occurred_at": now,
+            "received_at": now,
+        };
+        insert(app, "email_events", bounce).await;
+        id
+    }
+
+    #[rstest]
+    async fn sent_issue_should_have_its_numbers(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let id = sent_issue(&app).await;
+
+        let (status, report) = send(surf::get(url(&app, &format!("/{}/analytics", id)))).await;
+
+        assert_eq!(200, status);
+        assert_eq!("rust", report["list"]);
+        assert_eq!(3, report["recipients"]);
+        assert_eq!(2, report["delivered"]);
+        assert_eq!(1, report["failed"]);
+        assert_eq!(1, report["bounced"]);
+        assert_eq!(1, report["unique_opens"]);
+        assert_eq!(1, report["unique_clicks"]);
+        assert_eq!(1, report["unsubscribes"]);
+        assert_eq!(
+            json!([{ "url": "https://example.com/post", "clicks": 2, "unique_clicks": 1 }]),
+            report["top_links"]
+        );
+        let hours = report["hours"].as_array().unwrap();
+        assert_eq!(1, hours.len());
+        assert_eq!(2, hours[0]["opens"]);
+        assert_eq!(2, hours[0]["clicks"]);
+        let rollups = app
+            .db
+            .collection::<Document>("issue_analytics")
+            .count_documents(doc! { "_id": &id }, None)
+            .await
+            .unwrap();
+        assert_eq!(1, rollups);
+    }
+
+    #[rstest]
+    async fn numbers_should_be_exported_as_csv(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let id = sent_issue(&app).await;
+
+        let mut response = surf::get(url(&app, &format!("/{}/analytics?format=csv", id)))
+            .header("Authorization", format!("Bearer {}", TOKEN))
+            .await
+            .unwrap();
+
+        assert_eq!(200, response.status());
+        assert_eq!(
+            Some("text/csv; charset=utf-8"),
+            response
+                .header("Content-Type")
+                .map(|values| values.as_str())
+        );
+        let csv = response.body_string().await.unwrap();
+        assert!(csv.starts_with("metric,key,value\n"));
+        assert!(csv.contains("recipients,,3\n"));
+        assert!(csv.contains("link_clicks,https://example.com/post,2\n"));
+    }
+
+    #[rstest]
+    async fn unsent_issues_should_have_no_numbers(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        insert(&app, "lists", doc! { "_id": "rust", "name": "Rust" }).await;
+        let id = issue(&app, "draft").await;
+
+        let (draft, _) = send(surf::get(url(&app, &format!("/{}/analytics", id)))).await;
+        let (unknown, _) = send(surf::get(url(&app, "/5fd0a1b2c3d4e5f6a7b8c9d0/analytics"))).await;
+
+        assert_eq!(409, draft);
+        assert_eq!(404, unknown);
+    }
+}
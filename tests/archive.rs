@@ -0,0 +1,132 @@
+use rstest::rstest;
+use std::sync::Arc;
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, App};
+
+mod archive {
+    use super::*;
+
+    use serde_json::{json, Value};
+
+    const TOKEN: &str = "admin-token";
+
+    fn app(db_container: Arc<docker::Container>) -> App {
+        let mut cfg = configurations();
+        cfg.application.admin_token = Some(TOKEN.to_owned().into());
+        cfg.archive.page_size = 1;
+        cfg.archive.base_url = Some("https://news.example.com".to_owned());
+        spawn_app(cfg, db_container)
+    }
+
+    async fn admin(request: surf::RequestBuilder) -> Value {
+        let mut response = request
+            .header("Authorization", format!("Bearer {}", TOKEN))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        response.body_json().await.unwrap_or(Value::Null)
+    }
+
+    /// Create an issue, sent unless it's a `draft`: return its id.
+    async fn issue(app: &App, subject: &str, draft: bool) -> String {
+        let url = format!("http://{}/admin/newsletter_issues", app.address);
+        let created = admin(surf::post(&url).body(json!({
+            "subject": subject, "html": "<p>Tom & Jerry</p>", "text": "Hi!", "author": "antonio"
+        })))
+        .await;
+        let id = created["id"].as_str().unwrap().to_owned();
+        if draft {
+            return id;
+        }
+        for status in &["in_review", "approved"] {
+            let path = format!("{}/{}/status", url, id);
+            admin(surf::put(path).body(json!({ "status": status }))).await;
+        }
+        admin(surf::post(format!("{}/{}/publish", url, id))).await;
+        id
+    }
+
+    async fn get(app: &App, path: &str) -> surf::Response {
+        surf::get(format!("http://{}{}", app.address, path))
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    #[rstest]
+    async fn archive_should_list_sent_issues_only(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let first = issue(&app, "First issue", false).await;
+        let second = issue(&app, "Second issue", false).await;
+        let draft = issue(&app, "Work in progress", true).await;
+
+        let mut newest = get(&app, "/archive").await;
+        let mut oldest = get(&app, "/archive?page=2").await;
+
+        assert_eq!(200, newest.status());
+        let newest = newest.body_string().await.unwrap();
+        assert!(newest.contains(&format!("/archive/second-issue-{}", second)));
+        assert!(newest.contains("/archive?page=2"));
+        let oldest = oldest.body_string().await.unwrap();
+        assert!(oldest.contains(&format!("/archive/first-issue-{}", first)));
+        assert!(!oldest.contains("Work in progress"));
+        assert_eq!(404, get(&app, "/archive?page=3").await.status());
+
+        let mut page = get(&app, &format!("/archive/first-issue-{}", first)).await;
+        assert_eq!(200, page.status());
+        assert!(page
+            .body_string()
+            .await
+            .unwrap()
+            .contains("<p>Tom & Jerry</p>"));
+        let path = format!("/archive/work-in-progress-{}", draft);
+        assert_eq!(404, get(&app, &path).await.status());
+    }
+
+    #[rstest(path, content_type,
+        case::rss("/feed.rss", "application/rss+xml; charset=utf-8"),
+        case::atom("/feed.atom", "application/atom+xml; charset=utf-8"),
+    )]
+    async fn feeds_should_support_conditional_requests(
+        path: &str,
+        content_type: &str,
+        db_container: Arc<docker::Container>,
+    ) {
+        let app = app(db_container);
+        let id = issue(&app, "First issue", false).await;
+
+        let mut response = get(&app, path).await;
+
+        assert_eq!(200, response.status());
+        assert_eq!(
+            content_type,
+            response.header("Content-Type").unwrap().as_str()
+        );
+        let body = response.body_string().await.unwrap();
+        assert!(body.contains(&format!(
+            "https://news.example.com/archive/first-issue-{}",
+            id
+        )));
+        assert!(body.contains("&lt;p&gt;Tom &amp; Jerry&lt;/p&gt;"));
+        let etag = response.header("ETag").unwrap().as_str().to_owned();
+        let last_modified = response
+            .header("Last-Modified")
+            .unwrap()
+            .as_str()
+            .to_owned();
+        let url = format!("http://{}{}", app.address, path);
+        for (header, value) in &[
+            ("If-None-Match", etag.as_str()),
+            ("If-Modified-Since", last_modified.as_str()),
+        ] {
+            let response = surf::get(&url).header(*header, *value).await.unwrap();
+            assert_eq!(304, response.status(), "{}", header);
+        }
+        let response = surf::get(&url)
+            .header("If-None-Match", "\"stale\"")
+            .await
+            .unwrap();
+        assert_eq!(200, response.status());
+    }
+}
@@ -0,0 +1,108 @@
+use rstest::rstest;
+use std::sync::Arc;
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app};
+
+mod email_events {
+    use super::*;
+
+    use futures::TryStreamExt;
+    use hmac::{Hmac, Mac, NewMac};
+    use mongodb::bson::{doc, Document};
+    use sha2::Sha256;
+
+    const SECRET: &str = "webhook-secret";
+
+    fn sign(body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(SECRET.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn app(db_container: Arc<docker::Container>) -> utils::App {
+        let mut cfg = configurations();
+        cfg.email.webhook_secret = Some(SECRET.to_owned().into());
+        spawn_app(cfg, db_container)
+    }
+
+    async fn post_events(app: &utils::App, body: &str, signature: &str) -> surf::Response {
+        surf::post(format!("http://{}/webhooks/email-events", app.address))
+            .header("Content-Type", "application/json")
+            .header("X-Signature", signature)
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    #[rstest]
+    async fn should_suppress_hard_bounces_and_complaints(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let body = r#"[
+            {"type": "bounce", "bounce_type": "hard", "email": "Bounced@gmail.com"},
+            {"type": "bounce", "bounce_type": "soft", "email": "full@gmail.com"},
+            {"type": "complaint", "email": "angry@gmail.com"}
+        ]"#;
+
+        let response = post_events(&app, body, &sign(body)).await;
+
+        assert_eq!(204, response.status());
+        let mut suppressed: Vec<_> = app
+            .db
+            .collection::<Document>("suppressed_emails")
+            .find(None, None)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|d| d.get_str("_id").unwrap().to_owned())
+            .collect();
+        suppressed.sort();
+        assert_eq!(vec!["angry@gmail.com", "bounced@gmail.com"], suppressed);
+        assert_eq!(
+            3,
+            app.db
+                .collection::<Document>("email_events")
+                .count_documents(None, None)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[rstest]
+    async fn suppressed_address_should_not_subscribe_again(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let body = r#"{"type": "complaint", "email": "antonio@gmail.com"}"#;
+        post_events(&app, body, &sign(body)).await;
+
+        let response = surf::post(format!("http://{}/subscriptions", app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("name=Antonio&email=antonio%40gmail.com")
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        assert_eq!(422, response.status());
+        assert!(app
+            .db
+            .collection::<Document>("subscriptions")
+            .find_one(doc! { "email": "antonio@gmail.com" }, None)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[rstest]
+    async fn should_reject_unsigned_events(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let body = r#"{"type": "complaint", "email": "antonio@gmail.com"}"#;
+
+        let response = post_events(&app, body, "sha256=00").await;
+
+        assert_eq!(401, response.status());
+    }
+}
@@ -0,0 +1,63 @@
+use rstest::rstest;
+use std::{sync::Arc, time::Duration};
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, App};
+
+mod idempotency {
+    use super::*;
+
+    use mongodb::bson::{doc, Bson};
+
+    fn app(ttl: u64, db_container: Arc<docker::Container>) -> App {
+        let mut cfg = configurations();
+        cfg.idempotency.ttl = Duration::from_secs(ttl);
+        spawn_app(cfg, db_container)
+    }
+
+    async fn ttl_index(app: &App) -> Option<i64> {
+        let indexes = app
+            .db
+            .run_command(doc! { "listIndexes": "idempotency" }, None)
+            .await
+            .ok()?;
+        let index = indexes
+            .get_document("cursor")
+            .ok()?
+            .get_array("firstBatch")
+            .ok()?
+            .iter()
+            .filter_map(Bson::as_document)
+            .find(|index| index.get_str("name") == Ok("created_at_ttl"))?
+            .clone();
+        match index.get("expireAfterSeconds")? {
+            Bson::Int32(seconds) => Some(*seconds as i64),
+            Bson::Int64(seconds) => Some(*seconds),
+            _ => None,
+        }
+    }
+
+    /// The expiration of the TTL index once it's `expected`.
+    async fn settled(app: &App, expected: i64) -> Option<i64> {
+        let mut ttl = None;
+        for _ in 0..100 {
+            ttl = ttl_index(app).await;
+            if ttl == Some(expected) {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(50)).await;
+        }
+        ttl
+    }
+
+    #[rstest]
+    async fn ttl_index_should_follow_the_configured_ttl(db_container: Arc<docker::Container>) {
+        let first = app(60, db_container.clone());
+        assert_eq!(Some(60), settled(&first, 60).await);
+
+        let restarted = app(120, db_container);
+
+        assert_eq!(Some(120), settled(&restarted, 120).await);
+    }
+}
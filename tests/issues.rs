@@ -0,0 +1,128 @@
+use rstest::rstest;
+use std::sync::Arc;
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, App};
+
+mod issues {
+    use super::*;
+
+    use mongodb::bson::{doc, Document};
+    use serde_json::{json, Value};
+
+    const TOKEN: &str = "admin-token";
+
+    fn app(db_container: Arc<docker::Container>) -> App {
+        let mut cfg = configurations();
+        cfg.application.admin_token = Some(TOKEN.to_owned().into());
+        spawn_app(cfg, db_container)
+    }
+
+    async fn send(request: surf::RequestBuilder) -> (u16, Value) {
+        let mut response = request
+            .header("Authorization", format!("Bearer {}", TOKEN))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        let body = response.body_string().await.unwrap();
+        (
+            response.status().into(),
+            serde_json::from_str(&body).unwrap_or(Value::String(body)),
+        )
+    }
+
+    fn url(app: &App, path: &str) -> String {
+        format!("http://{}/admin/newsletter_issues{}", app.address, path)
+    }
+
+    fn edit(subject: &str, author: &str) -> Value {
+        json!({ "subject": subject, "html": "<p>Hi!</p>", "text": "Hi!", "author": author })
+    }
+
+    async fn set_status(app: &App, id: &str, status: &str) -> u16 {
+        let request =
+            surf::put(url(app, &format!("/{}/status", id))).body(json!({ "status": status }));
+        send(request).await.0
+    }
+
+    #[rstest]
+    async fn should_follow_the_workflow_up_to_publication(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        app.db
+            .collection::<Document>("subscriptions")
+            .insert_one(
+                doc! {
+                    "name": "Antonio",
+                    "email": "antonio@gmail.com",
+                    "locale": "en",
+                    "lists": [{ "list": "newsletter", "status": "subscribed" }],
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let (status, created) =
+            send(surf::post(url(&app, "")).body(edit("Issue #1", "antonio"))).await;
+        assert_eq!(201, status);
+        let id = created["id"].as_str().unwrap().to_owned();
+        let (status, revised) = send(
+            surf::put(url(&app, &format!("/{}", id))).body(edit("Issue #1 - fixed", "michele")),
+        )
+        .await;
+        assert_eq!(200, status);
+        assert_eq!(2, revised["revisions"].as_array().unwrap().len());
+        assert_eq!("michele", revised["revisions"][1]["author"]);
+
+        assert_eq!(409, set_status(&app, &id, "approved").await);
+        assert_eq!(200, set_status(&app, &id, "in_review").await);
+        assert_eq!(200, set_status(&app, &id, "approved").await);
+        let (status, published) = send(surf::post(url(&app, &format!("/{}/publish", id)))).await;
+
+        assert_eq!(200, status);
+        assert_eq!(1, published["queued"]);
+        let job = app
+            .db
+            .collection::<Document>("issue_delivery_queue")
+            .find_one(None, None)
+            .await
+            .expect("Cannot fetch delivery job")
+            .expect("No delivery job");
+        assert_eq!(
+            "Issue #1 - fixed",
+            job.get_document("email")
+                .unwrap()
+                .get_str("subject")
+                .unwrap()
+        );
+        let (_, issue) = send(surf::get(url(&app, &format!("/{}", id)))).await;
+        assert_eq!("sent", issue["status"]);
+    }
+
+    #[rstest]
+    async fn sent_issue_should_be_read_only(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let (_, created) = send(surf::post(url(&app, "")).body(edit("Issue #1", "antonio"))).await;
+        let id = created["id"].as_str().unwrap().to_owned();
+        set_status(&app, &id, "in_review").await;
+        set_status(&app, &id, "approved").await;
+        send(surf::post(url(&app, &format!("/{}/publish", id)))).await;
+
+        let (status, _) =
+            send(surf::put(url(&app, &format!("/{}", id))).body(edit("Too late", "antonio"))).await;
+        assert_eq!(409, status);
+        assert_eq!(409, set_status(&app, &id, "draft").await);
+        let (status, _) = send(surf::delete(url(&app, &format!("/{}", id)))).await;
+        assert_eq!(409, status);
+    }
+
+    #[rstest]
+    async fn should_reject_requests_without_the_admin_token(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+
+        let response = surf::get(url(&app, "")).await.unwrap();
+
+        assert_eq!(401, response.status());
+    }
+}
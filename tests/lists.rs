@@ -0,0 +1,172 @@
+use rstest::rstest;
+use std::sync::Arc;
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, App};
+
+mod lists {
+    use super::*;
+
+    use futures::TryStreamExt;
+    use mongodb::bson::{doc, Document};
+    use serde_json::{json, Value};
+
+    const TOKEN: &str = "admin-token";
+
+    fn app(db_container: Arc<docker::Container>) -> App {
+        let mut cfg = configurations();
+        cfg.application.admin_token = Some(TOKEN.to_owned().into());
+        spawn_app(cfg, db_container)
+    }
+
+    async fn admin(request: surf::RequestBuilder) -> (u16, Value) {
+        let mut response = request
+            .header("Authorization", format!("Bearer {}", TOKEN))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        let body = response.body_string().await.unwrap();
+        (
+            response.status().into(),
+            serde_json::from_str(&body).unwrap_or(Value::String(body)),
+        )
+    }
+
+    fn url(app: &App, path: &str) -> String {
+        format!("http://{}{}", app.address, path)
+    }
+
+    async fn subscribe(app: &App, body: &str) -> u16 {
+        surf::post(url(app, "/subscriptions"))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .status()
+            .into()
+    }
+
+    /// The emails queued for `to`, in no particular order.
+    async fn queued(app: &App, to: &str) -> Vec<Document> {
+        app.db
+            .collection::<Document>("issue_delivery_queue")
+            .find(doc! { "email.to": to }, None)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|job| job.get_document("email").unwrap().clone())
+            .collect()
+    }
+
+    #[rstest]
+    async fn should_manage_lists(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let rust = json!({ "slug": "rust", "name": "Rust weekly" });
+
+        let (status, created) =
+            admin(surf::post(url(&app, "/admin/lists")).body(rust.clone())).await;
+        assert_eq!(201, status);
+        assert_eq!("Rust weekly", created["name"]);
+        let (status, _) = admin(surf::post(url(&app, "/admin/lists")).body(rust)).await;
+        assert_eq!(409, status);
+        let invalid = json!({ "slug": "Rust Weekly", "name": "Rust weekly" });
+        let (status, _) = admin(surf::post(url(&app, "/admin/lists")).body(invalid)).await;
+        assert_eq!(422, status);
+
+        let change = json!({ "name": "Rust", "sender": "Rust <rust@example.com>" });
+        let (status, updated) =
+            admin(surf::put(url(&app, "/admin/lists/rust")).body(change.clone())).await;
+        assert_eq!(200, status);
+        assert_eq!("Rust <rust@example.com>", updated["sender"]);
+        let (status, _) = admin(surf::put(url(&app, "/admin/lists/go")).body(change)).await;
+        assert_eq!(404, status);
+
+        let (status, all) = admin(surf::get(url(&app, "/admin/lists"))).await;
+        assert_eq!(200, status);
+        assert_eq!(
+            vec!["newsletter", "rust"],
+            all.as_array()
+                .unwrap()
+                .iter()
+                .map(|list| list["slug"].as_str().unwrap())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[rstest]
+    async fn subscribers_should_get_the_issues_of_their_lists_only(
+        db_container: Arc<docker::Container>,
+    ) {
+        let app = app(db_container);
+        for list in &[
+            json!({ "slug": "rust", "name": "Rust", "sender": "Rust <rust@example.com>" }),
+            json!({ "slug": "go", "name": "Go" }),
+        ] {
+            admin(surf::post(url(&app, "/admin/lists")).body(list.clone())).await;
+        }
+
+        let both = "name=Antonio&email=antonio%40gmail.com&list=rust&list=go";
+        assert_eq!(200, subscribe(&app, both).await);
+        assert_eq!(
+            200,
+            subscribe(&app, "name=Michele&email=michele%40gmail.com&list=go").await
+        );
+        assert_eq!(
+            422,
+            subscribe(&app, "name=Anna&email=anna%40gmail.com&list=java").await
+        );
+        let subscribers = app
+            .db
+            .collection::<Document>("subscriptions")
+            .count_documents(None, None)
+            .await
+            .unwrap();
+        assert_eq!(2, subscribers);
+
+        let issue = json!({
+            "subject": "Rust 2021", "html": "<p>Hi!</p>", "text": "Hi!", "author": "antonio",
+            "list": "rust",
+        });
+        let issues = url(&app, "/admin/newsletter_issues");
+        let (_, created) = admin(surf::post(&issues).body(issue)).await;
+        let id = created["id"].as_str().unwrap().to_owned();
+        assert_eq!("rust", created["list"]);
+        for status in &["in_review", "approved"] {
+            let path = format!("{}/{}/status", issues, id);
+            admin(surf::put(path).body(json!({ "status": status }))).await;
+        }
+        let (status, published) = admin(surf::post(format!("{}/{}/publish", issues, id))).await;
+
+        assert_eq!(200, status);
+        assert_eq!(1, published["queued"]);
+        let issue = queued(&app, "antonio@gmail.com")
+            .await
+            .into_iter()
+            .find(|email| email.get_str("subject").ok() == Some("Rust 2021"))
+            .expect("No issue queued");
+        assert_eq!(Some("Rust <rust@example.com>"), issue.get_str("from").ok());
+        assert!(queued(&app, "michele@gmail.com")
+            .await
+            .iter()
+            .all(|email| email.get_str("subject").ok() != Some("Rust 2021")));
+    }
+
+    #[rstest]
+    async fn issues_should_be_written_for_existing_lists(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let issue = json!({
+            "subject": "Java news", "html": "<p>Hi!</p>", "text": "Hi!", "author": "antonio",
+            "list": "java",
+        });
+
+        let (status, _) =
+            admin(surf::post(url(&app, "/admin/newsletter_issues")).body(issue)).await;
+
+        assert_eq!(422, status);
+    }
+}
@@ -0,0 +1,55 @@
+use rstest::rstest;
+use std::{net::SocketAddr, sync::Arc};
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, App};
+use z2p::{
+    configuration::TelemetrySettings,
+    telemetry::{init_subscriber, subscriber_with_sink, MemorySink},
+};
+
+mod logs {
+
+    use super::*;
+
+    use surf::Response;
+
+    lazy_static::lazy_static! {
+        static ref LOGS: MemorySink = {
+            let sink = MemorySink::new();
+            let settings = TelemetrySettings::default();
+            init_subscriber(subscriber_with_sink("test", &settings, sink.clone()));
+            sink
+        };
+    }
+
+    /// Like `utils::app()` but every log line ends up in `LOGS`.
+    fn app(db_container: Arc<docker::Container>) -> App {
+        lazy_static::initialize(&LOGS);
+        spawn_app(configurations(), db_container)
+    }
+
+    async fn do_request(address: &SocketAddr, body: &str) -> Response {
+        surf::post(format!("http://{}/subscriptions", address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    #[rstest]
+    async fn subscriptions_should_not_log_the_email(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let valid = do_request(&app.address, "name=Antonio&email=antonio.logs%40gmail.com").await;
+        let invalid = do_request(&app.address, "name=Antonio&email=antonio.invalid").await;
+
+        assert_eq!(200, valid.status());
+        assert_eq!(400, invalid.status());
+        let logs = LOGS.contents();
+        assert!(logs.contains("Invalid subscription"), "{}", logs);
+        assert!(!logs.contains("antonio.logs@gmail.com"), "{}", logs);
+        assert!(!logs.contains("antonio.invalid"), "{}", logs);
+    }
+}
@@ -0,0 +1,228 @@
+use rstest::rstest;
+use std::{sync::Arc, time::Duration};
+
+pub mod utils;
+
+use utils::{
+    configurations, db_container, docker, replica_set_configurations, replica_set_container,
+    spawn_app, App,
+};
+
+mod outbox {
+    use super::*;
+
+    use chrono::Utc;
+    use mongodb::bson::{doc, Document};
+
+    /// Relaying quickly what the requests left behind.
+    fn app(db_container: Arc<docker::Container>) -> App {
+        let mut cfg = configurations();
+        cfg.outbox.poll_interval = Duration::from_millis(100);
+        cfg.outbox.lease = Duration::from_millis(200);
+        spawn_app(cfg, db_container)
+    }
+
+    /// An event saved by a request that died before relaying it.
+    fn left_behind(id: &str, email: &str, status: &str) -> Document {
+        left_behind_for(id, email, status, &[])
+    }
+
+    fn left_behind_for(id: &str, email: &str, status: &str, lists: &[&str]) -> Document {
+        let created_at = Utc::now() - chrono::Duration::minutes(5);
+        doc! {
+            "_id": id,
+            "event": {
+                "type": "subscribed",
+                "email": email,
+                "name": "Antonio",
+                "locale": "en",
+                "lists": lists,
+            },
+            "status": status,
+            "confirmed": status == "pending",
+            "attempts": 0,
+            "next_attempt_at": created_at,
+            "created_at": created_at,
+            "updated_at": created_at,
+        }
+    }
+
+    /// The outbox event `id` once it's no longer waiting.
+    async fn settled(app: &App, id: &str) -> Document {
+        for _ in 0..100 {
+            let event = app
+                .db
+                .collection::<Document>("outbox")
+                .find_one(doc! { "_id": id }, None)
+                .await
+                .unwrap()
+                .expect("No outbox event");
+            let status = event.get_str("status").unwrap();
+            if status != "pending" && status != "staged" && status != "in_progress" {
+                return event;
+            }
+            async_std::task::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("The outbox event was not relayed");
+    }
+
+    #[rstest]
+    async fn subscribing_should_relay_its_event(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+
+        let response = surf::post(format!("http://{}/subscriptions", app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("name=Antonio&email=antonio@gmail.com")
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(200, response.status());
+
+        let event = app
+            .db
+            .collection::<Document>("outbox")
+            .find_one(None, None)
+            .await
+            .unwrap()
+            .expect("No outbox event");
+        // Relayed by the request itself
+        assert_eq!("done", event.get_str("status").unwrap());
+        assert_eq!(
+            "antonio@gmail.com",
+            event
+                .get_document("event")
+                .unwrap()
+                .get_str("email")
+                .unwrap()
+        );
+    }
+
+    #[rstest]
+    async fn subscribing_should_relay_its_event_on_a_replica_set(
+        replica_set_container: Arc<docker::Container>,
+    ) {
+        let app = spawn_app(replica_set_configurations(), replica_set_container);
+
+        let response = surf::post(format!("http://{}/subscriptions", app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("name=Antonio&email=antonio@gmail.com")
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(200, response.status());
+
+        let subscriber = app
+            .db
+            .collection::<Document>("subscriptions")
+            .find_one(doc! { "email": "antonio@gmail.com" }, None)
+            .await
+            .unwrap();
+        assert!(subscriber.is_some());
+        let event = app
+            .db
+            .collection::<Document>("outbox")
+            .find_one(None, None)
+            .await
+            .unwrap()
+            .expect("No outbox event");
+        assert_eq!("done", event.get_str("status").unwrap());
+        let welcome = app
+            .db
+            .collection::<Document>("issue_delivery_queue")
+            .find_one(doc! { "email.to": "antonio@gmail.com" }, None)
+            .await
+            .unwrap();
+        assert!(welcome.is_some());
+    }
+
+    #[rstest]
+    async fn events_left_behind_should_be_relayed(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        app.db
+            .collection::<Document>("subscriptions")
+            .insert_one(
+                doc! { "email": "antonio@gmail.com", "name": "Antonio", "lists": [] },
+                None,
+            )
+            .await
+            .unwrap();
+        app.db
+            .collection::<Document>("outbox")
+            .insert_one(
+                left_behind("left-behind", "antonio@gmail.com", "pending"),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let event = settled(&app, "left-behind").await;
+
+        assert_eq!("done", event.get_str("status").unwrap());
+        let welcome = app
+            .db
+            .collection::<Document>("issue_delivery_queue")
+            .find_one(doc! { "email.to": "antonio@gmail.com" }, None)
+            .await
+            .unwrap();
+        assert!(welcome.is_some());
+    }
+
+    #[rstest]
+    async fn staged_events_without_their_write_should_be_discarded(
+        db_container: Arc<docker::Container>,
+    ) {
+        let app = app(db_container);
+        app.db
+            .collection::<Document>("outbox")
+            .insert_one(
+                left_behind("never-saved", "ghost@gmail.com", "staged"),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let event = settled(&app, "never-saved").await;
+
+        assert_eq!("discarded", event.get_str("status").unwrap());
+        let welcome = app
+            .db
+            .collection::<Document>("issue_delivery_queue")
+            .find_one(doc! { "email.to": "ghost@gmail.com" }, None)
+            .await
+            .unwrap();
+        assert!(welcome.is_none());
+    }
+
+    #[rstest]
+    async fn staged_events_should_be_discarded_if_their_lists_were_not_joined(
+        db_container: Arc<docker::Container>,
+    ) {
+        let app = app(db_container);
+        // Already there: the write that would have added them to the list failed
+        app.db
+            .collection::<Document>("subscriptions")
+            .insert_one(
+                doc! { "email": "antonio@gmail.com", "name": "Antonio", "lists": [] },
+                None,
+            )
+            .await
+            .unwrap();
+        app.db
+            .collection::<Document>("outbox")
+            .insert_one(
+                left_behind_for(
+                    "failed-join",
+                    "antonio@gmail.com",
+                    "staged",
+                    &["newsletter"],
+                ),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let event = settled(&app, "failed-join").await;
+
+        assert_eq!("discarded", event.get_str("status").unwrap());
+    }
+}
@@ -0,0 +1,158 @@
+use rstest::rstest;
+use std::sync::Arc;
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, App};
+
+mod preferences {
+    use super::*;
+
+    use mongodb::bson::{doc, Document};
+
+    fn app(db_container: Arc<docker::Container>) -> App {
+        let mut cfg = configurations();
+        cfg.preferences.base_url = Some("https://news.example.com".to_owned());
+        spawn_app(cfg, db_container)
+    }
+
+    fn url(app: &App, path: &str) -> String {
+        format!("http://{}{}", app.address, path)
+    }
+
+    async fn post_form(url: String, body: &str) -> (u16, String) {
+        let mut response = surf::post(url)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        let body = response.body_string().await.unwrap();
+        (response.status().into(), body)
+    }
+
+    async fn get(url: String) -> (u16, String) {
+        let mut response = surf::get(url)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        let body = response.body_string().await.unwrap();
+        (response.status().into(), body)
+    }
+
+    /// Subscribe Antonio and return the token of the link in his welcome email.
+    async fn subscribed(app: &App) -> String {
+        app.db
+            .collection::<Document>("lists")
+            .insert_one(doc! { "_id": "rust", "name": "Rust" }, None)
+            .await
+            .unwrap();
+        let (status, _) = post_form(
+            url(app, "/subscriptions"),
+            "name=Antonio&email=antonio%40gmail.com",
+        )
+        .await;
+        assert_eq!(200, status);
+        let welcome = app
+            .db
+            .collection::<Document>("issue_delivery_queue")
+            .find_one(doc! { "email.to": "antonio@gmail.com" }, None)
+            .await
+            .unwrap()
+            .expect("No welcome email queued");
+        let text = welcome
+            .get_document("email")
+            .unwrap()
+            .get_str("text")
+            .unwrap();
+        let start = text.find("token=").expect("No preferences link") + "token=".len();
+        text[start..].split_whitespace().next().unwrap().to_owned()
+    }
+
+    async fn subscriber(app: &App) -> Document {
+        app.db
+            .collection::<Document>("subscriptions")
+            .find_one(doc! { "email": "antonio@gmail.com" }, None)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn changes(app: &App) -> u64 {
+        app.db
+            .collection::<Document>("preference_changes")
+            .count_documents(doc! { "email": "antonio@gmail.com" }, None)
+            .await
+            .unwrap()
+    }
+
+    fn status_on(subscriber: &Document, list: &str) -> Option<String> {
+        subscriber
+            .get_array("lists")
+            .unwrap()
+            .iter()
+            .filter_map(|membership| membership.as_document())
+            .find(|membership| membership.get_str("list").ok() == Some(list))
+            .and_then(|membership| membership.get_str("status").ok())
+            .map(str::to_owned)
+    }
+
+    #[rstest]
+    async fn emailed_link_should_change_the_preferences(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let token = subscribed(&app).await;
+
+        let (status, page) = get(url(&app, &format!("/preferences?token={}", token))).await;
+        assert_eq!(200, status);
+        assert!(page.contains(r#"value="Antonio""#));
+
+        let form = format!(
+            "token={}&name=Antonio%20De%20Domenico&list=rust&frequency=weekly&pause_weeks=2",
+            token
+        );
+        let (status, page) = post_form(url(&app, "/preferences"), &form).await;
+
+        assert_eq!(200, status);
+        assert!(page.contains("Your preferences have been saved."));
+        let subscriber = subscriber(&app).await;
+        assert_eq!(Some("Antonio De Domenico"), subscriber.get_str("name").ok());
+        assert_eq!(Some("weekly"), subscriber.get_str("frequency").ok());
+        assert!(subscriber.get_datetime("paused_until").is_ok());
+        assert_eq!(
+            Some("subscribed".to_owned()),
+            status_on(&subscriber, "rust")
+        );
+        assert_eq!(
+            Some("unsubscribed".to_owned()),
+            status_on(&subscriber, "newsletter")
+        );
+        assert_eq!(4, changes(&app).await);
+    }
+
+    #[rstest]
+    async fn invalid_preferences_should_change_nothing(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let token = subscribed(&app).await;
+
+        let form = format!("token={}&name=Antonio&list=java&frequency=weekly", token);
+        let (status, page) = post_form(url(&app, "/preferences"), &form).await;
+
+        assert_eq!(422, status);
+        assert!(page.contains("There is no list called java."));
+        assert!(subscriber(&app).await.get_str("frequency").is_err());
+        assert_eq!(0, changes(&app).await);
+    }
+
+    #[rstest]
+    async fn forged_links_should_be_forbidden(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let token = subscribed(&app).await;
+        let forged = format!("{}x", token);
+
+        let (status, _) = get(url(&app, &format!("/preferences?token={}", forged))).await;
+        let (missing, _) = get(url(&app, "/preferences")).await;
+
+        assert_eq!(403, status);
+        assert_eq!(403, missing);
+    }
+}
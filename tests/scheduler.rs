@@ -0,0 +1,257 @@
+use rstest::rstest;
+use std::{sync::Arc, time::Duration};
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, App};
+
+mod scheduler {
+    use super::*;
+
+    use chrono::Utc;
+    use mongodb::bson::{doc, oid::ObjectId, Document};
+    use serde_json::{json, Value};
+    use z2p::configuration::{MissedSchedules, Settings};
+
+    const TOKEN: &str = "admin-token";
+
+    fn settings() -> Settings {
+        let mut cfg = configurations();
+        cfg.application.admin_token = Some(TOKEN.to_owned().into());
+        cfg.scheduler.enabled = true;
+        cfg.scheduler.tick = Duration::from_millis(100);
+        cfg.scheduler.lease = Duration::from_secs(1);
+        cfg
+    }
+
+    async fn send(request: surf::RequestBuilder) -> (u16, Value) {
+        let mut response = request
+            .header("Authorization", format!("Bearer {}", TOKEN))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        let body = response.body_string().await.unwrap();
+        (
+            response.status().into(),
+            serde_json::from_str(&body).unwrap_or(Value::String(body)),
+        )
+    }
+
+    fn url(app: &App, path: &str) -> String {
+        format!("http://{}/admin{}", app.address, path)
+    }
+
+    async fn approved_issue(app: &App) -> String {
+        let (_, created) = send(surf::post(url(app, "/newsletter_issues")).body(json!({
+            "subject": "Monday issue", "html": "<p>Hi!</p>", "text": "Hi!", "author": "antonio"
+        })))
+        .await;
+        let id = created["id"].as_str().unwrap().to_owned();
+        for status in &["in_review", "approved"] {
+            let path = format!("/newsletter_issues/{}/status", id);
+            send(surf::put(url(app, &path)).body(json!({ "status": status }))).await;
+        }
+        id
+    }
+
+    /// Wait till the only schedule is not pending anymore.
+    async fn closed_schedule(app: &App) -> Option<Document> {
+        for _ in 0..100 {
+            let schedule = app
+                .db
+                .collection::<Document>("schedules")
+                .find_one(doc! { "status": { "$ne": "pending" } }, None)
+                .await
+                .unwrap();
+            if schedule.is_some() {
+                return schedule;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        None
+    }
+
+    async fn issue_status(app: &App, id: &str) -> String {
+        let (_, issue) = send(surf::get(url(app, &format!("/newsletter_issues/{}", id)))).await;
+        issue["status"].as_str().unwrap().to_owned()
+    }
+
+    #[rstest]
+    async fn should_publish_due_schedules(db_container: Arc<docker::Container>) {
+        let app = spawn_app(settings(), db_container);
+        let issue = approved_issue(&app).await;
+
+        let (status, _) = send(surf::post(url(&app, "/schedules")).body(json!({
+            "issue_id": issue,
+            "publish_at": Utc::now() + chrono::Duration::milliseconds(500),
+        })))
+        .await;
+
+        assert_eq!(201, status);
+        let schedule = closed_schedule(&app).await.expect("Schedule never fired");
+        assert_eq!("published", schedule.get_str("status").unwrap());
+        assert_eq!("sent", issue_status(&app, &issue).await);
+    }
+
+    #[rstest]
+    async fn missed_schedules_should_follow_the_policy(db_container: Arc<docker::Container>) {
+        let mut cfg = settings();
+        cfg.scheduler.missed = MissedSchedules::Skip;
+        cfg.scheduler.grace = Duration::from_secs(60);
+        let app = spawn_app(cfg, db_container);
+        let issue = approved_issue(&app).await;
+        let yesterday = Utc::now() - chrono::Duration::days(1);
+
+        app.db
+            .collection::<Document>("schedules")
+            .insert_one(
+                doc! {
+                    "issue_id": &issue,
+                    "publish_at": yesterday,
+                    "status": "pending",
+                    "created_at": yesterday,
+                    "updated_at": yesterday,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let schedule = closed_schedule(&app).await.expect("Schedule never handled");
+        assert_eq!("missed", schedule.get_str("status").unwrap());
+        assert_eq!("approved", issue_status(&app, &issue).await);
+    }
+
+    #[rstest]
+    async fn should_reschedule_and_cancel_pending_schedules(db_container: Arc<docker::Container>) {
+        let app = spawn_app(settings(), db_container);
+        let issue = approved_issue(&app).await;
+        let (_, created) = send(surf::post(url(&app, "/schedules")).body(json!({
+            "issue_id": issue,
+            "publish_at": Utc::now() + chrono::Duration::hours(1),
+        })))
+        .await;
+        let id = created["id"].as_str().unwrap().to_owned();
+        let later = Utc::now() + chrono::Duration::hours(2);
+
+        let (status, _) = send(
+            surf::put(url(&app, &format!("/schedules/{}", id)))
+                .body(json!({ "publish_at": later })),
+        )
+        .await;
+        assert_eq!(204, status);
+        let schedule = app
+            .db
+            .collection::<Document>("schedules")
+            .find_one(doc! { "_id": ObjectId::parse_str(&id).unwrap() }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            later.timestamp_millis(),
+            schedule
+                .get_datetime("publish_at")
+                .unwrap()
+                .timestamp_millis()
+        );
+
+        let (status, _) = send(surf::delete(url(&app, &format!("/schedules/{}", id)))).await;
+        assert_eq!(204, status);
+        let (_, pending) = send(surf::get(url(&app, "/schedules"))).await;
+        assert_eq!(json!([]), pending);
+        let (status, _) = send(surf::delete(url(&app, &format!("/schedules/{}", id)))).await;
+        assert_eq!(404, status);
+    }
+
+    #[rstest]
+    async fn should_not_schedule_in_the_past(db_container: Arc<docker::Container>) {
+        let app = spawn_app(settings(), db_container);
+        let issue = approved_issue(&app).await;
+
+        let (status, _) = send(surf::post(url(&app, "/schedules")).body(json!({
+            "issue_id": issue,
+            "publish_at": Utc::now() - chrono::Duration::hours(1),
+        })))
+        .await;
+
+        assert_eq!(422, status);
+    }
+
+    async fn subscribe(app: &App, email: &str) {
+        let response = surf::post(format!("http://{}/subscriptions", app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(format!("name=Reader&email={}", email))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(200, response.status());
+    }
+
+    async fn queued(app: &App, issue: &str, to: &str) -> u64 {
+        app.db
+            .collection::<Document>("issue_delivery_queue")
+            .count_documents(doc! { "email.issue_id": issue, "email.to": to }, None)
+            .await
+            .unwrap()
+    }
+
+    #[rstest]
+    async fn should_resume_publications_left_sending(db_container: Arc<docker::Container>) {
+        let app = spawn_app(settings(), db_container);
+        let issue = approved_issue(&app).await;
+        subscribe(&app, "antonio@gmail.com").await;
+        subscribe(&app, "michele@gmail.com").await;
+        // An earlier attempt queued it for Antonio only, then failed
+        let now = Utc::now();
+        app.db
+            .collection::<Document>("issues")
+            .update_one(
+                doc! { "_id": ObjectId::parse_str(&issue).unwrap() },
+                doc! { "$set": { "status": "sending" } },
+                None,
+            )
+            .await
+            .unwrap();
+        app.db
+            .collection::<Document>("issue_delivery_queue")
+            .insert_one(
+                doc! {
+                    "email": {
+                        "to": "antonio@gmail.com",
+                        "subject": "Monday issue",
+                        "html": "<p>Hi!</p>",
+                        "text": "Hi!",
+                        "issue_id": &issue,
+                    },
+                    "status": "pending",
+                    "attempts": 0,
+                    "next_attempt_at": now,
+                    "updated_at": now,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        app.db
+            .collection::<Document>("schedules")
+            .insert_one(
+                doc! {
+                    "issue_id": &issue,
+                    "publish_at": now,
+                    "status": "pending",
+                    "created_at": now,
+                    "updated_at": now,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let schedule = closed_schedule(&app).await.expect("Schedule never fired");
+
+        assert_eq!("published", schedule.get_str("status").unwrap());
+        assert_eq!("sent", issue_status(&app, &issue).await);
+        assert_eq!(1, queued(&app, &issue, "antonio@gmail.com").await);
+        assert_eq!(1, queued(&app, &issue, "michele@gmail.com").await);
+    }
+}
@@ -0,0 +1,58 @@
+use rstest::rstest;
+use std::{sync::Arc, time::Duration};
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, smtp_sink, spawn_app};
+
+mod smtp {
+    use super::*;
+
+    use smtp_sink::SmtpSink;
+    use z2p::configuration::EmailTransport;
+
+    #[rstest]
+    async fn should_deliver_the_welcome_email_through_smtp(db_container: Arc<docker::Container>) {
+        let sink = SmtpSink::start().await;
+        let mut cfg = configurations();
+        cfg.delivery.worker = true;
+        cfg.delivery.poll_interval = Duration::from_millis(100);
+        cfg.email.transport = EmailTransport::Smtp;
+        cfg.email.smtp = Some(sink.settings());
+        let app = spawn_app(cfg, db_container);
+
+        let response = surf::post(format!("http://{}/subscriptions", app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("name=De%20Domenico&email=antonio_de_domenico%40gmail.com")
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(200, response.status());
+
+        let message = sink
+            .wait_message(Duration::from_secs(10))
+            .await
+            .expect("No message received");
+
+        assert_eq!(
+            Some((
+                smtp_sink::USERNAME.to_owned(),
+                smtp_sink::PASSWORD.to_owned()
+            )),
+            message.auth
+        );
+        assert_eq!(smtp_sink::SENDER, message.from);
+        assert_eq!(vec!["antonio_de_domenico@gmail.com"], message.to);
+        assert!(message.data.contains("Subject: Welcome!\r\n"));
+        assert!(message
+            .data
+            .contains("Content-Type: multipart/alternative;"));
+        assert!(message
+            .data
+            .contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(message
+            .data
+            .contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(message.data.contains("De Domenico"));
+    }
+}
@@ -1,9 +1,9 @@
 use rstest::rstest;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
 pub mod utils;
 
-use utils::app;
+use utils::{app, configurations, db_container, docker, spawn_app};
 
 mod subscribe {
 
@@ -11,6 +11,8 @@ mod subscribe {
 
     use super::*;
 
+    use futures::TryStreamExt;
+    use mongodb::bson::{doc, Document};
     use surf::Response;
 
     async fn do_request(address: &SocketAddr, body: &str) -> Response {
@@ -22,6 +24,16 @@ mod subscribe {
             .expect("Failed to execute request.")
     }
 
+    async fn do_request_with_key(address: &SocketAddr, body: &str, key: &str) -> Response {
+        surf::post(format!("http://{}/subscriptions", address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Idempotency-Key", key)
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     #[derive(Clone, Debug, Eq, PartialEq)]
     struct User {
         pub name: String,
@@ -48,7 +60,7 @@ mod subscribe {
         assert_eq!(200, response.status());
         let user = app
             .db
-            .collection("subscriptions")
+            .collection::<Document>("subscriptions")
             .find_one(None, None)
             .await
             .expect("Cannot fetch user");
@@ -62,6 +74,146 @@ mod subscribe {
         );
     }
 
+    #[rstest]
+    async fn should_lowercase_the_emails_saved_before(db_container: Arc<docker::Container>) {
+        let first = spawn_app(configurations(), db_container.clone());
+        let subscribers = first.db.collection::<Document>("subscriptions");
+        subscribers
+            .insert_one(
+                doc! { "name": "Antonio", "email": "Antonio.Case@Gmail.com" },
+                None,
+            )
+            .await
+            .expect("Cannot insert subscriber");
+
+        let restarted = spawn_app(configurations(), db_container);
+        let response = do_request(
+            &restarted.address,
+            "name=Antonio&email=antonio.case%40gmail.com",
+        )
+        .await;
+
+        assert_eq!(200, response.status());
+        let users = subscribers
+            .find(None, None)
+            .await
+            .expect("Cannot fetch users")
+            .try_collect::<Vec<Document>>()
+            .await
+            .expect("Cannot fetch users");
+        let emails: Vec<&str> = users
+            .iter()
+            .filter_map(|d| d.get_str("email").ok())
+            .collect();
+        assert_eq!(vec!["antonio.case@gmail.com"], emails);
+    }
+
+    #[rstest]
+    async fn should_enqueue_a_welcome_email(app: App) {
+        do_request(
+            &app.address,
+            "name=De%20Domenico&email=antonio_de_domenico%40gmail.com",
+        )
+        .await;
+
+        let job = app
+            .db
+            .collection::<Document>("issue_delivery_queue")
+            .find_one(None, None)
+            .await
+            .expect("Cannot fetch delivery job")
+            .expect("No delivery job");
+
+        assert_eq!("pending", job.get_str("status").unwrap());
+        assert_eq!(
+            "antonio_de_domenico@gmail.com",
+            job.get_document("email").unwrap().get_str("to").unwrap()
+        );
+    }
+
+    #[rstest]
+    async fn should_store_the_subscriber_language(app: App) {
+        let response = do_request(
+            &app.address,
+            "name=De%20Domenico&email=antonio_de_domenico%40gmail.com&lang=it",
+        )
+        .await;
+
+        assert_eq!(200, response.status());
+        assert_eq!("it", response["Content-Language"].as_str());
+        let subscriber = app
+            .db
+            .collection::<Document>("subscriptions")
+            .find_one(None, None)
+            .await
+            .expect("Cannot fetch user")
+            .expect("No subscriber");
+        assert_eq!("it", subscriber.get_str("locale").unwrap());
+        let job = app
+            .db
+            .collection::<Document>("issue_delivery_queue")
+            .find_one(None, None)
+            .await
+            .expect("Cannot fetch delivery job")
+            .expect("No delivery job");
+        assert_eq!(
+            "Benvenuto!",
+            job.get_document("email").unwrap().get_str("subject").unwrap()
+        );
+    }
+
+    #[rstest]
+    async fn should_subscribe_just_once_with_the_same_idempotency_key(app: App) {
+        let body = "name=De%20Domenico&email=antonio_de_domenico%40gmail.com";
+
+        let mut first = do_request_with_key(&app.address, body, "abc").await;
+        let mut replay = do_request_with_key(&app.address, body, "abc").await;
+
+        assert_eq!(200, first.status());
+        assert_eq!(200, replay.status());
+        assert_eq!(
+            first.body_bytes().await.unwrap(),
+            replay.body_bytes().await.unwrap()
+        );
+        let subscribers = app
+            .db
+            .collection::<Document>("subscriptions")
+            .count_documents(None, None)
+            .await
+            .expect("Cannot count subscribers");
+        assert_eq!(1, subscribers);
+    }
+
+    #[rstest]
+    async fn should_replay_errors_too(app: App) {
+        let first = do_request_with_key(&app.address, "name=De%20Domenico", "abc").await;
+        let replay = do_request_with_key(&app.address, "name=De%20Domenico", "abc").await;
+
+        assert_eq!(400, u16::from(first.status()));
+        assert_eq!(400, u16::from(replay.status()));
+    }
+
+    #[rstest]
+    async fn should_reject_the_same_idempotency_key_with_another_payload(app: App) {
+        let first = do_request_with_key(&app.address, "name=De%20Domenico", "abc").await;
+        let other = do_request_with_key(
+            &app.address,
+            "name=De%20Domenico&email=antonio_de_domenico%40gmail.com",
+            "abc",
+        )
+        .await;
+
+        assert_eq!(400, u16::from(first.status()));
+        assert_eq!(422, u16::from(other.status()));
+        let subscribers = app
+            .db
+            .collection::<Document>("subscriptions")
+            .count_documents(None, None)
+            .await
+            .expect("Cannot count subscribers");
+        assert_eq!(0, subscribers);
+    }
+
     #[rstest(
         body,
         case::missed_email("name=De%20Domenico"),
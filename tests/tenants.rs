@@ -0,0 +1,239 @@
+use rstest::rstest;
+use std::sync::Arc;
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, App};
+
+mod tenants {
+    use super::*;
+
+    use futures::TryStreamExt;
+    use mongodb::{
+        bson::{doc, Document},
+        Client, Database,
+    };
+    use serde_json::{json, Value};
+    use z2p::configuration::{LimitSettings, TenantSettings};
+
+    const TOKEN: &str = "admin-token";
+    const ACME_TOKEN: &str = "acme-token";
+    const ACME_HOST: &str = "news.acme.com";
+
+    /// `acme` has a host of its own and room for a single subscriber per list,
+    /// `globex` is reached through the path prefix.
+    fn app(db_container: Arc<docker::Container>) -> App {
+        let mut cfg = configurations();
+        cfg.application.admin_token = Some(TOKEN.to_owned().into());
+        cfg.preferences.base_url = Some("https://news.example.com".to_owned());
+        cfg.tenants.insert(
+            "acme".to_owned(),
+            TenantSettings {
+                hosts: vec![ACME_HOST.to_owned()],
+                admin_token: Some(ACME_TOKEN.to_owned().into()),
+                limits: LimitSettings {
+                    max_subscribers: Some(1),
+                    max_lists: None,
+                },
+                ..Default::default()
+            },
+        );
+        cfg.tenants.insert(
+            "globex".to_owned(),
+            TenantSettings {
+                base_url: Some("https://news.example.com/tenants/globex".to_owned()),
+                ..Default::default()
+            },
+        );
+        spawn_app(cfg, db_container)
+    }
+
+    fn url(app: &App, path: &str) -> String {
+        format!("http://{}{}", app.address, path)
+    }
+
+    /// Subscribe `email` through `path`, on `host` if given.
+    async fn subscribe(app: &App, host: Option<&str>, path: &str, email: &str) -> u16 {
+        let mut request = surf::post(url(app, path))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(format!("name=Reader&email={}", email));
+        if let Some(host) = host {
+            request = request.header("X-Forwarded-Host", host);
+        }
+        request
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .status()
+            .into()
+    }
+
+    async fn admin(request: surf::RequestBuilder, token: &str) -> (u16, Value) {
+        let mut response = request
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        let body = response.body_string().await.unwrap();
+        (
+            response.status().into(),
+            serde_json::from_str(&body).unwrap_or(Value::String(body)),
+        )
+    }
+
+    async fn tenant_db(app: &App, slug: &str) -> Database {
+        let options = app.db_cfg.client_options().await.unwrap();
+        Client::with_options(options)
+            .unwrap()
+            .database(&format!("{}_{}", app.db_cfg.name, slug))
+    }
+
+    /// The preferences token in the welcome email of `email`.
+    async fn preferences_token(db: &Database, email: &str) -> String {
+        let welcome = db
+            .collection::<Document>("issue_delivery_queue")
+            .find_one(doc! { "email.to": email }, None)
+            .await
+            .unwrap()
+            .expect("No welcome email queued");
+        let text = welcome
+            .get_document("email")
+            .unwrap()
+            .get_str("text")
+            .unwrap();
+        let start = text.find("token=").expect("No preferences link") + "token=".len();
+        text[start..].split_whitespace().next().unwrap().to_owned()
+    }
+
+    async fn page(url: String) -> (u16, String, Option<String>) {
+        let mut response = surf::get(url)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        let cookie = response
+            .header("Set-Cookie")
+            .map(|values| values.as_str().to_owned());
+        (
+            response.status().into(),
+            response.body_string().await.unwrap(),
+            cookie,
+        )
+    }
+
+    async fn subscribers(db: &Database) -> Vec<String> {
+        let mut emails: Vec<_> = db
+            .collection::<Document>("subscriptions")
+            .find(doc! {}, None)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|subscriber| subscriber.get_str("email").ok().map(str::to_owned))
+            .collect();
+        emails.sort();
+        emails
+    }
+
+    #[rstest]
+    async fn subscribers_should_stay_in_their_tenant(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+
+        let acme = subscribe(&app, Some(ACME_HOST), "/subscriptions", "ursula@acme.com").await;
+        let globex = subscribe(
+            &app,
+            None,
+            "/tenants/globex/subscriptions",
+            "hank@globex.com",
+        )
+        .await;
+        let default = subscribe(&app, None, "/subscriptions", "antonio@gmail.com").await;
+
+        assert_eq!((200, 200, 200), (acme, globex, default));
+        assert_eq!(
+            vec!["ursula@acme.com"],
+            subscribers(&tenant_db(&app, "acme").await).await
+        );
+        assert_eq!(
+            vec!["hank@globex.com"],
+            subscribers(&tenant_db(&app, "globex").await).await
+        );
+        assert_eq!(vec!["antonio@gmail.com"], subscribers(&app.db).await);
+    }
+
+    #[rstest]
+    async fn admin_tokens_should_belong_to_their_tenant(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let acme_lists = url(&app, "/tenants/acme/admin/lists");
+        let rust = json!({ "slug": "rust", "name": "Rust" });
+
+        let (created, _) = admin(surf::post(&acme_lists).body(rust), ACME_TOKEN).await;
+        let (foreign, _) = admin(surf::get(&acme_lists), TOKEN).await;
+        let (acme, lists) = admin(surf::get(&acme_lists), ACME_TOKEN).await;
+        let (default, default_lists) = admin(surf::get(url(&app, "/admin/lists")), TOKEN).await;
+        let (globex, _) = admin(surf::get(url(&app, "/tenants/globex/admin/lists")), TOKEN).await;
+
+        assert_eq!(201, created);
+        assert_eq!(401, foreign);
+        assert_eq!(200, acme);
+        assert!(lists
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|list| list["slug"] == "rust"));
+        assert_eq!(200, default);
+        assert!(!default_lists
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|list| list["slug"] == "rust"));
+        // No token of its own: its admin endpoints are disabled
+        assert_eq!(404, globex);
+    }
+
+    #[rstest]
+    async fn tenant_limits_should_be_enforced(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+
+        let first = subscribe(&app, Some(ACME_HOST), "/subscriptions", "ursula@acme.com").await;
+        let again = subscribe(&app, Some(ACME_HOST), "/subscriptions", "ursula@acme.com").await;
+        let second = subscribe(&app, Some(ACME_HOST), "/subscriptions", "wile@acme.com").await;
+        let default = subscribe(&app, None, "/subscriptions", "wile@acme.com").await;
+
+        assert_eq!(200, first);
+        assert_eq!(200, again);
+        assert_eq!(403, second);
+        assert_eq!(200, default);
+    }
+
+    #[rstest]
+    async fn pages_should_stay_under_the_tenant_prefix(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        let subscribed = subscribe(
+            &app,
+            None,
+            "/tenants/globex/subscriptions",
+            "hank@globex.com",
+        )
+        .await;
+        assert_eq!(200, subscribed);
+        let token = preferences_token(&tenant_db(&app, "globex").await, "hank@globex.com").await;
+
+        let (status, preferences, _) = page(url(
+            &app,
+            &format!("/tenants/globex/preferences?token={}", token),
+        ))
+        .await;
+        let (foreign, _, _) = page(url(&app, &format!("/preferences?token={}", token))).await;
+        let (login, login_form, cookie) = page(url(&app, "/tenants/globex/admin/login")).await;
+
+        assert_eq!(200, status);
+        assert!(preferences.contains(r#"action="/tenants/globex/preferences""#));
+        // Neither a subscriber nor a valid signature there
+        assert_eq!(403, foreign);
+        assert_eq!(200, login);
+        assert!(login_form.contains(r#"action="/tenants/globex/admin/login""#));
+        assert!(cookie.unwrap().starts_with("z2p.sid.globex="));
+    }
+}
@@ -0,0 +1,195 @@
+use rstest::rstest;
+use std::sync::Arc;
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, App};
+
+mod tracking {
+    use super::*;
+
+    use mongodb::bson::{doc, Document};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    const TOKEN: &str = "admin-token";
+
+    fn app(db_container: Arc<docker::Container>) -> App {
+        let mut cfg = configurations();
+        cfg.application.admin_token = Some(TOKEN.to_owned().into());
+        cfg.tracking.base_url = Some("https://news.example.com".to_owned());
+        spawn_app(cfg, db_container)
+    }
+
+    async fn send(request: surf::RequestBuilder) -> (u16, Value) {
+        let mut response = request
+            .header("Authorization", format!("Bearer {}", TOKEN))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        let body = response.body_string().await.unwrap();
+        (
+            response.status().into(),
+            serde_json::from_str(&body).unwrap_or(Value::String(body)),
+        )
+    }
+
+    fn url(app: &App, path: &str) -> String {
+        format!("http://{}{}", app.address, path)
+    }
+
+    /// Antonio reads both lists, but only `rust` tracks opens and clicks.
+    async fn lists(app: &App) {
+        app.db
+            .collection::<Document>("lists")
+            .insert_one(
+                doc! { "_id": "rust", "name": "Rust", "tracking": true },
+                None,
+            )
+            .await
+            .unwrap();
+        app.db
+            .collection::<Document>("subscriptions")
+            .insert_one(
+                doc! {
+                    "name": "Antonio",
+                    "email": "antonio@gmail.com",
+                    "locale": "en",
+                    "lists": [
+                        { "list": "newsletter", "status": "subscribed" },
+                        { "list": "rust", "status": "subscribed" },
+                    ],
+                },
+                None,
+            )
+            .await
+            .unwrap();
+    }
+
+    /// Publish an issue on `list` and return the html Antonio gets.
+    async fn published(app: &App, list: &str) -> String {
+        let issues = |path: &str| url(app, &format!("/admin/newsletter_issues{}", path));
+        let (status, created) = send(surf::post(issues("")).body(json!({
+            "subject": format!("News from {}", list),
+            "html": r#"<p>Read <a href="https://example.com/post?id=1">the post</a></p>"#,
+            "text": "Read the post",
+            "author": "antonio",
+            "list": list,
+        })))
+        .await;
+        assert_eq!(201, status);
+        let id = created["id"].as_str().unwrap().to_owned();
+        for status in &["in_review", "approved"] {
+            let request =
+                surf::put(issues(&format!("/{}/status", id))).body(json!({ "status": status }));
+            assert_eq!(200, send(request).await.0);
+        }
+        let (status, _) = send(surf::post(issues(&format!("/{}/publish", id)))).await;
+        assert_eq!(200, status);
+        let job = app
+            .db
+            .collection::<Document>("issue_delivery_queue")
+            .find_one(
+                doc! { "email.subject": format!("News from {}", list) },
+                None,
+            )
+            .await
+            .unwrap()
+            .expect("No delivery job");
+        job.get_document("email")
+            .unwrap()
+            .get_str("html")
+            .unwrap()
+            .to_owned()
+    }
+
+    /// The path of the first tracking link starting with `prefix`.
+    fn link(html: &str, prefix: &str) -> String {
+        let start = html.find(prefix).expect("No tracking link");
+        let path = &html[start + "https://news.example.com".len()..];
+        path[..path.find('"').unwrap()].to_owned()
+    }
+
+    /// Wait till an event of `kind` is recorded.
+    async fn event(app: &App, kind: &str) -> Option<Document> {
+        for _ in 0..100 {
+            let event = app
+                .db
+                .collection::<Document>("tracking_events")
+                .find_one(doc! { "kind": kind }, None)
+                .await
+                .unwrap();
+            if event.is_some() {
+                return event;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        None
+    }
+
+    #[rstest]
+    async fn clicks_and_opens_should_be_recorded(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        lists(&app).await;
+        let html = published(&app, "rust").await;
+
+        let click = link(&html, "https://news.example.com/t/c/");
+        assert_eq!(54, click.trim_start_matches("/t/c/").len());
+        let response = surf::get(url(&app, &click))
+            .header("User-Agent", "Mozilla/5.0 Thunderbird/78.6.0")
+            .await
+            .unwrap();
+        assert_eq!(302, response.status());
+        assert_eq!(
+            Some("https://example.com/post?id=1"),
+            response.header("Location").map(|values| values.as_str())
+        );
+        let open = link(&html, "https://news.example.com/t/o/");
+        let response = surf::get(url(&app, &open))
+            .header("User-Agent", "curl/7.68.0")
+            .await
+            .unwrap();
+        assert_eq!(200, response.status());
+        assert_eq!(
+            Some("image/gif"),
+            response
+                .header("Content-Type")
+                .map(|values| values.as_str())
+        );
+
+        let click = event(&app, "click").await.expect("No click recorded");
+        assert_eq!(Some("antonio@gmail.com"), click.get_str("email").ok());
+        assert_eq!(
+            Some("https://example.com/post?id=1"),
+            click.get_str("url").ok()
+        );
+        assert_eq!(Some(false), click.get_bool("bot").ok());
+        let open = event(&app, "open").await.expect("No open recorded");
+        assert_eq!(Some("curl/7.68.0"), open.get_str("user_agent").ok());
+        assert_eq!(Some(true), open.get_bool("bot").ok());
+    }
+
+    #[rstest]
+    async fn lists_without_tracking_should_keep_their_links(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        lists(&app).await;
+
+        let html = published(&app, "newsletter").await;
+
+        assert!(html.contains(r#"<a href="https://example.com/post?id=1">"#));
+        assert!(!html.contains("/t/o/"));
+    }
+
+    #[rstest]
+    async fn forged_links_should_not_redirect(db_container: Arc<docker::Container>) {
+        let app = app(db_container);
+        lists(&app).await;
+        let html = published(&app, "rust").await;
+        let click = link(&html, "https://news.example.com/t/c/");
+
+        let response = surf::get(url(&app, &format!("{}x", click))).await.unwrap();
+
+        assert_eq!(404, response.status());
+        assert!(response.header("Location").is_none());
+    }
+}
@@ -6,12 +6,24 @@ use std::{
 
 use async_std::net::TcpListener;
 use chrono::{DateTime, Utc};
-use mongodb::{bson::doc, options::ClientOptions, Client, Database};
+use mongodb::{
+    bson::{doc, Document},
+    options::ClientOptions,
+    Client, Database,
+};
 use rstest::fixture;
 use z2p::{
-    configuration::DatabaseSettings, run, telemetry::get_subscriber, telemetry::init_subscriber,
+    configuration::{DatabaseSettings, Settings, TelemetrySettings},
+    run,
+    telemetry::{get_subscriber, init_subscriber},
 };
 
+/// Not every test uses it
+#[allow(dead_code)]
+pub mod smtp_sink;
+#[allow(dead_code)]
+pub mod webhook_receiver;
+
 pub struct App {
     pub address: SocketAddr,
     pub db: Database,
@@ -26,7 +38,7 @@ fn start_db_container(
     timeout: Duration,
 ) -> docker::DockerResult<docker::Container> {
     let opts = docker::DockerOptions::default()
-        .name(&name)
+        .name(name)
         .env("MONGO_INITDB_ROOT_USERNAME".to_owned(), "mongo".to_owned())
         .env(
             "MONGO_INITDB_ROOT_PASSWORD".to_owned(),
@@ -50,6 +62,82 @@ fn start_db_container(
 }
 
 const DEFAULT_DB_HOST_PORT: u16 = 37017;
+/// Mapped on the same port: the member is reached at the same address from
+/// inside the container and from the tests
+const REPLICA_SET_HOST_PORT: u16 = 37018;
+const REPLICA_SET: &str = "rs0";
+
+/// A single member replica set. It has no authentication: that would need a key file.
+fn start_replica_set_container(timeout: Duration) -> docker::DockerResult<docker::Container> {
+    let port = REPLICA_SET_HOST_PORT.to_string();
+    let opts = docker::DockerOptions::default()
+        .name(&format!("z2p_tests_{}", REPLICA_SET_HOST_PORT))
+        .port(REPLICA_SET_HOST_PORT, REPLICA_SET_HOST_PORT)
+        .command(&["--replSet", REPLICA_SET, "--port", &port, "--bind_ip_all"]);
+    let container = docker::Container::run("mongo", Some(&opts))?;
+    async_std::task::block_on(initiate_replica_set(timeout));
+    Ok(container)
+}
+
+async fn initiate_replica_set(timeout: Duration) {
+    let url = format!(
+        "mongodb://127.0.0.1:{}/?directConnection=true",
+        REPLICA_SET_HOST_PORT
+    );
+    let client =
+        Client::with_options(mongodb_client_options(&url).await).expect("Cannot create db client");
+    let admin = client.database("admin");
+    let config = doc! {
+        "_id": REPLICA_SET,
+        "members": [{ "_id": 0, "host": format!("127.0.0.1:{}", REPLICA_SET_HOST_PORT) }],
+    };
+    let end = std::time::Instant::now() + timeout;
+    let mut initiated = false;
+    while std::time::Instant::now() < end {
+        if !initiated {
+            initiated = admin
+                .run_command(doc! { "replSetInitiate": config.clone() }, None)
+                .await
+                .is_ok();
+        } else if let Ok(reply) = admin.run_command(doc! { "isMaster": 1 }, None).await {
+            if reply.get_bool("ismaster").unwrap_or(false) {
+                return;
+            }
+        }
+        async_std::task::sleep(Duration::from_millis(200)).await;
+    }
+    panic!("The replica set has no primary");
+}
+
+/// Only for the tests that need what a standalone server lacks
+#[allow(dead_code)]
+#[fixture]
+pub fn replica_set_container() -> Arc<docker::Container> {
+    lazy_static::lazy_static! {
+        static ref RSREF: Mutex<Weak<docker::Container>> = Mutex::new(Weak::new());
+    };
+    let mut weak = RSREF.lock().unwrap();
+    if let Some(strong) = weak.upgrade() {
+        return strong;
+    }
+    let strong = Arc::new(start_replica_set_container(Duration::from_secs(30)).unwrap());
+    *weak = Arc::downgrade(&strong);
+    strong
+}
+
+/// The settings of the tests on the replica set.
+#[allow(dead_code)]
+pub fn replica_set_configurations() -> Settings {
+    let mut configurations = configurations();
+    configurations.database.uri = Some(
+        format!(
+            "mongodb://127.0.0.1:{}/?replicaSet={}",
+            REPLICA_SET_HOST_PORT, REPLICA_SET
+        )
+        .into(),
+    );
+    configurations
+}
 
 #[fixture]
 pub fn db_container() -> Arc<docker::Container> {
@@ -80,20 +168,30 @@ pub fn db_container() -> Arc<docker::Container> {
     }
 }
 
+/// Not for the tests that capture the logs themselves
+#[allow(dead_code)]
 #[fixture]
 pub fn tracing() {
     lazy_static::lazy_static! {
         static ref SUBSCRIBER: () = {
             let filter = if std::env::var("TEST_LOG").is_ok() { "debug" } else { "" };
-            let subscriber = get_subscriber("test", filter);
+            let settings = TelemetrySettings { level: filter.to_owned(), ..Default::default() };
+            let (subscriber, guard) = get_subscriber("test", &settings);
+            std::mem::forget(guard);
             init_subscriber(subscriber);
         };
     }
     *SUBSCRIBER
 }
 
+#[allow(dead_code)]
 #[fixture(cfg=configurations())]
-pub fn app(cfg: DatabaseSettings, db_container: Arc<docker::Container>, _tracing: ()) -> App {
+pub fn app(cfg: Settings, db_container: Arc<docker::Container>, _tracing: ()) -> App {
+    spawn_app(cfg, db_container)
+}
+
+/// Start the application with `cfg`: for tests that need settings known at run time.
+pub fn spawn_app(cfg: Settings, db_container: Arc<docker::Container>) -> App {
     let listener = async_std::task::block_on(async {
         TcpListener::bind("127.0.0.1:0")
             .await
@@ -101,15 +199,14 @@ pub fn app(cfg: DatabaseSettings, db_container: Arc<docker::Container>, _tracing
     });
 
     let address = listener.local_addr().expect("Cannot get server address");
-    let c = cfg.clone();
-    async_std::task::block_on(create_db(&c));
-    let c = cfg.clone();
-    async_std::task::spawn(async { run(c).await.listen(listener).await });
-    let db = async_std::task::block_on(db(&cfg));
+    async_std::task::block_on(create_db(&cfg.database));
+    let db_cfg = cfg.database.clone();
+    async_std::task::spawn(async { run(cfg).await.listen(listener).await });
+    let db = async_std::task::block_on(db(&db_cfg));
     App {
         address,
         db,
-        db_cfg: cfg,
+        db_cfg,
         db_container,
     }
 }
@@ -139,16 +236,21 @@ fn sanitize_db_name(name: impl AsRef<str>) -> String {
     name
 }
 
-pub fn configurations() -> DatabaseSettings {
+pub fn configurations() -> Settings {
     let mut configurations =
         z2p::configuration::get_configuration().expect("Failed to read configurations");
     configurations.database.name = sanitize_db_name(testname());
     configurations.database.port = DEFAULT_DB_HOST_PORT;
-    configurations.database
+    // Tests look at the queue: nobody should consume it
+    configurations.delivery.worker = false;
+    configurations.scheduler.enabled = false;
+    configurations.application.secret_key =
+        Some("a-test-secret-key-of-at-least-32-bytes".to_owned().into());
+    configurations
 }
 
 async fn mongodb_client_options(url: &str) -> ClientOptions {
-    let mut client_options = ClientOptions::parse(&url)
+    let mut client_options = ClientOptions::parse(url)
         .await
         .expect("Cannot parse db connection string");
     client_options.server_selection_timeout = Some(Duration::from_millis(5000));
@@ -158,17 +260,13 @@ async fn mongodb_client_options(url: &str) -> ClientOptions {
 }
 
 async fn create_db(cfg: &DatabaseSettings) {
-    let url = format!(
-        "mongodb://{}:{}@{}:{}",
-        cfg.username, cfg.password, cfg.host, cfg.port
-    );
-    let mut client_options = mongodb_client_options(&url).await;
+    let mut client_options = mongodb_client_options(cfg.connection_string().expose()).await;
     client_options.app_name = Some("CreateDb".to_string());
 
     let client = Client::with_options(client_options).expect("Cannot create db client");
     let db = client.database(&cfg.name);
 
-    let collection = db.collection("test_entry__");
+    let collection = db.collection::<Document>("test_entry__");
 
     let doc = doc! { "test_name": testname(), "created": now() };
 
@@ -180,7 +278,7 @@ async fn create_db(cfg: &DatabaseSettings) {
 }
 
 async fn db(cfg: &DatabaseSettings) -> Database {
-    let mut client_options = mongodb_client_options(&cfg.connection_string()).await;
+    let mut client_options = mongodb_client_options(cfg.connection_string().expose()).await;
     client_options.app_name = Some(testname());
 
     Client::with_options(client_options)
@@ -230,6 +328,8 @@ pub mod docker {
         name: Option<String>,
         envs: HashMap<String, String>,
         ports: HashMap<u16, u16>,
+        /// Passed to the image entrypoint
+        command: Vec<String>,
     }
 
     impl DockerOptions {
@@ -253,15 +353,20 @@ pub mod docker {
             self
         }
 
+        pub fn command(mut self, args: &[&str]) -> Self {
+            self.command = args.iter().map(|arg| arg.to_string()).collect();
+            self
+        }
+
         fn add_args(&self, mut cmd: Command) -> Command {
             if let Some(name) = &self.name {
-                cmd.args(&["--name", name]);
+                cmd.args(["--name", name]);
             }
             for (k, v) in &self.envs {
-                cmd.args(&["-e", &format!("{}={}", k, v)]);
+                cmd.args(["-e", &format!("{}={}", k, v)]);
             }
             for (f, t) in &self.ports {
-                cmd.args(&["-p", &format!("{}:{}", f, t)]);
+                cmd.args(["-p", &format!("{}:{}", f, t)]);
             }
             cmd
         }
@@ -277,6 +382,9 @@ pub mod docker {
             let image = image.as_ref();
             let mut cmd = Self::docker_run(options);
             cmd.arg("-d").arg(image);
+            if let Some(opts) = options {
+                cmd.args(&opts.command);
+            }
             cmd.output().map_err(|e| e.into()).and_then(|out| {
                 if out.status.success() {
                     Ok(Self {
@@ -314,14 +422,14 @@ pub mod docker {
     pub fn running_container(name: &str) -> DockerResult<Option<Container>> {
         let out = Command::new("docker")
             .arg("ps")
-            .args(&["--filter", &format!("name={}", name)])
+            .args(["--filter", &format!("name={}", name)])
             .arg("-q")
             .output()?;
         if out.status.code() != Some(0) {
             return Err(DockerError::CmdErr(out));
         }
         let id = String::from_utf8_lossy(&out.stdout).trim().to_owned();
-        if id.len() > 0 {
+        if !id.is_empty() {
             Ok(Some(Container { id }))
         } else {
             Ok(None)
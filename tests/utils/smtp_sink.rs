@@ -0,0 +1,146 @@
+//! An in-process SMTP server that accepts every message and keeps it, with the
+//! credentials it was sent with.
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_std::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+};
+use z2p::configuration::{SmtpSettings, SmtpTls};
+
+pub const USERNAME: &str = "newsletter";
+pub const PASSWORD: &str = "smtp-password";
+pub const SENDER: &str = "newsletter@example.com";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Received {
+    /// The decoded `AUTH PLAIN` credentials
+    pub auth: Option<(String, String)>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Without the dot stuffing
+    pub data: String,
+}
+
+pub struct SmtpSink {
+    pub address: SocketAddr,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+impl SmtpSink {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Cannot bind SMTP sink");
+        let address = listener.local_addr().expect("Cannot get SMTP sink address");
+        let received: Arc<Mutex<Vec<Received>>> = Default::default();
+        let messages = received.clone();
+        async_std::task::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(Ok(stream)) = incoming.next().await {
+                let messages = messages.clone();
+                async_std::task::spawn(async move {
+                    let _ = session(stream, messages).await;
+                });
+            }
+        });
+        Self { address, received }
+    }
+
+    /// Plain text, authenticated.
+    pub fn settings(&self) -> SmtpSettings {
+        SmtpSettings {
+            host: self.address.ip().to_string(),
+            port: Some(self.address.port()),
+            tls: SmtpTls::None,
+            sender: SENDER.to_owned(),
+            username: Some(USERNAME.to_owned()),
+            password: Some(PASSWORD.to_owned().into()),
+            auth: None,
+            pool_size: 1,
+            timeout: None,
+        }
+    }
+
+    pub async fn wait_message(&self, timeout: Duration) -> Option<Received> {
+        let end = Instant::now() + timeout;
+        while Instant::now() < end {
+            if let Some(message) = self.received.lock().unwrap().first().cloned() {
+                return Some(message);
+            }
+            async_std::task::sleep(Duration::from_millis(50)).await;
+        }
+        None
+    }
+}
+
+async fn session(stream: TcpStream, received: Arc<Mutex<Vec<Received>>>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = stream;
+    writer.write_all(b"220 sink ESMTP\r\n").await?;
+    let mut auth = None;
+    let mut message = Received::default();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let command = line.trim_end();
+        let upper = command.to_uppercase();
+        let reply = if upper.starts_with("EHLO") {
+            "250-sink\r\n250-AUTH PLAIN LOGIN\r\n250 8BITMIME\r\n"
+        } else if upper.starts_with("AUTH PLAIN ") {
+            let decoded = base64::decode(&command["AUTH PLAIN ".len()..]).unwrap_or_default();
+            let decoded = String::from_utf8_lossy(&decoded).into_owned();
+            let mut parts = decoded.split('\0').skip(1);
+            auth = match (parts.next(), parts.next()) {
+                (Some(username), Some(password)) => {
+                    Some((username.to_owned(), password.to_owned()))
+                }
+                _ => None,
+            };
+            "235 Authenticated\r\n"
+        } else if upper.starts_with("MAIL FROM:") {
+            message.from = address(&command["MAIL FROM:".len()..]);
+            "250 OK\r\n"
+        } else if upper.starts_with("RCPT TO:") {
+            message.to.push(address(&command["RCPT TO:".len()..]));
+            "250 OK\r\n"
+        } else if upper == "DATA" {
+            writer.write_all(b"354 Go ahead\r\n").await?;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await? == 0 {
+                    return Ok(());
+                }
+                if line == ".\r\n" {
+                    break;
+                }
+                message
+                    .data
+                    .push_str(line.strip_prefix('.').unwrap_or(&line));
+            }
+            message.auth = auth.clone();
+            received.lock().unwrap().push(std::mem::take(&mut message));
+            "250 Queued\r\n"
+        } else if upper == "RSET" {
+            message = Received::default();
+            "250 OK\r\n"
+        } else if upper == "QUIT" {
+            writer.write_all(b"221 Bye\r\n").await?;
+            return Ok(());
+        } else {
+            "502 Not implemented\r\n"
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+}
+
+fn address(argument: &str) -> String {
+    argument.trim().trim_matches(&['<', '>'][..]).to_owned()
+}
@@ -0,0 +1,111 @@
+//! An in-process HTTP endpoint that keeps the webhooks posted to it. It can be
+//! told to fail the first requests, to exercise the retries.
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_std::net::TcpListener;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use tide::{Request, StatusCode};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Received {
+    pub id: Option<String>,
+    pub timestamp: Option<String>,
+    pub signature: Option<String>,
+    pub body: String,
+}
+
+impl Received {
+    /// Whether it was signed with `secret`, as a receiver would check it.
+    pub fn signed_with(&self, secret: &str) -> bool {
+        let (timestamp, signature) = match (&self.timestamp, &self.signature) {
+            (Some(timestamp), Some(signature)) => (timestamp, signature),
+            _ => return false,
+        };
+        let signature = match signature
+            .strip_prefix("sha256=")
+            .and_then(|signature| hex::decode(signature).ok())
+        {
+            Some(signature) => signature,
+            None => return false,
+        };
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", timestamp, self.body).as_bytes());
+        mac.verify(&signature).is_ok()
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    received: Vec<Received>,
+    /// Requests still to be answered with 503
+    failures: usize,
+}
+
+pub struct WebhookReceiver {
+    pub address: SocketAddr,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl WebhookReceiver {
+    pub async fn start() -> Self {
+        Self::failing(0).await
+    }
+
+    /// Answer the first `failures` requests with 503.
+    pub async fn failing(failures: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Cannot bind webhook receiver");
+        let address = listener
+            .local_addr()
+            .expect("Cannot get webhook receiver address");
+        let inner = Arc::new(Mutex::new(Inner {
+            received: Vec::new(),
+            failures,
+        }));
+        let mut server = tide::with_state(inner.clone());
+        server.at("/hooks").post(receive);
+        async_std::task::spawn(async move { server.listen(listener).await });
+        Self { address, inner }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/hooks", self.address)
+    }
+
+    /// Every request, once at least `count` arrived.
+    pub async fn wait_requests(&self, count: usize, timeout: Duration) -> Option<Vec<Received>> {
+        let end = Instant::now() + timeout;
+        while Instant::now() < end {
+            let received = self.inner.lock().unwrap().received.clone();
+            if received.len() >= count {
+                return Some(received);
+            }
+            async_std::task::sleep(Duration::from_millis(50)).await;
+        }
+        None
+    }
+}
+
+async fn receive(mut req: Request<Arc<Mutex<Inner>>>) -> tide::Result {
+    let header = |name: &str| req.header(name).map(|values| values.as_str().to_owned());
+    let mut received = Received {
+        id: header("X-Webhook-Id"),
+        timestamp: header("X-Webhook-Timestamp"),
+        signature: header("X-Webhook-Signature"),
+        body: String::new(),
+    };
+    received.body = req.body_string().await?;
+    let mut inner = req.state().lock().unwrap();
+    inner.received.push(received);
+    if inner.failures > 0 {
+        inner.failures -= 1;
+        return Ok(StatusCode::ServiceUnavailable.into());
+    }
+    Ok(StatusCode::NoContent.into())
+}
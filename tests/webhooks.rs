@@ -0,0 +1,164 @@
+use rstest::rstest;
+use std::{sync::Arc, time::Duration};
+
+pub mod utils;
+
+use utils::{configurations, db_container, docker, spawn_app, webhook_receiver, App};
+
+mod webhooks {
+    use super::*;
+
+    use chrono::Utc;
+    use futures::TryStreamExt;
+    use mongodb::bson::{doc, Document};
+    use serde_json::Value;
+    use webhook_receiver::WebhookReceiver;
+    use z2p::configuration::WebhookEndpointSettings;
+
+    const TOKEN: &str = "admin-token";
+    const SECRET: &str = "crm-secret";
+
+    fn endpoint(url: &str, events: &[&str]) -> WebhookEndpointSettings {
+        WebhookEndpointSettings {
+            url: url.to_owned(),
+            secret: SECRET.to_owned().into(),
+            events: events.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    /// Posting to `crm` at `url`, retrying quickly.
+    fn app(db_container: Arc<docker::Container>, url: &str) -> App {
+        let mut cfg = configurations();
+        cfg.application.admin_token = Some(TOKEN.to_owned().into());
+        cfg.webhooks
+            .endpoints
+            .insert("crm".to_owned(), endpoint(url, &[]));
+        cfg.webhooks.poll_interval = Duration::from_millis(100);
+        cfg.webhooks.backoff_base = Duration::from_millis(100);
+        spawn_app(cfg, db_container)
+    }
+
+    async fn subscribe(app: &App, email: &str) {
+        let response = surf::post(format!("http://{}/subscriptions", app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(format!("name=Reader&email={}", email))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(200, response.status());
+    }
+
+    async fn deliveries(app: &App, endpoint: &str) -> (u16, Value) {
+        let mut response = surf::get(format!(
+            "http://{}/admin/webhooks/{}/deliveries",
+            app.address, endpoint
+        ))
+        .header("Authorization", format!("Bearer {}", TOKEN))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+        let body = response.body_string().await.unwrap();
+        (
+            response.status().into(),
+            serde_json::from_str(&body).unwrap_or(Value::String(body)),
+        )
+    }
+
+    /// The log of `crm` once its latest delivery is closed.
+    async fn closed_log(app: &App) -> Value {
+        for _ in 0..100 {
+            let (_, log) = deliveries(app, "crm").await;
+            if log[0]["status"] == "done" || log[0]["status"] == "dead" {
+                return log;
+            }
+            async_std::task::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("The delivery was not closed");
+    }
+
+    #[rstest]
+    async fn new_subscribers_should_be_posted_signed(db_container: Arc<docker::Container>) {
+        let receiver = WebhookReceiver::start().await;
+        let app = app(db_container, &receiver.url());
+
+        subscribe(&app, "antonio@gmail.com").await;
+
+        let received = receiver
+            .wait_requests(1, Duration::from_secs(10))
+            .await
+            .expect("No webhook received");
+        let hook = &received[0];
+        assert!(hook.signed_with(SECRET));
+        assert!(!hook.signed_with("another-secret"));
+        let timestamp: i64 = hook.timestamp.as_ref().unwrap().parse().unwrap();
+        assert!((Utc::now().timestamp() - timestamp).abs() < 60);
+        let event: Value = serde_json::from_str(&hook.body).unwrap();
+        assert_eq!("subscriber.created", event["type"]);
+        assert_eq!(hook.id.as_deref(), event["id"].as_str());
+        assert_eq!("antonio@gmail.com", event["data"]["email"]);
+        assert_eq!("Reader", event["data"]["name"]);
+        let log = closed_log(&app).await;
+        assert_eq!("done", log[0]["status"]);
+        assert_eq!(event["id"], log[0]["event_id"]);
+        assert_eq!(204, log[0]["attempts"][0]["status"]);
+    }
+
+    #[rstest]
+    async fn failed_deliveries_should_be_retried(db_container: Arc<docker::Container>) {
+        let receiver = WebhookReceiver::failing(1).await;
+        let app = app(db_container, &receiver.url());
+
+        subscribe(&app, "antonio@gmail.com").await;
+
+        let received = receiver
+            .wait_requests(2, Duration::from_secs(10))
+            .await
+            .expect("The webhook was not retried");
+        // Same event: the receiver can tell it's a retry
+        assert_eq!(received[0].id, received[1].id);
+        assert!(received[1].signed_with(SECRET));
+        let log = closed_log(&app).await;
+        assert_eq!("done", log[0]["status"]);
+        let attempts = log[0]["attempts"].as_array().unwrap();
+        assert_eq!(2, attempts.len());
+        assert_eq!(503, attempts[0]["status"]);
+        assert!(attempts[0]["error"].is_string());
+        assert_eq!(204, attempts[1]["status"]);
+    }
+
+    #[rstest]
+    async fn endpoints_should_only_get_the_events_they_want(db_container: Arc<docker::Container>) {
+        let mut cfg = configurations();
+        cfg.application.admin_token = Some(TOKEN.to_owned().into());
+        // Nobody consumes the queue: it's looked at directly
+        cfg.webhooks.worker = false;
+        let url = "http://127.0.0.1:9/hooks";
+        cfg.webhooks
+            .endpoints
+            .insert("crm".to_owned(), endpoint(url, &["subscriber.created"]));
+        cfg.webhooks
+            .endpoints
+            .insert("audit".to_owned(), endpoint(url, &["subscriber.deleted"]));
+        let app = spawn_app(cfg, db_container);
+
+        subscribe(&app, "antonio@gmail.com").await;
+
+        let queued: Vec<_> = app
+            .db
+            .collection::<Document>("webhook_deliveries")
+            .find(doc! {}, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(1, queued.len());
+        assert_eq!("crm", queued[0].get_str("endpoint").unwrap());
+        assert_eq!("pending", queued[0].get_str("status").unwrap());
+        let (audit, log) = deliveries(&app, "audit").await;
+        assert_eq!(200, audit);
+        assert_eq!(Some(0), log.as_array().map(Vec::len));
+        let (unknown, _) = deliveries(&app, "billing").await;
+        assert_eq!(404, unknown);
+    }
+}